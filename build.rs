@@ -0,0 +1,23 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // protox compiles the definition, so no protoc is needed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/cloud_zsync.proto");
+
+        let descriptors = protox::compile(["proto/cloud_zsync.proto"], ["proto"])?;
+        tonic_prost_build::configure()
+            .build_client(true)
+            .compile_fds(descriptors)?;
+    }
+
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR")?;
+        cbindgen::generate(&crate_dir)?.write_to_file("include/cloud_zsync.h");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::platform;
+use crate::signature::{ChunkSizes, Diff, SignOptions, Signature};
+
+/// Number and length of samples read from a file.
+const SAMPLE_COUNT: u64 = 64;
+const SAMPLE_LENGTH: u64 = 64 * 1024;
+
+/// Entropy in bits per byte below which patch compression is worth enabling.
+const COMPRESSIBLE_ENTROPY: f64 = 7.0;
+
+/// Smallest average chunk size recommended for scattered edits,
+/// smaller chunks make signatures too large.
+const MIN_RECOMMENDED_AVG_SIZE: u32 = 4 * 1024;
+
+/// How changes since a previous version are spread over the file.
+#[derive(Debug, Clone, Copy)]
+pub struct Locality {
+    /// number of contiguous changed ranges
+    pub ranges: usize,
+
+    /// total length of changed ranges
+    pub changed: u64,
+
+    /// file length
+    pub length: u64,
+
+    /// average chunk size the changes were measured with
+    pub avg_size: u32,
+}
+
+/// Result of a file analysis.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub length: u64,
+
+    /// number of bytes read
+    pub sampled: u64,
+
+    /// Shannon entropy of sampled bytes, bits per byte
+    pub entropy: f64,
+
+    /// share of zero bytes in samples
+    pub zeros: f64,
+
+    /// set if a previous version was given
+    pub locality: Option<Locality>,
+
+    /// recommended chunk sizes
+    pub sizes: ChunkSizes,
+
+    /// true if the data looks compressible
+    pub compress: bool,
+}
+
+impl Locality {
+    /// Returns mean length of a changed range.
+    pub fn mean_range(&self) -> u64 {
+        self.changed / self.ranges.max(1) as u64
+    }
+}
+
+impl Analysis {
+    /// Returns estimated compressed size of data relative to its size, 0..=1.
+    pub fn compression_ratio(&self) -> f64 {
+        self.entropy / 8.0
+    }
+}
+
+/// Samples a file and recommends chunking parameters. Evenly spaced samples
+/// are read, small files are read as a whole. If a signature of a previous
+/// version is given, the file is signed with its chunk sizes to find out
+/// how changes are spread, scattered small changes call for smaller chunks.
+///
+/// # Parameters:
+/// - `path`: file to analyze
+/// - `previous`: signature of a previous version of the file
+///
+/// # Returns:
+/// - `Result<Analysis, Box<dyn Error>>`: analysis or error
+pub fn analyze(path: &Path, previous: Option<&Signature>) -> Result<Analysis, Box<dyn Error>> {
+    let mut file = platform::open_shared(path)?;
+    let length = file.metadata()?.len();
+
+    let mut counts = [0u64; 256];
+    let mut sampled: u64 = 0;
+    let mut sample: Vec<u8> = Vec::new();
+
+    let step = match length > SAMPLE_COUNT * SAMPLE_LENGTH {
+        true => length / SAMPLE_COUNT,
+        false => SAMPLE_LENGTH,
+    };
+
+    let mut offset: u64 = 0;
+    while offset < length {
+        sample.clear();
+        file.seek(SeekFrom::Start(offset))?;
+        (&mut file).take(SAMPLE_LENGTH).read_to_end(&mut sample)?;
+
+        for &byte in &sample {
+            counts[byte as usize] += 1;
+        }
+
+        sampled += sample.len() as u64;
+        offset += step;
+    }
+
+    let entropy = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / sampled as f64;
+            -p * p.log2()
+        })
+        .sum::<f64>();
+
+    let zeros = match sampled {
+        0 => 0.0,
+        sampled => counts[0] as f64 / sampled as f64,
+    };
+
+    let locality = match previous {
+        Some(previous) => Some(locality(path, previous)?),
+        None => None,
+    };
+
+    let mut avg_size = SignOptions::auto_avg_size(length);
+    if let Some(locality) = &locality {
+        let mean_range = locality.mean_range() as u32;
+
+        // Ranges are whole chunks, so ranges of a chunk or two mean small
+        // changes which cost more than they take
+        if locality.ranges > 0 && mean_range < locality.avg_size * 2 {
+            avg_size = (locality.avg_size / 2).clamp(MIN_RECOMMENDED_AVG_SIZE, avg_size);
+        }
+    }
+
+    Ok(Analysis {
+        length,
+        sampled,
+        entropy,
+        zeros,
+        locality,
+        sizes: SignOptions::default().with_avg_size(avg_size).chunk_sizes(),
+        compress: entropy < COMPRESSIBLE_ENTROPY,
+    })
+}
+
+/// Signs the file with chunk sizes of its previous signature
+/// and measures ranges which have to be fetched.
+fn locality(path: &Path, previous: &Signature) -> Result<Locality, Box<dyn Error>> {
+    let sizes = match previous.chunk_sizes() {
+        Some(sizes) => sizes,
+        None => SignOptions::default().chunk_sizes(),
+    };
+
+    let options = SignOptions {
+        min_size: sizes.min_size,
+        avg_size: sizes.avg_size,
+        max_size: sizes.max_size,
+        mmap: true,
+        ..Default::default()
+    };
+
+    let current = Signature::generate_file(path, &options)?;
+
+    Ok(match Diff::new(previous, &current) {
+        Some(diff) => Locality {
+            ranges: diff.insert_ops().len(),
+            changed: diff.fetch_length(),
+            length: current.length(),
+            avg_size: sizes.avg_size,
+        },
+        None => Locality {
+            ranges: 0,
+            changed: 0,
+            length: current.length(),
+            avg_size: sizes.avg_size,
+        },
+    })
+}
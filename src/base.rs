@@ -0,0 +1,44 @@
+use crate::signature::{Diff, Signature};
+
+/// Candidate base file ranked against a target.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    /// index of the candidate in the list given to `rank`
+    pub index: usize,
+
+    /// target bytes which can be copied from the candidate
+    pub shared_length: u64,
+
+    /// target bytes which have to be downloaded
+    pub download_length: u64,
+}
+
+/// Ranks candidate base files by the number of target bytes they share.
+///
+/// # Parameters:
+/// - `target`: target file signature
+/// - `candidates`: signatures of local files
+///
+/// # Returns:
+/// - `Vec<Candidate>`: candidates, the one minimizing downloads first
+pub fn rank(target: &Signature, candidates: &[&Signature]) -> Vec<Candidate> {
+    let mut ranked: Vec<Candidate> = candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| {
+            let shared_length = match Diff::new(candidate, target) {
+                Some(diff) => diff.copy_length(),
+                None => target.length(),
+            };
+
+            Candidate {
+                index,
+                shared_length,
+                download_length: target.length() - shared_length,
+            }
+        })
+        .collect();
+
+    ranked.sort_by_key(|c| (c.download_length, c.index));
+    ranked
+}
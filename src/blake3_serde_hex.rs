@@ -33,7 +33,7 @@ where
             // Implementing From needs wrapper
             match blake3::Hash::from_hex(v) {
                 Ok(hash) => Ok(hash),
-                Err(e) => return Err(de::Error::custom(e)),
+                Err(e) => Err(de::Error::custom(e)),
             }
         }
     }
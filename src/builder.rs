@@ -1,36 +1,1603 @@
-use crate::signature::{InsertOp, Op, Operation};
-use std::collections::HashMap;
+use crate::journal::Journal;
+use crate::metrics;
+use crate::platform;
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+use crate::signature::Diff;
+use crate::signature::{InsertOp, Op, Operation, Signature};
+#[cfg(not(target_arch = "wasm32"))]
+use fs4::fs_std::FileExt;
+use memmap2::Mmap;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::io::{copy, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::OpenOptions;
+use std::io::{self, copy, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use tracing::{debug, warn};
 
-#[derive(Debug, Clone, Copy)]
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Bounds of the source range used as a dictionary for a segment,
+/// the range is four times longer than the segment within the bounds.
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+const DICTIONARY_MIN_LENGTH: u64 = 256 * 1024;
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+const DICTIONARY_MAX_LENGTH: u64 = 8 * 1024 * 1024;
+
+/// zstd window must cover the dictionary and the segment, see `zstd --patch-from`
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+const MAX_WINDOW_LOG: u32 = 27;
+
+/// Length of the parts a whole file is downloaded in, see `download_file`
+#[cfg(not(target_arch = "wasm32"))]
+const DOWNLOAD_PART_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Longest InsertOp a delta is tried for, bsdiff sorts suffixes of the
+/// source range in memory
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+const DELTA_MAX_LENGTH: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Segment {
     at: u64,
-    length: usize,
+    length: u64,
+
+    /// length of zstd compressed data in the diff file, if the segment is compressed
+    compressed_length: Option<u64>,
+
+    /// offset and length of the source file range the segment is compressed against
+    dictionary: Option<(u64, u64)>,
+
+    /// offset and length of the source file range the segment is a delta of
+    delta: Option<(u64, u64)>,
+}
+
+/// How segments of the diff file are compressed.
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+#[derive(Clone, Copy)]
+enum SegmentCompression<'a> {
+    Zstd(i32),
+
+    /// zstd with nearby source file data as a dictionary
+    PatchFrom(i32, &'a [u8], &'a Diff),
+}
+
+/// Segments of the diff file by id. Ordered, so a patch header
+/// serializes the same way every time.
+pub type DiffSchema = BTreeMap<uuid::Uuid, Segment>;
+
+/// Data of InsertOp segments fetched from the target file.
+pub type FetchedSegments = HashMap<uuid::Uuid, Vec<u8>>;
+
+/// Controls how range reads from the target stream are retried.
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`,
+/// the actual pause is picked randomly within that bound (full jitter).
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// total number of attempts per range, including the first one
+    pub attempts: u32,
+
+    /// delay before the first retry
+    pub base_delay: Duration,
+
+    /// upper bound for a single delay
+    pub max_delay: Duration,
+}
+
+/// Range which could not be read from the target stream.
+#[derive(Debug)]
+pub struct FailedRange {
+    pub offset: u64,
+    pub length: u64,
+    pub error: io::Error,
+}
+
+/// Returned when some ranges could not be read after all attempts.
+#[derive(Debug)]
+pub struct FetchError {
+    pub failed: Vec<FailedRange>,
+}
+
+/// Counts bytes written through it.
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+struct CountingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    count: u64,
+}
+
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes and counts bytes written through it, verifies results
+/// which can not be read back, like a file written to stdout.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+    length: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    /// # Parameters:
+    /// - `inner`: writer the data goes to
+    /// - `hasher`: hasher of the expected hash, keyed or plain, see `key::hasher`
+    pub fn new(inner: W, hasher: blake3::Hasher) -> Self {
+        Self {
+            inner,
+            hasher,
+            length: 0,
+        }
+    }
+
+    /// Returns the hash and the length of the data written.
+    pub fn finalize(&self) -> (blake3::Hash, u64) {
+        (self.hasher.finalize(), self.length)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Segment {
+    /// Returns number of bytes the segment takes in the diff file.
+    pub fn stored_length(&self) -> u64 {
+        self.compressed_length.unwrap_or(self.length)
+    }
+
+    /// Returns the source file range the segment is a delta of,
+    /// the op must be replaced with DELTA, see `Diff::convert_to_deltas`.
+    pub fn delta_base(&self) -> Option<(u64, u64)> {
+        self.delta
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns delay before the given retry (starting from 1).
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry - 1).unwrap_or(u32::MAX);
+        let cap = self
+            .base_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+            .as_millis() as u64;
+
+        Duration::from_millis(fastrand::u64(0..=cap))
+    }
+
+    /// Calls `f` until it succeeds, fails with an error which is not
+    /// transient or runs out of attempts. Used for single requests like
+    /// bucket listing pages, ranges are retried by `copy_range_with_retry`.
+    pub fn call<T>(&self, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut retry: u32 = 0;
+
+        loop {
+            match f() {
+                Err(e) if is_transient(&e) && retry + 1 < self.attempts => {
+                    warn!(error = %e, "Request failed, retrying");
+                    retry += 1;
+                    thread::sleep(self.delay(retry));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Failed to read {} range(s):", self.failed.len())?;
+
+        for range in &self.failed {
+            writeln!(
+                f,
+                "[ {:<12}: {:<12} ] {}",
+                range.offset, range.length, range.error
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for FetchError {}
+
+/// Returns true if the error is worth retrying. Readers backed by remote
+/// storage are expected to report throttling (429) and server errors (5xx)
+/// with one of these kinds.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+    )
+}
+
+/// Copies `length` bytes at `offset` from `r` to `w`. A failed read is
+/// retried from the position where it stopped, so nothing is written twice.
+///
+/// # Returns:
+/// - `(u64, Option<io::Error>)`: bytes written and the last error if the range failed.
+fn copy_range_with_retry<R, W>(
+    r: &mut R,
+    w: &mut W,
+    offset: u64,
+    length: u64,
+    policy: &RetryPolicy,
+) -> (u64, Option<io::Error>)
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let mut buf = vec![0u8; (COPY_BUFFER_SIZE as u64).min(length) as usize];
+    let mut done: u64 = 0;
+    let mut retry: u32 = 0;
+
+    loop {
+        let (started, done_before) = (Instant::now(), done);
+
+        debug!(
+            offset = offset + done,
+            length = length - done,
+            attempt = retry + 1,
+            "Range request"
+        );
+
+        let result = (|| -> io::Result<()> {
+            r.seek(SeekFrom::Start(offset + done))?;
+
+            while done < length {
+                let want = (buf.len() as u64).min(length - done) as usize;
+                let read = r.read(&mut buf[..want])?;
+                if read == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+
+                w.write_all(&buf[..read])?;
+                done += read as u64;
+            }
+
+            Ok(())
+        })();
+
+        metrics::record_range(done - done_before, started.elapsed());
+
+        match result {
+            Ok(()) => return (done, None),
+            Err(e) if is_transient(&e) && retry + 1 < policy.attempts => {
+                warn!(offset, length, error = %e, "Range request failed, retrying");
+                metrics::record_retry();
+                retry += 1;
+                thread::sleep(policy.delay(retry));
+            }
+            Err(e) => {
+                metrics::record_failure();
+                return (done, Some(e));
+            }
+        }
+    }
+}
+
+/// Builds local temporary file with segments for InsertOp.
+///
+/// # Parameters:
+/// - `r`: source stream
+/// - `w`: destination stream
+/// - `ops`: InsertOp iterator
+/// - `policy`: retry policy for range reads
+///
+/// # Returns:
+/// - `Result<Segments, Box<dyn Error>>` where Segment represents a segment for InsertOp.
+///   Ranges which failed after all attempts are reported as `FetchError`.
+pub fn build_local_diff_file<'a, R, W, I>(
+    r: &mut R,
+    w: &mut W,
+    ops: I,
+    policy: &RetryPolicy,
+) -> Result<DiffSchema, Box<dyn Error>>
+where
+    R: Read + Seek,
+    W: Write,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    let mut segments: DiffSchema = DiffSchema::new();
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    let mut at: u64 = 0;
+
+    for op in ops {
+        let offset = op.offset();
+        let length = op.length();
+
+        let (written, error) = copy_range_with_retry(r, w, offset, length, policy);
+
+        match error {
+            None => {
+                segments.insert(
+                    op.uuid(),
+                    Segment {
+                        at,
+                        length,
+                        compressed_length: None,
+                        dictionary: None,
+                        delta: None,
+                    },
+                );
+            }
+            Some(error) => failed.push(FailedRange {
+                offset,
+                length,
+                error,
+            }),
+        }
+
+        at += written;
+    }
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(segments)
+}
+
+/// Fetches segments for InsertOp to a diff file recording progress to the
+/// journal, see `build_local_diff_file`. Ops which are already fetched according
+/// to the journal are skipped, the diff file must be positioned at `journal.offset()`.
+/// Progress is recorded up to the first range which fails.
+///
+/// # Parameters:
+/// - `ops`: all InsertOps of the diff, in the same order for every attempt
+///
+/// # Returns:
+/// - `Result<Segments, Box<dyn Error>>` with the segments of all ops, fetched before or now.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn build_local_diff_file_journaled<'a, R, I>(
+    r: &mut R,
+    w: &mut File,
+    ops: I,
+    policy: &RetryPolicy,
+    journal: &mut Journal,
+) -> Result<DiffSchema, Box<dyn Error>>
+where
+    R: Read + Seek,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    let mut segments: DiffSchema = DiffSchema::new();
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    let fetched = journal.applied();
+    let mut at: u64 = 0;
+
+    for (index, op) in ops.into_iter().enumerate() {
+        let offset = op.offset();
+        let length = op.length();
+
+        let (written, error) = match index < fetched {
+            true => (length, None),
+            false => copy_range_with_retry(r, w, offset, length, policy),
+        };
+
+        match error {
+            None => {
+                segments.insert(
+                    op.uuid(),
+                    Segment {
+                        at,
+                        length,
+                        compressed_length: None,
+                        dictionary: None,
+                        delta: None,
+                    },
+                );
+            }
+            Some(error) => failed.push(FailedRange {
+                offset,
+                length,
+                error,
+            }),
+        }
+
+        at += written;
+
+        if index >= fetched && failed.is_empty() {
+            journal.record(w, at)?;
+        }
+    }
+
+    journal.sync(w)?;
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(segments)
+}
+
+/// Copies a whole file in parts read on several threads, used instead of
+/// range requests when most of the target file changed. Each thread reads
+/// its parts through its own stream and writes them at their offsets.
+///
+/// # Parameters:
+/// - `open`: opens a stream of the file, called once per thread
+/// - `destination`: path of the file to write, must exist
+/// - `length`: file length
+/// - `threads`: number of threads
+/// - `policy`: retry policy for part reads
+///
+/// # Returns:
+/// - Parts which failed after all attempts are reported as `FetchError`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn download_file<R, F>(
+    open: F,
+    destination: &Path,
+    length: u64,
+    threads: usize,
+    policy: &RetryPolicy,
+) -> Result<(), Box<dyn Error>>
+where
+    R: Read + Seek,
+    F: Fn() -> io::Result<R> + Sync,
+{
+    let parts = length.div_ceil(DOWNLOAD_PART_SIZE);
+    let next = AtomicU64::new(0);
+    let failed: Mutex<Vec<FailedRange>> = Mutex::new(Vec::new());
+
+    let download = || -> io::Result<()> {
+        let mut r = open()?;
+        let mut w = OpenOptions::new().write(true).open(destination)?;
+
+        loop {
+            let part = next.fetch_add(1, Ordering::SeqCst);
+            if part >= parts {
+                return Ok(());
+            }
+
+            let offset = part * DOWNLOAD_PART_SIZE;
+            let part_length = DOWNLOAD_PART_SIZE.min(length - offset);
+
+            w.seek(SeekFrom::Start(offset))?;
+            if let (_, Some(error)) =
+                copy_range_with_retry(&mut r, &mut w, offset, part_length, policy)
+            {
+                failed.lock().unwrap().push(FailedRange {
+                    offset,
+                    length: part_length,
+                    error,
+                });
+            }
+        }
+    };
+
+    let results: Vec<io::Result<()>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.clamp(1, parts.max(1) as usize))
+            .map(|_| scope.spawn(download))
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("Download thread panicked")))
+            })
+            .collect()
+    });
+
+    for result in results {
+        result?;
+    }
+
+    let failed = failed.into_inner().unwrap();
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(())
+}
+
+/// Overwrites ranges of a local file with the data at the same offsets of
+/// the source stream, see `repair::find_damage`.
+///
+/// # Parameters:
+/// - `r`: source stream
+/// - `w`: file to patch
+/// - `ranges`: `(offset, length)` ranges to copy
+/// - `policy`: retry policy for range reads
+///
+/// # Returns:
+/// - Ranges which failed after all attempts are reported as `FetchError`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn patch_local_file<'a, R, I>(
+    r: &mut R,
+    w: &mut File,
+    ranges: I,
+    policy: &RetryPolicy,
+) -> Result<(), Box<dyn Error>>
+where
+    R: Read + Seek,
+    I: IntoIterator<Item = &'a (u64, u64)>,
+{
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    for &(offset, length) in ranges {
+        w.seek(SeekFrom::Start(offset))?;
+
+        if let (_, Some(error)) = copy_range_with_retry(r, w, offset, length, policy) {
+            failed.push(FailedRange {
+                offset,
+                length,
+                error,
+            });
+        }
+    }
+
+    w.sync_data()?;
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(())
+}
+
+/// Builds local temporary file with zstd compressed segments for InsertOp,
+/// see `build_local_diff_file`. Each segment is compressed separately.
+///
+/// # Parameters:
+/// - `level`: zstd compression level
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+pub fn build_compressed_diff_file<'a, R, W, I>(
+    r: &mut R,
+    w: &mut W,
+    ops: I,
+    policy: &RetryPolicy,
+    level: i32,
+) -> Result<DiffSchema, Box<dyn Error>>
+where
+    R: Read + Seek,
+    W: Write,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    build_diff_file(r, w, ops, policy, SegmentCompression::Zstd(level))
+}
+
+/// Builds local temporary file with zstd compressed segments for InsertOp,
+/// each segment is compressed against the source file data around the place
+/// it takes in the source file, see `Diff::source_position`. This catches
+/// similarity within chunks. The same source file is needed to build the
+/// destination file.
+///
+/// # Parameters:
+/// - `level`: zstd compression level
+/// - `source`: source file contents, `sources[0]` of the diff
+/// - `diff`: diff the ops belong to
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+pub fn build_patch_from_diff_file<'a, R, W, I>(
+    r: &mut R,
+    w: &mut W,
+    ops: I,
+    policy: &RetryPolicy,
+    level: i32,
+    source: &[u8],
+    diff: &Diff,
+) -> Result<DiffSchema, Box<dyn Error>>
+where
+    R: Read + Seek,
+    W: Write,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    build_diff_file(
+        r,
+        w,
+        ops,
+        policy,
+        SegmentCompression::PatchFrom(level, source, diff),
+    )
+}
+
+/// Builds local temporary file with zstd compressed segments for InsertOp,
+/// for each op a bsdiff delta against the source file data around the place
+/// it takes in the source file is tried, see `Diff::source_position`. The
+/// delta is stored if it is smaller than the segment, such ops have to be
+/// replaced with DELTA ops, see `Segment::delta_base`. The same source file
+/// is needed to build the destination file.
+///
+/// # Parameters:
+/// - `level`: zstd compression level
+/// - `source`: source file contents, `sources[0]` of the diff
+/// - `diff`: diff the ops belong to
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+pub fn build_delta_diff_file<'a, R, W, I>(
+    r: &mut R,
+    w: &mut W,
+    ops: I,
+    policy: &RetryPolicy,
+    level: i32,
+    source: &[u8],
+    diff: &Diff,
+) -> Result<DiffSchema, Box<dyn Error>>
+where
+    R: Read + Seek,
+    W: Write,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    let mut segments: DiffSchema = DiffSchema::new();
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    // Reused data is taken from segments as it is, so it can not be a delta
+    let reused: HashSet<uuid::Uuid> = diff.reused_ops().iter().map(|op| op.uuid()).collect();
+
+    let mut at: u64 = 0;
+
+    for op in ops {
+        let offset = op.offset();
+        let length = op.length();
+
+        let mut data: Vec<u8> = Vec::with_capacity(length as usize);
+        if let (_, Some(error)) = copy_range_with_retry(r, &mut data, offset, length, policy) {
+            failed.push(FailedRange {
+                offset,
+                length,
+                error,
+            });
+            continue;
+        }
+
+        let mut stored = zstd::encode_all(data.as_slice(), level)?;
+        let mut delta: Option<(u64, u64)> = None;
+
+        if length <= DELTA_MAX_LENGTH && !reused.contains(&op.uuid()) {
+            let (start, base_length) = source_range(op, length * 2, source.len() as u64, diff);
+
+            if base_length > 0 {
+                let base = &source[start as usize..(start + base_length) as usize];
+
+                let mut patch: Vec<u8> = Vec::new();
+                bsdiff::diff(base, &data, &mut patch)?;
+                let patch = zstd::encode_all(patch.as_slice(), level)?;
+
+                if patch.len() < stored.len() {
+                    stored = patch;
+                    delta = Some((start, base_length));
+                }
+            }
+        }
+
+        w.write_all(&stored)?;
+
+        segments.insert(
+            op.uuid(),
+            Segment {
+                at,
+                length,
+                compressed_length: Some(stored.len() as u64),
+                dictionary: None,
+                delta,
+            },
+        );
+
+        at += stored.len() as u64;
+    }
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(segments)
+}
+
+/// Returns the source range used as a dictionary for an op.
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+fn dictionary_range(op: &InsertOp, source_length: u64, diff: &Diff) -> (u64, u64) {
+    let length = (op.length() * 4).clamp(DICTIONARY_MIN_LENGTH, DICTIONARY_MAX_LENGTH);
+    source_range(op, length, source_length, diff)
+}
+
+/// Returns the source range of at most `length` bytes centred
+/// at the position of an op in the source file.
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+fn source_range(op: &InsertOp, length: u64, source_length: u64, diff: &Diff) -> (u64, u64) {
+    let length = length.min(source_length);
+
+    let center = diff.source_position(op.offset()) + op.length() / 2;
+    let start = center
+        .saturating_sub(length / 2)
+        .min(source_length - length);
+
+    (start, length)
+}
+
+/// Returns zstd window log covering the dictionary and the segment.
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+fn window_log(dictionary_length: u64, length: u64) -> u32 {
+    let total = (dictionary_length + length).max(1).next_power_of_two();
+    total.ilog2().clamp(10, MAX_WINDOW_LOG)
+}
+
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+fn build_diff_file<'a, R, W, I>(
+    r: &mut R,
+    w: &mut W,
+    ops: I,
+    policy: &RetryPolicy,
+    compression: SegmentCompression,
+) -> Result<DiffSchema, Box<dyn Error>>
+where
+    R: Read + Seek,
+    W: Write,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    let mut segments: DiffSchema = DiffSchema::new();
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    let mut at: u64 = 0;
+
+    for op in ops {
+        let offset = op.offset();
+        let length = op.length();
+
+        let (level, dictionary) = match compression {
+            SegmentCompression::Zstd(level) => (level, None),
+            SegmentCompression::PatchFrom(level, source, diff) => {
+                let (start, length) = dictionary_range(op, source.len() as u64, diff);
+                let data = &source[start as usize..(start + length) as usize];
+                (level, Some((start, length, data)))
+            }
+        };
+
+        let mut counter = CountingWriter {
+            inner: &mut *w,
+            count: 0,
+        };
+
+        let mut encoder = match dictionary {
+            Some((_, dictionary_length, data)) => {
+                let mut encoder = zstd::Encoder::with_dictionary(&mut counter, level, data)?;
+                encoder.window_log(window_log(dictionary_length, length))?;
+                encoder
+            }
+            None => zstd::Encoder::new(&mut counter, level)?,
+        };
+
+        let (_, error) = copy_range_with_retry(r, &mut encoder, offset, length, policy);
+        encoder.finish()?;
+
+        let (stored, compressed_length, error) = (counter.count, Some(counter.count), error);
+        let dictionary = dictionary.map(|(start, length, _)| (start, length));
+
+        match error {
+            None => {
+                segments.insert(
+                    op.uuid(),
+                    Segment {
+                        at,
+                        length,
+                        compressed_length,
+                        dictionary,
+                        delta: None,
+                    },
+                );
+            }
+            Some(error) => failed.push(FailedRange {
+                offset,
+                length,
+                error,
+            }),
+        }
+
+        at += stored;
+    }
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(segments)
+}
+
+/// Reads segments for InsertOp into memory.
+///
+/// # Parameters:
+/// - `r`: target stream
+/// - `ops`: InsertOp iterator
+/// - `policy`: retry policy for range reads
+///
+/// # Returns:
+/// - `Result<FetchedSegments, Box<dyn Error>>`: segment data by op id.
+///   Ranges which failed after all attempts are reported as `FetchError`.
+pub fn fetch_segments<'a, R, I>(
+    r: &mut R,
+    ops: I,
+    policy: &RetryPolicy,
+) -> Result<FetchedSegments, Box<dyn Error>>
+where
+    R: Read + Seek,
+    I: IntoIterator<Item = &'a InsertOp>,
+{
+    let mut segments: FetchedSegments = FetchedSegments::new();
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    for op in ops {
+        let mut data = Vec::with_capacity(op.length() as usize);
+
+        match copy_range_with_retry(r, &mut data, op.offset(), op.length(), policy) {
+            (_, None) => {
+                segments.insert(op.uuid(), data);
+            }
+            (_, Some(error)) => failed.push(FailedRange {
+                offset: op.offset(),
+                length: op.length(),
+                error,
+            }),
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(segments)
+}
+
+/// Reconstructs the target file in memory. Meant for small files,
+/// the whole result is held in memory.
+///
+/// # Parameters:
+/// - `source`: source file contents
+/// - `target`: target file signature, the result is verified against it
+/// - `ops`: operations of the diff between source and target
+/// - `segments`: data for InsertOp, see `fetch_segments`
+///
+/// # Returns:
+/// - `Result<Vec<u8>, Box<dyn Error>>`: target file contents
+pub fn build_in_memory<'a, I>(
+    source: &[u8],
+    target: &Signature,
+    ops: I,
+    segments: &FetchedSegments,
+) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Operation>,
+{
+    let mut result: Vec<u8> = Vec::with_capacity(target.length() as usize);
+
+    for op in ops {
+        let data = match op {
+            Operation::COPY(cp) if cp.source_index() != 0 => {
+                return Err("In-memory build supports a single source".into())
+            }
+            Operation::COPY(cp) => {
+                match memory_range(cp.source_offset(), cp.length()).and_then(|r| source.get(r)) {
+                    Some(data) => data,
+                    None => return Err(format!("Source is too short for {:?}", cp).into()),
+                }
+            }
+            Operation::DELTA(_) => return Err("In-memory build does not support DELTA ops".into()),
+            Operation::ZERO(zero) => {
+                result.resize(result.len() + zero.length() as usize, 0);
+                continue;
+            }
+            Operation::INSERT(ins) => {
+                let data = match segments.get(&ins.uuid()) {
+                    Some(data) => data,
+                    None => return Err(format!("Can not find segment {}", ins.uuid()).into()),
+                };
+
+                match memory_range(ins.segment_offset(), ins.length()).and_then(|r| data.get(r)) {
+                    Some(data) => data,
+                    None => return Err(format!("Segment {} is too short", ins.uuid()).into()),
+                }
+            }
+        };
+
+        result.extend_from_slice(data);
+    }
+
+    if result.len() as u64 != target.length() || blake3::hash(&result) != target.strong_hash() {
+        return Err("Reconstructed data does not match the target signature".into());
+    }
+
+    Ok(result)
+}
+
+/// Returns the range of in-memory data, `None` if it does not fit the address space.
+pub(crate) fn memory_range(offset: u64, length: u64) -> Option<Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(length).ok()?)?;
+
+    Some(start..end)
+}
+
+/// Source of data for COPY ops.
+pub trait CopySource {
+    /// Writes `length` bytes at `offset` to `w`.
+    fn copy_range(&mut self, offset: u64, length: u64, w: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes `length` bytes at `offset` of the source file `index` to `w`.
+    /// A single file has only index 0, see `Seeds` for several files.
+    fn copy_seed_range(
+        &mut self,
+        index: usize,
+        offset: u64,
+        length: u64,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        match index {
+            0 => self.copy_range(offset, length, w),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("No source file with index {}", index),
+            )),
+        }
+    }
+
+    /// Copies `length` bytes at `offset` to the current position of a local
+    /// file inside the kernel. Returns false if the source can not do it,
+    /// the range is written with `copy_range` then.
+    fn copy_range_to_file(
+        &mut self,
+        _offset: u64,
+        _length: u64,
+        _destination: &mut File,
+    ) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// `copy_range_to_file` for the source file `index`.
+    fn copy_seed_range_to_file(
+        &mut self,
+        index: usize,
+        offset: u64,
+        length: u64,
+        destination: &mut File,
+    ) -> io::Result<bool> {
+        match index {
+            0 => self.copy_range_to_file(offset, length, destination),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Several local files COPY ops can take data from: the source file
+/// followed by additional seed files, in the order given to `Diff::new_multi`.
+pub struct Seeds {
+    sources: Vec<Box<dyn CopySource>>,
+}
+
+impl Seeds {
+    pub fn new(sources: Vec<Box<dyn CopySource>>) -> Self {
+        Self { sources }
+    }
 }
 
-pub type DiffSchema = HashMap<uuid::Uuid, Segment>;
+impl CopySource for Seeds {
+    fn copy_range(&mut self, offset: u64, length: u64, w: &mut dyn Write) -> io::Result<()> {
+        self.copy_seed_range(0, offset, length, w)
+    }
 
-/// Builds local temporary file with segments for InsertOp.
+    fn copy_seed_range(
+        &mut self,
+        index: usize,
+        offset: u64,
+        length: u64,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        match self.sources.get_mut(index) {
+            Some(source) => source.copy_range(offset, length, w),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("No source file with index {}", index),
+            )),
+        }
+    }
+
+    fn copy_range_to_file(
+        &mut self,
+        offset: u64,
+        length: u64,
+        destination: &mut File,
+    ) -> io::Result<bool> {
+        self.copy_seed_range_to_file(0, offset, length, destination)
+    }
+
+    fn copy_seed_range_to_file(
+        &mut self,
+        index: usize,
+        offset: u64,
+        length: u64,
+        destination: &mut File,
+    ) -> io::Result<bool> {
+        match self.sources.get_mut(index) {
+            Some(source) => source.copy_range_to_file(offset, length, destination),
+            None => Ok(false),
+        }
+    }
+}
+
+impl<R: Read + Seek> CopySource for R {
+    fn copy_range(&mut self, offset: u64, length: u64, w: &mut dyn Write) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        let mut chunk = Read::by_ref(self).take(length);
+        copy(&mut chunk, w)?;
+
+        Ok(())
+    }
+}
+
+/// Local source file mapped into memory. COPY ops are written straight
+/// from the mapping, without intermediate buffers.
+///
+/// On Linux COPY ops into a local file go through `copy_file_range`: the
+/// data is not read into userspace, btrfs and XFS share the extents (reflink)
+/// when both files are on the same filesystem.
+pub struct MappedSource {
+    file: File,
+    map: Mmap,
+}
+
+impl MappedSource {
+    /// Maps the file at `path`. The file must not be modified while it is mapped.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = platform::open_shared(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+
+        Ok(Self { file, map })
+    }
+}
+
+impl CopySource for MappedSource {
+    fn copy_range(&mut self, offset: u64, length: u64, w: &mut dyn Write) -> io::Result<()> {
+        match memory_range(offset, length).and_then(|r| self.map.get(r)) {
+            Some(data) => w.write_all(data),
+            None => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    // std copies between two files with copy_file_range, falling back
+    // to read+write where the kernel or the filesystems do not support it
+    #[cfg(target_os = "linux")]
+    fn copy_range_to_file(
+        &mut self,
+        offset: u64,
+        length: u64,
+        destination: &mut File,
+    ) -> io::Result<bool> {
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        match copy(&mut Read::by_ref(&mut self.file).take(length), destination)? {
+            copied if copied == length => Ok(true),
+            _ => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Source file and seed files mapped into memory, shared between threads.
+#[cfg(not(target_arch = "wasm32"))]
+struct MemorySources<'a>(&'a [&'a [u8]]);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CopySource for MemorySources<'_> {
+    fn copy_range(&mut self, offset: u64, length: u64, w: &mut dyn Write) -> io::Result<()> {
+        self.copy_seed_range(0, offset, length, w)
+    }
+
+    fn copy_seed_range(
+        &mut self,
+        index: usize,
+        offset: u64,
+        length: u64,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        let source = self.0.get(index).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("No source file with index {}", index),
+            )
+        })?;
+
+        match memory_range(offset, length).and_then(|r| source.get(r)) {
+            Some(data) => w.write_all(data),
+            None => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Writes at increasing offsets of a file shared between threads.
+#[cfg(not(target_arch = "wasm32"))]
+struct PositionedWriter<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Write for PositionedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let written = std::os::unix::fs::FileExt::write_at(self.file, buf, self.offset)?;
+        #[cfg(windows)]
+        let written = std::os::windows::fs::FileExt::seek_write(self.file, buf, self.offset)?;
+
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds destination file from source and diff file.
+pub fn build_local_file<'a, S, R, W, I>(
+    source: &mut S,
+    destination: &mut W,
+    ops: I,
+    diff_file: &mut R,
+    diff_schema: &DiffSchema,
+) -> Result<(), Box<dyn Error>>
+where
+    S: CopySource + ?Sized,
+    R: Read + Seek,
+    W: Write,
+    I: IntoIterator<Item = &'a Operation>,
+{
+    for op in ops {
+        apply_op(op, source, destination, diff_file, diff_schema)?;
+    }
+
+    Ok(())
+}
+
+/// Builds destination file from source and diff file recording progress
+/// to the journal. Ops which are already applied according to the journal
+/// must be skipped by the caller, destination must be positioned at `journal.offset()`
+/// and end there or be preallocated, see `preallocate`. ZERO ops are left as holes,
+/// so sparse files stay sparse, COPY ops are copied inside the kernel if the source
+/// supports it, see `CopySource::copy_range_to_file`.
+pub fn build_local_file_journaled<'a, S, R, I>(
+    source: &mut S,
+    destination: &mut File,
+    ops: I,
+    diff_file: &mut R,
+    diff_schema: &DiffSchema,
+    journal: &mut Journal,
+) -> Result<(), Box<dyn Error>>
+where
+    S: CopySource + ?Sized,
+    R: Read + Seek,
+    I: IntoIterator<Item = &'a Operation>,
+{
+    for op in ops {
+        match op {
+            // Extending the file leaves a hole instead of writing zeros
+            Operation::ZERO(zero) => {
+                let end = destination.seek(SeekFrom::Current(zero.length() as i64))?;
+                if destination.metadata()?.len() < end {
+                    destination.set_len(end)?;
+                }
+            }
+            Operation::COPY(cp)
+                if source.copy_seed_range_to_file(
+                    cp.source_index(),
+                    cp.source_offset(),
+                    cp.length(),
+                    destination,
+                )? => {}
+            _ => apply_op(op, source, destination, diff_file, diff_schema)?,
+        }
+        journal.record(destination, op.offset() + op.length())?;
+    }
+
+    journal.sync(destination)?;
+
+    Ok(())
+}
+
+/// Builds destination file in a single pass, reading INSERT ranges straight
+/// from the target stream instead of staging them in a diff file. INSERT ops
+/// which repeat data of another op read it from the target again. ZERO ops are
+/// left as holes, COPY ops are copied inside the kernel if the source supports it.
 ///
 /// # Parameters:
-/// - `r`: source stream
-/// - `w`: destination stream
+/// - `source`: source files for COPY ops
+/// - `target`: target stream for INSERT ops
+/// - `destination`: empty or preallocated file, see `preallocate`
+/// - `ops`: all ops of the diff in order, DELTA ops need a diff file
+/// - `policy`: retry policy for range reads
+///
+/// # Returns:
+/// - Ranges which failed after all attempts are reported as `FetchError`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn build_local_file_streaming<'a, S, R, I>(
+    source: &mut S,
+    target: &mut R,
+    destination: &mut File,
+    ops: I,
+    policy: &RetryPolicy,
+) -> Result<(), Box<dyn Error>>
+where
+    S: CopySource + ?Sized,
+    R: Read + Seek,
+    I: IntoIterator<Item = &'a Operation>,
+{
+    let mut failed: Vec<FailedRange> = Vec::new();
+
+    for op in ops {
+        match op {
+            Operation::INSERT(ins) => {
+                let (_, error) =
+                    copy_range_with_retry(target, destination, ins.offset(), ins.length(), policy);
+
+                if let Some(error) = error {
+                    failed.push(FailedRange {
+                        offset: ins.offset(),
+                        length: ins.length(),
+                        error,
+                    });
+                    destination.seek(SeekFrom::Start(ins.offset() + ins.length()))?;
+                }
+            }
+            Operation::ZERO(zero) => {
+                let end = destination.seek(SeekFrom::Current(zero.length() as i64))?;
+                if destination.metadata()?.len() < end {
+                    destination.set_len(end)?;
+                }
+            }
+            Operation::COPY(cp)
+                if source.copy_seed_range_to_file(
+                    cp.source_index(),
+                    cp.source_offset(),
+                    cp.length(),
+                    destination,
+                )? => {}
+            Operation::COPY(cp) => {
+                source.copy_seed_range(
+                    cp.source_index(),
+                    cp.source_offset(),
+                    cp.length(),
+                    destination,
+                )?;
+            }
+            Operation::DELTA(delta) => {
+                return Err(format!("DELTA op {} needs a diff file", delta.uuid()).into())
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
+    }
+
+    Ok(())
+}
+
+/// Builds destination file applying ops on several threads. The offset of
+/// every op in the target file is known, so each one is written there with
+/// positioned writes, independently of the others. ZERO ops are left as holes.
+///
+/// # Parameters:
+/// - `sources`: source file followed by additional seed files, mapped into memory
+/// - `destination`: file of the target length, see `preallocate`
+/// - `ops`: all ops of the diff
+/// - `diff_file`: diff file mapped into memory
+/// - `diff_schema`: segments of the diff file
+/// - `jobs`: number of threads
+#[cfg(not(target_arch = "wasm32"))]
+pub fn build_local_file_parallel(
+    sources: &[&[u8]],
+    destination: &File,
+    ops: &[Operation],
+    diff_file: &[u8],
+    diff_schema: &DiffSchema,
+    jobs: usize,
+) -> Result<(), Box<dyn Error>> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+
+    // Box<dyn Error> is not Send, errors are converted on the threads
+    let result: Result<(), String> = pool.install(|| {
+        ops.par_iter()
+            .filter(|op| !matches!(op, Operation::ZERO(_)))
+            .try_for_each(|op| {
+                let mut w = PositionedWriter {
+                    file: destination,
+                    offset: op.offset(),
+                };
+                let mut diff_file = io::Cursor::new(diff_file);
+
+                apply_op(
+                    op,
+                    &mut MemorySources(sources),
+                    &mut w,
+                    &mut diff_file,
+                    diff_schema,
+                )
+                .map_err(|e| e.to_string())
+            })
+    });
+
+    Ok(result?)
+}
+
+/// Reserves disk space for a file being built, so that the build fails right
+/// away rather than midway if the disk is full. Sets the file length to `length`.
+///
+/// # Parameters:
+/// - `path`: path of the destination
+/// - `destination`: destination file
+/// - `length`: target file length
+/// - `holes`: bytes left as holes, the space is only checked then since
+///   allocating it would make the file dense
+#[cfg(not(target_arch = "wasm32"))]
+pub fn preallocate(
+    path: &Path,
+    destination: &File,
+    length: u64,
+    holes: u64,
+) -> Result<(), Box<dyn Error>> {
+    let no_space = || format!("Not enough disk space for {}", path.display());
+
+    if holes > 0 {
+        let written = destination.metadata()?.len();
+        if fs4::available_space(path)? < length.saturating_sub(holes + written) {
+            return Err(no_space().into());
+        }
+        return Ok(());
+    }
+
+    match destination.allocate(length) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::StorageFull => Err(no_space().into()),
+        // The file is extended as it is written
+        Err(e) if e.kind() == ErrorKind::Unsupported => {
+            debug!("Can not preallocate {}: {}", path.display(), e);
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes a single op to the destination.
+fn apply_op<S, R, W>(
+    op: &Operation,
+    source: &mut S,
+    destination: &mut W,
+    diff_file: &mut R,
+    diff_schema: &DiffSchema,
+) -> Result<(), Box<dyn Error>>
+where
+    S: CopySource + ?Sized,
+    R: Read + Seek,
+    W: Write,
+{
+    match op {
+        Operation::ZERO(zero) => {
+            copy(&mut io::repeat(0).take(zero.length()), destination)?;
+        }
+        Operation::COPY(cp) => {
+            source.copy_seed_range(
+                cp.source_index(),
+                cp.source_offset(),
+                cp.length(),
+                destination,
+            )?;
+        }
+        Operation::DELTA(delta) => {
+            let segment = match diff_schema.get(&delta.uuid()) {
+                Some(s) => s,
+                None => return Err(format!("Can not find segment {}", delta.uuid()).into()),
+            };
+
+            let compressed_length = match segment.compressed_length {
+                Some(compressed_length) => compressed_length,
+                None => return Err(format!("Segment {} is not a delta", delta.uuid()).into()),
+            };
+
+            let mut base: Vec<u8> = Vec::with_capacity(delta.source_length() as usize);
+            source.copy_seed_range(0, delta.source_offset(), delta.source_length(), &mut base)?;
+
+            diff_file.seek(SeekFrom::Start(segment.at))?;
+            let stored = BufReader::new(diff_file.take(compressed_length));
+            let mut patch = segment_decoder(stored, None)?;
+
+            let mut data: Vec<u8> = Vec::with_capacity(delta.length() as usize);
+            bsdiff::patch(&base, &mut patch, &mut data)?;
+
+            if data.len() as u64 != delta.length() {
+                return Err(format!("Segment {} is corrupted", delta.uuid()).into());
+            }
+
+            destination.write_all(&data)?;
+        }
+        Operation::INSERT(ins) => {
+            let segment = match diff_schema.get(&ins.uuid()) {
+                Some(s) => s,
+                None => return Err(format!("Can not find segment {}", ins.uuid()).into()),
+            };
+
+            if ins.segment_offset() + ins.length() > segment.length {
+                return Err(format!("Segment {} is too short", ins.uuid()).into());
+            }
+
+            match segment.compressed_length {
+                Some(compressed_length) => {
+                    diff_file.seek(SeekFrom::Start(segment.at))?;
+                    let stored = BufReader::new(diff_file.take(compressed_length));
+
+                    let mut dictionary: Vec<u8> = Vec::new();
+                    if let Some((offset, length)) = segment.dictionary {
+                        dictionary.reserve(length as usize);
+                        source.copy_seed_range(0, offset, length, &mut dictionary)?;
+                    }
+
+                    let mut decoder =
+                        segment_decoder(stored, segment.dictionary.map(|_| dictionary.as_slice()))?;
+
+                    // Ops reusing the segment start in the middle of it
+                    copy(
+                        &mut (&mut decoder).take(ins.segment_offset()),
+                        &mut io::sink(),
+                    )?;
+
+                    let mut chunk = decoder.take(ins.length());
+                    if copy(&mut chunk, destination)? != ins.length() {
+                        return Err(format!("Segment {} is corrupted", ins.uuid()).into());
+                    }
+                }
+                None => {
+                    diff_file.seek(SeekFrom::Start(segment.at + ins.segment_offset()))?;
+                    let mut chunk = diff_file.take(ins.length());
+                    copy(&mut chunk, destination)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Async variant of `copy_range_with_retry`.
+#[cfg(feature = "async")]
+async fn copy_range_with_retry_async<R, W>(
+    r: &mut R,
+    w: &mut W,
+    offset: u64,
+    length: u64,
+    policy: &RetryPolicy,
+) -> (u64, Option<io::Error>)
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    let mut buf = vec![0u8; (COPY_BUFFER_SIZE as u64).min(length) as usize];
+    let mut done: u64 = 0;
+    let mut retry: u32 = 0;
+
+    loop {
+        let (started, done_before) = (Instant::now(), done);
+
+        debug!(
+            offset = offset + done,
+            length = length - done,
+            attempt = retry + 1,
+            "Range request"
+        );
+
+        let result = async {
+            r.seek(SeekFrom::Start(offset + done)).await?;
+
+            while done < length {
+                let want = (buf.len() as u64).min(length - done) as usize;
+                let read = r.read(&mut buf[..want]).await?;
+                if read == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+
+                w.write_all(&buf[..read]).await?;
+                done += read as u64;
+            }
+
+            Ok::<(), io::Error>(())
+        }
+        .await;
+
+        metrics::record_range(done - done_before, started.elapsed());
+
+        match result {
+            Ok(()) => return (done, None),
+            Err(e) if is_transient(&e) && retry + 1 < policy.attempts => {
+                warn!(offset, length, error = %e, "Range request failed, retrying");
+                metrics::record_retry();
+                retry += 1;
+                tokio::time::sleep(policy.delay(retry)).await;
+            }
+            Err(e) => {
+                metrics::record_failure();
+                return (done, Some(e));
+            }
+        }
+    }
+}
+
+/// Async variant of `build_local_diff_file`, for the target file behind
+/// an async client. Ranges are fetched one after another, several diff
+/// files can be built concurrently.
+///
+/// # Parameters:
+/// - `r`: target stream
+/// - `w`: diff file stream
 /// - `ops`: InsertOp iterator
+/// - `policy`: retry policy for range reads
 ///
 /// # Returns:
 /// - `Result<Segments, Box<dyn Error>>` where Segment represents a segment for InsertOp.
-pub fn build_local_diff_file<'a, R, W, I>(
+///   Ranges which failed after all attempts are reported as `FetchError`.
+#[cfg(feature = "async")]
+pub async fn build_local_diff_file_async<'a, R, W, I>(
     r: &mut R,
     w: &mut W,
     ops: I,
+    policy: &RetryPolicy,
 ) -> Result<DiffSchema, Box<dyn Error>>
 where
-    R: Read + Seek,
-    W: Write,
+    R: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
     I: IntoIterator<Item = &'a InsertOp>,
 {
+    use tokio::io::AsyncWriteExt;
+
     let mut segments: DiffSchema = DiffSchema::new();
+    let mut failed: Vec<FailedRange> = Vec::new();
 
     let mut at: u64 = 0;
 
@@ -38,37 +1605,107 @@ where
         let offset = op.offset();
         let length = op.length();
 
-        r.seek(SeekFrom::Start(offset))?;
-        let mut chunk = r.take(length as u64);
-        copy(&mut chunk, w)?;
+        let (written, error) = copy_range_with_retry_async(r, w, offset, length, policy).await;
+
+        match error {
+            None => {
+                segments.insert(
+                    op.uuid(),
+                    Segment {
+                        at,
+                        length,
+                        compressed_length: None,
+                        dictionary: None,
+                        delta: None,
+                    },
+                );
+            }
+            Some(error) => failed.push(FailedRange {
+                offset,
+                length,
+                error,
+            }),
+        }
 
-        segments.insert(op.uuid(), Segment { at, length });
+        at += written;
+    }
+
+    w.flush().await?;
 
-        at += length as u64;
+    if !failed.is_empty() {
+        return Err(FetchError { failed }.into());
     }
 
     Ok(segments)
 }
 
-/// Builds destination file from source and diff file.
-pub fn build_local_file<'a, R, W, I>(
-    source: &mut R,
+/// Async variant of `build_local_file`. COPY ops take data from the source
+/// file only, compressed and delta segments are decoded in memory.
+///
+/// # Parameters:
+/// - `source`: source file stream
+/// - `destination`: new file stream
+/// - `ops`: all ops of the diff in order
+/// - `diff_file`: diff file stream
+/// - `diff_schema`: segments of the diff file
+#[cfg(feature = "async")]
+pub async fn build_local_file_async<'a, S, R, W, I>(
+    source: &mut S,
     destination: &mut W,
     ops: I,
     diff_file: &mut R,
     diff_schema: &DiffSchema,
 ) -> Result<(), Box<dyn Error>>
 where
-    R: Read + Seek,
-    W: Write,
+    S: AsyncRead + AsyncSeek + Unpin,
+    R: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
     I: IntoIterator<Item = &'a Operation>,
 {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
     for op in ops {
         match op {
+            Operation::ZERO(zero) => {
+                tokio::io::copy(&mut tokio::io::repeat(0).take(zero.length()), destination).await?;
+            }
+            Operation::COPY(cp) if cp.source_index() != 0 => {
+                return Err(format!("No source file with index {}", cp.source_index()).into());
+            }
             Operation::COPY(cp) => {
-                source.seek(SeekFrom::Start(cp.source_offset() as u64))?;
-                let mut chunk = source.take(cp.length() as u64);
-                copy(&mut chunk, destination)?;
+                source.seek(SeekFrom::Start(cp.source_offset())).await?;
+                let mut chunk = (&mut *source).take(cp.length());
+                if tokio::io::copy(&mut chunk, destination).await? != cp.length() {
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                }
+            }
+            Operation::DELTA(delta) => {
+                let segment = match diff_schema.get(&delta.uuid()) {
+                    Some(s) => s,
+                    None => return Err(format!("Can not find segment {}", delta.uuid()).into()),
+                };
+
+                let compressed_length = match segment.compressed_length {
+                    Some(compressed_length) => compressed_length,
+                    None => return Err(format!("Segment {} is not a delta", delta.uuid()).into()),
+                };
+
+                let base =
+                    read_range_async(source, delta.source_offset(), delta.source_length()).await?;
+                let stored = read_range_async(diff_file, segment.at, compressed_length).await?;
+
+                let mut data: Vec<u8> = Vec::with_capacity(delta.length() as usize);
+                bsdiff::patch(
+                    &base,
+                    &mut segment_decoder(stored.as_slice(), None)?,
+                    &mut data,
+                )?;
+
+                if data.len() as u64 != delta.length() {
+                    return Err(format!("Segment {} is corrupted", delta.uuid()).into());
+                }
+
+                destination.write_all(&data).await?;
             }
             Operation::INSERT(ins) => {
                 let segment = match diff_schema.get(&ins.uuid()) {
@@ -76,12 +1713,227 @@ where
                     None => return Err(format!("Can not find segment {}", ins.uuid()).into()),
                 };
 
-                diff_file.seek(SeekFrom::Start(segment.at))?;
-                let mut chunk = diff_file.take(segment.length as u64);
-                copy(&mut chunk, destination)?;
+                if ins.segment_offset() + ins.length() > segment.length {
+                    return Err(format!("Segment {} is too short", ins.uuid()).into());
+                }
+
+                match segment.compressed_length {
+                    Some(compressed_length) => {
+                        let stored =
+                            read_range_async(diff_file, segment.at, compressed_length).await?;
+                        let dictionary = match segment.dictionary {
+                            Some((offset, length)) => {
+                                Some(read_range_async(source, offset, length).await?)
+                            }
+                            None => None,
+                        };
+
+                        // The decoder is not Send, it is dropped before the next await
+                        let data = {
+                            let mut decoder =
+                                segment_decoder(stored.as_slice(), dictionary.as_deref())?;
+
+                            // Ops reusing the segment start in the middle of it
+                            copy(
+                                &mut (&mut decoder).take(ins.segment_offset()),
+                                &mut io::sink(),
+                            )?;
+
+                            let mut data: Vec<u8> = Vec::with_capacity(ins.length() as usize);
+                            decoder.take(ins.length()).read_to_end(&mut data)?;
+                            data
+                        };
+
+                        if data.len() as u64 != ins.length() {
+                            return Err(format!("Segment {} is corrupted", ins.uuid()).into());
+                        }
+
+                        destination.write_all(&data).await?;
+                    }
+                    None => {
+                        diff_file
+                            .seek(SeekFrom::Start(segment.at + ins.segment_offset()))
+                            .await?;
+                        let mut chunk = (&mut *diff_file).take(ins.length());
+                        tokio::io::copy(&mut chunk, destination).await?;
+                    }
+                }
             }
         }
     }
 
+    destination.flush().await?;
+
     Ok(())
 }
+
+/// Reads `length` bytes at `offset` into memory.
+#[cfg(feature = "async")]
+async fn read_range_async<R>(r: &mut R, offset: u64, length: u64) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    r.seek(SeekFrom::Start(offset)).await?;
+
+    let mut data: Vec<u8> = Vec::with_capacity(length as usize);
+    (&mut *r).take(length).read_to_end(&mut data).await?;
+
+    match data.len() as u64 == length {
+        true => Ok(data),
+        false => Err(ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Returns the reader of a zstd compressed segment.
+///
+/// # Parameters:
+/// - `stored`: compressed segment data
+/// - `dictionary`: source file range the segment is compressed against, if any
+#[cfg(all(not(target_arch = "wasm32"), feature = "zstd"))]
+fn segment_decoder<'a, R: BufRead + 'a>(
+    stored: R,
+    dictionary: Option<&'a [u8]>,
+) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    match dictionary {
+        Some(dictionary) => {
+            let mut decoder = zstd::Decoder::with_dictionary(stored, dictionary)?;
+            decoder.window_log_max(MAX_WINDOW_LOG)?;
+            Ok(Box::new(decoder))
+        }
+        None => Ok(Box::new(zstd::Decoder::with_buffer(stored)?)),
+    }
+}
+
+/// zstd is not built for the browser or without the zstd feature, diffs
+/// with compressed segments can not be applied there.
+#[cfg(any(target_arch = "wasm32", not(feature = "zstd")))]
+fn segment_decoder<'a, R: BufRead + 'a>(
+    _stored: R,
+    _dictionary: Option<&'a [u8]>,
+) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    Err("Compressed segments are not supported in this build, it has no zstd".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{self, Journal};
+    use crate::test_util::random_data;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    /// Creates a sparse file of the given length with data at an offset.
+    fn sparse_file(path: &Path, length: u64, offset: u64, data: &[u8]) {
+        let mut file = File::create(path).unwrap();
+        file.set_len(length).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn build_writes_files_over_4_gib() {
+        let dir = tempfile::tempdir().unwrap();
+        let (copied, inserted) = (random_data(1, 4096), random_data(2, 4096));
+        let (zeros, copy_at, insert_at) = (4 * GIB + 512, 4 * GIB + 512, 4 * GIB + 4608);
+        let length = insert_at + 4096;
+
+        sparse_file(&dir.path().join("source"), 5 * GIB, 4 * GIB + 100, &copied);
+        sparse_file(&dir.path().join("target"), length, insert_at, &inserted);
+
+        let operations: Vec<Operation> = serde_json::from_value(serde_json::json!([
+            {"ZERO": {"offset": 0, "length": zeros}},
+            {"COPY": {"source_index": 0, "source_offset": 4 * GIB + 100, "offset": copy_at, "length": 4096}},
+            {"INSERT": {"offset": insert_at, "length": 4096, "uuid": uuid::Uuid::nil(), "segment_offset": 0}},
+        ]))
+        .unwrap();
+        let insert_ops: Vec<InsertOp> = operations
+            .iter()
+            .filter_map(|op| match op {
+                Operation::INSERT(op) => Some(*op),
+                _ => None,
+            })
+            .collect();
+
+        let mut diff_file = tempfile::tempfile().unwrap();
+        let schema = build_local_diff_file(
+            &mut File::open(dir.path().join("target")).unwrap(),
+            &mut diff_file,
+            &insert_ops,
+            &RetryPolicy::default(),
+        )
+        .unwrap();
+
+        let destination_path = dir.path().join("destination");
+        let mut destination = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&destination_path)
+            .unwrap();
+        let header = journal::Header::new(blake3::hash(b"target"), operations.len());
+        let mut journal = Journal::create(&dir.path().join("journal"), &header).unwrap();
+
+        build_local_file_journaled(
+            &mut MappedSource::open(&dir.path().join("source")).unwrap(),
+            &mut destination,
+            &operations,
+            &mut diff_file,
+            &schema,
+            &mut journal,
+        )
+        .unwrap();
+
+        assert_eq!(journal.offset(), length);
+        assert_eq!(destination.metadata().unwrap().len(), length);
+
+        let mut data = vec![0u8; 8192];
+        destination.seek(SeekFrom::Start(copy_at)).unwrap();
+        destination.read_exact(&mut data).unwrap();
+        assert_eq!(&data[..4096], &copied[..]);
+        assert_eq!(&data[4096..], &inserted[..]);
+    }
+
+    #[test]
+    fn memory_range_rejects_ranges_outside_the_address_space() {
+        assert_eq!(memory_range(10, 5), Some(10..15));
+        assert_eq!(memory_range(u64::MAX, 1), None);
+    }
+
+    #[test]
+    fn call_retries_transient_errors_only() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+
+        let mut calls = 0;
+        let result = policy.call(|| {
+            calls += 1;
+            match calls {
+                1 | 2 => Err(io::Error::from(ErrorKind::ConnectionReset)),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: io::Result<()> = policy.call(|| {
+            calls += 1;
+            Err(io::Error::from(ErrorKind::ConnectionAborted))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: io::Result<()> = policy.call(|| {
+            calls += 1;
+            Err(io::Error::from(ErrorKind::NotFound))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::blake3_serde_hex;
+use crate::platform;
+use crate::signature::SignOptions;
+
+/// What the signature of a file was generated from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    /// file size in bytes
+    size: u64,
+
+    /// modification time, nanoseconds since unix epoch
+    mtime: u128,
+
+    /// options which affect signature contents
+    options: String,
+
+    /// hash of the signature file contents
+    #[serde(with = "blake3_serde_hex")]
+    signature_hash: blake3::Hash,
+}
+
+/// Options of `sign` which change signature contents besides `SignOptions`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignModes {
+    /// chunk change times are tracked
+    pub track_changes: bool,
+
+    /// compressed files are signed decompressed
+    pub decompress: bool,
+
+    /// file metadata is recorded
+    pub metadata: bool,
+}
+
+/// Cache of file metadata used to skip files which weren't modified
+/// since their signatures were generated.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SignCache {
+    /// entries by canonical file path, see `platform::escape_path`
+    entries: HashMap<String, Entry>,
+}
+
+impl SignCache {
+    /// Loads cache from a file. Missing file means empty cache.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves cache to a file.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(path)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns true if the signature of a file is up to date: the file has the
+    /// same size and mtime, options are the same and the signature file is intact.
+    ///
+    /// # Parameters:
+    /// - `file`: signed file
+    /// - `signature`: signature file path
+    /// - `options`: options the signature would be generated with
+    /// - `modes`: other options of `sign` the signature would be generated with
+    pub fn is_fresh(
+        &self,
+        file: &Path,
+        signature: &Path,
+        options: &SignOptions,
+        modes: SignModes,
+    ) -> bool {
+        let cached = match self.entries.get(&Self::key(file)) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        match Self::entry(file, signature, options, modes) {
+            Ok(current) => current == *cached,
+            Err(_) => false,
+        }
+    }
+
+    /// Records the current state of a file and its signature.
+    pub fn update(
+        &mut self,
+        file: &Path,
+        signature: &Path,
+        options: &SignOptions,
+        modes: SignModes,
+    ) -> Result<(), Box<dyn Error>> {
+        let key = Self::key(file);
+        let entry = Self::entry(file, signature, options, modes)?;
+        self.entries.insert(key, entry);
+
+        Ok(())
+    }
+
+    fn key(file: &Path) -> String {
+        platform::escape_path(&fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()))
+    }
+
+    fn entry(
+        file: &Path,
+        signature: &Path,
+        options: &SignOptions,
+        modes: SignModes,
+    ) -> Result<Entry, Box<dyn Error>> {
+        let metadata = fs::metadata(file)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+
+        Ok(Entry {
+            size: metadata.len(),
+            mtime,
+            options: format!(
+                "{}/{}/{}/{}/{}/{}/{:?}/{}/{}/{}/{}/{}/{}",
+                options.min_size,
+                options.avg_size,
+                options.max_size,
+                options.crc32c,
+                options.md5,
+                options.block_size,
+                options.format,
+                options.key_id().unwrap_or_default(),
+                options.hash_length,
+                options.implicit_offsets,
+                modes.track_changes,
+                modes.decompress,
+                modes.metadata
+            ),
+            signature_hash: blake3::hash(&fs::read(signature)?),
+        })
+    }
+}
@@ -0,0 +1,249 @@
+use memmap2::Mmap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::key::{self, HashKey};
+use crate::naming::NamingStrategy;
+use crate::signature::{SignOptions, Signature};
+
+const CHUNKS_DIR: &str = "chunks";
+const MANIFESTS_DIR: &str = "manifests";
+
+/// Content-addressable chunk repository in a local directory. Chunks are
+/// stored once by their blake3 hash, so chunks shared between files take
+/// space only once. File signatures serve as manifests: they list file
+/// chunks in order and are named by the hash of the file with the
+/// signature template.
+pub struct ChunkStore {
+    root: PathBuf,
+    naming: NamingStrategy,
+}
+
+/// Result of a file ingestion.
+#[derive(Debug, Clone)]
+pub struct Ingested {
+    /// path of the manifest in the repository
+    pub manifest: PathBuf,
+
+    /// length of the file
+    pub length: u64,
+
+    /// bytes of chunks which were not in the repository before
+    pub stored: u64,
+}
+
+/// Result of a garbage collection.
+#[derive(Debug, Clone, Default)]
+pub struct Collected {
+    /// number of unreferenced chunks
+    pub chunks: usize,
+
+    /// total length of unreferenced chunks
+    pub bytes: u64,
+}
+
+impl ChunkStore {
+    /// Opens the repository creating it if it does not exist.
+    pub fn open(root: &Path, naming: NamingStrategy) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(root.join(CHUNKS_DIR))?;
+        fs::create_dir_all(root.join(MANIFESTS_DIR))?;
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            naming,
+        })
+    }
+
+    /// Returns true if the chunk is in the repository.
+    pub fn has(&self, hash: &blake3::Hash) -> bool {
+        self.chunk_path(hash).exists()
+    }
+
+    /// Adds a chunk to the repository.
+    ///
+    /// # Returns:
+    /// - `Result<bool, Box<dyn Error>>`: true if the chunk was not stored before
+    pub fn put(&self, hash: &blake3::Hash, data: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            return Ok(false);
+        }
+
+        let dir = path.parent().expect("chunk path has a parent");
+        fs::create_dir_all(dir)?;
+
+        // Chunk appears under its name only when it is complete
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(data)?;
+        file.persist(&path)?;
+
+        Ok(true)
+    }
+
+    /// Reads a chunk verifying its contents with the hash settings of the
+    /// manifest listing it.
+    pub fn get(
+        &self,
+        hash: &blake3::Hash,
+        manifest: &Signature,
+        key: Option<&HashKey>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data = fs::read(self.chunk_path(hash))?;
+
+        if manifest.keyed_chunk_hash(key, &data) != *hash {
+            return Err(format!("Chunk {} is corrupted", hash).into());
+        }
+
+        Ok(data)
+    }
+
+    /// Splits a file into chunks, adds missing chunks to the repository
+    /// and saves the file signature as its manifest.
+    ///
+    /// # Parameters:
+    /// - `path`: path to a local file
+    /// - `options`: chunking parameters
+    pub fn ingest(&self, path: &Path, options: &SignOptions) -> Result<Ingested, Box<dyn Error>> {
+        let file = File::open(path)?;
+        // The file must not be modified while it is being ingested
+        let map = unsafe { Mmap::map(&file)? };
+
+        let sig = Signature::generate_file(path, options)?;
+        let mut stored: u64 = 0;
+
+        for chunk in sig.chunks() {
+            let start = chunk.offset() as usize;
+            let data = match map.get(start..start + chunk.length() as usize) {
+                Some(data) => data,
+                None => return Err(format!("{:?} changed while it was ingested", path).into()),
+            };
+
+            if self.put(&chunk.strong_hash(), data)? {
+                stored += chunk.length();
+            }
+        }
+
+        let manifest = self.naming.signature_path(
+            &self
+                .root
+                .join(MANIFESTS_DIR)
+                .join(sig.strong_hash().to_hex().as_str()),
+        )?;
+        fs::write(&manifest, serde_json::to_string_pretty(&sig)?)?;
+
+        Ok(Ingested {
+            manifest,
+            length: sig.length(),
+            stored,
+        })
+    }
+
+    /// Writes the file described by a manifest to `w` and verifies it.
+    ///
+    /// # Parameters:
+    /// - `manifest`: signature of the file
+    /// - `key`: key the manifest was generated with, required for keyed manifests
+    /// - `w`: destination
+    pub fn materialize(
+        &self,
+        manifest: &Signature,
+        key: Option<&HashKey>,
+        w: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        match (manifest.key_id(), key) {
+            (None, None) => {}
+            (Some(key_id), Some(key)) if key::id(key) == key_id => {}
+            (Some(_), None) => return Err("The manifest is keyed, its key is needed".into()),
+            _ => return Err("The key is not the key of the manifest".into()),
+        }
+
+        let mut hasher = key::hasher(key);
+
+        for chunk in manifest.chunks() {
+            let data = self.get(&chunk.strong_hash(), manifest, key)?;
+            hasher.update(&data);
+            w.write_all(&data)?;
+        }
+
+        if hasher.finalize() != manifest.strong_hash() {
+            return Err("Materialized file does not match the manifest".into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns paths of manifests in the repository.
+    pub fn manifests(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut manifests: Vec<PathBuf> = Vec::new();
+
+        for entry in fs::read_dir(self.root.join(MANIFESTS_DIR))? {
+            let path = entry?.path();
+            if self.naming.file_path(&path).is_ok() {
+                manifests.push(path);
+            }
+        }
+
+        Ok(manifests)
+    }
+
+    /// Deletes chunks which are not referenced by any manifest in the
+    /// repository. Removing a manifest file makes its chunks collectable.
+    ///
+    /// # Parameters:
+    /// - `dry_run`: only report unreferenced chunks, keep them
+    ///
+    /// # Returns:
+    /// - `Result<Collected, Box<dyn Error>>`: unreferenced chunks and their size
+    pub fn gc(&self, dry_run: bool) -> Result<Collected, Box<dyn Error>> {
+        let mut live: HashSet<blake3::Hash> = HashSet::new();
+
+        for path in self.manifests()? {
+            let manifest: Signature =
+                serde_json::from_reader(BufReader::new(File::open(&path)?))
+                    .map_err(|e| format!("Can not read manifest {:?}: {}", path, e))?;
+
+            live.extend(manifest.chunks().iter().map(|c| c.strong_hash()));
+        }
+
+        let mut collected = Collected::default();
+
+        for prefix in fs::read_dir(self.root.join(CHUNKS_DIR))? {
+            for prefix in fs::read_dir(prefix?.path())? {
+                for entry in fs::read_dir(prefix?.path())? {
+                    let entry = entry?;
+
+                    // Temporary files of chunks being written are not chunks yet
+                    let hash = match entry.file_name().to_str().map(blake3::Hash::from_hex) {
+                        Some(Ok(hash)) => hash,
+                        _ => continue,
+                    };
+
+                    if live.contains(&hash) {
+                        continue;
+                    }
+
+                    collected.chunks += 1;
+                    collected.bytes += entry.metadata()?.len();
+
+                    if !dry_run {
+                        fs::remove_file(entry.path())?;
+                    }
+                }
+            }
+        }
+
+        Ok(collected)
+    }
+
+    fn chunk_path(&self, hash: &blake3::Hash) -> PathBuf {
+        let hex = hash.to_hex();
+        self.root
+            .join(CHUNKS_DIR)
+            .join(&hex[..2])
+            .join(&hex[2..4])
+            .join(hex.as_str())
+    }
+}
@@ -0,0 +1,497 @@
+use fastcdc::v2020::FastCDC;
+use memmap2::Mmap;
+use sha2::{Digest, Sha256, Sha512_256};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::http;
+use crate::signature::SignOptions;
+
+/// Record types of casync files, see caformat.h
+const CA_FORMAT_INDEX: u64 = 0x9682_4d9c_7b12_9ff9;
+const CA_FORMAT_TABLE: u64 = 0xe75b_9e11_2f17_417d;
+const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f_050e_5549_ecd1;
+
+/// Feature flag of indexes with SHA512/256 chunk IDs, SHA256 otherwise
+const CA_FORMAT_SHA512_256: u64 = 0x2000_0000_0000_0000;
+
+/// Length of the index header record
+const INDEX_HEADER_SIZE: u64 = 48;
+
+/// Length of a table item and of the table tail
+const TABLE_ITEM_SIZE: u64 = 40;
+
+/// Length of the table header: size and type
+const TABLE_HEADER_SIZE: u64 = 16;
+
+/// Extension of chunk files in a store
+const CHUNK_EXTENSION: &str = "cacnk";
+
+/// Hash of chunk contents used as chunk ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkDigest {
+    /// casync default
+    Sha256,
+
+    /// desync default
+    Sha512_256,
+}
+
+/// Chunk of a file listed by a `.caibx` index.
+#[derive(Debug, Clone)]
+pub struct IndexChunk {
+    pub offset: u64,
+    pub length: u64,
+    pub id: [u8; 32],
+}
+
+/// casync blob index (`.caibx`): chunking parameters and the chunks of a
+/// file in order. The file is assembled from chunks of a chunk store.
+#[derive(Debug, Clone)]
+pub struct CasyncIndex {
+    pub feature_flags: u64,
+    pub min_size: u64,
+    pub avg_size: u64,
+    pub max_size: u64,
+    pub chunks: Vec<IndexChunk>,
+}
+
+/// casync chunk store (`.castr`) in a local directory or on a web server.
+/// Chunks are compressed with zstd and stored by their ID under
+/// `<first four hex digits>/<ID>.cacnk`, desync uses the same layout.
+pub struct CasyncStore {
+    location: String,
+}
+
+impl FromStr for ChunkDigest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            "sha512-256" => Ok(Self::Sha512_256),
+            _ => Err(format!(
+                "Unknown chunk digest {}, expected sha256 or sha512-256",
+                s
+            )),
+        }
+    }
+}
+
+impl ChunkDigest {
+    pub fn hash(&self, data: &[u8]) -> [u8; 32] {
+        match self {
+            Self::Sha256 => Sha256::digest(data).into(),
+            Self::Sha512_256 => Sha512_256::digest(data).into(),
+        }
+    }
+}
+
+impl CasyncIndex {
+    /// Reads a `.caibx` index written by casync, desync or `write`.
+    pub fn read(r: &mut dyn Read) -> Result<Self, Box<dyn Error>> {
+        if read_u64(r)? != INDEX_HEADER_SIZE || read_u64(r)? != CA_FORMAT_INDEX {
+            return Err("Not a casync index".into());
+        }
+
+        let mut index = Self {
+            feature_flags: read_u64(r)?,
+            min_size: read_u64(r)?,
+            avg_size: read_u64(r)?,
+            max_size: read_u64(r)?,
+            chunks: Vec::new(),
+        };
+
+        if read_u64(r)? != u64::MAX || read_u64(r)? != CA_FORMAT_TABLE {
+            return Err("casync index has no chunk table".into());
+        }
+
+        let mut offset: u64 = 0;
+
+        loop {
+            let end = read_u64(r)?;
+            let mut id = [0u8; 32];
+            r.read_exact(&mut id)?;
+
+            // The tail starts with zero where items have the end offset
+            if end == 0 {
+                let tail: Vec<u64> = id
+                    .chunks(8)
+                    .map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes")))
+                    .collect();
+
+                let size = TABLE_HEADER_SIZE + TABLE_ITEM_SIZE * (index.chunks.len() as u64 + 1);
+                if tail != [0, INDEX_HEADER_SIZE, size, CA_FORMAT_TABLE_TAIL_MARKER] {
+                    return Err("casync index has an invalid table tail".into());
+                }
+
+                return Ok(index);
+            }
+
+            if end <= offset {
+                return Err(format!("casync index has a chunk ending at {}", end).into());
+            }
+
+            index.chunks.push(IndexChunk {
+                offset,
+                length: end - offset,
+                id,
+            });
+            offset = end;
+        }
+    }
+
+    /// Writes the index in `.caibx` format.
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        for value in [
+            INDEX_HEADER_SIZE,
+            CA_FORMAT_INDEX,
+            self.feature_flags,
+            self.min_size,
+            self.avg_size,
+            self.max_size,
+            u64::MAX,
+            CA_FORMAT_TABLE,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+
+        for chunk in &self.chunks {
+            w.write_all(&(chunk.offset + chunk.length).to_le_bytes())?;
+            w.write_all(&chunk.id)?;
+        }
+
+        for value in [
+            0,
+            0,
+            INDEX_HEADER_SIZE,
+            TABLE_HEADER_SIZE + TABLE_ITEM_SIZE * (self.chunks.len() as u64 + 1),
+            CA_FORMAT_TABLE_TAIL_MARKER,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the hash of chunk IDs set by the feature flags.
+    pub fn digest(&self) -> ChunkDigest {
+        match self.feature_flags & CA_FORMAT_SHA512_256 {
+            0 => ChunkDigest::Sha256,
+            _ => ChunkDigest::Sha512_256,
+        }
+    }
+
+    /// Returns the length of the indexed file.
+    pub fn length(&self) -> u64 {
+        self.chunks.last().map_or(0, |c| c.offset + c.length)
+    }
+}
+
+impl CasyncStore {
+    /// Opens a store: a local directory, created on the first write, or
+    /// an http(s) URL, which can only be read.
+    pub fn open(location: &str) -> Self {
+        Self {
+            location: location.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns true if the chunk is in the store.
+    pub fn has(&self, id: &[u8; 32]) -> bool {
+        !http::is_url(&self.location) && Path::new(&self.chunk_path(id)).exists()
+    }
+
+    /// Adds a chunk to a local store.
+    ///
+    /// # Returns:
+    /// - `Result<bool, Box<dyn Error>>`: true if the chunk was not stored before
+    pub fn put(&self, id: &[u8; 32], data: &[u8]) -> Result<bool, Box<dyn Error>> {
+        if http::is_url(&self.location) {
+            return Err(format!("Can not write chunks to {}", self.location).into());
+        }
+
+        let path = self.chunk_path(id);
+        let path = Path::new(&path);
+        if path.exists() {
+            return Ok(false);
+        }
+
+        let dir = path.parent().expect("chunk path has a parent");
+        fs::create_dir_all(dir)?;
+
+        // Chunk appears under its name only when it is complete
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(&zstd::encode_all(data, 0)?)?;
+        file.persist(path)?;
+
+        Ok(true)
+    }
+
+    /// Reads a chunk verifying its contents.
+    pub fn get(&self, id: &[u8; 32], digest: ChunkDigest) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = self.chunk_path(id);
+        let compressed = match http::is_url(&self.location) {
+            true => http::get(&path)?,
+            false => fs::read(&path)?,
+        };
+
+        let data = zstd::decode_all(&compressed[..])
+            .map_err(|e| format!("Chunk {} can not be decompressed: {}", hex(id), e))?;
+
+        if digest.hash(&data) != *id {
+            return Err(format!("Chunk {} is corrupted", hex(id)).into());
+        }
+
+        Ok(data)
+    }
+
+    /// Splits a file into chunks, adds missing chunks to the store and
+    /// returns the index of the file.
+    ///
+    /// Chunk boundaries are found with fastcdc rather than the buzhash of
+    /// casync, so chunks are shared with files ingested here, not with
+    /// files chunked by casync or desync.
+    ///
+    /// # Parameters:
+    /// - `path`: path to a local file
+    /// - `options`: chunk sizes
+    /// - `digest`: hash of chunk IDs
+    ///
+    /// # Returns:
+    /// - `(CasyncIndex, u64)`: the index and the length of chunks which were not in the store before
+    pub fn ingest(
+        &self,
+        path: &Path,
+        options: &SignOptions,
+        digest: ChunkDigest,
+    ) -> Result<(CasyncIndex, u64), Box<dyn Error>> {
+        options.validate()?;
+
+        let mut index = CasyncIndex {
+            feature_flags: match digest {
+                ChunkDigest::Sha256 => 0,
+                ChunkDigest::Sha512_256 => CA_FORMAT_SHA512_256,
+            },
+            min_size: options.min_size as u64,
+            avg_size: options.avg_size as u64,
+            max_size: options.max_size as u64,
+            chunks: Vec::new(),
+        };
+
+        let file = File::open(path)?;
+
+        // Empty files can not be mapped
+        if file.metadata()?.len() == 0 {
+            return Ok((index, 0));
+        }
+
+        // The file must not be modified while it is being ingested
+        let map = unsafe { Mmap::map(&file)? };
+        let mut stored: u64 = 0;
+
+        for chunk in FastCDC::new(&map, options.min_size, options.avg_size, options.max_size) {
+            let data = &map[chunk.offset..chunk.offset + chunk.length];
+            let id = digest.hash(data);
+
+            if self.put(&id, data)? {
+                stored += data.len() as u64;
+            }
+
+            index.chunks.push(IndexChunk {
+                offset: chunk.offset as u64,
+                length: chunk.length as u64,
+                id,
+            });
+        }
+
+        Ok((index, stored))
+    }
+
+    /// Writes the file described by an index to `w`, verifying each chunk.
+    pub fn materialize(
+        &self,
+        index: &CasyncIndex,
+        w: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let digest = index.digest();
+
+        for chunk in &index.chunks {
+            let data = self.get(&chunk.id, digest)?;
+
+            if data.len() as u64 != chunk.length {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Chunk {} has {} bytes, the index expects {}",
+                        hex(&chunk.id),
+                        data.len(),
+                        chunk.length
+                    ),
+                )
+                .into());
+            }
+
+            w.write_all(&data)?;
+        }
+
+        Ok(())
+    }
+
+    fn chunk_path(&self, id: &[u8; 32]) -> String {
+        let hex = hex(id);
+        format!(
+            "{}/{}/{}.{}",
+            self.location,
+            &hex[..4],
+            hex,
+            CHUNK_EXTENSION
+        )
+    }
+}
+
+fn hex(id: &[u8; 32]) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+
+    fn options() -> SignOptions {
+        SignOptions {
+            min_size: 4 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn index_write_and_read_round_trip() {
+        let index = CasyncIndex {
+            feature_flags: CA_FORMAT_SHA512_256,
+            min_size: 16,
+            avg_size: 64,
+            max_size: 256,
+            chunks: vec![
+                IndexChunk {
+                    offset: 0,
+                    length: 100,
+                    id: [1; 32],
+                },
+                IndexChunk {
+                    offset: 100,
+                    length: 5 * 1024 * 1024 * 1024,
+                    id: [2; 32],
+                },
+            ],
+        };
+
+        let mut written: Vec<u8> = Vec::new();
+        index.write(&mut written).unwrap();
+        assert_eq!(
+            written.len() as u64,
+            INDEX_HEADER_SIZE + TABLE_HEADER_SIZE + 3 * TABLE_ITEM_SIZE
+        );
+
+        let read = CasyncIndex::read(&mut &written[..]).unwrap();
+        assert_eq!(read.feature_flags, index.feature_flags);
+        assert_eq!((read.min_size, read.avg_size, read.max_size), (16, 64, 256));
+        assert_eq!(read.digest(), ChunkDigest::Sha512_256);
+        assert_eq!(read.length(), 100 + 5 * 1024 * 1024 * 1024);
+        assert_eq!(read.chunks[1].offset, 100);
+        assert_eq!(read.chunks[1].id, [2; 32]);
+    }
+
+    #[test]
+    fn read_rejects_damaged_indexes() {
+        let index = CasyncIndex {
+            feature_flags: 0,
+            min_size: 16,
+            avg_size: 64,
+            max_size: 256,
+            chunks: vec![IndexChunk {
+                offset: 0,
+                length: 100,
+                id: [1; 32],
+            }],
+        };
+
+        let mut written: Vec<u8> = Vec::new();
+        index.write(&mut written).unwrap();
+
+        let mut magic = written.clone();
+        magic[8] ^= 1;
+        assert!(CasyncIndex::read(&mut &magic[..]).is_err());
+
+        let mut tail = written.clone();
+        let last = tail.len() - 1;
+        tail[last] ^= 1;
+        assert!(CasyncIndex::read(&mut &tail[..]).is_err());
+
+        assert!(CasyncIndex::read(&mut &written[..written.len() - 8]).is_err());
+    }
+
+    #[test]
+    fn ingest_and_materialize_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CasyncStore::open(&dir.path().join("store.castr").display().to_string());
+
+        let data = random_data(1, 300 * 1024);
+        let path = dir.path().join("file");
+        fs::write(&path, &data).unwrap();
+
+        for digest in [ChunkDigest::Sha256, ChunkDigest::Sha512_256] {
+            let (index, stored) = store.ingest(&path, &options(), digest).unwrap();
+            assert_eq!(stored, data.len() as u64);
+            assert_eq!(index.length(), data.len() as u64);
+            assert_eq!(index.digest(), digest);
+            assert!(index.chunks.iter().all(|chunk| store.has(&chunk.id)));
+
+            // Chunks are stored once
+            let (_, stored) = store.ingest(&path, &options(), digest).unwrap();
+            assert_eq!(stored, 0);
+
+            let mut written: Vec<u8> = Vec::new();
+            index.write(&mut written).unwrap();
+            let index = CasyncIndex::read(&mut &written[..]).unwrap();
+
+            let mut materialized: Vec<u8> = Vec::new();
+            store.materialize(&index, &mut materialized).unwrap();
+            assert_eq!(materialized, data);
+        }
+    }
+
+    #[test]
+    fn get_rejects_corrupted_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CasyncStore::open(&dir.path().display().to_string());
+
+        let data = random_data(2, 1000);
+        let id = ChunkDigest::Sha256.hash(&data);
+        assert!(store.put(&id, &data).unwrap());
+        assert!(!store.put(&id, &data).unwrap());
+        assert_eq!(store.get(&id, ChunkDigest::Sha256).unwrap(), data);
+
+        fs::write(
+            store.chunk_path(&id),
+            zstd::encode_all(&b"other"[..], 0).unwrap(),
+        )
+        .unwrap();
+        assert!(store.get(&id, ChunkDigest::Sha256).is_err());
+
+        fs::write(store.chunk_path(&id), b"not zstd").unwrap();
+        assert!(store.get(&id, ChunkDigest::Sha256).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::signature::Chunk;
+
+/// Offset of a chunk in a file. Implicit offsets are left out of signature
+/// files and restored from lengths of the preceding chunks on load.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChunkOffset {
+    value: u64,
+    implicit: bool,
+}
+
+impl ChunkOffset {
+    pub fn new(value: u64, implicit: bool) -> Self {
+        Self { value, implicit }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// Missing offsets are deserialized as implicit ones.
+    pub fn missing() -> Self {
+        Self {
+            value: 0,
+            implicit: true,
+        }
+    }
+
+    pub fn is_implicit(&self) -> bool {
+        self.implicit
+    }
+}
+
+impl Serialize for ChunkOffset {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u64(self.value)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkOffset {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self::new(u64::deserialize(deserializer)?, false))
+    }
+}
+
+/// Deserializes chunks restoring implicit offsets from chunk lengths.
+pub fn deserialize_chunks<'de, D>(deserializer: D) -> Result<Vec<Chunk>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut chunks = Vec::<Chunk>::deserialize(deserializer)?;
+
+    let mut position = 0u64;
+    for chunk in chunks.iter_mut() {
+        chunk.restore_offset(position);
+        position = chunk.offset() + chunk.length();
+    }
+
+    Ok(chunks)
+}
@@ -0,0 +1,86 @@
+use crate::signature::Signature;
+
+/// Change statistics for a region of a file.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionChurn {
+    /// offset of the region in the file
+    pub offset: u64,
+
+    /// length of the region
+    pub length: u64,
+
+    /// bytes of the region which changed since the given time
+    pub changed: u64,
+
+    /// the most recent change within the region
+    pub last_changed_at: Option<u64>,
+}
+
+impl RegionChurn {
+    /// Returns share of changed bytes in the region, 0..=1.
+    pub fn ratio(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+
+        self.changed as f64 / self.length as f64
+    }
+}
+
+/// Returns true if the signature was generated with change tracking.
+pub fn is_tracked(sig: &Signature) -> bool {
+    sig.chunks().iter().any(|c| c.changed_at().is_some())
+}
+
+/// Splits the file into equal regions and calculates how many bytes
+/// of each region changed since the given time.
+///
+/// # Parameters:
+/// - `sig`: signature generated with change tracking
+/// - `regions`: number of regions
+/// - `since`: unix time, changes before it are ignored
+///
+/// # Returns:
+/// - `Vec<RegionChurn>`: regions in the file order
+pub fn analyze(sig: &Signature, regions: usize, since: u64) -> Vec<RegionChurn> {
+    let length = sig.length();
+    let region_length = length.div_ceil(regions.max(1) as u64).max(1);
+
+    let mut result: Vec<RegionChurn> = (0..length.div_ceil(region_length))
+        .map(|index| {
+            let offset = index * region_length;
+            RegionChurn {
+                offset,
+                length: region_length.min(length - offset),
+                changed: 0,
+                last_changed_at: None,
+            }
+        })
+        .collect();
+
+    for chunk in sig.chunks() {
+        let changed_at = match chunk.changed_at() {
+            Some(t) => t,
+            None => continue,
+        };
+
+        let start = chunk.offset();
+        let end = start + chunk.length();
+
+        // A chunk may span several regions
+        let mut at = start;
+        while at < end {
+            let region = &mut result[(at / region_length) as usize];
+            let region_end = (region.offset + region.length).min(end);
+
+            if changed_at >= since {
+                region.changed += region_end - at;
+            }
+
+            region.last_changed_at = region.last_changed_at.max(Some(changed_at));
+            at = region_end;
+        }
+    }
+
+    result
+}
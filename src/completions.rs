@@ -0,0 +1,249 @@
+use argh::{CommandInfoWithArgs, FlagInfo, FlagInfoKind};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Shell to generate a completion script for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Command of the tree: names from the program down to it and its arguments
+struct Node<'a> {
+    path: Vec<&'a str>,
+    info: &'a CommandInfoWithArgs,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            _ => Err(format!("Unknown shell {}, expected bash, zsh or fish", s)),
+        }
+    }
+}
+
+/// Returns the completion script of a command tree.
+///
+/// # Parameters:
+/// - `shell`: shell the script is for
+/// - `program`: name of the binary
+/// - `info`: arguments of the top-level command, see `argh::ArgsInfo`
+pub fn generate(shell: Shell, program: &str, info: &CommandInfoWithArgs) -> String {
+    let mut nodes: Vec<Node> = Vec::new();
+    collect(vec![program], info, &mut nodes);
+
+    match shell {
+        Shell::Bash => bash(program, &nodes),
+        Shell::Zsh => zsh(program, &nodes),
+        Shell::Fish => fish(program, &nodes),
+    }
+}
+
+/// Adds a command followed by its subcommands to `nodes`.
+fn collect<'a>(path: Vec<&'a str>, info: &'a CommandInfoWithArgs, nodes: &mut Vec<Node<'a>>) {
+    nodes.push(Node {
+        path: path.clone(),
+        info,
+    });
+
+    for sub in &info.commands {
+        let mut sub_path = path.clone();
+        sub_path.push(sub.name);
+        collect(sub_path, &sub.command, nodes);
+    }
+}
+
+/// Words completed after a command: its subcommands and flags.
+fn words(node: &Node) -> Vec<(String, String)> {
+    let commands = node
+        .info
+        .commands
+        .iter()
+        .map(|sub| (sub.name.to_string(), description(sub.command.description)));
+
+    let flags = visible_flags(node.info).flat_map(|flag| {
+        let short = flag
+            .short
+            .map(|c| (format!("-{}", c), description(flag.description)));
+        [(flag.long.to_string(), description(flag.description))]
+            .into_iter()
+            .chain(short)
+    });
+
+    commands.chain(flags).collect()
+}
+
+fn visible_flags(info: &CommandInfoWithArgs) -> impl Iterator<Item = &FlagInfo<'static>> {
+    info.flags.iter().filter(|flag| !flag.hidden)
+}
+
+/// First line of a description, argh escapes braces in doc comments
+fn description(s: &str) -> String {
+    s.lines()
+        .next()
+        .unwrap_or_default()
+        .replace("{{", "{")
+        .replace("}}", "}")
+}
+
+fn function_name(program: &str) -> String {
+    format!("_{}", program.replace('-', "_"))
+}
+
+/// Words of the command tree are tracked through the command line, files
+/// are completed where no word matches.
+fn bash(program: &str, nodes: &[Node]) -> String {
+    let function = function_name(program);
+    let mut out = String::new();
+
+    let _ = writeln!(out, "{}() {{", function);
+    let _ = writeln!(out, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"");
+    let _ = writeln!(out, "    local path=\"{}\" word opts", program);
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do"
+    );
+    let _ = writeln!(out, "        case \"$path $word\" in");
+    for node in nodes.iter().skip(1) {
+        let _ = writeln!(
+            out,
+            "            \"{}\") path=\"$path $word\" ;;",
+            node.path.join(" ")
+        );
+    }
+    let _ = writeln!(out, "        esac");
+    let _ = writeln!(out, "    done");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    case \"$path\" in");
+    for node in nodes {
+        let words: Vec<String> = words(node).into_iter().map(|(word, _)| word).collect();
+        let _ = writeln!(
+            out,
+            "        \"{}\") opts=\"{}\" ;;",
+            node.path.join(" "),
+            words.join(" ")
+        );
+    }
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "complete -o default -F {} {}", function, program);
+
+    out
+}
+
+fn zsh(program: &str, nodes: &[Node]) -> String {
+    let function = function_name(program);
+    let mut out = String::new();
+
+    let _ = writeln!(out, "#compdef {}", program);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "{}() {{", function);
+    let _ = writeln!(out, "    local command_path=\"{}\" word", program);
+    let _ = writeln!(out, "    local -a items");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    for word in ${{words[2,CURRENT-1]}}; do");
+    let _ = writeln!(out, "        case \"$command_path $word\" in");
+    for node in nodes.iter().skip(1) {
+        let _ = writeln!(
+            out,
+            "            (\"{}\") command_path=\"$command_path $word\" ;;",
+            node.path.join(" ")
+        );
+    }
+    let _ = writeln!(out, "        esac");
+    let _ = writeln!(out, "    done");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    case \"$command_path\" in");
+    for node in nodes {
+        let items: Vec<String> = words(node)
+            .into_iter()
+            .map(|(word, description)| quote(&format!("{}:{}", word, description)))
+            .collect();
+        let _ = writeln!(
+            out,
+            "        (\"{}\") items=({}) ;;",
+            node.path.join(" "),
+            items.join(" ")
+        );
+    }
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    _describe '{}' items", program);
+    let _ = writeln!(out, "    _files");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "{} \"$@\"", function);
+
+    out
+}
+
+fn fish(program: &str, nodes: &[Node]) -> String {
+    let mut out = String::new();
+
+    for node in nodes {
+        let names = &node.path[1..];
+
+        // The command is reached once all the names of its path are seen
+        // and none of its subcommands is
+        let mut conditions: Vec<String> = match names.is_empty() {
+            true => vec!["__fish_use_subcommand".to_string()],
+            false => names
+                .iter()
+                .map(|name| format!("__fish_seen_subcommand_from {}", name))
+                .collect(),
+        };
+        if !names.is_empty() && !node.info.commands.is_empty() {
+            let subcommands: Vec<&str> = node.info.commands.iter().map(|sub| sub.name).collect();
+            conditions.push(format!(
+                "not __fish_seen_subcommand_from {}",
+                subcommands.join(" ")
+            ));
+        }
+        let condition = quote(&conditions.join("; and "));
+
+        for sub in &node.info.commands {
+            let _ = writeln!(
+                out,
+                "complete -c {} -n {} -f -a {} -d {}",
+                program,
+                condition,
+                sub.name,
+                quote(&description(sub.command.description))
+            );
+        }
+
+        for flag in visible_flags(node.info) {
+            let mut line = format!(
+                "complete -c {} -n {} -l {}",
+                program,
+                condition,
+                flag.long.trim_start_matches("--")
+            );
+            if let Some(short) = flag.short {
+                let _ = write!(line, " -s {}", short);
+            }
+            if let FlagInfoKind::Option { .. } = flag.kind {
+                line.push_str(" -r");
+            }
+            let _ = writeln!(out, "{} -d {}", line, quote(&description(flag.description)));
+        }
+    }
+
+    out
+}
+
+/// Quotes a word for sh, zsh and fish.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
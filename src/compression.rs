@@ -0,0 +1,134 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use crate::platform;
+
+/// Length of the file prefix inspected to detect compression.
+const INSPECT_LENGTH: u64 = 4 * 1024 * 1024;
+
+/// `gzip --rsyncable` flushes the compressor at least this often on average,
+/// each flush leaves an empty stored block in the stream.
+const RSYNCABLE_FLUSH_INTERVAL: usize = 64 * 1024;
+
+/// Empty stored deflate block written by a sync flush.
+const SYNC_MARKER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Compression format of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+/// Compression detected in a file.
+#[derive(Debug, Clone, Copy)]
+pub struct Detected {
+    pub compression: Compression,
+
+    /// the stream is compressed with periodic flushes, such files
+    /// can be diffed as they are
+    pub rsyncable: bool,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Gzip => "gzip",
+            Self::Zip => "zip",
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bzip2",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl Compression {
+    /// Detects compression by magic bytes at the start of data.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        let magics: [(&[u8], Self); 5] = [
+            (&[0x1f, 0x8b], Self::Gzip),
+            (b"PK\x03\x04", Self::Zip),
+            (&[0x28, 0xb5, 0x2f, 0xfd], Self::Zstd),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Self::Xz),
+            (b"BZh", Self::Bzip2),
+        ];
+
+        magics
+            .iter()
+            .find(|(magic, _)| data.starts_with(magic))
+            .map(|&(_, compression)| compression)
+    }
+
+    /// Returns true if the file can be signed decompressed.
+    pub fn can_decompress(&self) -> bool {
+        *self == Self::Gzip
+    }
+
+    /// Returns a reader of decompressed data.
+    pub fn decoder<'a>(&self, r: impl Read + 'a) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+        match self {
+            Self::Gzip => Ok(Box::new(MultiGzDecoder::new(r))),
+            _ => Err(format!("{} decompression is not supported", self).into()),
+        }
+    }
+
+    /// Compresses data from `r` into `w`.
+    pub fn compress(&self, r: &mut dyn Read, w: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(w, flate2::Compression::default());
+                io::copy(r, &mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            }
+            _ => Err(format!("{} compression is not supported", self).into()),
+        }
+    }
+}
+
+/// Detects whether a file is compressed by inspecting its beginning.
+pub fn inspect(path: &Path) -> Result<Option<Detected>, Box<dyn Error>> {
+    let mut prefix: Vec<u8> = Vec::new();
+    platform::open_shared(path)?
+        .take(INSPECT_LENGTH)
+        .read_to_end(&mut prefix)?;
+
+    Ok(Compression::detect(&prefix).map(|compression| Detected {
+        compression,
+        rsyncable: compression == Compression::Gzip && is_rsyncable(&prefix),
+    }))
+}
+
+/// Writes decompressed contents of a file to `w`.
+pub fn decompress(
+    path: &Path,
+    compression: Compression,
+    w: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut decoder = compression.decoder(BufReader::new(platform::open_shared(path)?))?;
+    io::copy(&mut decoder, w)?;
+
+    Ok(())
+}
+
+/// Returns true if a gzip stream looks like produced by `gzip --rsyncable`:
+/// sync flush markers occur regularly. In other data a marker is a rare accident.
+fn is_rsyncable(data: &[u8]) -> bool {
+    let markers = data
+        .windows(SYNC_MARKER.len())
+        .filter(|window| *window == SYNC_MARKER)
+        .count();
+
+    markers >= 2 && markers * RSYNCABLE_FLUSH_INTERVAL >= data.len()
+}
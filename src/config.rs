@@ -0,0 +1,209 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Environment variable with the config file path.
+pub const CONFIG_ENV: &str = "CLOUD_RSYNC_CONFIG";
+
+/// Prefix of environment variables overriding config values, e.g. `CLOUD_RSYNC_AVG_SIZE`.
+const ENV_PREFIX: &str = "CLOUD_RSYNC_";
+
+/// Defaults for command options. Values come from the config file, then
+/// from environment variables, options given on the command line win.
+///
+/// ```toml
+/// avg_size = 65536
+/// threads = 4
+/// retries = 10
+/// key_file = "/etc/cloud-rsync/tenant.key"
+///
+/// [remotes.prod]
+/// type = "ssh"
+/// host = "deploy@prod-1"
+/// path = "/srv/assets"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// min chunk size
+    pub min_size: Option<u32>,
+
+    /// avg chunk size
+    pub avg_size: Option<u32>,
+
+    /// max chunk size
+    pub max_size: Option<u32>,
+
+    /// threads used to hash chunks of a file
+    pub threads: Option<usize>,
+
+    /// files signed concurrently, threads building a file in diff
+    pub jobs: Option<usize>,
+
+    /// attempts for each range read
+    pub retries: Option<u32>,
+
+    /// read limit, bytes/sec
+    pub bwlimit: Option<u64>,
+
+    /// share of the target file in percent above which diff downloads the whole file
+    pub full_download_threshold: Option<f64>,
+
+    /// most range requests diff sends for the target file
+    pub max_requests: Option<usize>,
+
+    /// egress price of the endpoint, $ per GB
+    pub egress_price: Option<f64>,
+
+    /// request price of the endpoint, $ per 10k requests
+    pub request_price: Option<f64>,
+
+    /// key file for keyed hashes
+    pub key_file: Option<PathBuf>,
+
+    /// age identity file to decrypt patches
+    pub identity: Option<PathBuf>,
+
+    /// remote shell of sync
+    pub rsh: Option<String>,
+
+    /// proxy of HTTP requests, overrides HTTPS_PROXY and HTTP_PROXY
+    pub proxy: Option<String>,
+
+    /// named remotes, `prod:path/to/file` refers to a file on the remote `prod`
+    pub remotes: HashMap<String, Remote>,
+}
+
+/// How a remote is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteType {
+    /// `cloud-zsync server` run over a remote shell, see `remote::RemoteSession`
+    Ssh,
+}
+
+/// Named remote profile.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Remote {
+    #[serde(rename = "type")]
+    pub kind: RemoteType,
+
+    /// host to run the remote shell on, `user@host` is passed as is
+    pub host: String,
+
+    /// directory paths on the remote are relative to, the login directory if not set
+    pub path: Option<PathBuf>,
+
+    /// remote shell, overrides `rsh` of the config
+    pub rsh: Option<String>,
+
+    /// path of cloud-zsync on the host
+    pub remote_bin: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file and applies environment variables on top of it.
+    /// A missing file at the default path means no config.
+    ///
+    /// # Parameters:
+    /// - `path`: config file path, `CLOUD_RSYNC_CONFIG` or `default_path` if not given
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let explicit = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+
+        let mut config = match (&explicit, Self::default_path()) {
+            (Some(path), _) => Self::from_file(path)?,
+            (None, Some(path)) => match fs::metadata(&path) {
+                Ok(_) => Self::from_file(&path)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+                Err(e) => return Err(e.into()),
+            },
+            (None, None) => Self::default(),
+        };
+
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    /// Returns the remote with the given name.
+    pub fn remote(&self, name: &str) -> Option<&Remote> {
+        self.remotes.get(name)
+    }
+
+    /// Reads a config file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Can not read config {}: {}", path.display(), e))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e).into())
+    }
+
+    /// `$XDG_CONFIG_HOME/cloud-rsync/config.toml`, `~/.config/cloud-rsync/config.toml`
+    /// if XDG_CONFIG_HOME is not set.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(config_home.join("cloud-rsync").join("config.toml"))
+    }
+
+    /// Overrides values with `CLOUD_RSYNC_<NAME>` environment variables.
+    fn apply_env(&mut self) -> Result<(), Box<dyn Error>> {
+        env_value("MIN_SIZE", &mut self.min_size)?;
+        env_value("AVG_SIZE", &mut self.avg_size)?;
+        env_value("MAX_SIZE", &mut self.max_size)?;
+        env_value("THREADS", &mut self.threads)?;
+        env_value("JOBS", &mut self.jobs)?;
+        env_value("RETRIES", &mut self.retries)?;
+        env_value("BWLIMIT", &mut self.bwlimit)?;
+        env_value("FULL_DOWNLOAD_THRESHOLD", &mut self.full_download_threshold)?;
+        env_value("MAX_REQUESTS", &mut self.max_requests)?;
+        env_value("EGRESS_PRICE", &mut self.egress_price)?;
+        env_value("REQUEST_PRICE", &mut self.request_price)?;
+        env_value("KEY_FILE", &mut self.key_file)?;
+        env_value("IDENTITY", &mut self.identity)?;
+        env_value("RSH", &mut self.rsh)?;
+        env_value("PROXY", &mut self.proxy)?;
+
+        Ok(())
+    }
+}
+
+impl Remote {
+    /// Returns the path of a file on the remote.
+    pub fn file_path(&self, path: &str) -> PathBuf {
+        match &self.path {
+            Some(root) => root.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+/// Sets `value` from the environment variable `CLOUD_RSYNC_<name>` if it is set.
+fn env_value<T>(name: &str, value: &mut Option<T>) -> Result<(), Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let var = format!("{}{}", ENV_PREFIX, name);
+
+    if let Ok(s) = env::var(&var) {
+        let parsed = s
+            .parse()
+            .map_err(|e| format!("Invalid {}={}: {}", var, s, e))?;
+        *value = Some(parsed);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+
+/// Files left out of a tree: matching exclude patterns or, optionally,
+/// ignored by git. Paths are relative to the tree root.
+pub struct Exclusions {
+    /// patterns without a slash, matched against every path component
+    names: GlobSet,
+
+    /// patterns with a slash, matched against the path and its parents
+    paths: GlobSet,
+
+    /// files git does not ignore, if .gitignore is respected
+    kept: Option<HashSet<PathBuf>>,
+}
+
+impl Exclusions {
+    /// Creates exclusions for a tree.
+    ///
+    /// # Parameters:
+    /// - `root`: tree root
+    /// - `patterns`: globs like in .gitignore: `*.tmp` excludes such files anywhere,
+    ///   `build/cache` the path under the root
+    /// - `respect_gitignore`: also exclude files ignored by .gitignore, .git/info/exclude
+    ///   and the global gitignore, as well as .git itself
+    pub fn new(
+        root: &Path,
+        patterns: &[String],
+        respect_gitignore: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let trimmed = pattern.trim_start_matches('/').trim_end_matches('/');
+            if trimmed.is_empty() {
+                return Err(format!("Empty exclude pattern {:?}", pattern).into());
+            }
+
+            let glob = GlobBuilder::new(trimmed)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("Invalid exclude pattern {:?}: {}", pattern, e))?;
+
+            match pattern.contains('/') {
+                true => paths.add(glob),
+                false => names.add(glob),
+            };
+        }
+
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+            kept: match respect_gitignore {
+                true => Some(not_ignored(root)?),
+                false => None,
+            },
+        })
+    }
+
+    /// Returns true if a file is excluded.
+    ///
+    /// # Parameters:
+    /// - `relative`: file path relative to the tree root
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        let components: Vec<&Path> = relative
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(Path::new(part)),
+                _ => None,
+            })
+            .collect();
+
+        if components.iter().any(|part| self.names.is_match(part)) {
+            return true;
+        }
+
+        let excluded_path = relative
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| self.paths.is_match(path));
+
+        if excluded_path {
+            return true;
+        }
+
+        match &self.kept {
+            Some(kept) => !kept.contains(&components.iter().collect::<PathBuf>()),
+            None => false,
+        }
+    }
+}
+
+/// Returns a path relative to a tree root. Files matched by relative
+/// masks start with `./`, the root may be `.` itself.
+pub fn relative_to<'a>(root: &Path, path: &'a Path) -> Option<&'a Path> {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    let root = root.strip_prefix(".").unwrap_or(root);
+
+    path.strip_prefix(root).ok()
+}
+
+/// Returns relative paths of the files of a tree git does not ignore.
+fn not_ignored(root: &Path) -> Result<HashSet<PathBuf>, Box<dyn Error>> {
+    let mut kept: HashSet<PathBuf> = HashSet::new();
+
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .ignore(false)
+        .parents(true)
+        .git_ignore(true)
+        .git_global(true)
+        .git_exclude(true)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build();
+
+    for entry in walker {
+        let entry = entry?;
+
+        if entry.file_type().is_some_and(|t| !t.is_dir()) {
+            if let Some(relative) = relative_to(root, entry.path()) {
+                kept.insert(relative.to_path_buf());
+            }
+        }
+    }
+
+    Ok(kept)
+}
@@ -0,0 +1,192 @@
+use std::cell::RefCell;
+use std::error::Error;
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+use crate::builder::RetryPolicy;
+use crate::patch;
+use crate::signature::{Diff, SignOptions, Signature};
+
+/// The call succeeded. Failed calls return `CLOUD_ZSYNC_ERROR` and
+/// `cloud_zsync_last_error` describes the failure.
+pub const CLOUD_ZSYNC_OK: c_int = 0;
+
+/// The call failed, see `cloud_zsync_last_error`.
+pub const CLOUD_ZSYNC_ERROR: c_int = -1;
+
+/// `cloud_zsync_diff` found no changes and wrote no patch.
+pub const CLOUD_ZSYNC_UP_TO_DATE: c_int = 1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Signs a file and writes its signature.
+///
+/// # Parameters:
+/// - `path`: file to sign
+/// - `signature_path`: signature file to write
+/// - `avg_size`: average chunk size in bytes, 0 picks one by the file length
+///
+/// # Safety
+/// `path` and `signature_path` must be valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn cloud_zsync_sign(
+    path: *const c_char,
+    signature_path: *const c_char,
+    avg_size: u32,
+) -> c_int {
+    call(|| {
+        let path = to_path(path)?;
+        let signature_path = to_path(signature_path)?;
+
+        let avg_size = match avg_size {
+            0 => SignOptions::auto_avg_size(fs::metadata(path)?.len()),
+            avg_size => avg_size,
+        };
+        let options = SignOptions {
+            mmap: true,
+            ..Default::default()
+        }
+        .with_avg_size(avg_size);
+
+        let sig = Signature::generate_file(path, &options)?;
+
+        let mut w = BufWriter::new(File::create(signature_path)?);
+        serde_json::to_writer(&mut w, &sig)?;
+        w.flush()?;
+
+        Ok(CLOUD_ZSYNC_OK)
+    })
+}
+
+/// Writes a plain patch which builds the target file from the source one.
+///
+/// # Parameters:
+/// - `source_signature_path`: signature of the file the client has
+/// - `target_signature_path`: signature of the target file
+/// - `target_path`: target file, the patch carries its changed ranges
+/// - `patch_path`: patch file to write
+///
+/// # Returns:
+/// `CLOUD_ZSYNC_UP_TO_DATE` without writing the patch if the files are equal.
+///
+/// # Safety
+/// All the arguments must be valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn cloud_zsync_diff(
+    source_signature_path: *const c_char,
+    target_signature_path: *const c_char,
+    target_path: *const c_char,
+    patch_path: *const c_char,
+) -> c_int {
+    call(|| {
+        let source_sig = read_signature(to_path(source_signature_path)?)?;
+        let target_sig = read_signature(to_path(target_signature_path)?)?;
+        let target_path = to_path(target_path)?;
+        let patch_path = to_path(patch_path)?;
+
+        let diff = match Diff::new(&source_sig, &target_sig) {
+            Some(diff) => diff,
+            None => return Ok(CLOUD_ZSYNC_UP_TO_DATE),
+        };
+
+        patch::build(
+            target_path,
+            &target_sig,
+            &diff,
+            &RetryPolicy::default(),
+            patch_path,
+        )?;
+
+        Ok(CLOUD_ZSYNC_OK)
+    })
+}
+
+/// Builds the target of a plain patch from the source file, the result
+/// is verified against the patch target.
+///
+/// # Parameters:
+/// - `source_path`: file the patch is made against
+/// - `patch_path`: patch file
+/// - `destination_path`: path of the new file
+///
+/// # Safety
+/// All the arguments must be valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn cloud_zsync_apply(
+    source_path: *const c_char,
+    patch_path: *const c_char,
+    destination_path: *const c_char,
+) -> c_int {
+    call(|| {
+        patch::rebuild(
+            to_path(source_path)?,
+            to_path(patch_path)?,
+            to_path(destination_path)?,
+        )?;
+
+        Ok(CLOUD_ZSYNC_OK)
+    })
+}
+
+/// Returns the message of the last failure on the calling thread, or NULL.
+/// The string is owned by the library and valid until the next call.
+#[no_mangle]
+pub extern "C" fn cloud_zsync_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Runs the body of an exported function, errors and panics are stored
+/// as the last error instead of crossing the C boundary.
+fn call<F>(f: F) -> c_int
+where
+    F: FnOnce() -> Result<c_int, Box<dyn Error>>,
+{
+    let message = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(code)) => return code,
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => "Panic in cloud-zsync".to_string(),
+    };
+
+    // Messages with NUL bytes can not be passed to C
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+
+    CLOUD_ZSYNC_ERROR
+}
+
+/// Borrows a path argument.
+///
+/// # Safety
+/// `s` must be NULL or a valid NUL-terminated string.
+unsafe fn to_path<'a>(s: *const c_char) -> Result<&'a Path, Box<dyn Error>> {
+    if s.is_null() {
+        return Err("Path is NULL".into());
+    }
+
+    let s = CStr::from_ptr(s);
+
+    // Paths are bytes on Unix, they are not necessarily UTF-8
+    #[cfg(unix)]
+    let path = Path::new(OsStr::from_bytes(s.to_bytes()));
+    #[cfg(not(unix))]
+    let path = Path::new(s.to_str()?);
+
+    Ok(path)
+}
+
+fn read_signature(path: &Path) -> Result<Signature, Box<dyn Error>> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
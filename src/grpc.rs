@@ -0,0 +1,334 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpListener;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{debug_span, error, info};
+
+use crate::metrics;
+use crate::server::{DiffService, Rejection};
+use crate::signature::{Op, Signature};
+
+/// Types generated from proto/cloud_zsync.proto
+pub mod proto {
+    tonic::include_proto!("cloud_zsync");
+}
+
+use proto::sync_engine_server::{SyncEngine, SyncEngineServer};
+use proto::{
+    apply_request, diff_reply, ApplyReply, ApplyRequest, DiffReply, DiffRequest, DiffSummary,
+    FetchRangesRequest, Range, RangeData, SignReply, SignRequest,
+};
+
+/// Bytes of patch or range data per message, well below the 4 MiB message limit
+const MESSAGE_DATA_LENGTH: usize = 1024 * 1024;
+
+/// Messages buffered per stream before the sender waits for the client
+const STREAM_BUFFER: usize = 4;
+
+type ReplyStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// gRPC API of the diff service.
+struct Engine {
+    service: Arc<DiffService>,
+
+    /// object updates are applied one at a time
+    applying: Mutex<()>,
+}
+
+impl From<Rejection> for Status {
+    fn from(rejection: Rejection) -> Self {
+        let code = match rejection.status {
+            400 => Code::InvalidArgument,
+            404 => Code::NotFound,
+            _ => Code::Internal,
+        };
+
+        Status::new(code, rejection.message)
+    }
+}
+
+/// Serves the gRPC API on `addr` from a background thread.
+///
+/// # Parameters:
+/// - `service`: diff service the API calls
+/// - `addr`: address to listen on
+pub fn serve(service: DiffService, addr: &str) -> Result<(), Box<dyn Error>> {
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("Can not serve gRPC on {}: {}", addr, e))?;
+    listener.set_nonblocking(true)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    let engine = Engine {
+        service: Arc::new(service),
+        applying: Mutex::new(()),
+    };
+
+    thread::spawn(move || {
+        let result = runtime.block_on(async move {
+            let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+
+            tonic::transport::Server::builder()
+                .add_service(SyncEngineServer::new(engine))
+                .serve_with_incoming(incoming)
+                .await
+                .map_err(|e| Box::new(e) as Box<dyn Error>)
+        });
+
+        if let Err(e) = result {
+            error!("gRPC server stopped: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Runs blocking work of a call off the async threads.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, Rejection> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result.map_err(Status::from),
+        Err(e) => Err(Status::internal(e.to_string())),
+    }
+}
+
+fn parse_signature(json: &[u8]) -> Result<Signature, Status> {
+    serde_json::from_slice(json)
+        .map_err(|e| Status::invalid_argument(format!("Invalid signature: {}", e)))
+}
+
+/// Sends a file in parts of `MESSAGE_DATA_LENGTH` bytes, stops when the
+/// client goes away.
+fn send_file<T>(
+    file: &mut File,
+    tx: &mpsc::Sender<Result<T, Status>>,
+    message: impl Fn(Vec<u8>) -> T,
+) -> Result<(), Rejection> {
+    loop {
+        let mut data = Vec::with_capacity(MESSAGE_DATA_LENGTH);
+        Read::by_ref(file)
+            .take(MESSAGE_DATA_LENGTH as u64)
+            .read_to_end(&mut data)?;
+
+        if data.is_empty() || tx.blocking_send(Ok(message(data))).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SyncEngine for Engine {
+    async fn sign(&self, request: Request<SignRequest>) -> Result<Response<SignReply>, Status> {
+        let request = request.into_inner();
+        let service = Arc::clone(&self.service);
+
+        let sig = blocking(move || {
+            let _span = debug_span!("sign", object = %request.name).entered();
+
+            service.sign(
+                &request.name,
+                (request.avg_size > 0).then_some(request.avg_size),
+                (request.hash_length > 0).then_some(request.hash_length as usize),
+            )
+        })
+        .await?;
+
+        let signature = serde_json::to_vec(&sig).map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(SignReply { signature }))
+    }
+
+    type DiffStream = ReplyStream<DiffReply>;
+
+    async fn diff(
+        &self,
+        request: Request<DiffRequest>,
+    ) -> Result<Response<Self::DiffStream>, Status> {
+        let request = request.into_inner();
+        let client = parse_signature(&request.signature)?;
+        let service = Arc::clone(&self.service);
+
+        let (path, sig, diff) = {
+            let service = Arc::clone(&service);
+            let name = request.name.clone();
+            blocking(move || service.diff(&name, &client)).await?
+        };
+
+        let summary = DiffSummary {
+            equal: diff.is_none(),
+            ranges: diff
+                .iter()
+                .flat_map(|diff| diff.insert_ops())
+                .map(|op| Range {
+                    offset: op.offset(),
+                    length: op.length(),
+                })
+                .collect(),
+            copy_length: diff.as_ref().map_or(0, |diff| diff.copy_length()),
+            fetch_length: diff.as_ref().map_or(0, |diff| diff.fetch_length()),
+        };
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let _ = tx
+            .send(Ok(DiffReply {
+                reply: Some(diff_reply::Reply::Summary(summary)),
+            }))
+            .await;
+
+        if let (Some(diff), false) = (diff, request.summary_only) {
+            tokio::task::spawn_blocking(move || {
+                let _span = debug_span!("diff", object = %request.name).entered();
+
+                let result = service
+                    .build_patch(&request.name, &path, &sig, &diff)
+                    .and_then(|mut patch| {
+                        send_file(&mut patch, &tx, |data| DiffReply {
+                            reply: Some(diff_reply::Reply::Patch(data)),
+                        })
+                    });
+
+                if let Err(rejection) = result {
+                    let _ = tx.blocking_send(Err(rejection.into()));
+                }
+            });
+        }
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    type FetchRangesStream = ReplyStream<RangeData>;
+
+    async fn fetch_ranges(
+        &self,
+        request: Request<FetchRangesRequest>,
+    ) -> Result<Response<Self::FetchRangesStream>, Status> {
+        let request = request.into_inner();
+        let service = Arc::clone(&self.service);
+
+        let (mut file, length) = {
+            let name = request.name.clone();
+            blocking(move || {
+                let file = File::open(service.object_path(&name)?)?;
+                let length = file.metadata()?.len();
+                Ok((file, length))
+            })
+            .await?
+        };
+
+        if let Some(range) = request
+            .ranges
+            .iter()
+            .find(|range| range.offset.saturating_add(range.length) > length)
+        {
+            return Err(Status::out_of_range(format!(
+                "Range {}+{} is beyond the end of {} ({} bytes)",
+                range.offset, range.length, request.name, length
+            )));
+        }
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::task::spawn_blocking(move || {
+            let _span = debug_span!("fetch", object = %request.name).entered();
+
+            for range in &request.ranges {
+                let start = Instant::now();
+                let mut sent = 0;
+
+                while sent < range.length {
+                    let offset = range.offset + sent;
+                    let part = (range.length - sent).min(MESSAGE_DATA_LENGTH as u64);
+
+                    let mut data = vec![0u8; part as usize];
+                    let read = file
+                        .seek(SeekFrom::Start(offset))
+                        .and_then(|_| file.read_exact(&mut data));
+
+                    let message = match read {
+                        Ok(()) => Ok(RangeData { offset, data }),
+                        Err(e) => Err(Status::internal(e.to_string())),
+                    };
+
+                    let failed = message.is_err();
+                    if tx.blocking_send(message).is_err() || failed {
+                        return;
+                    }
+
+                    sent += part;
+                }
+
+                metrics::record_range(range.length, start.elapsed());
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn apply(
+        &self,
+        request: Request<Streaming<ApplyRequest>>,
+    ) -> Result<Response<ApplyReply>, Status> {
+        let mut stream = request.into_inner();
+
+        let name = match stream.next().await {
+            Some(Ok(ApplyRequest {
+                request: Some(apply_request::Request::Name(name)),
+            })) => name,
+            Some(Err(status)) => return Err(status),
+            _ => {
+                return Err(Status::invalid_argument(
+                    "The first message must name the object",
+                ))
+            }
+        };
+
+        let mut patch_file =
+            tempfile::NamedTempFile::new().map_err(|e| Status::internal(e.to_string()))?;
+
+        while let Some(message) = stream.next().await {
+            match message?.request {
+                Some(apply_request::Request::Patch(data)) => patch_file
+                    .write_all(&data)
+                    .map_err(|e| Status::internal(e.to_string()))?,
+                _ => {
+                    return Err(Status::invalid_argument(
+                        "Only the first message names the object",
+                    ))
+                }
+            }
+        }
+
+        patch_file
+            .flush()
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        info!("Applying a patch to {}", name);
+
+        let service = Arc::clone(&self.service);
+        let _applying = self.applying.lock().await;
+
+        let sig = blocking(move || {
+            let _span = debug_span!("apply", object = %name).entered();
+            service.apply(&name, patch_file.path())
+        })
+        .await?;
+
+        Ok(Response::new(ApplyReply {
+            length: sig.length(),
+            strong_hash: sig.strong_hash().to_hex().to_string(),
+        }))
+    }
+}
@@ -0,0 +1,421 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::blake3_serde_hex;
+use crate::signature::{Chunk, InsertOp, Op, Signature};
+
+/// Destination is synced at least every `SYNC_INTERVAL` bytes.
+const SYNC_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// First line of a journal, binds it to a particular build. A journal
+/// is resumed only by the build with the same header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    #[serde(with = "blake3_serde_hex")]
+    target_hash: blake3::Hash,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_hash: Option<String>,
+    ops: usize,
+    #[serde(default)]
+    options: String,
+}
+
+impl Header {
+    /// Creates the header of a build of the target with the given hash.
+    ///
+    /// # Parameters:
+    /// - `target_hash`: hash of the target file
+    /// - `ops`: number of ops the build applies
+    pub fn new(target_hash: blake3::Hash, ops: usize) -> Self {
+        Self {
+            target_hash,
+            source_hash: None,
+            ops,
+            options: String::new(),
+        }
+    }
+
+    /// Binds the journal to the signatures the ops were computed from,
+    /// main source first, then seeds.
+    pub fn with_sources(mut self, sources: &[&Signature]) -> Result<Self, Box<dyn Error>> {
+        let mut hasher = blake3::Hasher::new();
+        for source in sources {
+            serde_json::to_writer(&mut hasher, source)?;
+        }
+        self.source_hash = Some(hasher.finalize().to_hex().to_string());
+        Ok(self)
+    }
+
+    /// Binds the journal to the options which change the ops or the data
+    /// written, e.g. `rolling=true max_requests=None`.
+    pub fn with_options(mut self, options: String) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Durable point: first `applied` ops are written and synced,
+/// destination is complete up to `offset`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Record {
+    applied: usize,
+    offset: u64,
+}
+
+/// Journal written alongside the destination file while it is being built,
+/// or alongside the diff file while target ranges are fetched.
+///
+/// Every record is appended only after the destination is synced, so the
+/// last complete record always describes data which survived a crash.
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    current: Record,
+    durable: Record,
+    previous: Record,
+}
+
+impl Journal {
+    /// Creates new journal for the build described by the header.
+    pub fn create(path: &Path, header: &Header) -> Result<Self, Box<dyn Error>> {
+        let mut file = File::create(path)?;
+
+        writeln!(file, "{}", serde_json::to_string(header)?)?;
+        file.sync_data()?;
+
+        Ok(Self {
+            file,
+            current: Record::default(),
+            durable: Record::default(),
+            previous: Record::default(),
+        })
+    }
+
+    /// Opens existing journal. Fails if the journal belongs to another build.
+    /// Incomplete trailing record (torn write) is ignored.
+    pub fn open(path: &Path, header: &Header) -> Result<Self, Box<dyn Error>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let written: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err("Journal is empty".into()),
+        };
+
+        if written.target_hash != header.target_hash
+            || written.ops != header.ops
+            || written.source_hash != header.source_hash
+            || written.options != header.options
+        {
+            return Err("Journal belongs to another build".into());
+        }
+
+        let mut durable = Record::default();
+        let mut previous = Record::default();
+
+        for line in lines {
+            match serde_json::from_str::<Record>(&line?) {
+                Ok(record) => {
+                    previous = durable;
+                    durable = record;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let file = OpenOptions::new().append(true).open(path)?;
+
+        Ok(Self {
+            file,
+            current: durable,
+            durable,
+            previous,
+        })
+    }
+
+    /// Returns the number of ops which are durably applied.
+    pub fn applied(&self) -> usize {
+        self.durable.applied
+    }
+
+    /// Returns the offset the destination is complete up to.
+    pub fn offset(&self) -> u64 {
+        self.durable.offset
+    }
+
+    /// Checks that the destination holds the durable data by verifying the
+    /// chunks written since the previous sync point against the target signature.
+    pub fn verify(
+        &self,
+        destination: &mut File,
+        target: &Signature,
+    ) -> Result<bool, Box<dyn Error>> {
+        if destination.metadata()?.len() < self.durable.offset {
+            return Ok(false);
+        }
+
+        for chunk in target.chunks() {
+            let end = chunk.offset() + chunk.length();
+            if chunk.offset() < self.previous.offset || end > self.durable.offset {
+                continue;
+            }
+
+            if !chunk_matches(destination, chunk.offset(), chunk, target)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Checks that the diff file holds the durable data of a fetch journal,
+    /// see `builder::build_local_diff_file_journaled`. Ranges fetched since
+    /// the previous sync point are verified against the target signature.
+    ///
+    /// # Parameters:
+    /// - `diff_file`: diff file the ranges are fetched to
+    /// - `target`: signature of the target file
+    /// - `ops`: all InsertOps of the diff, in the fetch order
+    pub fn verify_fetched(
+        &self,
+        diff_file: &mut File,
+        target: &Signature,
+        ops: &[InsertOp],
+    ) -> Result<bool, Box<dyn Error>> {
+        if diff_file.metadata()?.len() < self.durable.offset {
+            return Ok(false);
+        }
+
+        let mut at: u64 = 0;
+
+        for op in &ops[..self.durable.applied.min(ops.len())] {
+            if at >= self.previous.offset {
+                let end = op.offset() + op.length();
+                let chunks = target
+                    .chunks()
+                    .iter()
+                    .filter(|chunk| chunk.offset() >= op.offset() && chunk.offset() < end);
+
+                for chunk in chunks {
+                    let position = at + chunk.offset() - op.offset();
+                    if !chunk_matches(diff_file, position, chunk, target)? {
+                        return Ok(false);
+                    }
+                }
+            }
+
+            at += op.length();
+        }
+
+        Ok(true)
+    }
+
+    /// Records that the next op is written to the destination up to `offset`.
+    /// Syncs the destination and the journal once enough data is written.
+    pub fn record(&mut self, destination: &File, offset: u64) -> Result<(), Box<dyn Error>> {
+        self.current.applied += 1;
+        self.current.offset = offset;
+
+        if self.current.offset - self.durable.offset >= SYNC_INTERVAL {
+            self.sync(destination)?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the destination and appends a durable point to the journal.
+    pub fn sync(&mut self, destination: &File) -> Result<(), Box<dyn Error>> {
+        destination.sync_data()?;
+
+        writeln!(self.file, "{}", serde_json::to_string(&self.current)?)?;
+        self.file.sync_data()?;
+
+        self.previous = self.durable;
+        self.durable = self.current;
+
+        Ok(())
+    }
+}
+
+/// Returns true if `file` holds the data of the target chunk at `position`.
+fn chunk_matches(
+    file: &mut File,
+    position: u64,
+    chunk: &Chunk,
+    target: &Signature,
+) -> Result<bool, Box<dyn Error>> {
+    file.seek(SeekFrom::Start(position))?;
+
+    let mut data = Vec::with_capacity(chunk.length() as usize);
+    Read::by_ref(file)
+        .take(chunk.length())
+        .read_to_end(&mut data)?;
+
+    Ok(target.chunk_hash(&data) == chunk.strong_hash())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+    use std::io::Write;
+
+    fn header() -> Header {
+        Header::new(blake3::hash(b"target"), 3).with_options("rolling=false".to_string())
+    }
+
+    #[test]
+    fn open_resumes_from_the_last_complete_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.journal");
+        let destination = File::create(dir.path().join("file")).unwrap();
+
+        let mut journal = Journal::create(&path, &header()).unwrap();
+        journal.record(&destination, 100).unwrap();
+        journal.sync(&destination).unwrap();
+        journal.record(&destination, 250).unwrap();
+        journal.record(&destination, 300).unwrap();
+        journal.sync(&destination).unwrap();
+        drop(journal);
+
+        // Record torn by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"applied\":4,\"off").unwrap();
+
+        let journal = Journal::open(&path, &header()).unwrap();
+        assert_eq!(journal.applied(), 3);
+        assert_eq!(journal.offset(), 300);
+        assert_eq!(journal.previous.offset, 100);
+    }
+
+    #[test]
+    fn open_rejects_journals_of_other_builds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.journal");
+        Journal::create(&path, &header()).unwrap();
+
+        let other_target =
+            Header::new(blake3::hash(b"other"), 3).with_options("rolling=false".to_string());
+        let other_ops =
+            Header::new(blake3::hash(b"target"), 4).with_options("rolling=false".to_string());
+        let other_options =
+            Header::new(blake3::hash(b"target"), 3).with_options("rolling=true".to_string());
+
+        for other in [other_target, other_ops, other_options] {
+            assert!(Journal::open(&path, &other).is_err());
+        }
+        assert!(Journal::open(&path, &header()).is_ok());
+    }
+
+    #[test]
+    fn open_rejects_journals_of_other_sources() {
+        let data = random_data(1, 64 * 1024);
+        let source = Signature::generate(&mut &data[..], 1024, 4096, 16384).unwrap();
+        let other = Signature::generate(&mut &data[1..], 1024, 4096, 16384).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.journal");
+        Journal::create(&path, &header().with_sources(&[&source]).unwrap()).unwrap();
+
+        assert!(Journal::open(&path, &header().with_sources(&[&other]).unwrap()).is_err());
+        assert!(Journal::open(&path, &header()).is_err());
+        assert!(Journal::open(&path, &header().with_sources(&[&source]).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn verify_checks_chunks_written_since_the_previous_record() {
+        let data = random_data(2, 256 * 1024);
+        let target = Signature::generate(&mut &data[..], 4096, 16384, 65536).unwrap();
+        let ends: Vec<u64> = target
+            .chunks()
+            .iter()
+            .map(|chunk| chunk.offset() + chunk.length())
+            .collect();
+        assert!(ends.len() > 4);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.journal");
+        let mut destination = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("file"))
+            .unwrap();
+        destination.write_all(&data[..ends[3] as usize]).unwrap();
+
+        let mut journal = Journal::create(&path, &header()).unwrap();
+        journal.record(&destination, ends[1]).unwrap();
+        journal.sync(&destination).unwrap();
+        journal.record(&destination, ends[3]).unwrap();
+        journal.sync(&destination).unwrap();
+
+        assert!(journal.verify(&mut destination, &target).unwrap());
+
+        // Data before the previous record survived the sync before it
+        destination.seek(SeekFrom::Start(0)).unwrap();
+        destination.write_all(&[!data[0]]).unwrap();
+        assert!(journal.verify(&mut destination, &target).unwrap());
+
+        destination.seek(SeekFrom::Start(ends[2])).unwrap();
+        destination.write_all(&[!data[ends[2] as usize]]).unwrap();
+        assert!(!journal.verify(&mut destination, &target).unwrap());
+
+        destination.set_len(ends[2]).unwrap();
+        assert!(!journal.verify(&mut destination, &target).unwrap());
+    }
+
+    #[test]
+    fn verify_fetched_checks_ranges_fetched_since_the_previous_record() {
+        let source_data = random_data(3, 256 * 1024);
+        let mut target_data = source_data.clone();
+        for offset in [10_000, 100_000, 200_000] {
+            target_data[offset] ^= 1;
+        }
+
+        let source = Signature::generate(&mut &source_data[..], 4096, 16384, 65536).unwrap();
+        let target = Signature::generate(&mut &target_data[..], 4096, 16384, 65536).unwrap();
+        let diff = crate::signature::Diff::new(&source, &target).unwrap();
+        let ops = diff.insert_ops();
+        assert!(ops.len() >= 2);
+
+        // Fetched ranges follow each other in the diff file
+        let fetched: Vec<u8> = ops
+            .iter()
+            .flat_map(|op| &target_data[op.offset() as usize..(op.offset() + op.length()) as usize])
+            .copied()
+            .collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("diff.journal");
+        let mut diff_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("diff"))
+            .unwrap();
+        diff_file.write_all(&fetched).unwrap();
+
+        let mut journal = Journal::create(&path, &header()).unwrap();
+        let mut at: u64 = 0;
+        for op in ops {
+            at += op.length();
+            journal.record(&diff_file, at).unwrap();
+            journal.sync(&diff_file).unwrap();
+        }
+
+        assert!(journal
+            .verify_fetched(&mut diff_file, &target, ops)
+            .unwrap());
+
+        let last = fetched.len() as u64 - 1;
+        diff_file.seek(SeekFrom::Start(last)).unwrap();
+        diff_file.write_all(&[!fetched[last as usize]]).unwrap();
+        assert!(!journal
+            .verify_fetched(&mut diff_file, &target, ops)
+            .unwrap());
+    }
+}
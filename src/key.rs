@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Context of the hash key derivation, see `blake3::derive_key`.
+const KEY_CONTEXT: &str = "cloud-zsync 2024-06-01 signature hash key";
+
+/// Message which MAC identifies a key.
+const KEY_ID_MESSAGE: &[u8] = b"cloud-zsync key id";
+
+/// Message which MAC seeds the gear table of the chunker.
+const GEAR_SEED_MESSAGE: &[u8] = b"cloud-zsync gear seed";
+
+/// Key for keyed blake3 hashes. Keyed hashes of equal data are equal only
+/// under the same key, so signatures do not tell anyone without the key
+/// which files or chunks are the same.
+pub type HashKey = [u8; blake3::KEY_LEN];
+
+/// Derives a hash key from a key file of any length and contents.
+pub fn load(path: &Path) -> Result<HashKey, Box<dyn Error>> {
+    let material = fs::read(path)?;
+    if material.is_empty() {
+        return Err(format!("Key file {} is empty", path.display()).into());
+    }
+
+    Ok(blake3::derive_key(KEY_CONTEXT, &material))
+}
+
+/// Returns hex id of a key: MAC of a fixed message, it tells keys
+/// apart without revealing them.
+pub fn id(key: &HashKey) -> String {
+    blake3::keyed_hash(key, KEY_ID_MESSAGE).to_hex()[..16].to_string()
+}
+
+/// Returns seed of the chunker gear table, 0 keeps the plain table. Chunk
+/// boundaries under a keyed table do not tell anyone without the key
+/// whether a file contains known data, chunk lengths are still visible.
+pub fn gear_seed(key: Option<&HashKey>) -> u64 {
+    match key {
+        Some(key) => {
+            let mac = blake3::keyed_hash(key, GEAR_SEED_MESSAGE);
+            let seed = u64::from_le_bytes(mac.as_bytes()[..8].try_into().expect("8 bytes"));
+            seed.max(1)
+        }
+        None => 0,
+    }
+}
+
+/// Returns hasher which is keyed if the key is given.
+pub fn hasher(key: Option<&HashKey>) -> blake3::Hasher {
+    match key {
+        Some(key) => blake3::Hasher::new_keyed(key),
+        None => blake3::Hasher::new(),
+    }
+}
+
+/// Hashes data with the key if it is given.
+pub fn hash(key: Option<&HashKey>, data: &[u8]) -> blake3::Hash {
+    match key {
+        Some(key) => blake3::keyed_hash(key, data),
+        None => blake3::hash(data),
+    }
+}
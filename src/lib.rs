@@ -1,3 +1,59 @@
+pub mod analyze;
+pub mod base;
 mod blake3_serde_hex;
+#[cfg(all(not(target_arch = "wasm32"), feature = "http"))]
+pub mod bucket;
 pub mod builder;
+pub mod cache;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cas;
+#[cfg(all(not(target_arch = "wasm32"), feature = "http", feature = "zstd"))]
+pub mod casync;
+mod chunk_offset;
+pub mod churn;
+pub mod compression;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod exclude;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(all(not(target_arch = "wasm32"), feature = "http"))]
+pub mod http;
+pub mod journal;
+pub mod key;
+pub mod librsync;
+pub mod manifest;
+pub mod metadata;
+pub mod metrics;
+pub mod naming;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod patch;
+pub mod plan;
+pub mod platform;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod remote;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod repair;
+pub mod rolling;
+pub mod safety;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod selftest;
+#[cfg(all(not(target_arch = "wasm32"), feature = "serve"))]
+pub mod server;
 pub mod signature;
+pub mod stats;
+#[cfg(all(not(target_arch = "wasm32"), feature = "store"))]
+pub mod store;
+pub mod tar;
+#[cfg(test)]
+mod test_util;
+pub mod throttle;
+mod truncated_hash;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+pub mod zsync;
@@ -0,0 +1,705 @@
+use blake2::digest::consts::U32;
+use blake2::Blake2b;
+use md4::{Digest, Md4};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+/// Magic numbers of librsync signatures: weak sum and strong hash
+const MD4_SIG_MAGIC: u32 = 0x7273_0136;
+const BLAKE2_SIG_MAGIC: u32 = 0x7273_0137;
+const RK_MD4_SIG_MAGIC: u32 = 0x7273_0146;
+const RK_BLAKE2_SIG_MAGIC: u32 = 0x7273_0147;
+
+/// Magic number of librsync deltas
+const DELTA_MAGIC: u32 = 0x7273_0236;
+
+/// Delta commands: literals up to 64 bytes are encoded in the command itself,
+/// longer ones and copies carry big-endian integers of 1, 2, 4 or 8 bytes
+const OP_END: u8 = 0x00;
+const OP_LITERAL_64: u8 = 0x40;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+
+/// Block size rdiff uses by default
+pub const DEFAULT_BLOCK_SIZE: u32 = 2048;
+
+/// rsync rolling sum offset added to each byte
+const ROLLSUM_CHAR_OFFSET: u32 = 31;
+
+/// RabinKarp rolling hash parameters
+const RABINKARP_SEED: u32 = 1;
+const RABINKARP_MULT: u32 = 0x0810_4225;
+const RABINKARP_INVM: u32 = 0x98f0_09ad;
+const RABINKARP_ADJ: u32 = 0x0810_4224;
+
+/// Weak rolling sum of librsync signature blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeakSum {
+    /// rsync rolling checksum, librsync before 2.2
+    Rollsum,
+
+    /// RabinKarp rolling hash, the default since librsync 2.2
+    RabinKarp,
+}
+
+/// Strong hash of librsync signature blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrongSum {
+    /// MD4, librsync before 1.0
+    Md4,
+
+    /// BLAKE2b with 32 bytes output, the default since librsync 1.0
+    Blake2,
+}
+
+/// Signature of a basis file in librsync format, read and written by
+/// `rdiff signature`. Blocks are fixed-size, the last one may be shorter,
+/// its length is not stored.
+#[derive(Debug, Clone)]
+pub struct RdiffSignature {
+    weak_sum: WeakSum,
+    strong_sum: StrongSum,
+    block_size: u32,
+    strong_length: u32,
+
+    /// weak sum of each block
+    weak: Vec<u32>,
+
+    /// truncated strong hash of each block, one after another
+    strong: Vec<u8>,
+}
+
+/// Running weak sum of a window which can be moved by one byte.
+enum Rolling {
+    Rollsum { s1: u32, s2: u32, count: u32 },
+    RabinKarp { hash: u32, mult: u32 },
+}
+
+impl Rolling {
+    fn new(kind: WeakSum, data: &[u8]) -> Self {
+        match kind {
+            WeakSum::Rollsum => {
+                let (mut s1, mut s2) = (0u32, 0u32);
+                for &x in data {
+                    s1 = s1.wrapping_add(x as u32 + ROLLSUM_CHAR_OFFSET);
+                    s2 = s2.wrapping_add(s1);
+                }
+
+                Self::Rollsum {
+                    s1,
+                    s2,
+                    count: data.len() as u32,
+                }
+            }
+            WeakSum::RabinKarp => {
+                let (mut hash, mut mult) = (RABINKARP_SEED, 1u32);
+                for &x in data {
+                    hash = hash.wrapping_mul(RABINKARP_MULT).wrapping_add(x as u32);
+                    mult = mult.wrapping_mul(RABINKARP_MULT);
+                }
+
+                Self::RabinKarp { hash, mult }
+            }
+        }
+    }
+
+    /// Moves the window by one byte: `out` leaves it, `inc` enters it.
+    fn roll(&mut self, out: u8, inc: u8) {
+        match self {
+            Self::Rollsum { s1, s2, count } => {
+                *s1 = s1.wrapping_add(inc as u32).wrapping_sub(out as u32);
+                *s2 = s2
+                    .wrapping_add(*s1)
+                    .wrapping_sub(count.wrapping_mul(out as u32 + ROLLSUM_CHAR_OFFSET));
+            }
+            Self::RabinKarp { hash, mult } => {
+                *hash = hash
+                    .wrapping_mul(RABINKARP_MULT)
+                    .wrapping_add(inc as u32)
+                    .wrapping_sub(mult.wrapping_mul((out as u32).wrapping_add(RABINKARP_ADJ)));
+            }
+        }
+    }
+
+    /// Shrinks the window by one byte from the start, used at the end of a file.
+    fn roll_out(&mut self, out: u8) {
+        match self {
+            Self::Rollsum { s1, s2, count } => {
+                *s1 = s1.wrapping_sub(out as u32 + ROLLSUM_CHAR_OFFSET);
+                *s2 = s2.wrapping_sub(count.wrapping_mul(out as u32 + ROLLSUM_CHAR_OFFSET));
+                *count -= 1;
+            }
+            Self::RabinKarp { hash, mult } => {
+                *mult = mult.wrapping_mul(RABINKARP_INVM);
+                *hash =
+                    hash.wrapping_sub(mult.wrapping_mul((out as u32).wrapping_add(RABINKARP_ADJ)));
+            }
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        match self {
+            Self::Rollsum { s1, s2, .. } => (s2 << 16) | (s1 & 0xffff),
+            Self::RabinKarp { hash, .. } => *hash,
+        }
+    }
+}
+
+impl FromStr for WeakSum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rollsum" => Ok(Self::Rollsum),
+            "rabinkarp" => Ok(Self::RabinKarp),
+            _ => Err(format!(
+                "Unknown rolling sum {}, expected rollsum or rabinkarp",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for StrongSum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md4" => Ok(Self::Md4),
+            "blake2" => Ok(Self::Blake2),
+            _ => Err(format!("Unknown hash {}, expected md4 or blake2", s)),
+        }
+    }
+}
+
+impl StrongSum {
+    /// Returns the full digest length.
+    pub fn length(&self) -> u32 {
+        match self {
+            Self::Md4 => 16,
+            Self::Blake2 => 32,
+        }
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Md4 => Md4::digest(data).to_vec(),
+            Self::Blake2 => Blake2b::<U32>::digest(data).to_vec(),
+        }
+    }
+}
+
+impl RdiffSignature {
+    /// Calculates the signature of a basis file like `rdiff signature`.
+    ///
+    /// # Parameters:
+    /// - `reader`: basis file reader
+    /// - `block_size`: block size in bytes, see `DEFAULT_BLOCK_SIZE`
+    /// - `weak_sum`: rolling sum, RabinKarp is expected by librsync 2.2 and later
+    /// - `strong_sum`: strong hash
+    /// - `strong_length`: stored bytes of the strong hash, the full digest if `None`
+    pub fn generate(
+        reader: &mut dyn Read,
+        block_size: u32,
+        weak_sum: WeakSum,
+        strong_sum: StrongSum,
+        strong_length: Option<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let strong_length = strong_length.unwrap_or(strong_sum.length());
+
+        if block_size == 0 {
+            return Err("Block size must be positive".into());
+        }
+
+        if !(1..=strong_sum.length()).contains(&strong_length) {
+            return Err(format!(
+                "Strong sum length must be between 1 and {}",
+                strong_sum.length()
+            )
+            .into());
+        }
+
+        let mut sig = Self {
+            weak_sum,
+            strong_sum,
+            block_size,
+            strong_length,
+            weak: Vec::new(),
+            strong: Vec::new(),
+        };
+
+        let mut block = vec![0u8; block_size as usize];
+
+        loop {
+            let mut filled = 0;
+            while filled < block.len() {
+                match reader.read(&mut block[filled..])? {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+
+            if filled == 0 {
+                break;
+            }
+
+            let data = &block[..filled];
+            sig.weak.push(Rolling::new(weak_sum, data).digest());
+            sig.strong
+                .extend_from_slice(&strong_sum.digest(data)[..strong_length as usize]);
+
+            if filled < block.len() {
+                break;
+            }
+        }
+
+        Ok(sig)
+    }
+
+    /// Reads a signature written by `rdiff signature` or `write`.
+    pub fn read(r: &mut dyn Read) -> Result<Self, Box<dyn Error>> {
+        let (weak_sum, strong_sum) = match read_u32(r)? {
+            MD4_SIG_MAGIC => (WeakSum::Rollsum, StrongSum::Md4),
+            BLAKE2_SIG_MAGIC => (WeakSum::Rollsum, StrongSum::Blake2),
+            RK_MD4_SIG_MAGIC => (WeakSum::RabinKarp, StrongSum::Md4),
+            RK_BLAKE2_SIG_MAGIC => (WeakSum::RabinKarp, StrongSum::Blake2),
+            magic => return Err(format!("Not a librsync signature, magic {:#010x}", magic).into()),
+        };
+
+        let block_size = read_u32(r)?;
+        let strong_length = read_u32(r)?;
+
+        if block_size == 0 || !(1..=strong_sum.length()).contains(&strong_length) {
+            return Err("Invalid librsync signature header".into());
+        }
+
+        let mut sig = Self {
+            weak_sum,
+            strong_sum,
+            block_size,
+            strong_length,
+            weak: Vec::new(),
+            strong: Vec::new(),
+        };
+
+        let mut strong = vec![0u8; strong_length as usize];
+
+        loop {
+            let mut weak = [0u8; 4];
+            match r.read_exact(&mut weak) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            r.read_exact(&mut strong)?;
+            sig.weak.push(u32::from_be_bytes(weak));
+            sig.strong.extend_from_slice(&strong);
+        }
+
+        Ok(sig)
+    }
+
+    /// Writes the signature, `rdiff delta` can read it.
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let magic = match (self.weak_sum, self.strong_sum) {
+            (WeakSum::Rollsum, StrongSum::Md4) => MD4_SIG_MAGIC,
+            (WeakSum::Rollsum, StrongSum::Blake2) => BLAKE2_SIG_MAGIC,
+            (WeakSum::RabinKarp, StrongSum::Md4) => RK_MD4_SIG_MAGIC,
+            (WeakSum::RabinKarp, StrongSum::Blake2) => RK_BLAKE2_SIG_MAGIC,
+        };
+
+        w.write_all(&magic.to_be_bytes())?;
+        w.write_all(&self.block_size.to_be_bytes())?;
+        w.write_all(&self.strong_length.to_be_bytes())?;
+
+        for (block, weak) in self.weak.iter().enumerate() {
+            w.write_all(&weak.to_be_bytes())?;
+            w.write_all(self.strong(block))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.weak.len()
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Writes a delta turning the basis file into the new one, like `rdiff delta`.
+    /// Blocks of the basis are searched at any offset of the new file.
+    ///
+    /// # Parameters:
+    /// - `new`: new file contents
+    /// - `w`: delta output
+    ///
+    /// # Returns:
+    /// - `DeltaStats`: bytes copied from the basis and sent as literals
+    pub fn delta(&self, new: &[u8], w: &mut dyn Write) -> Result<DeltaStats, Box<dyn Error>> {
+        let size = self.block_size as usize;
+        let mut delta = DeltaWriter::new(w)?;
+
+        let mut candidates: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (block, &weak) in self.weak.iter().enumerate() {
+            candidates.entry(weak).or_default().push(block);
+        }
+
+        let mut position: usize = 0;
+        let mut literal_start: usize = 0;
+
+        if new.len() >= size {
+            let mut rolling = Rolling::new(self.weak_sum, &new[..size]);
+
+            loop {
+                if let Some(block) = self.find(
+                    &candidates,
+                    rolling.digest(),
+                    &new[position..position + size],
+                ) {
+                    delta.literal(&new[literal_start..position])?;
+                    delta.copy(block as u64 * size as u64, size as u64)?;
+                    position += size;
+                    literal_start = position;
+
+                    if position + size > new.len() {
+                        break;
+                    }
+                    rolling = Rolling::new(self.weak_sum, &new[position..position + size]);
+                } else {
+                    if position + size >= new.len() {
+                        break;
+                    }
+                    rolling.roll(new[position], new[position + size]);
+                    position += 1;
+                }
+            }
+        }
+
+        // The window shrinks at the end of the new file, its tail may match
+        // the short last block of the basis
+        if let Some(last) = self.blocks().checked_sub(1) {
+            let start = literal_start.max(new.len().saturating_sub(size - 1));
+            let mut rolling = Rolling::new(self.weak_sum, &new[start..]);
+
+            for tail in start..new.len() {
+                let window = &new[tail..];
+                if rolling.digest() == self.weak[last]
+                    && self.strong_sum.digest(window)[..self.strong_length as usize]
+                        == *self.strong(last)
+                {
+                    delta.literal(&new[literal_start..tail])?;
+                    delta.copy(last as u64 * size as u64, window.len() as u64)?;
+                    literal_start = new.len();
+                    break;
+                }
+                rolling.roll_out(new[tail]);
+            }
+        }
+
+        delta.literal(&new[literal_start..])?;
+        delta.finish()
+    }
+
+    /// Returns the block with the window's weak sum and strong hash.
+    fn find(
+        &self,
+        candidates: &HashMap<u32, Vec<usize>>,
+        weak: u32,
+        window: &[u8],
+    ) -> Option<usize> {
+        let blocks = candidates.get(&weak)?;
+        let strong = self.strong_sum.digest(window);
+        let strong = &strong[..self.strong_length as usize];
+
+        blocks
+            .iter()
+            .copied()
+            .find(|&block| self.strong(block) == strong)
+    }
+
+    fn strong(&self, block: usize) -> &[u8] {
+        let length = self.strong_length as usize;
+        &self.strong[block * length..(block + 1) * length]
+    }
+}
+
+/// Bytes of the new file a delta copies from the basis and carries itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeltaStats {
+    pub copy_length: u64,
+    pub literal_length: u64,
+}
+
+/// Writes delta commands, merging adjacent copies.
+struct DeltaWriter<'a> {
+    w: &'a mut dyn Write,
+
+    /// copy which may still be extended: offset and length
+    pending: Option<(u64, u64)>,
+
+    stats: DeltaStats,
+}
+
+impl<'a> DeltaWriter<'a> {
+    fn new(w: &'a mut dyn Write) -> io::Result<Self> {
+        w.write_all(&DELTA_MAGIC.to_be_bytes())?;
+
+        Ok(Self {
+            w,
+            pending: None,
+            stats: DeltaStats::default(),
+        })
+    }
+
+    fn copy(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.stats.copy_length += length;
+
+        match &mut self.pending {
+            Some((pending_offset, pending_length))
+                if *pending_offset + *pending_length == offset =>
+            {
+                *pending_length += length;
+                Ok(())
+            }
+            _ => {
+                self.flush_copy()?;
+                self.pending = Some((offset, length));
+                Ok(())
+            }
+        }
+    }
+
+    fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.flush_copy()?;
+        self.stats.literal_length += data.len() as u64;
+
+        match data.len() as u64 {
+            length @ 1..=64 => self.w.write_all(&[length as u8])?,
+            length => {
+                let width = int_width(length);
+                self.w
+                    .write_all(&[OP_LITERAL_N1 + width.trailing_zeros() as u8])?;
+                self.w.write_all(&length.to_be_bytes()[8 - width..])?;
+            }
+        }
+
+        self.w.write_all(data)
+    }
+
+    fn flush_copy(&mut self) -> io::Result<()> {
+        if let Some((offset, length)) = self.pending.take() {
+            let (offset_width, length_width) = (int_width(offset), int_width(length));
+            let op = OP_COPY_N1_N1
+                + 4 * offset_width.trailing_zeros() as u8
+                + length_width.trailing_zeros() as u8;
+
+            self.w.write_all(&[op])?;
+            self.w
+                .write_all(&offset.to_be_bytes()[8 - offset_width..])?;
+            self.w
+                .write_all(&length.to_be_bytes()[8 - length_width..])?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<DeltaStats, Box<dyn Error>> {
+        self.flush_copy()?;
+        self.w.write_all(&[OP_END])?;
+        Ok(self.stats)
+    }
+}
+
+/// Applies a librsync delta to the basis file like `rdiff patch`.
+///
+/// # Parameters:
+/// - `basis`: file the delta was made against
+/// - `delta`: delta stream
+/// - `w`: new file output
+///
+/// # Returns:
+/// - `u64`: length of the new file
+pub fn patch<R: Read + Seek>(
+    basis: &mut R,
+    delta: &mut dyn Read,
+    w: &mut dyn Write,
+) -> Result<u64, Box<dyn Error>> {
+    if read_u32(delta)? != DELTA_MAGIC {
+        return Err("Not a librsync delta".into());
+    }
+
+    let mut written: u64 = 0;
+
+    loop {
+        let mut op = [0u8; 1];
+        delta.read_exact(&mut op)?;
+
+        let copied = match op[0] {
+            OP_END => return Ok(written),
+            length @ 1..=OP_LITERAL_64 => io::copy(&mut delta.take(length as u64), w)?,
+            op @ OP_LITERAL_N1..OP_COPY_N1_N1 => {
+                let length = read_int(delta, 1 << (op - OP_LITERAL_N1))?;
+                io::copy(&mut delta.take(length), w)?
+            }
+            op @ OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                let offset = read_int(delta, 1 << ((op - OP_COPY_N1_N1) / 4))?;
+                let length = read_int(delta, 1 << ((op - OP_COPY_N1_N1) % 4))?;
+
+                basis.seek(SeekFrom::Start(offset))?;
+                let copied = io::copy(&mut basis.take(length), w)?;
+                if copied != length {
+                    return Err(format!("Copy beyond the end of the basis at {}", offset).into());
+                }
+                copied
+            }
+            op => return Err(format!("Unknown delta command {:#04x}", op).into()),
+        };
+
+        written += copied;
+    }
+}
+
+/// Returns the width of the shortest big-endian integer holding the value.
+fn int_width(value: u64) -> usize {
+    match value {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x1_0000..=0xffff_ffff => 4,
+        _ => 8,
+    }
+}
+
+fn read_int(r: &mut dyn Read, width: usize) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes[8 - width..])?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+    use std::io::Cursor;
+
+    const SUMS: [(WeakSum, StrongSum); 4] = [
+        (WeakSum::Rollsum, StrongSum::Md4),
+        (WeakSum::Rollsum, StrongSum::Blake2),
+        (WeakSum::RabinKarp, StrongSum::Md4),
+        (WeakSum::RabinKarp, StrongSum::Blake2),
+    ];
+
+    #[test]
+    fn signature_write_and_read_round_trip() {
+        let basis = random_data(1, 5 * 512 + 7);
+
+        for (weak_sum, strong_sum) in SUMS {
+            let sig = RdiffSignature::generate(&mut &basis[..], 512, weak_sum, strong_sum, Some(8))
+                .unwrap();
+
+            let mut written: Vec<u8> = Vec::new();
+            sig.write(&mut written).unwrap();
+            assert_eq!(written.len(), 12 + 6 * (4 + 8));
+
+            let read = RdiffSignature::read(&mut &written[..]).unwrap();
+            assert_eq!((read.weak_sum, read.strong_sum), (weak_sum, strong_sum));
+            assert_eq!(read.block_size(), 512);
+            assert_eq!(read.blocks(), 6);
+            assert_eq!(read.weak, sig.weak);
+            assert_eq!(read.strong, sig.strong);
+        }
+    }
+
+    #[test]
+    fn signature_header_is_the_one_of_rdiff() {
+        let sig = RdiffSignature::generate(
+            &mut &b"data"[..],
+            DEFAULT_BLOCK_SIZE,
+            WeakSum::RabinKarp,
+            StrongSum::Blake2,
+            None,
+        )
+        .unwrap();
+
+        let mut written: Vec<u8> = Vec::new();
+        sig.write(&mut written).unwrap();
+
+        assert_eq!(
+            &written[..12],
+            &[0x72, 0x73, 0x01, 0x47, 0, 0, 0x08, 0, 0, 0, 0, 32]
+        );
+        assert!(RdiffSignature::read(&mut &written[4..]).is_err());
+    }
+
+    #[test]
+    fn delta_and_patch_round_trip() {
+        let basis = random_data(2, 64 * 1024);
+        let mut new = random_data(3, 100);
+        new.extend_from_slice(&basis[..30_000]);
+        new.extend_from_slice(&random_data(4, 1000));
+        new.extend_from_slice(&basis[31_000..]);
+
+        for (weak_sum, strong_sum) in SUMS {
+            let sig = RdiffSignature::generate(&mut &basis[..], 1024, weak_sum, strong_sum, None)
+                .unwrap();
+
+            let mut delta: Vec<u8> = Vec::new();
+            let stats = sig.delta(&new, &mut delta).unwrap();
+            assert_eq!(stats.copy_length + stats.literal_length, new.len() as u64);
+            assert!(stats.literal_length < 4 * 1024);
+
+            let mut patched: Vec<u8> = Vec::new();
+            let length = patch(&mut Cursor::new(&basis), &mut &delta[..], &mut patched).unwrap();
+            assert_eq!(length, new.len() as u64);
+            assert_eq!(patched, new);
+        }
+    }
+
+    #[test]
+    fn delta_of_a_file_shorter_than_a_block_is_a_literal() {
+        let basis = random_data(5, 4096);
+        let new = random_data(6, 1000);
+        let sig = RdiffSignature::generate(
+            &mut &basis[..],
+            2048,
+            WeakSum::RabinKarp,
+            StrongSum::Blake2,
+            None,
+        )
+        .unwrap();
+
+        let mut delta: Vec<u8> = Vec::new();
+        let stats = sig.delta(&new, &mut delta).unwrap();
+        assert_eq!(stats.copy_length, 0);
+
+        let mut patched: Vec<u8> = Vec::new();
+        patch(&mut Cursor::new(&basis), &mut &delta[..], &mut patched).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn patch_rejects_other_files() {
+        let mut patched: Vec<u8> = Vec::new();
+        let result = patch(
+            &mut Cursor::new(Vec::new()),
+            &mut &b"rs\x016...."[..],
+            &mut patched,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -1,280 +1,5487 @@
-use argh::FromArgs;
+use argh::{ArgsInfo, FromArgs};
 use console::style;
 use humansize::{format_size, DECIMAL};
-use indicatif::ProgressIterator;
+use indicatif::{MultiProgress, ProgressBar};
+use memmap2::Mmap;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Write};
-use std::time::Instant;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug_span, error, info, warn, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 
-use crate::signature::{Diff, Op, Signature};
+use cloud_zsync::bucket::{self, BucketUrl};
+use cloud_zsync::builder::{CopySource, HashingWriter, MappedSource, Seeds};
+use cloud_zsync::cache::{SignCache, SignModes};
+use cloud_zsync::cas::ChunkStore;
+use cloud_zsync::casync::{CasyncIndex, CasyncStore, ChunkDigest};
+use cloud_zsync::config::Config;
+use cloud_zsync::exclude::{self, Exclusions};
+use cloud_zsync::journal::{self, Journal};
+use cloud_zsync::key;
+use cloud_zsync::librsync::{self, RdiffSignature, StrongSum, WeakSum};
+use cloud_zsync::manifest::{self, FileChange, TreeManifest};
+use cloud_zsync::metadata::FileMetadata;
+use cloud_zsync::naming::{self, NamingStrategy};
+use cloud_zsync::patch::{self, Patch, PatchHeader};
+use cloud_zsync::plan::TransferPlan;
+use cloud_zsync::remote::{self, RemoteSession};
+use cloud_zsync::repair::FileHealth;
+use cloud_zsync::rolling::BlockMatch;
+use cloud_zsync::safety::SymlinkMode;
+use cloud_zsync::server::{self, DiffService};
+use cloud_zsync::signature::{ChunkSizes, Diff, Format, Op, Operation, SignOptions, Signature};
+use cloud_zsync::stats::{BatchStats, DiffStats, Pricing};
+use cloud_zsync::store::Store;
+use cloud_zsync::zsync::{self, ControlFile};
+use cloud_zsync::{
+    analyze, base, builder, churn, compression, http, metrics, platform, repair, safety, selftest,
+    stats, throttle,
+};
 
-mod blake3_serde_hex;
-mod builder;
+mod completions;
 mod progress_bar;
-mod signature;
+#[cfg(feature = "otlp")]
+mod telemetry;
 
-const SIG_EXT: &str = ".rsig";
+use completions::Shell;
+use progress_bar::ProgressFormat;
+
+const JOURNAL_EXT: &str = ".journal";
+const FETCH_EXT: &str = ".fetch";
+
+/// Share of the target file in percent above which diff downloads the whole
+/// file, see `DiffCommand::full_download_threshold`
+const DEFAULT_FULL_DOWNLOAD_THRESHOLD: f64 = 90.0;
+
+/// Threads downloading parts of a whole target file
+const FULL_DOWNLOAD_THREADS: usize = 8;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const DEFAULT_STORE: &str = "signatures.db";
+
+/// Path of stdin or stdout in arguments
+const STDIO_PATH: &str = "-";
+
+/// Exit code of diff when the files differ, as of diff and cmp
+const EXIT_DIFFERENT: u8 = 1;
+
+/// Exit code of failed commands and invalid arguments
+const EXIT_ERROR: u8 = 2;
+
+/// Defaults of command options, see `Config::load`
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
 trait Runner {
     fn run(&self) -> Result<(), Box<dyn Error>>;
 }
 
-#[derive(FromArgs, PartialEq, Debug)]
-/// zsync for GCS
-struct CLI {
-    #[argh(subcommand)]
-    command: Command,
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+/// zsync for GCS
+#[allow(clippy::upper_case_acronyms)]
+struct CLI {
+    /// only print warnings and errors
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// print debug logs, including every range request, -vv for trace logs
+    #[argh(switch, short = 'v')]
+    verbose: u8,
+
+    /// progress output: bar (default) or json, JSON lines of progress events on stderr
+    #[argh(option, default = "ProgressFormat::Bar")]
+    progress: ProgressFormat,
+
+    /// config file with option defaults, ~/.config/cloud-rsync/config.toml by default
+    #[argh(option, from_str_fn(path_arg))]
+    config: Option<PathBuf>,
+
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand)]
+enum Command {
+    Sign(SignCommand),
+    Diff(DiffCommand),
+    Apply(ApplyCommand),
+    ApplyPlan(ApplyPlanCommand),
+    Repair(RepairCommand),
+    Scrub(ScrubCommand),
+    Churn(ChurnCommand),
+    Stats(StatsCommand),
+    Analyze(AnalyzeCommand),
+    Selftest(SelftestCommand),
+    Store(StoreCommand),
+    Cas(CasCommand),
+    Casync(CasyncCommand),
+    TreeDiff(TreeDiffCommand),
+    ChooseBase(ChooseBaseCommand),
+    Serve(ServeCommand),
+    Sync(SyncCommand),
+    Server(ServerCommand),
+    Zsync(ZsyncCommand),
+    Rdiff(RdiffCommand),
+    Ls(LsCommand),
+    Completions(CompletionsCommand),
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "sign")]
+/// Generate file signature
+struct SignCommand {
+    /// file mask (ex: "*.psd"), - signs stdin
+    #[argh(positional, from_str_fn(utf8_arg))]
+    mask: String,
+
+    /// min chunk size, the one of the previous signature or a quarter of avg by default
+    #[argh(option)]
+    min_size: Option<u32>,
+
+    /// avg chunk size, the one of the previous signature or picked from the file size by default
+    #[argh(option)]
+    avg_size: Option<u32>,
+
+    /// max chunk size, the one of the previous signature or four times avg by default
+    #[argh(option)]
+    max_size: Option<u32>,
+
+    /// signature file name template, supports {{name}}, {{stem}}, {{ext}} and {{version}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+
+    /// write the signature to this file, or into this directory if it exists or ends with a slash
+    #[argh(option, from_str_fn(path_arg))]
+    output: Option<PathBuf>,
+
+    /// write signatures under this directory, mirroring the tree under the mask directory
+    #[argh(option, from_str_fn(path_arg))]
+    sig_dir: Option<PathBuf>,
+
+    /// skip files matching this glob, a pattern without a slash matches names at any depth (repeatable)
+    #[argh(option, from_str_fn(utf8_arg))]
+    exclude: Vec<String>,
+
+    /// skip files ignored by git: .gitignore files, .git/info/exclude and the global gitignore
+    #[argh(switch)]
+    respect_gitignore: bool,
+
+    /// record when each chunk was last changed, using the existing signature as history
+    #[argh(switch)]
+    track_changes: bool,
+
+    /// record modification time, permissions and ownership of files, diff --preserve-metadata restores them
+    #[argh(switch)]
+    metadata: bool,
+
+    /// how to treat symbolic links: follow (default, links leading outside the tree are skipped), preserve (record them in the --manifest) or skip
+    #[argh(option, default = "SymlinkMode::Follow")]
+    symlinks: SymlinkMode,
+
+    /// store crc32c of each chunk to validate against GCS checksums
+    #[argh(switch)]
+    crc32c: bool,
+
+    /// store md5 of a whole file to compare with GCS md5Hash
+    #[argh(switch)]
+    md5: bool,
+
+    /// number of threads used to hash chunks of a file, 0 means all cores, 1 by default
+    #[argh(option)]
+    threads: Option<usize>,
+
+    /// stream files instead of memory-mapping them (for network filesystems)
+    #[argh(switch)]
+    no_mmap: bool,
+
+    /// number of files to sign concurrently, 1 by default
+    #[argh(option)]
+    jobs: Option<usize>,
+
+    /// reuse chunk boundaries of the existing signature, it must be generated with the same chunk sizes
+    #[argh(switch)]
+    warm_start: bool,
+
+    /// write a single manifest for all matched files instead of a signature per file
+    #[argh(option, from_str_fn(path_arg))]
+    manifest: Option<PathBuf>,
+
+    /// keep signatures up to date, re-signing files as they change
+    #[argh(switch)]
+    watch: bool,
+
+    /// overwrite existing signatures and manifests, --warm-start, --track-changes and --watch update them anyway
+    #[argh(switch)]
+    force: bool,
+
+    /// milliseconds without changes to wait before re-signing in watch mode
+    #[argh(option, default = "500")]
+    debounce: u64,
+
+    /// serve Prometheus metrics on this address while watching, e.g. 127.0.0.1:9100
+    #[argh(option, from_str_fn(utf8_arg))]
+    metrics_addr: Option<String>,
+
+    /// cache file with sizes and mtimes of signed files, unchanged files are skipped
+    #[argh(option, from_str_fn(path_arg))]
+    cache: Option<PathBuf>,
+
+    /// index fixed-size blocks of this size for diff --rolling, 0 disables the index
+    #[argh(option, default = "0")]
+    block_size: usize,
+
+    /// file format hint: raw or tar, tar aligns chunks to archive entries
+    #[argh(option, default = "Format::Raw")]
+    format: Format,
+
+    /// sign decompressed contents of gzip files, diff rebuilds and recompresses them,
+    /// the whole file is downloaded if the result differs from the target
+    #[argh(switch)]
+    decompress: bool,
+
+    /// key file for keyed hashes and chunk boundaries, signatures do not reveal equal data
+    /// to anyone without the key, chunk lengths are still visible
+    #[argh(option, from_str_fn(path_arg))]
+    key_file: Option<PathBuf>,
+
+    /// bytes of each chunk hash to keep, from 4 to 32 (full), 8 keep the chance of a false match below one in a million for up to a million chunks, see stats
+    #[argh(option)]
+    hash_length: Option<usize>,
+
+    /// leave chunk offsets out of signatures, restored from chunk lengths on load, shrinks signatures by a sixth, by a quarter with --hash-length 8
+    #[argh(switch)]
+    implicit_offsets: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug, Clone)]
+#[argh(subcommand, name = "diff")]
+/// Generate diff between two signatures and print the stats
+struct DiffCommand {
+    /// source signature path, or a directory or glob of signatures to diff against the target ones by relative path
+    #[argh(positional, from_str_fn(path_arg))]
+    source: PathBuf,
+
+    /// target signature path, or a directory or glob of signatures
+    #[argh(positional, from_str_fn(path_arg))]
+    target: PathBuf,
+
+    /// keep the temporary diff file and log its path
+    #[argh(switch)]
+    keep_diff_file: bool,
+
+    /// write the diff file to this path and keep it
+    #[argh(option, from_str_fn(path_arg))]
+    diff_output: Option<PathBuf>,
+
+    /// number of attempts for each range read from the target file, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// limit reads from the target file (bytes/sec)
+    #[argh(option)]
+    bwlimit: Option<u64>,
+
+    /// continue an interrupted fetch or build using its journal
+    #[argh(switch)]
+    resume: bool,
+
+    /// read the changed ranges straight from the target file into the new file in a single pass, without a temporary diff file
+    #[argh(switch)]
+    stream: bool,
+
+    /// apply the ops on this many threads writing each at its offset, 1 by default
+    #[argh(option)]
+    jobs: Option<usize>,
+
+    /// restore modification time and permissions recorded with sign --metadata on the new file
+    #[argh(switch)]
+    preserve_metadata: bool,
+
+    /// also restore the owner and group recorded with sign --metadata, usually needs root
+    #[argh(switch)]
+    preserve_owner: bool,
+
+    /// signature of an additional local file to copy chunks from (repeatable)
+    #[argh(option, from_str_fn(path_arg))]
+    seed: Vec<PathBuf>,
+
+    /// allow the destination to overwrite the target file
+    #[argh(switch)]
+    in_place: bool,
+
+    /// overwrite an existing new file
+    #[argh(switch)]
+    force: bool,
+
+    /// stream the source file instead of memory-mapping it (for network filesystems)
+    #[argh(switch)]
+    no_mmap: bool,
+
+    /// search the source file for shifted data with a rolling hash, the target must be signed with --block-size
+    #[argh(switch)]
+    rolling: bool,
+
+    /// compress segments of the diff file with zstd at this level
+    #[argh(option)]
+    zstd_level: Option<i32>,
+
+    /// compress segments of the diff file with zstd using nearby source file data as a dictionary, like zstd --patch-from
+    #[argh(switch)]
+    patch_from: bool,
+
+    /// store bsdiff deltas against nearby source file data for changed chunks when they are smaller, implies zstd compression
+    #[argh(switch)]
+    delta: bool,
+
+    /// also write a self-contained patch file to build the new file from with apply
+    #[argh(option, from_str_fn(path_arg))]
+    patch: Option<PathBuf>,
+
+    /// encrypt the patch file with age to this recipient, age1... (repeatable)
+    #[argh(option, from_str_fn(utf8_arg))]
+    encrypt_to: Vec<String>,
+
+    /// print the diff stats as a single JSON document instead of the report
+    #[argh(switch)]
+    json: bool,
+
+    /// only write a plan of the build to this path, for apply-plan to execute later
+    #[argh(option, from_str_fn(path_arg))]
+    plan: Option<PathBuf>,
+
+    /// print what would be requested and written without writing anything
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// download the whole target file in parallel parts instead if more than this share of it in percent changed, 90 by default, 100 disables it
+    #[argh(option)]
+    full_download_threshold: Option<f64>,
+
+    /// merge the ranges to request closest to each other until there are at most this many, fetching the data between them too
+    #[argh(option)]
+    max_requests: Option<usize>,
+
+    /// egress price of the target file endpoint in $ per GB, prints the estimated transfer cost
+    #[argh(option)]
+    egress_price: Option<f64>,
+
+    /// request price of the target file endpoint in $ per 10k requests, prints the estimated transfer cost
+    #[argh(option)]
+    request_price: Option<f64>,
+
+    /// only print the stats calculated from the signatures, without reading the files
+    #[argh(switch)]
+    stats_only: bool,
+
+    /// signature file name template, must contain {{name}} or both {{stem}} and {{ext}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// reconstructed file name template, supports {{name}}, {{stem}}, {{ext}} and {{version}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_OUTPUT_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    output_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+
+    /// write the new file to stdout instead of the output template path
+    #[argh(switch)]
+    stdout: bool,
+
+    /// write the new file to this path instead of the output template path, - for stdout
+    #[argh(option, from_str_fn(path_arg))]
+    output: Option<PathBuf>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "apply")]
+/// Build the new file from the source file and a patch written by diff --patch
+struct ApplyCommand {
+    /// source file path
+    #[argh(positional, from_str_fn(path_arg))]
+    source: PathBuf,
+
+    /// patch file path
+    #[argh(positional, from_str_fn(path_arg))]
+    patch: PathBuf,
+
+    /// path of the new file, - writes it to stdout
+    #[argh(positional, from_str_fn(path_arg))]
+    destination: PathBuf,
+
+    /// age identity file to decrypt an encrypted patch
+    #[argh(option, from_str_fn(path_arg))]
+    identity: Option<PathBuf>,
+
+    /// key file the target was signed with, to verify the new file
+    #[argh(option, from_str_fn(path_arg))]
+    key_file: Option<PathBuf>,
+
+    /// additional local file to copy chunks from, in the order given to diff --seed (repeatable)
+    #[argh(option, from_str_fn(path_arg))]
+    seed: Vec<PathBuf>,
+
+    /// print what would be written without writing anything
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// continue an interrupted build using its journal
+    #[argh(switch)]
+    resume: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "apply-plan")]
+/// Build the new file following a plan written by diff --plan
+struct ApplyPlanCommand {
+    /// plan file path
+    #[argh(positional, from_str_fn(path_arg))]
+    plan: PathBuf,
+
+    /// key file the signatures were generated with, to verify the new file
+    #[argh(option, from_str_fn(path_arg))]
+    key_file: Option<PathBuf>,
+
+    /// number of attempts for each range read from the target file, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// limit reads from the target file (bytes/sec)
+    #[argh(option)]
+    bwlimit: Option<u64>,
+
+    /// print what would be requested and written without writing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "repair")]
+/// Verify a local file against its signature and fetch only the damaged chunks from the file of the signature
+struct RepairCommand {
+    /// damaged file path
+    #[argh(positional, from_str_fn(path_arg))]
+    file: PathBuf,
+
+    /// authoritative signature of the file
+    #[argh(positional, from_str_fn(path_arg))]
+    signature: PathBuf,
+
+    /// number of attempts for each range read, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// limit reads from the file of the signature (bytes/sec)
+    #[argh(option)]
+    bwlimit: Option<u64>,
+
+    /// only print the damaged ranges without writing anything
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// signature file name template, must contain {{name}} or both {{stem}} and {{ext}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "scrub")]
+/// Verify the files of a tree against their signatures or a manifest at a limited rate, and report or repair silent corruption
+struct ScrubCommand {
+    /// tree root
+    #[argh(positional, from_str_fn(path_arg))]
+    root: PathBuf,
+
+    /// manifest of the tree written by sign --manifest, signature files next to the files are used otherwise
+    #[argh(option, from_str_fn(path_arg))]
+    manifest: Option<PathBuf>,
+
+    /// limit reads of the verified files (bytes/sec)
+    #[argh(option)]
+    bwlimit: Option<u64>,
+
+    /// root of a good copy of the tree to repair corrupted files from
+    #[argh(option, from_str_fn(path_arg))]
+    repair_from: Option<PathBuf>,
+
+    /// number of attempts for each range read from the good copy, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// print the report as a single JSON document
+    #[argh(switch)]
+    json: bool,
+
+    /// signature file name template, must contain {{name}} or both {{stem}} and {{ext}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "churn")]
+/// Show which regions of a file change most often
+struct ChurnCommand {
+    /// signature generated with --track-changes
+    #[argh(positional, from_str_fn(path_arg))]
+    signature: PathBuf,
+
+    /// number of regions to split the file into
+    #[argh(option, default = "20")]
+    regions: usize,
+
+    /// count changes within the last N days
+    #[argh(option, default = "30")]
+    days: u64,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "stats")]
+/// Show chunk size distribution and expected cost of edits
+struct StatsCommand {
+    /// signature file path
+    #[argh(positional, from_str_fn(path_arg))]
+    signature: PathBuf,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "analyze")]
+/// Sample a file and recommend chunk sizes and patch compression
+struct AnalyzeCommand {
+    /// file path
+    #[argh(positional, from_str_fn(path_arg))]
+    file: PathBuf,
+
+    /// signature of a previous version of the file, used to measure how changes are spread
+    #[argh(option, from_str_fn(path_arg))]
+    previous: Option<PathBuf>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "selftest")]
+/// Validate this build against the canonical test vectors
+struct SelftestCommand {
+    /// print test vectors with expected values calculated by this build
+    #[argh(switch)]
+    regenerate: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "store")]
+/// Manage signatures kept in a SQLite store
+struct StoreCommand {
+    #[argh(subcommand)]
+    command: StoreSubcommand,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand)]
+enum StoreSubcommand {
+    Add(StoreAddCommand),
+    List(StoreListCommand),
+    Prune(StorePruneCommand),
+    Export(StoreExportCommand),
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "add")]
+/// Sign files and add their signatures to the store
+struct StoreAddCommand {
+    /// file mask (ex: "*.psd")
+    #[argh(positional, from_str_fn(utf8_arg))]
+    mask: String,
+
+    /// store database path
+    #[argh(
+        option,
+        default = "PathBuf::from(DEFAULT_STORE)",
+        from_str_fn(path_arg)
+    )]
+    db: PathBuf,
+
+    /// min chunk size, the one of the stored signature or a quarter of avg by default
+    #[argh(option)]
+    min_size: Option<u32>,
+
+    /// avg chunk size, the one of the stored signature or picked from the file size by default
+    #[argh(option)]
+    avg_size: Option<u32>,
+
+    /// max chunk size, the one of the stored signature or four times avg by default
+    #[argh(option)]
+    max_size: Option<u32>,
+
+    /// number of threads used to hash chunks of a file, 0 means all cores, 1 by default
+    #[argh(option)]
+    threads: Option<usize>,
+
+    /// stream files instead of memory-mapping them (for network filesystems)
+    #[argh(switch)]
+    no_mmap: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+/// List signatures in the store
+struct StoreListCommand {
+    /// store database path
+    #[argh(
+        option,
+        default = "PathBuf::from(DEFAULT_STORE)",
+        from_str_fn(path_arg)
+    )]
+    db: PathBuf,
+
+    /// list only files with this content hash
+    #[argh(option, from_str_fn(utf8_arg))]
+    hash: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "prune")]
+/// Remove signatures of files which no longer exist
+struct StorePruneCommand {
+    /// store database path
+    #[argh(
+        option,
+        default = "PathBuf::from(DEFAULT_STORE)",
+        from_str_fn(path_arg)
+    )]
+    db: PathBuf,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "export")]
+/// Write a signature from the store to a signature file
+struct StoreExportCommand {
+    /// signed file path
+    #[argh(positional, from_str_fn(path_arg))]
+    file: PathBuf,
+
+    /// store database path
+    #[argh(
+        option,
+        default = "PathBuf::from(DEFAULT_STORE)",
+        from_str_fn(path_arg)
+    )]
+    db: PathBuf,
+
+    /// signature file name template, supports {{name}}, {{stem}}, {{ext}} and {{version}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "cas")]
+/// Deduplicate files in a content-addressable chunk repository
+struct CasCommand {
+    #[argh(subcommand)]
+    command: CasSubcommand,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand)]
+enum CasSubcommand {
+    Ingest(CasIngestCommand),
+    Materialize(CasMaterializeCommand),
+    Gc(CasGcCommand),
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "ingest")]
+/// Add files to the repository, prints a manifest path for each file
+struct CasIngestCommand {
+    /// file mask (ex: "*.psd")
+    #[argh(positional, from_str_fn(utf8_arg))]
+    mask: String,
+
+    /// repository directory
+    #[argh(option, from_str_fn(path_arg))]
+    repo: PathBuf,
+
+    /// min chunk size
+    #[argh(option, default = "4096")]
+    min_size: u32,
+
+    /// avg chunk size
+    #[argh(option, default = "16384")]
+    avg_size: u32,
+
+    /// max chunk size
+    #[argh(option, default = "65536")]
+    max_size: u32,
+
+    /// manifest file name template, supports {{name}}, {{stem}}, {{ext}} and {{version}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the template
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+
+    /// key file for keyed hashes, manifests and chunk names do not reveal equal data to anyone without the key
+    #[argh(option, from_str_fn(path_arg))]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "materialize")]
+/// Restore a file from the repository using its manifest
+struct CasMaterializeCommand {
+    /// manifest path
+    #[argh(positional, from_str_fn(path_arg))]
+    manifest: PathBuf,
+
+    /// output file path
+    #[argh(positional, from_str_fn(path_arg))]
+    output: PathBuf,
+
+    /// repository directory
+    #[argh(option, from_str_fn(path_arg))]
+    repo: PathBuf,
+
+    /// key file the manifest was generated with, to verify chunks
+    #[argh(option, from_str_fn(path_arg))]
+    key_file: Option<PathBuf>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "gc")]
+/// Delete chunks not referenced by any manifest in the repository
+struct CasGcCommand {
+    /// repository directory
+    #[argh(option, from_str_fn(path_arg))]
+    repo: PathBuf,
+
+    /// manifest file name template, manifests of any version are kept
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// only report reclaimable space
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "casync")]
+/// Store files in casync chunk stores and restore them from casync indexes
+struct CasyncCommand {
+    #[argh(subcommand)]
+    command: CasyncSubcommand,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand)]
+enum CasyncSubcommand {
+    Ingest(CasyncIngestCommand),
+    Materialize(CasyncMaterializeCommand),
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "ingest")]
+/// Add files to a .castr chunk store, writes a .caibx index for each file
+struct CasyncIngestCommand {
+    /// file mask (ex: "*.psd")
+    #[argh(positional, from_str_fn(utf8_arg))]
+    mask: String,
+
+    /// chunk store directory
+    #[argh(option, from_str_fn(utf8_arg))]
+    store: String,
+
+    /// directory of indexes, next to the files by default
+    #[argh(option, from_str_fn(path_arg))]
+    index_dir: Option<PathBuf>,
+
+    /// chunk ID hash: sha512-256 like desync or sha256 like casync, sha512-256 by default
+    #[argh(option, default = "ChunkDigest::Sha512_256")]
+    digest: ChunkDigest,
+
+    /// min chunk size
+    #[argh(option, default = "16384")]
+    min_size: u32,
+
+    /// avg chunk size
+    #[argh(option, default = "65536")]
+    avg_size: u32,
+
+    /// max chunk size
+    #[argh(option, default = "262144")]
+    max_size: u32,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "materialize")]
+/// Restore a file from a .castr chunk store using its .caibx index
+struct CasyncMaterializeCommand {
+    /// index path or URL
+    #[argh(positional, from_str_fn(utf8_arg))]
+    index: String,
+
+    /// output file path
+    #[argh(positional, from_str_fn(path_arg))]
+    output: PathBuf,
+
+    /// chunk store directory or URL
+    #[argh(option, from_str_fn(utf8_arg))]
+    store: String,
+
+    /// proxy of HTTP requests (ex: http://proxy:3128), HTTPS_PROXY or HTTP_PROXY by default
+    #[argh(option, from_str_fn(utf8_arg))]
+    proxy: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "tree-diff")]
+/// Compare two tree manifests
+struct TreeDiffCommand {
+    /// source tree manifest
+    #[argh(positional, from_str_fn(path_arg))]
+    source: PathBuf,
+
+    /// target tree manifest
+    #[argh(positional, from_str_fn(path_arg))]
+    target: PathBuf,
+
+    /// print changes as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "choose-base")]
+/// Rank candidate source signatures by how much of the target they share
+struct ChooseBaseCommand {
+    /// target signature
+    #[argh(positional, from_str_fn(path_arg))]
+    target: PathBuf,
+
+    /// candidate signatures
+    #[argh(positional, from_str_fn(path_arg))]
+    candidates: Vec<PathBuf>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "serve")]
+/// Serve patches to the signed files of a directory over HTTP: POST a signature to /objects/{{name}}/patch
+struct ServeCommand {
+    /// directory with the files and their signatures
+    #[argh(positional, from_str_fn(path_arg))]
+    root: PathBuf,
+
+    /// address to listen on
+    #[argh(
+        option,
+        default = "String::from(\"127.0.0.1:8080\")",
+        from_str_fn(utf8_arg)
+    )]
+    addr: String,
+
+    /// signature file name template, supports {{name}}, {{stem}}, {{ext}} and {{version}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+
+    /// number of attempts for each range read from an object file, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// also serve the gRPC API of proto/cloud_zsync.proto on this address, requires the grpc feature
+    #[argh(option, from_str_fn(utf8_arg))]
+    grpc_addr: Option<String>,
+
+    /// number of threads handling HTTP requests, the number of cores by default
+    #[argh(option)]
+    workers: Option<usize>,
+
+    /// largest signature in bytes a client may upload, 256 MiB by default
+    #[argh(option, default = "server::DEFAULT_MAX_BODY")]
+    max_body: u64,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "sync")]
+/// Sync a file with another host over a remote shell, like rsync: one of the paths is host:path or remote:path of a configured remote
+struct SyncCommand {
+    /// file to copy, local, host:path or remote:path
+    #[argh(positional, from_str_fn(utf8_arg))]
+    source: String,
+
+    /// file to update, local, host:path or remote:path
+    #[argh(positional, from_str_fn(utf8_arg))]
+    destination: String,
+
+    /// remote shell to run cloud-zsync server on the host with, ssh by default
+    #[argh(option, from_str_fn(utf8_arg))]
+    rsh: Option<String>,
+
+    /// path of cloud-zsync on the host, cloud-zsync by default
+    #[argh(option, from_str_fn(utf8_arg))]
+    remote_bin: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "server")]
+/// Serve sync over stdin and stdout, sync runs it on the host over the remote shell
+struct ServerCommand {}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "zsync")]
+/// Write zsync control files and fetch files published with them
+struct ZsyncCommand {
+    #[argh(subcommand)]
+    command: ZsyncSubcommand,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand)]
+enum ZsyncSubcommand {
+    Make(ZsyncMakeCommand),
+    Pull(ZsyncPullCommand),
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "make")]
+/// Write a zsync control file, zsync clients fetch the file from a web server with it
+struct ZsyncMakeCommand {
+    /// file to publish
+    #[argh(positional, from_str_fn(path_arg))]
+    file: PathBuf,
+
+    /// control file path, the file path with .zsync appended by default
+    #[argh(option, from_str_fn(path_arg))]
+    output: Option<PathBuf>,
+
+    /// block size, a power of two, 2048 for files up to 100 MB and 4096 for larger ones by default
+    #[argh(option)]
+    block_size: Option<usize>,
+
+    /// URL of the file, absolute or relative to the control file, the file name by default
+    #[argh(option, from_str_fn(utf8_arg))]
+    url: Option<String>,
+
+    /// signature of the file, the control file is written only if the file matches it
+    #[argh(option, from_str_fn(path_arg))]
+    signature: Option<PathBuf>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "pull")]
+/// Fetch a file published with a zsync control file, reusing blocks of local files
+struct ZsyncPullCommand {
+    /// control file path or URL
+    #[argh(positional, from_str_fn(utf8_arg))]
+    control: String,
+
+    /// path of the new file, the Filename of the control file in the current directory by default
+    #[argh(positional, from_str_fn(path_arg))]
+    destination: Option<PathBuf>,
+
+    /// local file to reuse blocks from, can be repeated, the destination is used if it exists
+    #[argh(option, from_str_fn(path_arg))]
+    seed: Vec<PathBuf>,
+
+    /// URL of the file, overrides the URL of the control file
+    #[argh(option, from_str_fn(utf8_arg))]
+    url: Option<String>,
+
+    /// number of attempts for each range request, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// limit downloads (bytes/sec)
+    #[argh(option)]
+    bwlimit: Option<u64>,
+
+    /// write a signature of the new file there, later versions can be diffed against it
+    #[argh(option, from_str_fn(path_arg))]
+    signature: Option<PathBuf>,
+
+    /// proxy of HTTP requests (ex: http://proxy:3128), HTTPS_PROXY or HTTP_PROXY by default
+    #[argh(option, from_str_fn(utf8_arg))]
+    proxy: Option<String>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "rdiff")]
+/// Read and write librsync signatures and deltas, compatible with rdiff
+struct RdiffCommand {
+    #[argh(subcommand)]
+    command: RdiffSubcommand,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand)]
+enum RdiffSubcommand {
+    Signature(RdiffSignatureCommand),
+    Delta(RdiffDeltaCommand),
+    Patch(RdiffPatchCommand),
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "signature")]
+/// Write a librsync signature of a basis file, like rdiff signature
+struct RdiffSignatureCommand {
+    /// basis file
+    #[argh(positional, from_str_fn(path_arg))]
+    basis: PathBuf,
+
+    /// signature path
+    #[argh(positional, from_str_fn(path_arg))]
+    signature: PathBuf,
+
+    /// block size, 2048 by default
+    #[argh(option, default = "librsync::DEFAULT_BLOCK_SIZE")]
+    block_size: u32,
+
+    /// strong hash: md4 or blake2, blake2 by default
+    #[argh(option, default = "StrongSum::Blake2")]
+    hash: StrongSum,
+
+    /// rolling sum: rollsum or rabinkarp, rabinkarp by default, librsync before 2.2 needs rollsum
+    #[argh(option, default = "WeakSum::RabinKarp")]
+    rollsum: WeakSum,
+
+    /// stored bytes of the strong hash, the full hash by default
+    #[argh(option)]
+    sum_size: Option<u32>,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "delta")]
+/// Write a librsync delta of a new file against a signature, like rdiff delta
+struct RdiffDeltaCommand {
+    /// librsync signature of the basis file
+    #[argh(positional, from_str_fn(path_arg))]
+    signature: PathBuf,
+
+    /// new file
+    #[argh(positional, from_str_fn(path_arg))]
+    new: PathBuf,
+
+    /// delta path
+    #[argh(positional, from_str_fn(path_arg))]
+    delta: PathBuf,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "patch")]
+/// Apply a librsync delta to a basis file, like rdiff patch
+struct RdiffPatchCommand {
+    /// basis file
+    #[argh(positional, from_str_fn(path_arg))]
+    basis: PathBuf,
+
+    /// librsync delta
+    #[argh(positional, from_str_fn(path_arg))]
+    delta: PathBuf,
+
+    /// new file path
+    #[argh(positional, from_str_fn(path_arg))]
+    new: PathBuf,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "ls")]
+/// List objects under a gs:// or s3:// prefix with their size, generation and signature
+struct LsCommand {
+    /// bucket prefix (ex: gs://assets/psd/)
+    #[argh(positional, from_str_fn(utf8_arg))]
+    url: String,
+
+    /// signature file name template, must contain {{name}} or both {{stem}} and {{ext}}
+    #[argh(
+        option,
+        default = "String::from(naming::DEFAULT_SIGNATURE_TEMPLATE)",
+        from_str_fn(utf8_arg)
+    )]
+    sig_template: String,
+
+    /// value of the {{version}} placeholder of the templates
+    #[argh(option, from_str_fn(utf8_arg))]
+    version: Option<String>,
+
+    /// number of attempts for each listing request, 5 by default
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// proxy of HTTP requests (ex: http://proxy:3128), HTTPS_PROXY or HTTP_PROXY by default
+    #[argh(option, from_str_fn(utf8_arg))]
+    proxy: Option<String>,
+
+    /// print the listing as a single JSON document
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "completions")]
+/// Print a completion script for a shell: bash, zsh or fish
+struct CompletionsCommand {
+    /// shell to complete in: bash, zsh or fish
+    #[argh(positional)]
+    shell: Shell,
+}
+
+impl Command {
+    fn run(&self) -> Result<ExitCode, Box<dyn Error>> {
+        let result = match &self {
+            Self::Diff(diff) => {
+                return diff.compare().map(|differ| match differ {
+                    true => ExitCode::from(EXIT_DIFFERENT),
+                    false => ExitCode::SUCCESS,
+                })
+            }
+            Self::Sign(sign) => sign.run(),
+            Self::Apply(apply) => apply.run(),
+            Self::ApplyPlan(apply_plan) => apply_plan.run(),
+            Self::Repair(repair) => repair.run(),
+            Self::Scrub(scrub) => scrub.run(),
+            Self::Churn(churn) => churn.run(),
+            Self::Stats(stats) => stats.run(),
+            Self::Analyze(analyze) => analyze.run(),
+            Self::Selftest(selftest) => selftest.run(),
+            Self::Store(store) => store.run(),
+            Self::Cas(cas) => cas.run(),
+            Self::Casync(casync) => casync.run(),
+            Self::TreeDiff(tree_diff) => tree_diff.run(),
+            Self::ChooseBase(choose_base) => choose_base.run(),
+            Self::Serve(serve) => serve.run(),
+            Self::Sync(sync) => sync.run(),
+            Self::Server(server) => server.run(),
+            Self::Zsync(zsync) => zsync.run(),
+            Self::Rdiff(rdiff) => rdiff.run(),
+            Self::Ls(ls) => ls.run(),
+            Self::Completions(completions) => completions.run(),
+        };
+
+        result.map(|_| ExitCode::SUCCESS)
+    }
+}
+
+impl Runner for SignCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        info!("Calculating signatures for {}", &self.mask);
+
+        let total_start = Instant::now();
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+
+        if self.watch && self.manifest.is_some() {
+            return Err("--watch can not be combined with --manifest".into());
+        }
+
+        if self.metrics_addr.is_some() && !self.watch {
+            return Err("--metrics-addr requires --watch".into());
+        }
+
+        if self.output.is_some() && self.sig_dir.is_some() {
+            return Err("--output can not be combined with --sig-dir".into());
+        }
+
+        if self.manifest.is_some() && (self.output.is_some() || self.sig_dir.is_some()) {
+            return Err("--manifest can not be combined with --output or --sig-dir".into());
+        }
+
+        if self.symlinks == SymlinkMode::Preserve && self.manifest.is_none() {
+            return Err("--symlinks preserve requires --manifest".into());
+        }
+
+        if self.mask == STDIO_PATH {
+            return self.sign_stdin();
+        }
+
+        match &self.manifest {
+            Some(manifest) => {
+                confirm_overwrite(&[Path::new(manifest)], self.force)?;
+                self.sign_tree(&naming, Path::new(manifest))?
+            }
+            None => {
+                let files = self.matched_files(&naming)?;
+                self.sign_files(files)?
+            }
+        }
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        if self.watch {
+            if let Some(addr) = &self.metrics_addr {
+                metrics::serve(addr)?;
+                info!("Serving metrics on http://{}/metrics", addr);
+            }
+
+            self.watch(&naming)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SignCommand {
+    /// Returns paths matching the mask which are not excluded: files and
+    /// symbolic links, including links to directories.
+    fn matched_paths(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+
+        let root = mask_root(&self.mask);
+        let exclusions = Exclusions::new(&root, &self.exclude, self.respect_gitignore)?;
+
+        for source_dir_entry in globwalk::glob(&self.mask)? {
+            let source_dir_entry = source_dir_entry?;
+            let source_path = source_dir_entry.path();
+
+            if source_dir_entry.file_type().is_dir() {
+                continue;
+            }
+
+            if exclude::relative_to(&root, source_path)
+                .is_some_and(|relative| exclusions.is_excluded(relative))
+            {
+                continue;
+            }
+
+            paths.push(source_path.to_path_buf());
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns files matching the mask along with their signature paths.
+    /// Symbolic links are resolved according to --symlinks.
+    fn matched_files(
+        &self,
+        naming: &NamingStrategy,
+    ) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
+        let mut files: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+        let root = mask_root(&self.mask);
+
+        for source_path in self.matched_paths()? {
+            if source_path.is_symlink() {
+                if self.symlinks != SymlinkMode::Follow {
+                    continue;
+                }
+
+                if safety::escapes(&root, &source_path) {
+                    warn!(
+                        "Skipping {}: the link leads outside of {}",
+                        source_path.display(),
+                        root.display()
+                    );
+                    continue;
+                }
+
+                if source_path.is_dir() {
+                    continue;
+                }
+            }
+
+            let target_path = self.signature_path(naming, &source_path)?;
+            safety::ensure_distinct(&target_path, &[source_path.as_path()])?;
+
+            files.push((source_path, target_path));
+        }
+
+        if files.len() > 1 && self.output.is_some() && !self.output_is_dir() {
+            return Err(format!(
+                "{} files match the mask, --output must be a directory",
+                files.len()
+            )
+            .into());
+        }
+
+        Ok(files)
+    }
+
+    /// Returns the signature path of a file: next to it by default, in
+    /// the --output directory, or under --sig-dir at the same relative path
+    /// the file has under the mask directory.
+    fn signature_path(
+        &self,
+        naming: &NamingStrategy,
+        source_path: &Path,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        if let Some(output) = &self.output {
+            if !self.output_is_dir() {
+                return Ok(output.clone());
+            }
+
+            let name = source_path
+                .file_name()
+                .ok_or_else(|| format!("{:?} has no file name", source_path))?;
+            return naming.signature_path(&Path::new(output).join(name));
+        }
+
+        if let Some(sig_dir) = &self.sig_dir {
+            let root = mask_root(&self.mask);
+            let relative = exclude::relative_to(&root, source_path)
+                .ok_or_else(|| format!("{:?} is outside of {:?}", source_path, root))?;
+            return naming.signature_path(&Path::new(sig_dir).join(relative));
+        }
+
+        naming.signature_path(source_path)
+    }
+
+    fn output_is_dir(&self) -> bool {
+        match &self.output {
+            Some(output) => {
+                let last = output.as_os_str().as_encoded_bytes().last();
+                last.is_some_and(|&c| c == b'/' || c == MAIN_SEPARATOR as u8) || output.is_dir()
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the options of the command which change signatures besides `SignOptions`.
+    fn sign_modes(&self) -> SignModes {
+        SignModes {
+            track_changes: self.track_changes,
+            decompress: self.decompress,
+            metadata: self.metadata,
+        }
+    }
+
+    /// Signs files skipping the ones which are up to date according to the cache,
+    /// existing signatures of the others are overwritten as `confirm_overwrite` allows.
+    fn sign_files(&self, mut files: Vec<(PathBuf, PathBuf)>) -> Result<(), Box<dyn Error>> {
+        let mut cache = match &self.cache {
+            Some(path) => Some(SignCache::load(path)?),
+            None => None,
+        };
+
+        if let Some(cache) = &cache {
+            let before = files.len();
+            files.retain(|(source_path, target_path)| {
+                match self.sign_options(source_path, signature_sizes(target_path)) {
+                    Ok(options) => {
+                        !cache.is_fresh(source_path, target_path, &options, self.sign_modes())
+                    }
+                    Err(_) => true,
+                }
+            });
+
+            info!("Skipped {} unchanged file(s)", before - files.len());
+        }
+
+        // Signatures are inputs of these modes, updating them is expected
+        if !(self.warm_start || self.track_changes || self.watch) {
+            let signatures: Vec<&Path> = files
+                .iter()
+                .map(|(_, target_path)| target_path.as_path())
+                .collect();
+            confirm_overwrite(&signatures, self.force)?;
+        }
+
+        if self.jobs() > 1 {
+            self.sign_parallel(&files)?;
+        } else {
+            for (source_path, target_path) in &files {
+                let spinner = progress_bar::create_spinner(format!(
+                    "Calculating signature for {:?}...",
+                    source_path
+                ));
+
+                spinner.finish_with_message(self.sign_file(source_path, target_path)?);
+            }
+        }
+
+        if let (Some(cache), Some(path)) = (&mut cache, &self.cache) {
+            for (source_path, target_path) in &files {
+                cache.update(
+                    source_path,
+                    target_path,
+                    &self.sign_options(source_path, signature_sizes(target_path))?,
+                    self.sign_modes(),
+                )?;
+            }
+
+            cache.save(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Signs files matching the mask into a single tree manifest. Paths in the
+    /// manifest are relative to the mask directory.
+    fn sign_tree(
+        &self,
+        naming: &NamingStrategy,
+        manifest_path: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let root = mask_root(&self.mask);
+        let mut manifest = TreeManifest::new();
+
+        // Files are signed again with the chunk sizes they were signed with
+        let previous: Option<TreeManifest> = File::open(manifest_path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok());
+
+        for (source_path, _) in self.matched_files(naming)? {
+            if safety::is_same_file(&source_path, manifest_path) {
+                continue;
+            }
+
+            let spinner = progress_bar::create_spinner(format!(
+                "Calculating signature for {:?}...",
+                source_path
+            ));
+
+            let sizes = previous
+                .as_ref()
+                .and_then(|previous| previous.find(&root, &source_path))
+                .and_then(|entry| entry.signature.chunk_sizes());
+            let mut sig =
+                Signature::generate_file(&source_path, &self.sign_options(&source_path, sizes)?)?;
+            if self.metadata {
+                sig.set_metadata(FileMetadata::read(&source_path)?);
+            }
+            manifest.add(&root, &source_path, sig)?;
+
+            spinner.finish_with_message(format!("Signed {}", source_path.display()));
+        }
+
+        if self.symlinks == SymlinkMode::Preserve {
+            self.add_links(&root, &mut manifest)?;
+        }
+
+        let mut output_file = File::create(manifest_path)?;
+        output_file.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+
+        info!(
+            "{} file(s), {} link(s), {} saved to: {}",
+            manifest.entries().len(),
+            manifest.links().len(),
+            format_size(manifest.length(), DECIMAL),
+            manifest_path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Records symbolic links matching the mask in the manifest. Links which
+    /// would lead outside of the tree once recreated are skipped.
+    fn add_links(&self, root: &Path, manifest: &mut TreeManifest) -> Result<(), Box<dyn Error>> {
+        for link_path in self.matched_paths()? {
+            if !link_path.is_symlink() {
+                continue;
+            }
+
+            let target = fs::read_link(&link_path)?;
+            let relative = exclude::relative_to(root, &link_path)
+                .ok_or_else(|| format!("{:?} is outside of {:?}", link_path, root))?;
+
+            if !safety::link_stays_inside(relative, &target) {
+                warn!(
+                    "Skipping {}: the link target {} leads outside of {}",
+                    link_path.display(),
+                    target.display(),
+                    root.display()
+                );
+                continue;
+            }
+
+            manifest.add_link(root, &link_path, &target)?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-signs files matching the mask as they change. Events are collected
+    /// until no new ones arrive for `debounce` milliseconds, so a file saved
+    /// in several writes is signed once.
+    fn watch(&self, naming: &NamingStrategy) -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&mask_root(&self.mask), RecursiveMode::Recursive)?;
+
+        let debounce = Duration::from_millis(self.debounce);
+
+        info!("Watching for changes, press Ctrl+C to stop...");
+
+        loop {
+            let mut changed: HashSet<PathBuf> = HashSet::new();
+            collect_changes(rx.recv()?, &mut changed);
+
+            loop {
+                match rx.recv_timeout(debounce) {
+                    Ok(event) => collect_changes(event, &mut changed),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return Err("Watcher stopped".into()),
+                }
+            }
+
+            let files: Vec<(PathBuf, PathBuf)> = self
+                .matched_files(naming)?
+                .into_iter()
+                .filter(|(source_path, _)| match fs::canonicalize(source_path) {
+                    Ok(path) => changed.contains(&path),
+                    Err(_) => false,
+                })
+                .collect();
+
+            if files.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = self.sign_files(files) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Returns sign options of a file, `previous` are the chunk sizes of
+    /// its previous signature.
+    fn sign_options(
+        &self,
+        path: &Path,
+        previous: Option<ChunkSizes>,
+    ) -> Result<SignOptions, Box<dyn Error>> {
+        file_options(
+            self.base_options()?,
+            path,
+            previous,
+            self.min_size,
+            self.avg_size,
+            self.max_size,
+        )
+    }
+
+    /// Returns sign options without chunk sizes.
+    fn base_options(&self) -> Result<SignOptions, Box<dyn Error>> {
+        let options = SignOptions {
+            crc32c: self.crc32c,
+            md5: self.md5,
+            threads: self.threads.or(config().threads).unwrap_or(1),
+            mmap: !self.no_mmap,
+            block_size: self.block_size,
+            format: self.format,
+            ..Default::default()
+        };
+
+        let options = SignOptions {
+            key: match self.key_file.as_ref().or(config().key_file.as_ref()) {
+                Some(key_file) => Some(key::load(Path::new(key_file))?),
+                None => None,
+            },
+            hash_length: self.hash_length.unwrap_or(options.hash_length),
+            implicit_offsets: self.implicit_offsets,
+            ..options
+        };
+
+        Ok(options)
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs.or(config().jobs).unwrap_or(1)
+    }
+
+    /// Signs stdin, the signature is written to --output or to stdout.
+    fn sign_stdin(&self) -> Result<(), Box<dyn Error>> {
+        if self.manifest.is_some() || self.watch || self.sig_dir.is_some() {
+            return Err(
+                "Signing stdin can not be combined with --manifest, --watch or --sig-dir".into(),
+            );
+        }
+
+        if self.decompress || self.warm_start || self.track_changes || self.cache.is_some() {
+            return Err(
+                "Signing stdin can not be combined with --decompress, --warm-start, --track-changes or --cache"
+                    .into(),
+            );
+        }
+
+        if self.block_size > 0 {
+            return Err(
+                "Block index requires a local file, stdin can not be signed with --block-size"
+                    .into(),
+            );
+        }
+
+        if let Some(output) = &self.output {
+            confirm_overwrite(&[Path::new(output)], self.force)?;
+        }
+
+        let options = chunk_options(
+            self.base_options()?,
+            "stdin",
+            None,
+            None,
+            self.min_size,
+            self.avg_size,
+            self.max_size,
+        )?;
+
+        let start = Instant::now();
+        let sig = Signature::generate_with_options(&mut io::stdin().lock(), &options)?;
+        let serialized = serde_json::to_string_pretty(&sig)?;
+
+        match &self.output {
+            Some(output) => fs::write(output, serialized)?,
+            None => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(serialized.as_bytes())?;
+                stdout.flush()?;
+            }
+        }
+
+        metrics::record_signed(sig.length());
+        info!(
+            "Took {:.2?}, stdin size: {}",
+            start.elapsed(),
+            format_size(sig.length(), DECIMAL)
+        );
+
+        Ok(())
+    }
+
+    /// Generates and saves signature for a single file.
+    ///
+    /// # Returns:
+    /// - `Result<String, Box<dyn Error>>`: summary line for the file
+    fn sign_file(&self, source_path: &Path, target_path: &Path) -> Result<String, Box<dyn Error>> {
+        let _span = debug_span!("sign", file = %source_path.display()).entered();
+        let start = Instant::now();
+
+        let file_name = source_path.display().to_string();
+        let file_length = fs::metadata(source_path)?.len();
+        progress_bar::report("sign", &file_name, 0, file_length, Duration::ZERO);
+
+        let previous: Option<Signature> = match File::open(target_path) {
+            Ok(file) if self.track_changes || self.warm_start => {
+                Some(serde_json::from_reader(BufReader::new(file))?)
+            }
+            _ => None,
+        };
+
+        let sizes = match &previous {
+            Some(previous) => previous.chunk_sizes(),
+            None => signature_sizes(target_path),
+        };
+        let options = self.sign_options(source_path, sizes)?;
+        let detected = compression::inspect(source_path)?;
+
+        // Files compressed with periodic flushes diff well as they are
+        let decompress = match detected {
+            Some(detected) if self.decompress && !detected.rsyncable => detected
+                .compression
+                .can_decompress()
+                .then_some(detected.compression),
+            _ => None,
+        };
+
+        let mut sig = match (&previous, decompress) {
+            (_, Some(compression)) => {
+                Signature::generate_decompressed(source_path, compression, &options)?
+            }
+            (Some(previous), None) if self.warm_start => {
+                Signature::generate_file_from(source_path, previous, &options)?
+            }
+            _ => Signature::generate_file(source_path, &options)?,
+        };
+
+        if self.track_changes {
+            sig.track_changes(previous.as_ref(), unix_now());
+        }
+
+        if self.metadata {
+            sig.set_metadata(FileMetadata::read(source_path)?);
+        }
+
+        let serialized = serde_json::to_string_pretty(&sig)?;
+
+        // --output and --sig-dir directories are created on demand
+        if let Some(parent) = target_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut output_file = File::create(target_path)?;
+        output_file.write_all(serialized.as_bytes())?;
+        metrics::record_signed(file_length);
+
+        progress_bar::report(
+            "sign",
+            &file_name,
+            file_length,
+            file_length,
+            start.elapsed(),
+        );
+
+        let summary = format!(
+            "Took {:.2?}, source file size: {}, saved to: {}",
+            start.elapsed(),
+            format_size(sig.length(), DECIMAL),
+            target_path.display()
+        );
+
+        let warning = match detected {
+            Some(detected) if detected.rsyncable || decompress.is_some() => None,
+            Some(detected) if detected.compression.can_decompress() => Some(format!(
+                "{} compressed, chunks will hardly match between versions, sign with --decompress",
+                detected.compression
+            )),
+            Some(detected) => Some(format!(
+                "{} compressed, chunks will hardly match between versions",
+                detected.compression
+            )),
+            None => None,
+        };
+
+        Ok(match warning {
+            Some(warning) => format!("{}\n  {}", summary, style(warning).yellow()),
+            None => summary,
+        })
+    }
+
+    /// Signs files on `jobs` worker threads, each worker has its own spinner.
+    fn sign_parallel(&self, files: &[(PathBuf, PathBuf)]) -> Result<(), Box<dyn Error>> {
+        let multi = MultiProgress::new();
+        let total = multi.add(progress_bar::create_bar(files.len() as u64));
+        let spinners: Vec<ProgressBar> = (0..self.jobs().min(files.len()))
+            .map(|_| multi.add(progress_bar::create_spinner(String::new())))
+            .collect();
+
+        let next = AtomicUsize::new(0);
+        let failed: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        thread::scope(|scope| {
+            let drawer = scope.spawn(move || multi.join());
+
+            let workers: Vec<_> = spinners
+                .into_iter()
+                .map(|spinner| {
+                    let (total, next, failed) = (&total, &next, &failed);
+
+                    scope.spawn(move || {
+                        while let Some((source_path, target_path)) =
+                            files.get(next.fetch_add(1, Ordering::SeqCst))
+                        {
+                            spinner.set_message(format!(
+                                "Calculating signature for {:?}...",
+                                source_path
+                            ));
+
+                            match self.sign_file(source_path, target_path) {
+                                Ok(summary) => total.println(summary),
+                                Err(e) => failed.lock().unwrap().push(format!(
+                                    "{}: {}",
+                                    source_path.display(),
+                                    e
+                                )),
+                            }
+
+                            total.inc(1);
+                        }
+
+                        spinner.finish_and_clear();
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                worker.join().ok();
+            }
+
+            total.finish_and_clear();
+            drawer.join().ok();
+        });
+
+        let failed = failed.into_inner().unwrap();
+        if !failed.is_empty() {
+            return Err(format!(
+                "Failed to sign {} file(s):\n{}",
+                failed.len(),
+                failed.join("\n")
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+impl DiffCommand {
+    /// Builds the new file and returns true, or returns false if the files are equal.
+    /// Directories or globs diff each pair of signatures, true if any files differ.
+    fn compare(&self) -> Result<bool, Box<dyn Error>> {
+        if is_batch(&self.source) || is_batch(&self.target) {
+            return self.compare_batch();
+        }
+
+        let stats = self.compare_pair()?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+
+        Ok(!stats.equal)
+    }
+
+    /// Diffs signatures matched by relative path, a failed pair does not stop the others.
+    fn compare_batch(&self) -> Result<bool, Box<dyn Error>> {
+        if !is_batch(&self.source) || !is_batch(&self.target) {
+            return Err(
+                "Both source and target must be directories or globs to diff many files".into(),
+            );
+        }
+
+        if self.stdout
+            || self.output.is_some()
+            || self.patch.is_some()
+            || self.plan.is_some()
+            || self.diff_output.is_some()
+        {
+            return Err(
+                "--stdout, --output, --patch, --plan and --diff-output can not be used to diff many files"
+                    .into(),
+            );
+        }
+
+        let total_start = Instant::now();
+        let naming = NamingStrategy::new(&self.sig_template, &self.output_template)?
+            .with_version(self.version.as_deref());
+        let sources = batch_signatures(&self.source, &naming)?;
+        let targets = batch_signatures(&self.target, &naming)?;
+
+        let mut batch = BatchStats::default();
+
+        for (relative, target) in &targets {
+            let source = match sources.get(relative) {
+                Some(source) => source,
+                None => {
+                    batch.only_in_target.push(relative.display().to_string());
+                    continue;
+                }
+            };
+
+            let pair = DiffCommand {
+                source: source.clone(),
+                target: target.clone(),
+                ..self.clone()
+            };
+
+            let result = pair.compare_pair().map_err(|e| e.to_string());
+            if let Err(e) = &result {
+                error!("{}: {}", relative.display(), e);
+            }
+            batch.push(relative.display().to_string(), result);
+        }
+
+        for relative in sources.keys().filter(|r| !targets.contains_key(*r)) {
+            batch.only_in_source.push(relative.display().to_string());
+        }
+
+        match self.json {
+            true => println!("{}", serde_json::to_string_pretty(&batch)?),
+            false => print_batch_stats(&batch),
+        }
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        if batch.failed > 0 {
+            return Err(format!("{} of {} file(s) failed", batch.failed, batch.files.len()).into());
+        }
+
+        Ok(batch.changed > 0)
+    }
+
+    /// Diffs a single pair of signatures and builds the new file.
+    fn compare_pair(&self) -> Result<DiffStats, Box<dyn Error>> {
+        let _span = debug_span!(
+            "diff",
+            source = %self.source.display(),
+            target = %self.target.display()
+        )
+        .entered();
+
+        info!(
+            "Calculating diff for {} .. {}",
+            self.source.display(),
+            self.target.display()
+        );
+
+        let total_start = Instant::now();
+
+        let source_sig_file = File::open(&self.source)?;
+        let target_sig_file = File::open(&self.target)?;
+
+        let source_sig: Signature = serde_json::from_reader(source_sig_file)?;
+        let target_sig: Signature = serde_json::from_reader(target_sig_file)?;
+
+        let mut seed_sigs: Vec<Signature> = Vec::new();
+        for seed in &self.seed {
+            seed_sigs.push(serde_json::from_reader(BufReader::new(File::open(seed)?))?);
+        }
+
+        let sources: Vec<&Signature> = std::iter::once(&source_sig).chain(&seed_sigs).collect();
+
+        if sources
+            .iter()
+            .any(|source| source.key_id() != target_sig.key_id())
+        {
+            return Err("All signatures must be generated with the same --key-file".into());
+        }
+
+        if sources
+            .iter()
+            .any(|source| source.hash_length() != target_sig.hash_length())
+        {
+            return Err("All signatures must be generated with the same --hash-length".into());
+        }
+
+        if source_sig.decompressed() != target_sig.decompressed() {
+            return Err(
+                "Either both or none of the signatures must be signed with --decompress".into(),
+            );
+        }
+
+        if !self.encrypt_to.is_empty() && self.patch.is_none() {
+            return Err("--encrypt-to requires --patch".into());
+        }
+
+        if self.patch.is_some() && target_sig.decompressed().is_some() {
+            return Err("Patches of files signed with --decompress are not supported".into());
+        }
+
+        if self.plan.is_some()
+            && (self.patch.is_some()
+                || self.patch_from
+                || self.delta
+                || self.zstd_level.is_some()
+                || self.resume
+                || self.diff_output.is_some())
+        {
+            return Err(
+                "--plan can not be used with --patch, --patch-from, --delta, --zstd-level, --resume or --diff-output"
+                    .into(),
+            );
+        }
+
+        if self.stats_only && self.rolling {
+            return Err(
+                "--rolling reads the source file, it can not be used with --stats-only".into(),
+            );
+        }
+
+        if self.plan.is_some() && target_sig.decompressed().is_some() {
+            return Err("Plans of files signed with --decompress are not supported".into());
+        }
+
+        if self.stdout && self.output.is_some() {
+            return Err("--stdout can not be combined with --output".into());
+        }
+
+        if self.writes_stdout()
+            && (self.json || self.stats_only || self.resume || self.in_place || self.plan.is_some())
+        {
+            return Err(
+                "--stdout can not be combined with --json, --stats-only, --resume, --in-place or --plan"
+                    .into(),
+            );
+        }
+
+        if self.delta && self.patch_from {
+            return Err("--delta and --patch-from can not be used together".into());
+        }
+
+        if self.stream
+            && (self.patch.is_some()
+                || self.zstd_level.is_some()
+                || self.patch_from
+                || self.delta
+                || self.resume
+                || self.in_place
+                || self.keep_diff_file
+                || self.diff_output.is_some())
+        {
+            return Err(
+                "--stream can not be combined with --patch, --zstd-level, --patch-from, --delta, --resume, --in-place, --keep-diff-file or --diff-output"
+                    .into(),
+            );
+        }
+
+        // The journal and the target stream need the ops in order, threads share mapped sources
+        let sequential = self.resume || self.stream || self.no_mmap;
+        if sequential && self.jobs.is_some_and(|jobs| jobs > 1) {
+            return Err("--jobs can not be combined with --resume, --stream or --no-mmap".into());
+        }
+        let jobs = match sequential {
+            true => 1,
+            false => self.jobs.or(config().jobs).unwrap_or(1),
+        };
+
+        let recipients = self
+            .encrypt_to
+            .iter()
+            .map(|recipient| patch::parse_recipient(recipient))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let (Some(source_sizes), Some(target_sizes)) =
+            (source_sig.chunk_sizes(), target_sig.chunk_sizes())
+        {
+            if source_sizes != target_sizes {
+                warn!("Signatures have different chunk sizes, few chunks will match");
+            }
+        }
+
+        let mut diff = match Diff::new_multi(&sources, &target_sig) {
+            Some(diff) => diff,
+            None if self.writes_stdout() => {
+                let naming = NamingStrategy::new(&self.sig_template, &self.output_template)?
+                    .with_version(self.version.as_deref());
+                let source_file_path = naming.file_path(Path::new(&self.source))?;
+
+                let mut stdout = io::stdout().lock();
+                io::copy(&mut platform::open_shared(&source_file_path)?, &mut stdout)?;
+                stdout.flush()?;
+
+                info!("Files are equal, written the source file to stdout");
+                return Ok(DiffStats::new(
+                    &source_sig,
+                    &target_sig,
+                    None,
+                    self.seed.len(),
+                ));
+            }
+            None => {
+                if !self.json {
+                    println!("{}", style("Files are equal!").green());
+                }
+                return Ok(DiffStats::new(
+                    &source_sig,
+                    &target_sig,
+                    None,
+                    self.seed.len(),
+                ));
+            }
+        };
+
+        let max_requests = self.max_requests.or(config().max_requests);
+
+        if self.stats_only {
+            let merged = max_requests.map(|max| diff.merge_inserts(max));
+            let mut stats = DiffStats::new(&source_sig, &target_sig, Some(&diff), self.seed.len());
+            stats.merged_length = merged;
+            stats.cost = self.pricing().map(|pricing| stats.estimate_cost(&pricing));
+
+            if !self.json {
+                print_diff_stats(&stats, &self.seed, &diff);
+            }
+
+            return Ok(stats);
+        }
+
+        let naming = NamingStrategy::new(&self.sig_template, &self.output_template)?
+            .with_version(self.version.as_deref());
+        let source_file_path = naming.file_path(Path::new(&self.source))?;
+
+        // Decompressed copies of files signed with --decompress, ops are applied to them
+        let mut copies: Vec<tempfile::NamedTempFile> = Vec::new();
+        let source_read_path = readable_path(&source_file_path, &source_sig, &mut copies)?;
+
+        let shifted = match (self.rolling, target_sig.blocks()) {
+            (false, _) => 0,
+            (true, Some(blocks)) => {
+                let source_file = platform::open_shared(&source_read_path)?;
+                // The source must not be modified during the build anyway
+                let map = unsafe { Mmap::map(&source_file)? };
+                diff.refine(&map, blocks)
+            }
+            (true, None) => {
+                return Err(format!(
+                    "{} has no block index, sign the target with --block-size",
+                    self.target.display()
+                )
+                .into())
+            }
+        };
+
+        // Ranges found by the rolling hash are not fetched, so merging goes after it
+        let merged = max_requests.map(|max| diff.merge_inserts(max));
+
+        // Ops depend on the sources and on these options, a journal of
+        // another diff must not be resumed
+        let journal_header = |ops: usize| {
+            journal::Header::new(target_sig.strong_hash(), ops)
+                .with_sources(&sources)
+                .map(|header| {
+                    header.with_options(format!(
+                        "rolling={} max_requests={:?} patch_from={} delta={} zstd_level={:?}",
+                        self.rolling, max_requests, self.patch_from, self.delta, self.zstd_level
+                    ))
+                })
+        };
+
+        let mut stats = DiffStats::new(&source_sig, &target_sig, Some(&diff), self.seed.len());
+        stats.rolling_length = self.rolling.then_some(shifted);
+        stats.merged_length = merged;
+        stats.cost = self.pricing().map(|pricing| stats.estimate_cost(&pricing));
+
+        // Stats would mix with the new file on stdout
+        if !self.json && !self.writes_stdout() {
+            print_diff_stats(&stats, &self.seed, &diff);
+        }
+
+        let target_file_path = naming.file_path(Path::new(&self.target))?;
+
+        // The new file is built aside and copied to stdout once it is verified
+        let stdout_build = match self.writes_stdout() {
+            true => Some(tempfile::NamedTempFile::new()?),
+            false => None,
+        };
+        let destination_path = match &stdout_build {
+            Some(build) => build.path().to_path_buf(),
+            None => match &self.output {
+                Some(output) => output.clone(),
+                None => naming.output_path(&target_file_path)?,
+            },
+        };
+
+        let mut seed_file_paths: Vec<PathBuf> = Vec::new();
+        for seed in &self.seed {
+            seed_file_paths.push(naming.file_path(Path::new(seed))?);
+        }
+
+        // COPY ops read the sources while the destination is being written.
+        let mut inputs: Vec<&Path> = vec![
+            &source_file_path,
+            Path::new(&self.source),
+            Path::new(&self.target),
+        ];
+        inputs.extend(seed_file_paths.iter().map(PathBuf::as_path));
+        inputs.extend(self.seed.iter().map(Path::new));
+
+        safety::ensure_distinct(&destination_path, &inputs)?;
+
+        if let Some(patch_path) = &self.patch {
+            let mut outputs = inputs.clone();
+            outputs.push(&destination_path);
+            safety::ensure_distinct(Path::new(patch_path), &outputs)?;
+        }
+
+        if let Some(diff_output) = &self.diff_output {
+            let mut outputs = inputs.clone();
+            outputs.push(&destination_path);
+            safety::ensure_distinct(Path::new(diff_output), &outputs)?;
+        }
+
+        // The target is only read while building the diff file, before
+        // the destination is opened, so it can be replaced in place.
+        let in_place = safety::is_same_file(&destination_path, &target_file_path);
+        if in_place && !self.in_place {
+            return Err(format!(
+                "Destination {} is the same file as target {}, pass --in-place to overwrite it",
+                destination_path.display(),
+                target_file_path.display()
+            )
+            .into());
+        }
+
+        if in_place && self.resume {
+            return Err("In-place build can not be resumed".into());
+        }
+
+        // Builds which may fall back to the whole target file can not overwrite it
+        if in_place && target_sig.hash_length() < blake3::OUT_LEN {
+            return Err(
+                "In-place build needs full chunk hashes, sign the target without --hash-length"
+                    .into(),
+            );
+        }
+
+        if in_place && target_sig.decompressed().is_some() {
+            return Err("In-place build of a decompressed file is not supported".into());
+        }
+
+        // A resumed build continues the destination, a dry run reports it
+        if !in_place
+            && !self.resume
+            && !self.dry_run
+            && stdout_build.is_none()
+            && self.plan.is_none()
+        {
+            confirm_overwrite(&[&destination_path], self.force)?;
+        }
+
+        // A resumed fetch continues the diff file
+        if let Some(diff_output) = &self.diff_output {
+            if !self.resume && !self.dry_run {
+                confirm_overwrite(&[Path::new(diff_output)], self.force)?;
+            }
+        }
+
+        // The journal verifies written chunks against plain hashes
+        if target_sig.key_id().is_some() && self.resume {
+            return Err("Build of a file with a keyed signature can not be resumed".into());
+        }
+
+        if target_sig.decompressed().is_some() && self.resume {
+            return Err("Build of a decompressed file can not be resumed".into());
+        }
+
+        if target_sig.decompressed().is_some() && target_sig.compressed_hash().is_none() {
+            return Err(
+                "The target signature has no hash of the compressed file to check the recompressed one, sign the target again"
+                    .into(),
+            );
+        }
+
+        // Many range requests for a mostly changed file are slower than a plain download,
+        // a patch or a plan needs the ranges, an in-place destination already is the target,
+        // a compressed target must be decompressed anyway
+        let threshold = self
+            .full_download_threshold
+            .or(config().full_download_threshold)
+            .unwrap_or(DEFAULT_FULL_DOWNLOAD_THRESHOLD);
+        stats.full_download = stats.fetch_percent() > threshold
+            && self.patch.is_none()
+            && self.plan.is_none()
+            && self.diff_output.is_none()
+            && !in_place
+            && !self.resume
+            && target_sig.decompressed().is_none();
+
+        if self.dry_run {
+            if self.json {
+                return Ok(stats);
+            }
+
+            println!("Dry run, nothing is written.");
+
+            if let Some(plan_path) = &self.plan {
+                print_dry_write(Path::new(plan_path));
+                return Ok(stats);
+            }
+
+            match stats.full_download {
+                true => println!(
+                    "Would download the whole {}: {:.0}% of it changed",
+                    target_file_path.display(),
+                    stats.fetch_percent()
+                ),
+                false => println!(
+                    "Would request {} ranges of {}: {} ({} bytes)",
+                    diff.insert_ops().len(),
+                    target_file_path.display(),
+                    format_size(diff.fetch_length(), DECIMAL),
+                    diff.fetch_length()
+                ),
+            }
+
+            match (in_place, self.writes_stdout()) {
+                (true, _) => println!("Would overwrite {} in place", destination_path.display()),
+                (false, true) => println!("Would write the new file to stdout"),
+                (false, false) => print_dry_write(&destination_path),
+            }
+
+            if let Some(patch_path) = &self.patch {
+                print_dry_write(Path::new(patch_path));
+            }
+
+            match &self.diff_output {
+                Some(diff_output) => print_dry_write(Path::new(diff_output)),
+                None if self.keep_diff_file => println!("Would keep the temporary diff file"),
+                None => {}
+            }
+
+            return Ok(stats);
+        }
+
+        if let Some(plan_path) = &self.plan {
+            let mut outputs = inputs.clone();
+            outputs.push(&destination_path);
+            safety::ensure_distinct(Path::new(plan_path), &outputs)?;
+
+            let mut planned: Vec<(&Path, &Signature)> = vec![(&source_file_path, &source_sig)];
+            planned.extend(seed_file_paths.iter().map(PathBuf::as_path).zip(&seed_sigs));
+
+            let plan = TransferPlan::new(
+                &planned,
+                (&target_file_path, &target_sig),
+                &destination_path,
+                in_place,
+                &diff,
+            );
+            plan.write(Path::new(plan_path))?;
+
+            info!("Written the plan: {}", plan_path.display());
+
+            return Ok(stats);
+        }
+
+        let policy = builder::RetryPolicy {
+            attempts: retries(self.retries),
+            ..Default::default()
+        };
+
+        if stats.full_download {
+            info!(
+                "{:.0}% of {} changed, downloading the whole file...",
+                stats.fetch_percent(),
+                target_file_path.display()
+            );
+
+            let length = fs::metadata(&target_file_path)?.len();
+            let dst_file = File::create(&destination_path)?;
+            builder::preallocate(&destination_path, &dst_file, length, 0)?;
+            drop(dst_file);
+
+            // The limit is shared by the threads
+            let limit = throttle::Limit::new(bwlimit(self.bwlimit)?.unwrap_or(u64::MAX));
+
+            let spinner = progress_bar::create_spinner(format!(
+                "Downloading {}...",
+                target_file_path.display()
+            ));
+            builder::download_file(
+                || {
+                    platform::open_shared(&target_file_path)
+                        .map(|file| throttle::Throttled::with_limit(file, limit.clone()))
+                },
+                &destination_path,
+                length,
+                FULL_DOWNLOAD_THREADS,
+                &policy,
+            )?;
+            spinner.finish_and_clear();
+
+            self.restore_metadata(&target_sig, &destination_path)?;
+            return self.finish(stats, &destination_path, total_start);
+        }
+
+        let mut read_paths: Vec<PathBuf> = vec![source_read_path];
+        for (path, sig) in seed_file_paths.iter().zip(&seed_sigs) {
+            read_paths.push(readable_path(path, sig, &mut copies)?);
+        }
+        let target_read_path = readable_path(&target_file_path, &target_sig, &mut copies)?;
+
+        let mut sources: Vec<Box<dyn CopySource>> = Vec::new();
+        for path in &read_paths {
+            sources.push(if self.no_mmap {
+                Box::new(platform::open_shared(path)?)
+            } else {
+                Box::new(MappedSource::open(path)?)
+            });
+        }
+        let mut source_file = Seeds::new(sources);
+        let mut target_file = throttle::Throttled::new(
+            platform::open_shared(&target_read_path)?,
+            bwlimit(self.bwlimit)?.unwrap_or(u64::MAX),
+        );
+
+        // A decompressed file is built aside and compressed into the destination
+        let decompressed_build = match target_sig.decompressed() {
+            Some(_) => Some(tempfile::NamedTempFile::new()?),
+            None => None,
+        };
+        let build_path = match &decompressed_build {
+            Some(build) => build.path().to_path_buf(),
+            None => destination_path.clone(),
+        };
+
+        if self.stream {
+            let mut dst_file = File::create(&build_path)?;
+            builder::preallocate(
+                &build_path,
+                &dst_file,
+                target_sig.length(),
+                diff.zero_length(),
+            )?;
+
+            let build_span = debug_span!("build", ops = diff.operations().len()).entered();
+
+            builder::build_local_file_streaming(
+                &mut source_file,
+                &mut target_file,
+                &mut dst_file,
+                progress_bar::track(
+                    diff.operations(),
+                    "build",
+                    build_path.display().to_string(),
+                    |op| op.length(),
+                ),
+                &policy,
+            )?;
+
+            drop(build_span);
+            drop(dst_file);
+
+            self.finish_build(
+                &target_sig,
+                &build_path,
+                &target_file_path,
+                &target_read_path,
+                &destination_path,
+                false,
+            )?;
+            return self.finish(stats, &destination_path, total_start);
+        }
+
+        let fetch_path = match &self.diff_output {
+            Some(diff_output) => diff_output.clone(),
+            None => {
+                let mut fetch_file_name = destination_path.clone().into_os_string();
+                fetch_file_name.push(FETCH_EXT);
+                PathBuf::from(fetch_file_name)
+            }
+        };
+
+        let mut fetch_journal_name = fetch_path.clone().into_os_string();
+        fetch_journal_name.push(JOURNAL_EXT);
+        let fetch_journal_path = PathBuf::from(fetch_journal_name);
+
+        // Plain ranges are fetched next to the destination to continue an interrupted fetch,
+        // compressed segments can not be told apart once written
+        let resumable_fetch =
+            self.resume && !self.patch_from && !self.delta && self.zstd_level.is_none();
+
+        let (mut diff_file, diff_path, diff_temp_path) = match resumable_fetch {
+            true => (
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&fetch_path)?,
+                fetch_path.clone(),
+                None,
+            ),
+            false if self.diff_output.is_some() => (
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&fetch_path)?,
+                fetch_path.clone(),
+                None,
+            ),
+            false => {
+                let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
+                (file, path.to_path_buf(), Some(path))
+            }
+        };
+
+        let mut fetch_journal: Option<Journal> = None;
+        // Journals verify only the data written since the previous sync point
+        let mut resumed_run = false;
+
+        if resumable_fetch && fetch_journal_path.exists() {
+            match Journal::open(
+                &fetch_journal_path,
+                &journal_header(diff.insert_ops().len())?,
+            ) {
+                Ok(journal) => {
+                    if journal.verify_fetched(&mut diff_file, &target_sig, diff.insert_ops())? {
+                        info!(
+                            "Resuming fetch from range {} of {} at {} bytes.",
+                            journal.applied(),
+                            diff.insert_ops().len(),
+                            journal.offset()
+                        );
+                        fetch_journal = Some(journal);
+                        resumed_run = true;
+                    } else {
+                        warn!("Diff file does not match the journal, fetching again.");
+                    }
+                }
+                Err(e) => warn!("Can not resume the fetch: {}, fetching again.", e),
+            }
+        }
+
+        if resumable_fetch && fetch_journal.is_none() {
+            fetch_journal = Some(Journal::create(
+                &fetch_journal_path,
+                &journal_header(diff.insert_ops().len())?,
+            )?);
+        }
+
+        if let Some(journal) = &fetch_journal {
+            diff_file.set_len(journal.offset())?;
+            diff_file.seek(SeekFrom::Start(journal.offset()))?;
+        }
+
+        info!("Building {} temporary file...", diff_path.display());
+
+        // target_file can be a wrapper over Read which does HTTP queries to GCS.
+        // Or, this wrapper may collect the read+seek calls and do actual queries later.
+        // Or, this method may be used in a middleware service to generate a diff file.
+        let insert_ops = progress_bar::track(
+            diff.insert_ops(),
+            "fetch",
+            target_file_path.display().to_string(),
+            |op| op.length(),
+        );
+
+        let fetch_span = debug_span!("fetch", ranges = diff.insert_ops().len()).entered();
+
+        let diff_schema = match (self.patch_from || self.delta, self.zstd_level) {
+            (true, level) => {
+                let source_data = platform::open_shared(&read_paths[0])?;
+                // The source must not be modified during the build anyway
+                let map = unsafe { Mmap::map(&source_data)? };
+
+                let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+
+                match self.delta {
+                    true => builder::build_delta_diff_file(
+                        &mut target_file,
+                        &mut diff_file,
+                        insert_ops,
+                        &policy,
+                        level,
+                        &map,
+                        &diff,
+                    )?,
+                    false => builder::build_patch_from_diff_file(
+                        &mut target_file,
+                        &mut diff_file,
+                        insert_ops,
+                        &policy,
+                        level,
+                        &map,
+                        &diff,
+                    )?,
+                }
+            }
+            (false, Some(level)) => builder::build_compressed_diff_file(
+                &mut target_file,
+                &mut diff_file,
+                insert_ops,
+                &policy,
+                level,
+            )?,
+            (false, None) => match &mut fetch_journal {
+                Some(journal) => builder::build_local_diff_file_journaled(
+                    &mut target_file,
+                    &mut diff_file,
+                    insert_ops,
+                    &policy,
+                    journal,
+                )?,
+                None => builder::build_local_diff_file(
+                    &mut target_file,
+                    &mut diff_file,
+                    insert_ops,
+                    &policy,
+                )?,
+            },
+        };
+
+        drop(fetch_span);
+
+        let stored: u64 = diff_schema.values().map(|s| s.stored_length()).sum();
+        stats.diff_file_length = Some(stored);
+
+        info!(
+            "Built {} segments in the temporary diff file: {} ({} bytes).",
+            diff_schema.len(),
+            format_size(stored, DECIMAL),
+            stored
+        );
+
+        if self.delta {
+            let bases: HashMap<uuid::Uuid, (u64, u64)> = diff_schema
+                .iter()
+                .filter_map(|(uuid, segment)| segment.delta_base().map(|base| (*uuid, base)))
+                .collect();
+
+            let deltas = diff.convert_to_deltas(&bases);
+            stats.delta_ops = deltas;
+            stats.operations = diff.operations().clone();
+
+            info!("    of them deltas of the source file: {}", deltas);
+        }
+
+        if jobs > 1 {
+            let dst_file = File::create(&build_path)?;
+            builder::preallocate(
+                &build_path,
+                &dst_file,
+                target_sig.length(),
+                diff.zero_length(),
+            )?;
+            dst_file.set_len(target_sig.length())?;
+
+            let mut maps: Vec<Mmap> = Vec::new();
+            for path in &read_paths {
+                // The sources must not be modified during the build anyway
+                maps.push(unsafe { Mmap::map(&platform::open_shared(path)?)? });
+            }
+            let sources: Vec<&[u8]> = maps.iter().map(|map| &map[..]).collect();
+            let diff_map = unsafe { Mmap::map(&diff_file)? };
+
+            let spinner = progress_bar::create_spinner(format!(
+                "Building {} on {} threads...",
+                build_path.display(),
+                jobs
+            ));
+            let build_span = debug_span!("build", ops = diff.operations().len()).entered();
+
+            builder::build_local_file_parallel(
+                &sources,
+                &dst_file,
+                diff.operations(),
+                &diff_map,
+                &diff_schema,
+                jobs,
+            )?;
+
+            drop(build_span);
+            spinner.finish_and_clear();
+        } else {
+            let ops_count = diff.operations().len();
+            let mut journal_file_name = destination_path.clone().into_os_string();
+            journal_file_name.push(JOURNAL_EXT);
+            let journal_path = Path::new(&journal_file_name);
+            let header = journal_header(ops_count)?;
+
+            let mut resumed: Option<(File, Journal)> = None;
+
+            if self.resume && journal_path.exists() {
+                match Journal::open(journal_path, &header) {
+                    Ok(journal) => {
+                        let mut dst_file = OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .open(&build_path)?;
+
+                        if journal.verify(&mut dst_file, &target_sig)? {
+                            info!(
+                                "Resuming from op {} of {} at {} bytes.",
+                                journal.applied(),
+                                ops_count,
+                                journal.offset()
+                            );
+                            resumed = Some((dst_file, journal));
+                        } else {
+                            warn!("Destination file does not match the journal, starting over.");
+                        }
+                    }
+                    Err(e) => warn!("Can not resume: {}, starting over.", e),
+                }
+            }
+
+            resumed_run |= resumed.is_some();
+
+            let (mut dst_file, mut journal) = match resumed {
+                Some(resumed) => resumed,
+                None => (
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&build_path)?,
+                    Journal::create(journal_path, &header)?,
+                ),
+            };
+
+            dst_file.set_len(journal.offset())?;
+            builder::preallocate(
+                &build_path,
+                &dst_file,
+                target_sig.length(),
+                diff.zero_length(),
+            )?;
+            dst_file.seek(SeekFrom::Start(journal.offset()))?;
+
+            let build_span = debug_span!("build", ops = ops_count - journal.applied()).entered();
+
+            // Builds local file
+            builder::build_local_file_journaled(
+                &mut source_file,
+                &mut dst_file,
+                progress_bar::track(
+                    diff.operations().iter().skip(journal.applied()),
+                    "build",
+                    build_path.display().to_string(),
+                    |op| op.length(),
+                ),
+                &mut diff_file,
+                &diff_schema,
+                &mut journal,
+            )?;
+
+            drop(build_span);
+            fs::remove_file(journal_path)?;
+        }
+
+        self.finish_build(
+            &target_sig,
+            &build_path,
+            &target_file_path,
+            &target_read_path,
+            &destination_path,
+            resumed_run,
+        )?;
+
+        if let Some(patch_path) = &self.patch {
+            let header = PatchHeader::new(&target_sig, diff.operations().clone(), diff_schema);
+
+            diff_file.seek(SeekFrom::Start(0))?;
+            patch::write_patch(Path::new(patch_path), &header, &mut diff_file, &recipients)?;
+
+            match recipients.is_empty() {
+                true => info!("Written the patch: {}", patch_path.display()),
+                false => info!("Written the encrypted patch: {}", patch_path.display()),
+            }
+        }
+
+        if resumable_fetch {
+            fs::remove_file(&fetch_journal_path)?;
+        }
+
+        match (self.keep_diff_file, diff_temp_path) {
+            (true, Some(temp_path)) => {
+                info!("Kept the diff file: {}", temp_path.keep()?.display());
+            }
+            (_, None) if self.diff_output.is_some() => {
+                info!("Written the diff file: {}", diff_path.display());
+            }
+            // The .fetch file of a resumable fetch
+            (true, None) => info!("Kept the diff file: {}", diff_path.display()),
+            (false, None) => fs::remove_file(&diff_path)?,
+            // The temporary file is removed when dropped
+            (false, Some(_)) => {}
+        }
+
+        self.finish(stats, &destination_path, total_start)
+    }
+
+    /// Checks the built file if chunk hashes are truncated or the run was
+    /// resumed from a journal, compresses it
+    /// into the destination if the target is signed with --decompress.
+    /// Another encoder or level compresses the same data differently, so a
+    /// recompressed file which differs from the target is downloaded whole.
+    /// `target_read_path` is the target file ops read, a decompressed copy
+    /// of `target_file_path` if it is signed with --decompress.
+    fn finish_build(
+        &self,
+        target_sig: &Signature,
+        build_path: &Path,
+        target_file_path: &Path,
+        target_read_path: &Path,
+        destination_path: &Path,
+        resumed: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        // A chunk which only looks equal because of a truncated hash spoils the whole file,
+        // a resumed run trusts data written before the interruption. The hash of a keyed
+        // signature can not be checked without the key.
+        let resumed = resumed && target_sig.key_id().is_none();
+        if resumed || target_sig.hash_length() < blake3::OUT_LEN {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_mmap(build_path)?;
+
+            if hasher.finalize() != target_sig.strong_hash() {
+                warn!("The new file does not match the target, downloading the whole target file.");
+                fs::copy(target_read_path, build_path)?;
+            }
+        }
+
+        if let Some(compression) = target_sig.decompressed() {
+            info!("Compressing the new file with {}...", compression);
+
+            let mut built = BufReader::new(File::open(build_path)?);
+            let mut destination = File::create(destination_path)?;
+            compression.compress(&mut built, &mut destination)?;
+
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_mmap(destination_path)?;
+
+            if target_sig.compressed_hash() != Some(hasher.finalize().to_hex().as_str()) {
+                warn!("The recompressed file differs from the target, downloading the whole target file.");
+                fs::copy(target_file_path, destination_path)?;
+            }
+        }
+
+        self.restore_metadata(target_sig, destination_path)
+    }
+
+    /// Applies metadata recorded in the target signature to the new file if asked.
+    fn restore_metadata(
+        &self,
+        target_sig: &Signature,
+        destination_path: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.preserve_metadata && !self.preserve_owner {
+            return Ok(());
+        }
+
+        match target_sig.metadata() {
+            Some(metadata) => metadata.restore(destination_path, self.preserve_owner)?,
+            None => warn!(
+                "{} has no metadata, sign the target with --metadata",
+                self.target.display()
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the prices of the target file endpoint, if any is given.
+    fn pricing(&self) -> Option<Pricing> {
+        let egress = self.egress_price.or(config().egress_price);
+        let requests = self.request_price.or(config().request_price);
+
+        (egress.is_some() || requests.is_some()).then(|| Pricing {
+            egress: egress.unwrap_or_default(),
+            requests: requests.unwrap_or_default(),
+        })
+    }
+
+    /// Returns true if the new file goes to stdout, with --stdout or --output -.
+    fn writes_stdout(&self) -> bool {
+        self.stdout || self.output.as_deref() == Some(Path::new(STDIO_PATH))
+    }
+
+    /// Reports a finished build, copies the new file to stdout if asked.
+    fn finish(
+        &self,
+        stats: DiffStats,
+        destination_path: &Path,
+        total_start: Instant,
+    ) -> Result<DiffStats, Box<dyn Error>> {
+        if self.writes_stdout() {
+            let mut stdout = io::stdout().lock();
+            io::copy(&mut File::open(destination_path)?, &mut stdout)?;
+            stdout.flush()?;
+
+            info!("Written the new file to stdout");
+            return Ok(stats);
+        }
+
+        info!("Written the new file: {}", destination_path.display());
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(stats)
+    }
+}
+
+impl Runner for ApplyCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let _span = debug_span!("apply", patch = %self.patch.display()).entered();
+        let total_start = Instant::now();
+
+        let destination_path = Path::new(&self.destination);
+        let to_stdout = self.destination == Path::new(STDIO_PATH);
+
+        if to_stdout && self.resume {
+            return Err("Build to stdout can not be resumed".into());
+        }
+
+        if !to_stdout {
+            let mut inputs: Vec<&Path> = vec![Path::new(&self.source), Path::new(&self.patch)];
+            inputs.extend(self.seed.iter().map(Path::new));
+            safety::ensure_distinct(destination_path, &inputs)?;
+        }
+
+        let patch = Patch::open(
+            Path::new(&self.patch),
+            self.identity
+                .as_ref()
+                .or(config().identity.as_ref())
+                .map(Path::new),
+        )?;
+        let (header, mut data) = patch.into_parts();
+
+        let key_file = self.key_file.as_ref().or(config().key_file.as_ref());
+        let hash_key = match (header.key_id(), key_file) {
+            (None, _) => None,
+            (Some(key_id), Some(key_file)) => {
+                let hash_key = key::load(Path::new(key_file))?;
+                if key::id(&hash_key) != key_id {
+                    return Err(
+                        format!("{} is not the key of the patch", key_file.display()).into(),
+                    );
+                }
+                Some(hash_key)
+            }
+            (Some(_), None) => {
+                return Err("The patch target is signed with a key, pass --key-file".into())
+            }
+        };
+
+        if self.dry_run {
+            let stored: u64 = header.segments().values().map(|s| s.stored_length()).sum();
+
+            println!("Dry run, nothing is written.");
+            println!(
+                "Would apply {} ops of {} to {}, {} ({} bytes) of them stored in the patch",
+                header.operations().len(),
+                self.patch.display(),
+                self.source.display(),
+                format_size(stored, DECIMAL),
+                stored
+            );
+
+            match to_stdout {
+                true => println!("Would write the new file to stdout"),
+                false => print_dry_write(destination_path),
+            }
+
+            return Ok(());
+        }
+
+        info!(
+            "Applying {} ops of {} to {}...",
+            header.operations().len(),
+            self.patch.display(),
+            self.source.display()
+        );
+
+        let mut sources: Vec<Box<dyn CopySource>> =
+            vec![Box::new(MappedSource::open(Path::new(&self.source))?)];
+        for seed in &self.seed {
+            sources.push(Box::new(MappedSource::open(Path::new(seed))?));
+        }
+        let mut source_file = Seeds::new(sources);
+
+        let _span = debug_span!("build", ops = header.operations().len()).entered();
+
+        let destination_name = match to_stdout {
+            true => String::from("stdout"),
+            false => destination_path.display().to_string(),
+        };
+
+        let (hash, length) = match self.resume {
+            true => {
+                let mut journal_file_name = destination_path.as_os_str().to_os_string();
+                journal_file_name.push(JOURNAL_EXT);
+                let journal_path = Path::new(&journal_file_name);
+                let ops_count = header.operations().len();
+                // A patch has no source signature, the patch target check below
+                // catches a source changed between the runs
+                let journal_header = journal::Header::new(header.target_hash(), ops_count)
+                    .with_options(format!("seeds={}", self.seed.len()));
+
+                let mut resumed: Option<(File, Journal)> = None;
+
+                // Without the target signature written chunks can not be verified,
+                // the whole file is checked against the patch target once built
+                if journal_path.exists() {
+                    match Journal::open(journal_path, &journal_header) {
+                        Ok(journal) => {
+                            let dst_file = OpenOptions::new()
+                                .read(true)
+                                .write(true)
+                                .open(destination_path)?;
+
+                            if dst_file.metadata()?.len() >= journal.offset() {
+                                info!(
+                                    "Resuming from op {} of {} at {} bytes.",
+                                    journal.applied(),
+                                    ops_count,
+                                    journal.offset()
+                                );
+                                resumed = Some((dst_file, journal));
+                            } else {
+                                warn!(
+                                    "Destination file does not match the journal, starting over."
+                                );
+                            }
+                        }
+                        Err(e) => warn!("Can not resume: {}, starting over.", e),
+                    }
+                }
+
+                let (mut dst_file, mut journal) = match resumed {
+                    Some(resumed) => resumed,
+                    None => (
+                        OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(destination_path)?,
+                        Journal::create(journal_path, &journal_header)?,
+                    ),
+                };
+
+                let holes: u64 = header
+                    .operations()
+                    .iter()
+                    .filter(|op| matches!(op, Operation::ZERO(_)))
+                    .map(|op| op.length())
+                    .sum();
+
+                dst_file.set_len(journal.offset())?;
+                builder::preallocate(destination_path, &dst_file, header.target_length(), holes)?;
+                dst_file.seek(SeekFrom::Start(journal.offset()))?;
+
+                builder::build_local_file_journaled(
+                    &mut source_file,
+                    &mut dst_file,
+                    progress_bar::track(
+                        header.operations().iter().skip(journal.applied()),
+                        "build",
+                        destination_name.clone(),
+                        |op| op.length(),
+                    ),
+                    &mut data,
+                    header.segments(),
+                    &mut journal,
+                )?;
+
+                drop(dst_file);
+                fs::remove_file(journal_path)?;
+
+                let mut hasher = key::hasher(hash_key.as_ref());
+                hasher.update_mmap(destination_path)?;
+
+                (hasher.finalize(), fs::metadata(destination_path)?.len())
+            }
+            false => {
+                // The result is hashed as it is written, stdout can not be read back
+                let destination: Box<dyn Write> = match to_stdout {
+                    true => Box::new(io::stdout().lock()),
+                    false => {
+                        let file = File::create(destination_path)?;
+                        builder::preallocate(destination_path, &file, header.target_length(), 0)?;
+                        Box::new(file)
+                    }
+                };
+                let mut dst_file =
+                    HashingWriter::new(BufWriter::new(destination), key::hasher(hash_key.as_ref()));
+
+                builder::build_local_file(
+                    &mut source_file,
+                    &mut dst_file,
+                    progress_bar::track(
+                        header.operations(),
+                        "build",
+                        destination_name.clone(),
+                        |op| op.length(),
+                    ),
+                    &mut data,
+                    header.segments(),
+                )?;
+
+                dst_file.flush()?;
+                dst_file.finalize()
+            }
+        };
+
+        if length != header.target_length() || hash != header.target_hash() {
+            return Err(format!(
+                "{} does not match the patch target, is {} the right source file?",
+                destination_name,
+                self.source.display()
+            )
+            .into());
+        }
+
+        info!("Written the new file: {}", destination_name);
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for ApplyPlanCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let _span = debug_span!("apply-plan", plan = %self.plan.display()).entered();
+        let total_start = Instant::now();
+
+        let plan = TransferPlan::open(Path::new(&self.plan))?;
+        let destination_path = plan.destination();
+
+        let key_file = self.key_file.as_ref().or(config().key_file.as_ref());
+        let hash_key = match (plan.key_id(), key_file) {
+            (None, _) => None,
+            (Some(key_id), Some(key_file)) => {
+                let hash_key = key::load(Path::new(key_file))?;
+                if key::id(&hash_key) != key_id {
+                    return Err(format!("{} is not the key of the plan", key_file.display()).into());
+                }
+                Some(hash_key)
+            }
+            (Some(_), None) => {
+                return Err("The plan target is signed with a key, pass --key-file".into())
+            }
+        };
+
+        let mut inputs: Vec<&Path> = vec![Path::new(&self.plan)];
+        inputs.extend(plan.sources().iter().map(|source| source.path()));
+        safety::ensure_distinct(destination_path, &inputs)?;
+
+        let in_place = safety::is_same_file(destination_path, plan.target().path());
+        if in_place && !plan.in_place() {
+            return Err(format!(
+                "Destination {} is the same file as target {}, but the plan was made without --in-place",
+                destination_path.display(),
+                plan.target().path().display()
+            )
+            .into());
+        }
+
+        for file in plan.sources().iter().chain(std::iter::once(plan.target())) {
+            file.check()?;
+        }
+
+        if self.dry_run {
+            let fetch_length: u64 = plan.ranges().iter().map(|op| op.length()).sum();
+
+            println!("Dry run, nothing is written.");
+            println!(
+                "Would request {} ranges of {}: {} ({} bytes)",
+                plan.ranges().len(),
+                plan.target().path().display(),
+                format_size(fetch_length, DECIMAL),
+                fetch_length
+            );
+
+            match in_place {
+                true => println!("Would overwrite {} in place", destination_path.display()),
+                false => print_dry_write(destination_path),
+            }
+
+            return Ok(());
+        }
+
+        info!(
+            "Applying {} ops of {} to {}...",
+            plan.operations().len(),
+            self.plan.display(),
+            destination_path.display()
+        );
+
+        let mut sources: Vec<Box<dyn CopySource>> = Vec::new();
+        for source in plan.sources() {
+            sources.push(Box::new(MappedSource::open(source.path())?));
+        }
+        let mut source_file = Seeds::new(sources);
+
+        let mut target_file = throttle::Throttled::new(
+            File::open(plan.target().path())?,
+            bwlimit(self.bwlimit)?.unwrap_or(u64::MAX),
+        );
+        let mut diff_file = tempfile::NamedTempFile::new()?;
+
+        let policy = builder::RetryPolicy {
+            attempts: retries(self.retries),
+            ..Default::default()
+        };
+
+        let fetch_span = debug_span!("fetch", ranges = plan.ranges().len()).entered();
+
+        // The target is only read here, before the destination is opened
+        let diff_schema = builder::build_local_diff_file(
+            &mut target_file,
+            &mut diff_file,
+            progress_bar::track(
+                plan.ranges(),
+                "fetch",
+                plan.target().path().display().to_string(),
+                |op| op.length(),
+            ),
+            &policy,
+        )?;
+        drop(target_file);
+        drop(fetch_span);
+
+        let build_span = debug_span!("build", ops = plan.operations().len()).entered();
+
+        let dst_file = File::create(destination_path)?;
+        builder::preallocate(destination_path, &dst_file, plan.target().length(), 0)?;
+
+        let mut dst_file = BufWriter::new(dst_file);
+        builder::build_local_file(
+            &mut source_file,
+            &mut dst_file,
+            progress_bar::track(
+                plan.operations(),
+                "build",
+                destination_path.display().to_string(),
+                |op| op.length(),
+            ),
+            diff_file.as_file_mut(),
+            &diff_schema,
+        )?;
+
+        dst_file.flush()?;
+        drop(dst_file);
+        drop(build_span);
+
+        let mut hasher = key::hasher(hash_key.as_ref());
+        hasher.update_mmap(destination_path)?;
+
+        if hasher.finalize() != plan.target().strong_hash() {
+            // A chunk which only looks equal because of a truncated hash spoils the whole file
+            if plan.hash_length() < blake3::OUT_LEN && !in_place {
+                warn!("Chunk hash collision, downloading the whole target file.");
+                fs::copy(plan.target().path(), destination_path)?;
+            } else {
+                return Err(format!(
+                    "{} does not match the plan target, the files changed since the plan was made",
+                    destination_path.display()
+                )
+                .into());
+            }
+        }
+
+        info!("Written the new file: {}", destination_path.display());
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for RepairCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let _span = debug_span!("repair", file = %self.file.display()).entered();
+        let total_start = Instant::now();
+
+        let sig: Signature = serde_json::from_reader(BufReader::new(File::open(&self.signature)?))?;
+
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+        let good_path = naming.file_path(Path::new(&self.signature))?;
+        let file_path = Path::new(&self.file);
+
+        safety::ensure_distinct(
+            file_path,
+            &[good_path.as_path(), Path::new(&self.signature)],
+        )?;
+
+        let spinner = progress_bar::create_spinner(format!("Verifying {}...", self.file.display()));
+        let damage = repair::find_damage(file_path, &sig)?;
+        spinner.finish_and_clear();
+
+        if damage.is_intact() {
+            info!(
+                "{}",
+                style(format!("{} is intact!", self.file.display())).green()
+            );
+            return Ok(());
+        }
+
+        println!(
+            "Damaged chunks: {} of {}, {} ranges to fetch from {}: {} ({} bytes)",
+            damage.chunks,
+            sig.chunks().len(),
+            damage.ranges.len(),
+            good_path.display(),
+            format_size(damage.length(), DECIMAL),
+            damage.length()
+        );
+
+        if damage.resized {
+            println!(
+                "File size: {} bytes, the signature has {} bytes",
+                fs::metadata(file_path)?.len(),
+                sig.length()
+            );
+        }
+
+        if self.dry_run {
+            println!("Dry run, nothing is written.");
+            return Ok(());
+        }
+
+        let mut good_file = throttle::Throttled::new(
+            File::open(&good_path)?,
+            bwlimit(self.bwlimit)?.unwrap_or(u64::MAX),
+        );
+        let policy = builder::RetryPolicy {
+            attempts: retries(self.retries),
+            ..Default::default()
+        };
+
+        repair::repair_file(
+            file_path,
+            &sig,
+            &mut good_file,
+            progress_bar::track(
+                &damage.ranges,
+                "fetch",
+                good_path.display().to_string(),
+                |(_, length)| *length,
+            ),
+            &policy,
+        )?;
+
+        info!("Repaired {}", self.file.display());
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for ScrubCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let _span = debug_span!("scrub", root = %self.root.display()).entered();
+        let total_start = Instant::now();
+        let root = Path::new(&self.root);
+
+        let files: Vec<ScrubbedFile> = match &self.manifest {
+            Some(manifest_path) => {
+                let manifest: TreeManifest =
+                    serde_json::from_reader(BufReader::new(File::open(manifest_path)?))?;
+
+                manifest
+                    .entries()
+                    .iter()
+                    .map(|entry| (entry.path.clone(), Ok(entry.signature.clone())))
+                    .collect()
+            }
+            None => self.signed_files(root)?,
+        };
+
+        let mut report: Vec<FileHealth> = Vec::new();
+
+        for (relative, sig) in files {
+            let health = match sig {
+                Ok(sig) => self.scrub(root, &relative, &sig)?,
+                Err(reason) => FileHealth::Skipped {
+                    path: relative,
+                    reason,
+                },
+            };
+
+            if !self.json {
+                print_file_health(&health);
+            }
+
+            report.push(health);
+        }
+
+        let bad = report.iter().filter(|health| health.is_bad()).count();
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+
+        info!(
+            "{}",
+            style(format!(
+                "Scrubbed {} files in {:.2?}!",
+                report.len(),
+                total_start.elapsed()
+            ))
+            .green()
+        );
+
+        match bad {
+            0 => Ok(()),
+            _ => Err(format!("{} files are corrupted or missing", bad).into()),
+        }
+    }
+}
+
+/// Relative path of a file to scrub with its signature, or the reason it can not be read
+type ScrubbedFile = (String, Result<Signature, String>);
+
+impl ScrubCommand {
+    /// Returns relative paths of the files of the tree which have signatures
+    /// next to them, with the signatures or the reason they can not be read.
+    fn signed_files(&self, root: &Path) -> Result<Vec<ScrubbedFile>, Box<dyn Error>> {
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+        let mut files: Vec<ScrubbedFile> = Vec::new();
+
+        let walker = ignore::WalkBuilder::new(root)
+            .standard_filters(false)
+            .sort_by_file_path(|a, b| a.cmp(b))
+            .build();
+
+        for entry in walker {
+            let entry = entry?;
+            if entry.file_type().is_none_or(|t| t.is_dir()) {
+                continue;
+            }
+
+            let sig_path = naming.signature_path(entry.path())?;
+            if !sig_path.is_file() {
+                continue;
+            }
+
+            // Relative paths are written the way manifests keep them
+            let relative = exclude::relative_to(root, entry.path()).unwrap_or(entry.path());
+            let relative = relative
+                .components()
+                .map(|part| platform::escape_path(Path::new(part.as_os_str())))
+                .collect::<Vec<String>>()
+                .join("/");
+
+            let sig = File::open(&sig_path)
+                .map_err(|e| e.to_string())
+                .and_then(|file| {
+                    serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())
+                });
+
+            files.push((relative, sig));
+        }
+
+        Ok(files)
+    }
+
+    /// Verifies a single file and repairs it if a good copy is given.
+    fn scrub(
+        &self,
+        root: &Path,
+        relative: &str,
+        sig: &Signature,
+    ) -> Result<FileHealth, Box<dyn Error>> {
+        let path = root.join(platform::unescape_path(relative)?);
+        let _span = debug_span!("file", path = %relative).entered();
+
+        if !path.exists() {
+            return Ok(FileHealth::Missing {
+                path: relative.to_string(),
+            });
+        }
+
+        let found = match bwlimit(self.bwlimit)? {
+            Some(rate) => {
+                let mut file =
+                    throttle::Throttled::new(BufReader::new(platform::open_shared(&path)?), rate);
+                repair::find_damage_streaming(&mut file, sig)
+            }
+            None => repair::find_damage(&path, sig),
+        };
+
+        let damage = match found {
+            Ok(damage) => damage,
+            Err(e) => {
+                return Ok(FileHealth::Skipped {
+                    path: relative.to_string(),
+                    reason: e.to_string(),
+                })
+            }
+        };
+
+        if damage.is_intact() {
+            return Ok(FileHealth::Intact {
+                path: relative.to_string(),
+                length: sig.length(),
+            });
+        }
+
+        let repaired = match &self.repair_from {
+            Some(good_root) => {
+                let good_path = Path::new(good_root).join(platform::unescape_path(relative)?);
+                let policy = builder::RetryPolicy {
+                    attempts: retries(self.retries),
+                    ..Default::default()
+                };
+
+                let fix = || -> Result<(), Box<dyn Error>> {
+                    let mut good = File::open(&good_path)?;
+                    repair::repair_file(&path, sig, &mut good, &damage.ranges, &policy)
+                };
+
+                match fix() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!(
+                            "Can not repair {} from {}: {}",
+                            relative,
+                            good_path.display(),
+                            e
+                        );
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+
+        Ok(FileHealth::Corrupted {
+            path: relative.to_string(),
+            chunks: damage.chunks,
+            damaged_length: damage.length(),
+            resized: damage.resized,
+            repaired,
+        })
+    }
+}
+
+impl Runner for ChurnCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let sig: Signature = serde_json::from_reader(BufReader::new(File::open(&self.signature)?))?;
+
+        if !churn::is_tracked(&sig) {
+            return Err(
+                "Signature has no change history, generate it with sign --track-changes".into(),
+            );
+        }
+
+        let now = unix_now();
+        let since = now.saturating_sub(self.days * SECONDS_PER_DAY);
+        let regions = churn::analyze(&sig, self.regions, since);
+
+        println!(
+            "Changes of {} within the last {} days:",
+            self.signature.display(),
+            self.days
+        );
+        println!();
+
+        for (index, region) in regions.iter().enumerate() {
+            let filled = (region.ratio() * 20.0).round() as usize;
+            let last_changed = match region.last_changed_at {
+                Some(t) => format!(
+                    "{:.1} days ago",
+                    now.saturating_sub(t) as f64 / SECONDS_PER_DAY as f64
+                ),
+                None => String::from("-"),
+            };
+
+            println!(
+                "{:<4} [ {:<12}: {:<12} ] [{}{}] {:>6.2}% last change: {}",
+                format!("{})", index + 1),
+                region.offset,
+                region.length,
+                style("#".repeat(filled)).red(),
+                "-".repeat(20 - filled),
+                region.ratio() * 100.0,
+                last_changed
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Runner for StatsCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let sig: Signature = serde_json::from_reader(BufReader::new(File::open(&self.signature)?))?;
+        let chunk_stats = stats::analyze(&sig);
+
+        println!("Chunks of {}:", self.signature.display());
+        println!();
+
+        if let Some(sizes) = sig.chunk_sizes() {
+            println!(
+                "Chunk sizes: min {}, avg {}, max {}",
+                sizes.min_size, sizes.avg_size, sizes.max_size
+            );
+        }
+
+        println!(
+            "{} chunks, {} ({} bytes)",
+            chunk_stats.count,
+            format_size(sig.length(), DECIMAL),
+            sig.length()
+        );
+        println!(
+            "Length: min {}, median {}, mean {:.0}, max {}",
+            chunk_stats.min, chunk_stats.median, chunk_stats.mean, chunk_stats.max
+        );
+        println!(
+            "Duplicate chunks: {}, {} ({} bytes)",
+            chunk_stats.duplicates,
+            format_size(chunk_stats.duplicate_bytes, DECIMAL),
+            chunk_stats.duplicate_bytes
+        );
+        println!(
+            "Chunk hash: {} bytes, chance of a false match with a same-sized file {:.1e}",
+            sig.hash_length(),
+            stats::collision_probability(&sig)
+        );
+
+        if let Some(md5) = sig.md5_base64() {
+            println!("md5 as GCS md5Hash: {}", md5);
+        }
+
+        println!();
+        println!("Length histogram:");
+        println!();
+
+        let most = chunk_stats
+            .buckets
+            .iter()
+            .map(|b| b.count)
+            .max()
+            .unwrap_or(1);
+
+        for bucket in &chunk_stats.buckets {
+            let filled = (bucket.count * 40).div_ceil(most);
+
+            println!(
+                "[ {:>9} .. {:<9} ) {:>8} {}",
+                bucket.lower,
+                bucket.upper,
+                bucket.count,
+                style("#".repeat(filled)).green()
+            );
+        }
+
+        println!();
+        println!("Expected cost of random single-byte edits:");
+        println!();
+
+        for edits in [1, 10, 100, 1000] {
+            let estimate = stats::estimate_edits(&sig, edits);
+
+            println!(
+                "{:>5} edit(s): {:>8.1} requests, {}",
+                estimate.edits,
+                estimate.requests,
+                format_size(estimate.bytes as u64, DECIMAL)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Runner for AnalyzeCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let previous: Option<Signature> = match &self.previous {
+            Some(path) => Some(serde_json::from_reader(BufReader::new(File::open(path)?))?),
+            None => None,
+        };
+
+        let spinner = progress_bar::create_spinner(format!("Analyzing {}...", self.file.display()));
+        let analysis = analyze::analyze(Path::new(&self.file), previous.as_ref())?;
+        spinner.finish_and_clear();
+
+        println!("Analysis of {}:", self.file.display());
+        println!();
+        println!(
+            "File size: {} ({} bytes), sampled: {}",
+            format_size(analysis.length, DECIMAL),
+            analysis.length,
+            format_size(analysis.sampled, DECIMAL)
+        );
+        println!(
+            "Entropy: {:.2} bits/byte, zeros: {:.1}%, estimated compressed size: {:.0}%",
+            analysis.entropy,
+            analysis.zeros * 100.0,
+            analysis.compression_ratio() * 100.0
+        );
+
+        match &analysis.locality {
+            Some(locality) => println!(
+                "Changes since the previous version: {} range(s), {} of {}, mean range {}",
+                locality.ranges,
+                format_size(locality.changed, DECIMAL),
+                format_size(locality.length, DECIMAL),
+                format_size(locality.mean_range(), DECIMAL)
+            ),
+            None => println!("Changes since the previous version: unknown, pass --previous"),
+        }
+
+        println!();
+        println!(
+            "Recommended: --min-size {} --avg-size {} --max-size {}",
+            analysis.sizes.min_size, analysis.sizes.avg_size, analysis.sizes.max_size
+        );
+        println!(
+            "Patch compression: {}",
+            match analysis.compress {
+                true => style("worth enabling").green(),
+                false => style("not worth it, data looks incompressible").yellow(),
+            }
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for SelftestCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        if self.regenerate {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&selftest::regenerate()?)?
+            );
+            return Ok(());
+        }
+
+        let outcomes = selftest::run()?;
+
+        for outcome in &outcomes {
+            match outcome.passed() {
+                true => println!("{:<24} {}", outcome.name, style("ok").green()),
+                false => println!("{:<24} {}", outcome.name, style("FAILED").red()),
+            }
+
+            for failure in &outcome.failures {
+                println!("    {}", failure);
+            }
+        }
+
+        let failed = outcomes.iter().filter(|o| !o.passed()).count();
+
+        println!();
+        if failed > 0 {
+            return Err(format!("{} of {} test vectors failed", failed, outcomes.len()).into());
+        }
+
+        println!(
+            "{}",
+            style(format!("All {} test vectors passed!", outcomes.len())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for StoreCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            StoreSubcommand::Add(add) => add.run(),
+            StoreSubcommand::List(list) => list.run(),
+            StoreSubcommand::Prune(prune) => prune.run(),
+            StoreSubcommand::Export(export) => export.run(),
+        }
+    }
+}
+
+impl Runner for StoreAddCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        info!(
+            "Adding signatures for {} to {}",
+            &self.mask,
+            self.db.display()
+        );
+
+        let total_start = Instant::now();
+        let store = Store::open(Path::new(&self.db))?;
+
+        let defaults = SignOptions {
+            threads: self.threads.or(config().threads).unwrap_or(1),
+            mmap: !self.no_mmap,
+            ..Default::default()
+        };
+
+        for source_dir_entry in globwalk::glob(&self.mask)? {
+            let source_dir_entry = source_dir_entry?;
+            let source_path = source_dir_entry.path();
+
+            if source_path.is_dir() {
+                continue;
+            }
+
+            let start = Instant::now();
+            let spinner = progress_bar::create_spinner(format!(
+                "Calculating signature for {:?}...",
+                source_path
+            ));
+
+            let sizes = store
+                .get(source_path)?
+                .and_then(|previous| previous.chunk_sizes());
+            let options = file_options(
+                defaults,
+                source_path,
+                sizes,
+                self.min_size,
+                self.avg_size,
+                self.max_size,
+            )?;
+
+            let sig = Signature::generate_file(source_path, &options)?;
+            store.put(source_path, &sig, unix_now())?;
+
+            spinner.finish_with_message(format!(
+                "Took {:.2?}, source file size: {}, added: {}",
+                start.elapsed(),
+                format_size(sig.length(), DECIMAL),
+                source_path.display()
+            ));
+        }
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for StoreListCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let store = Store::open(Path::new(&self.db))?;
+
+        let entries = match &self.hash {
+            Some(hash) => store.find_by_hash(&blake3::Hash::from_hex(hash)?)?,
+            None => store.entries()?,
+        };
+
+        for entry in &entries {
+            println!(
+                "{} {:>12} {}",
+                entry.strong_hash,
+                format_size(entry.length, DECIMAL),
+                entry.path.display()
+            );
+        }
+
+        println!();
+        println!("{} signature(s)", entries.len());
+
+        Ok(())
+    }
+}
+
+impl Runner for StorePruneCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let store = Store::open(Path::new(&self.db))?;
+        let removed = store.prune()?;
+
+        for path in &removed {
+            println!("Removed {}", path.display());
+        }
+
+        println!();
+        println!(
+            "{}",
+            style(format!("Pruned {} signature(s)", removed.len())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for StoreExportCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let store = Store::open(Path::new(&self.db))?;
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+
+        let file = Path::new(&self.file);
+        let sig = match store.get(file)? {
+            Some(sig) => sig,
+            None => {
+                return Err(format!(
+                    "No signature for {} in {}",
+                    self.file.display(),
+                    self.db.display()
+                )
+                .into())
+            }
+        };
+
+        let target_path = naming.signature_path(file)?;
+        safety::ensure_distinct(&target_path, &[file])?;
+
+        let mut output_file = File::create(&target_path)?;
+        output_file.write_all(serde_json::to_string_pretty(&sig)?.as_bytes())?;
+
+        info!("Saved to: {}", target_path.display());
+
+        Ok(())
+    }
+}
+
+impl Runner for CasCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            CasSubcommand::Ingest(ingest) => ingest.run(),
+            CasSubcommand::Materialize(materialize) => materialize.run(),
+            CasSubcommand::Gc(gc) => gc.run(),
+        }
+    }
+}
+
+impl Runner for CasIngestCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        info!("Ingesting {} into {}", &self.mask, self.repo.display());
+
+        let total_start = Instant::now();
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+        let repo = ChunkStore::open(Path::new(&self.repo), naming)?;
+
+        let options = SignOptions {
+            min_size: self.min_size,
+            avg_size: self.avg_size,
+            max_size: self.max_size,
+            key: match self.key_file.as_ref().or(config().key_file.as_ref()) {
+                Some(key_file) => Some(key::load(Path::new(key_file))?),
+                None => None,
+            },
+            ..Default::default()
+        };
+        options.validate()?;
+
+        let mut total_length: u64 = 0;
+        let mut total_stored: u64 = 0;
+
+        for source_dir_entry in globwalk::glob(&self.mask)? {
+            let source_dir_entry = source_dir_entry?;
+            let source_path = source_dir_entry.path();
+
+            if source_path.is_dir() {
+                continue;
+            }
+
+            let spinner = progress_bar::create_spinner(format!("Ingesting {:?}...", source_path));
+
+            let ingested = repo.ingest(source_path, &options)?;
+            total_length += ingested.length;
+            total_stored += ingested.stored;
+
+            spinner.finish_with_message(format!(
+                "{}: {} new of {}, manifest: {}",
+                source_path.display(),
+                format_size(ingested.stored, DECIMAL),
+                format_size(ingested.length, DECIMAL),
+                ingested.manifest.display()
+            ));
+        }
+
+        println!();
+        println!(
+            "Stored {} of {} ({} deduplicated)",
+            format_size(total_stored, DECIMAL),
+            format_size(total_length, DECIMAL),
+            format_size(total_length - total_stored, DECIMAL)
+        );
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for CasMaterializeCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        let repo = ChunkStore::open(Path::new(&self.repo), NamingStrategy::default())?;
+
+        let manifest_path = Path::new(&self.manifest);
+        let output_path = Path::new(&self.output);
+        safety::ensure_distinct(output_path, &[manifest_path])?;
+
+        let manifest: Signature =
+            serde_json::from_reader(BufReader::new(File::open(manifest_path)?))?;
+
+        let hash_key = match self.key_file.as_ref().or(config().key_file.as_ref()) {
+            Some(key_file) if manifest.key_id().is_some() => Some(key::load(Path::new(key_file))?),
+            _ => None,
+        };
+
+        let mut output_file = File::create(output_path)?;
+        repo.materialize(&manifest, hash_key.as_ref(), &mut output_file)?;
+
+        println!(
+            "{}",
+            style(format!(
+                "Materialized {} to {} in {:.2?}!",
+                format_size(manifest.length(), DECIMAL),
+                self.output.display(),
+                start.elapsed()
+            ))
+            .green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for CasGcCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?;
+        let repo = ChunkStore::open(Path::new(&self.repo), naming)?;
+        let collected = repo.gc(self.dry_run)?;
+
+        let action = match self.dry_run {
+            true => "Can reclaim",
+            false => "Reclaimed",
+        };
+
+        println!(
+            "{}",
+            style(format!(
+                "{} {} in {} chunk(s), took {:.2?}",
+                action,
+                format_size(collected.bytes, DECIMAL),
+                collected.chunks,
+                start.elapsed()
+            ))
+            .green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for CasyncCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            CasyncSubcommand::Ingest(ingest) => ingest.run(),
+            CasyncSubcommand::Materialize(materialize) => materialize.run(),
+        }
+    }
+}
+
+impl Runner for CasyncIngestCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        info!("Ingesting {} into {}", &self.mask, &self.store);
+
+        let total_start = Instant::now();
+        let store = CasyncStore::open(&self.store);
+
+        let options = SignOptions {
+            min_size: self.min_size,
+            avg_size: self.avg_size,
+            max_size: self.max_size,
+            ..Default::default()
+        };
+        options.validate()?;
+
+        if let Some(index_dir) = &self.index_dir {
+            fs::create_dir_all(index_dir)?;
+        }
+
+        let mut total_length: u64 = 0;
+        let mut total_stored: u64 = 0;
+
+        for source_dir_entry in globwalk::glob(&self.mask)? {
+            let source_dir_entry = source_dir_entry?;
+            let source_path = source_dir_entry.path();
+
+            // Indexes written next to the files must not be ingested themselves
+            if source_path.is_dir() || source_path.extension().is_some_and(|ext| ext == "caibx") {
+                continue;
+            }
+
+            let mut index_name = source_dir_entry.file_name().to_os_string();
+            index_name.push(".caibx");
+            let index_path = match &self.index_dir {
+                Some(index_dir) => Path::new(index_dir).join(index_name),
+                None => source_path.with_file_name(index_name),
+            };
+
+            let spinner = progress_bar::create_spinner(format!("Ingesting {:?}...", source_path));
+
+            let (index, stored) = store.ingest(source_path, &options, self.digest)?;
+            total_length += index.length();
+            total_stored += stored;
+
+            let mut w = BufWriter::new(File::create(&index_path)?);
+            index.write(&mut w)?;
+            w.flush()?;
+
+            spinner.finish_with_message(format!(
+                "{}: {} new of {}, index: {}",
+                source_path.display(),
+                format_size(stored, DECIMAL),
+                format_size(index.length(), DECIMAL),
+                index_path.display()
+            ));
+        }
+
+        println!();
+        println!(
+            "Stored {} of {} ({} deduplicated)",
+            format_size(total_stored, DECIMAL),
+            format_size(total_length, DECIMAL),
+            format_size(total_length - total_stored, DECIMAL)
+        );
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for CasyncMaterializeCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        proxy(&self.proxy)?;
+
+        let start = Instant::now();
+        let store = CasyncStore::open(&self.store);
+
+        let output_path = Path::new(&self.output);
+        let index = match http::is_url(&self.index) {
+            true => CasyncIndex::read(&mut &http::get(&self.index)?[..])?,
+            false => {
+                safety::ensure_distinct(output_path, &[Path::new(&self.index)])?;
+                CasyncIndex::read(&mut BufReader::new(File::open(&self.index)?))?
+            }
+        };
+
+        let directory = match output_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut output_file = tempfile::NamedTempFile::new_in(directory)?;
+
+        let mut w = BufWriter::new(output_file.as_file_mut());
+        store.materialize(&index, &mut w)?;
+        w.flush()?;
+        drop(w);
+
+        output_file.persist(output_path).map_err(|e| e.error)?;
+
+        println!(
+            "{}",
+            style(format!(
+                "Materialized {} to {} in {:.2?}!",
+                format_size(index.length(), DECIMAL),
+                self.output.display(),
+                start.elapsed()
+            ))
+            .green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for TreeDiffCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let source: TreeManifest =
+            serde_json::from_reader(BufReader::new(File::open(&self.source)?))?;
+        let target: TreeManifest =
+            serde_json::from_reader(BufReader::new(File::open(&self.target)?))?;
+
+        let changes = manifest::diff(&source, &target);
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&changes)?);
+            return Ok(());
+        }
+
+        println!(
+            "Tree diff {} .. {}:",
+            self.source.display(),
+            self.target.display()
+        );
+        println!();
+
+        for change in &changes {
+            match change {
+                FileChange::Added { path, length } => println!(
+                    "{} {} ({})",
+                    style("+").green(),
+                    path,
+                    format_size(*length, DECIMAL)
+                ),
+                FileChange::Removed { path, length } => println!(
+                    "{} {} ({})",
+                    style("-").red(),
+                    path,
+                    format_size(*length, DECIMAL)
+                ),
+                FileChange::Renamed {
+                    from,
+                    path,
+                    source_length,
+                    target_length,
+                    copy_length,
+                    insert_length,
+                    ..
+                } => println!(
+                    "{} {} -> {} ({} -> {}, reused: {}, transfer: {})",
+                    style("R").cyan(),
+                    from,
+                    path,
+                    format_size(*source_length, DECIMAL),
+                    format_size(*target_length, DECIMAL),
+                    format_size(*copy_length, DECIMAL),
+                    format_size(*insert_length, DECIMAL)
+                ),
+                FileChange::Modified {
+                    path,
+                    source_length,
+                    target_length,
+                    copy_length,
+                    insert_length,
+                } => println!(
+                    "{} {} ({} -> {}, reused: {}, transfer: {})",
+                    style("M").yellow(),
+                    path,
+                    format_size(*source_length, DECIMAL),
+                    format_size(*target_length, DECIMAL),
+                    format_size(*copy_length, DECIMAL),
+                    format_size(*insert_length, DECIMAL)
+                ),
+                FileChange::Link { path, target } => {
+                    println!("{} {} -> {}", style("L").cyan(), path, target)
+                }
+            }
+        }
+
+        let count = |f: fn(&FileChange) -> bool| changes.iter().filter(|c| f(c)).count();
+        let transfer: u64 = changes.iter().map(|c| c.transfer_length()).sum();
+
+        println!();
+        println!(
+            "Added: {}, removed: {}, renamed: {}, modified: {}, links: {}, unchanged: {}",
+            count(|c| matches!(c, FileChange::Added { .. })),
+            count(|c| matches!(c, FileChange::Removed { .. })),
+            count(|c| matches!(c, FileChange::Renamed { .. })),
+            count(|c| matches!(c, FileChange::Modified { .. })),
+            count(|c| matches!(c, FileChange::Link { .. })),
+            target.entries().len()
+                - count(|c| !matches!(c, FileChange::Removed { .. } | FileChange::Link { .. }))
+        );
+        println!("To transfer: {}", format_size(transfer, DECIMAL));
+
+        Ok(())
+    }
+}
+
+impl Runner for ChooseBaseCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        if self.candidates.is_empty() {
+            return Err("No candidates given".into());
+        }
+
+        let target: Signature = serde_json::from_reader(BufReader::new(File::open(&self.target)?))?;
+
+        let mut candidates: Vec<Signature> = Vec::new();
+        for candidate in &self.candidates {
+            candidates.push(serde_json::from_reader(BufReader::new(File::open(
+                candidate,
+            )?))?);
+        }
+
+        let ranked = base::rank(&target, &candidates.iter().collect::<Vec<_>>());
+
+        println!(
+            "Candidates for {} ({}):",
+            self.target.display(),
+            format_size(target.length(), DECIMAL)
+        );
+        println!();
+
+        for (place, candidate) in ranked.iter().enumerate() {
+            println!(
+                "{:<4} {} shared: {}, to download: {}",
+                format!("{})", place + 1),
+                self.candidates[candidate.index].display(),
+                format_size(candidate.shared_length, DECIMAL),
+                format_size(candidate.download_length, DECIMAL)
+            );
+        }
+
+        println!();
+        println!(
+            "{}",
+            style(format!(
+                "Best base: {}",
+                self.candidates[ranked[0].index].display()
+            ))
+            .green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for ServeCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let root = Path::new(&self.root);
+        if !root.is_dir() {
+            return Err(format!("{} is not a directory", self.root.display()).into());
+        }
+
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+        let policy = builder::RetryPolicy {
+            attempts: retries(self.retries),
+            ..Default::default()
+        };
+
+        let service = DiffService::new(root, naming, policy).with_max_body(self.max_body);
+
+        if let Some(grpc_addr) = &self.grpc_addr {
+            #[cfg(feature = "grpc")]
+            {
+                cloud_zsync::grpc::serve(service.clone(), grpc_addr)?;
+                info!("Serving gRPC on {}", grpc_addr);
+            }
+
+            #[cfg(not(feature = "grpc"))]
+            return Err(format!(
+                "Can not serve gRPC on {}, built without the grpc feature",
+                grpc_addr
+            )
+            .into());
+        }
+
+        info!("Serving {} on http://{}/", self.root.display(), self.addr);
+
+        let workers = match self.workers {
+            Some(workers) => workers,
+            None => thread::available_parallelism()?.get(),
+        };
+
+        service.serve(&self.addr, workers)
+    }
+}
+
+impl Runner for SyncCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let total_start = Instant::now();
+
+        let synced = match (remote_path(&self.source), remote_path(&self.destination)) {
+            (Some((host, remote)), None) => {
+                let (mut session, remote) = self.connect(host, remote)?;
+                let synced = remote::pull(&mut session, &remote, Path::new(&self.destination))?;
+                session.close()?;
+                synced
+            }
+            (None, Some((host, remote))) => {
+                let (mut session, remote) = self.connect(host, remote)?;
+                let synced = remote::push(&mut session, Path::new(&self.source), &remote)?;
+                session.close()?;
+                synced
+            }
+            (Some(_), Some(_)) => return Err("Both paths are remote, one must be local".into()),
+            (None, None) => return Err("Both paths are local, one must be host:path".into()),
+        };
+
+        match synced {
+            true => info!("Updated {} from {}", self.destination, self.source),
+            false => info!("{}", style("Files are equal!").green()),
+        }
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl SyncCommand {
+    /// Starts the server on the host of a host:path argument, the name of
+    /// a configured remote is taken first.
+    ///
+    /// # Returns:
+    /// - `(RemoteSession, PathBuf)`: session and the file path on the host
+    fn connect(&self, name: &str, path: &str) -> Result<(RemoteSession, PathBuf), Box<dyn Error>> {
+        let profile = config().remote(name);
+
+        let host = profile.map_or(name, |profile| profile.host.as_str());
+        let rsh = self
+            .rsh
+            .as_deref()
+            .or(profile.and_then(|profile| profile.rsh.as_deref()))
+            .or(config().rsh.as_deref())
+            .unwrap_or("ssh");
+        let remote_bin = self
+            .remote_bin
+            .as_deref()
+            .or(profile.and_then(|profile| profile.remote_bin.as_deref()))
+            .unwrap_or("cloud-zsync");
+
+        let path = match profile {
+            Some(profile) => profile.file_path(path),
+            None => PathBuf::from(path),
+        };
+
+        Ok((RemoteSession::connect(rsh, host, remote_bin)?, path))
+    }
+}
+
+impl Runner for ServerCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        remote::serve(&mut io::stdin().lock(), &mut io::stdout().lock())
+    }
+}
+
+impl Runner for ZsyncCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            ZsyncSubcommand::Make(make) => make.run(),
+            ZsyncSubcommand::Pull(pull) => pull.run(),
+        }
+    }
+}
+
+impl Runner for ZsyncMakeCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let total_start = Instant::now();
+        let path = Path::new(&self.file);
+        let output = self.output.clone().unwrap_or_else(|| {
+            let mut output = self.file.clone().into_os_string();
+            output.push(".zsync");
+            PathBuf::from(output)
+        });
+
+        safety::ensure_distinct(Path::new(&output), &[path])?;
+
+        if let Some(signature) = &self.signature {
+            let sig: Signature = serde_json::from_reader(BufReader::new(File::open(signature)?))?;
+            if sig.key_id().is_some() || sig.decompressed().is_some() {
+                return Err(format!(
+                    "{} can not be checked against the file",
+                    signature.display()
+                )
+                .into());
+            }
+
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_reader(platform::open_shared(path)?)?;
+            if hasher.finalize() != sig.strong_hash() {
+                return Err(format!(
+                    "{} does not match {}",
+                    self.file.display(),
+                    signature.display()
+                )
+                .into());
+            }
+        }
+
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("{:?} has no valid UTF-8 file name", path))?;
+        let block_size = match self.block_size {
+            Some(block_size) => block_size,
+            None => zsync::default_block_size(fs::metadata(path)?.len()),
+        };
+
+        let spinner =
+            progress_bar::create_spinner(format!("Calculating block checksums for {:?}...", path));
+
+        let mut control = ControlFile::generate(
+            &mut BufReader::new(platform::open_shared(path)?),
+            block_size,
+            name,
+        )?;
+        control
+            .urls
+            .push(self.url.clone().unwrap_or(name.to_string()));
+
+        let mut w = BufWriter::new(File::create(&output)?);
+        control.write(&mut w)?;
+        w.flush()?;
+
+        spinner.finish_with_message(format!(
+            "{} blocks of {} saved to: {}",
+            control.blocks(),
+            format_size(control.block_size() as u64, DECIMAL),
+            output.display()
+        ));
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for ZsyncPullCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        proxy(&self.proxy)?;
+
+        let total_start = Instant::now();
+
+        let control = match http::is_url(&self.control) {
+            true => ControlFile::read(&mut &http::get(&self.control)?[..])?,
+            false => ControlFile::read(&mut BufReader::new(File::open(&self.control)?))?,
+        };
+
+        let url = match (&self.url, control.urls.first()) {
+            (Some(url), _) => url.clone(),
+            (None, Some(url)) if http::is_url(url) => url.clone(),
+            (None, Some(url)) if http::is_url(&self.control) => http::resolve(&self.control, url),
+            _ => return Err("The control file has no absolute URL of the file, pass --url".into()),
+        };
+
+        // The name comes from the server, only its last component is used
+        let destination = match &self.destination {
+            Some(destination) => destination.clone(),
+            None => Path::new(&control.filename)
+                .file_name()
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("Invalid Filename {:?}", control.filename))?,
+        };
+
+        let mut seeds: Vec<PathBuf> = self.seed.clone();
+        if destination.is_file() && !seeds.iter().any(|s| safety::is_same_file(s, &destination)) {
+            seeds.push(destination.clone());
+        }
+
+        let directory = match destination.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut new_file = tempfile::NamedTempFile::new_in(directory)?;
+        new_file.as_file().set_len(control.length())?;
+
+        let mut matches: Vec<BlockMatch> = Vec::new();
+        let mut found: HashSet<u64> = HashSet::new();
+
+        for seed in &seeds {
+            // Empty files can not be mapped
+            if fs::metadata(seed)?.len() == 0 {
+                continue;
+            }
+
+            // The seeds must not be modified while they are read anyway
+            let map = unsafe { Mmap::map(&platform::open_shared(seed)?)? };
+            let file = new_file.as_file_mut();
+
+            for m in control.find_matches(&map) {
+                if !found.insert(m.offset) {
+                    continue;
+                }
+
+                let data = &map[m.source_offset as usize..(m.source_offset + m.length) as usize];
+                file.seek(SeekFrom::Start(m.offset))?;
+                file.write_all(data)?;
+                matches.push(m);
+            }
+        }
+
+        matches.sort_by_key(|m| m.offset);
+        let ranges = control.missing_ranges(&matches);
+        let reused: u64 = matches.iter().map(|m| m.length).sum();
+
+        info!(
+            "Reused {} from {} local file(s), fetching {} in {} range(s) from {}",
+            format_size(reused, DECIMAL),
+            seeds.len(),
+            format_size(control.length() - reused, DECIMAL),
+            ranges.len(),
+            url
+        );
+
+        let mut remote = throttle::Throttled::new(
+            http::HttpReader::new(&url),
+            bwlimit(self.bwlimit)?.unwrap_or(u64::MAX),
+        );
+        let policy = builder::RetryPolicy {
+            attempts: retries(self.retries),
+            ..Default::default()
+        };
+
+        builder::patch_local_file(
+            &mut remote,
+            new_file.as_file_mut(),
+            progress_bar::track(&ranges, "fetch", url.clone(), |(_, length)| *length),
+            &policy,
+        )?;
+
+        control.verify(new_file.path())?;
+
+        if let Some(signature) = &self.signature {
+            let options = chunk_options(
+                SignOptions {
+                    mmap: true,
+                    ..Default::default()
+                },
+                &destination.display().to_string(),
+                Some(control.length()),
+                signature_sizes(Path::new(signature)),
+                None,
+                None,
+                None,
+            )?;
+            let sig = Signature::generate_file(new_file.path(), &options)?;
+
+            let mut w = BufWriter::new(File::create(signature)?);
+            serde_json::to_writer(&mut w, &sig)?;
+            w.flush()?;
+        }
+
+        new_file.persist(&destination).map_err(|e| e.error)?;
+
+        info!("Saved {}", destination.display());
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
+}
+
+impl Runner for RdiffCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            RdiffSubcommand::Signature(signature) => signature.run(),
+            RdiffSubcommand::Delta(delta) => delta.run(),
+            RdiffSubcommand::Patch(patch) => patch.run(),
+        }
+    }
+}
+
+impl Runner for RdiffSignatureCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let total_start = Instant::now();
+        let path = Path::new(&self.basis);
+
+        safety::ensure_distinct(Path::new(&self.signature), &[path])?;
+
+        let spinner =
+            progress_bar::create_spinner(format!("Calculating block checksums for {:?}...", path));
+
+        let sig = RdiffSignature::generate(
+            &mut BufReader::new(platform::open_shared(path)?),
+            self.block_size,
+            self.rollsum,
+            self.hash,
+            self.sum_size,
+        )?;
+
+        let mut w = BufWriter::new(File::create(&self.signature)?);
+        sig.write(&mut w)?;
+        w.flush()?;
+
+        spinner.finish_with_message(format!(
+            "{} blocks of {} saved to: {}",
+            sig.blocks(),
+            format_size(sig.block_size() as u64, DECIMAL),
+            self.signature.display()
+        ));
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
 }
 
-#[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand)]
-enum Command {
-    Sign(SignCommand),
-    Diff(DiffCommand),
-}
+impl Runner for RdiffDeltaCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let total_start = Instant::now();
+        let path = Path::new(&self.new);
 
-#[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "sign")]
-/// Generate file signature
-struct SignCommand {
-    /// file mask (ex: "*.psd")
-    #[argh(positional)]
-    mask: String,
+        safety::ensure_distinct(Path::new(&self.delta), &[path, Path::new(&self.signature)])?;
 
-    /// min chunk size
-    #[argh(option, default = "4096")]
-    min_size: u32,
+        let sig = RdiffSignature::read(&mut BufReader::new(File::open(&self.signature)?))?;
 
-    /// avg chunk size
-    #[argh(option, default = "16384")]
-    avg_size: u32,
+        let spinner =
+            progress_bar::create_spinner(format!("Searching blocks of the basis in {:?}...", path));
 
-    /// max chunk size
-    #[argh(option, default = "65536")]
-    max_size: u32,
+        // Empty files can not be mapped
+        let file = platform::open_shared(path)?;
+        let map = match file.metadata()?.len() {
+            0 => None,
+            _ => Some(unsafe { Mmap::map(&file)? }),
+        };
+
+        let mut w = BufWriter::new(File::create(&self.delta)?);
+        let stats = sig.delta(map.as_deref().unwrap_or_default(), &mut w)?;
+        w.flush()?;
+
+        spinner.finish_with_message(format!(
+            "Copied {}, literal {}, saved to: {}",
+            format_size(stats.copy_length, DECIMAL),
+            format_size(stats.literal_length, DECIMAL),
+            self.delta.display()
+        ));
+
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
 }
 
-#[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "diff")]
-/// Generate diff between two signatures and print the stats
-struct DiffCommand {
-    /// source file path
-    #[argh(positional)]
-    source: String,
+impl Runner for RdiffPatchCommand {
+    fn run(&self) -> Result<(), Box<dyn Error>> {
+        let total_start = Instant::now();
+        let path = Path::new(&self.new);
 
-    /// target file path
-    #[argh(positional)]
-    target: String,
+        safety::ensure_distinct(path, &[Path::new(&self.basis), Path::new(&self.delta)])?;
 
-    /// keep diff file
-    #[argh(option, default = "true")]
-    keep_diff_file: bool,
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut new_file = tempfile::NamedTempFile::new_in(directory)?;
+
+        let mut w = BufWriter::new(new_file.as_file_mut());
+        let length = librsync::patch(
+            &mut BufReader::new(platform::open_shared(Path::new(&self.basis))?),
+            &mut BufReader::new(File::open(&self.delta)?),
+            &mut w,
+        )?;
+        w.flush()?;
+        drop(w);
+
+        new_file.persist(path).map_err(|e| e.error)?;
+
+        info!(
+            "Saved {} of {}",
+            path.display(),
+            format_size(length, DECIMAL)
+        );
+        info!(
+            "{}",
+            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
+        );
+
+        Ok(())
+    }
 }
 
-impl Runner for Command {
+impl Runner for LsCommand {
     fn run(&self) -> Result<(), Box<dyn Error>> {
-        match &self {
-            Self::Sign(sign) => sign.run(),
-            Self::Diff(diff) => diff.run(),
+        proxy(&self.proxy)?;
+
+        let url = BucketUrl::parse(&self.url)?;
+        let naming = NamingStrategy::new(&self.sig_template, naming::DEFAULT_OUTPUT_TEMPLATE)?
+            .with_version(self.version.as_deref());
+        let policy = builder::RetryPolicy {
+            attempts: retries(self.retries),
+            ..Default::default()
+        };
+        let objects = bucket::match_signatures(url.list(&policy)?, &naming)?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&objects)?);
+            return Ok(());
         }
+
+        // Tab-separated for scripts: size, generation, signature, name
+        for listed in &objects {
+            println!(
+                "{}\t{}\t{}\t{}",
+                listed.object.size,
+                listed.object.generation,
+                listed.signature.as_deref().unwrap_or("-"),
+                listed.object.name
+            );
+        }
+
+        Ok(())
     }
 }
 
-impl Runner for SignCommand {
+impl Runner for CompletionsCommand {
     fn run(&self) -> Result<(), Box<dyn Error>> {
-        println!("Calculating signatures for {}:", &self.mask);
-        println!();
+        print!(
+            "{}",
+            completions::generate(self.shell, "cloud-zsync", &CLI::get_args_info())
+        );
 
-        let total_start = Instant::now();
+        Ok(())
+    }
+}
 
-        for source_dir_entry in globwalk::glob(&self.mask)? {
-            let source_dir_entry = source_dir_entry?;
-            let source_path = source_dir_entry.path();
-            let source_path_str = match source_path.to_str() {
-                Some(path) => path,
-                _ => return Err("Source file path is empty".into()),
-            };
+/// Splits `host:path` into host and path, like rsync: a path is remote if
+/// a colon comes before the first slash
+fn remote_path(path: &str) -> Option<(&str, &str)> {
+    let colon = path.find(':')?;
 
-            if source_path.is_dir() {
-                continue;
-            }
+    match path.find('/') {
+        Some(slash) if slash < colon => None,
+        _ if colon == 0 => None,
+        _ => Some((&path[..colon], &path[colon + 1..])),
+    }
+}
 
-            let target_path = String::from(source_path_str) + SIG_EXT;
+/// Returns true if a diff argument names many signatures: a directory or a glob.
+fn is_batch(path: &Path) -> bool {
+    let glob = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .any(|c| b"*?[{".contains(c));
 
-            let source_file = File::open(source_path)?;
-            let mut reader = BufReader::new(source_file);
+    glob || path.is_dir()
+}
 
-            let spinner = progress_bar::create_spinner(format!(
-                "Calculating signature for {:?}...",
-                source_path_str
-            ));
+/// Returns signatures of a directory or matched by a glob by the path of
+/// the signed file relative to the directory or the glob root. Matched files which are not
+/// signatures stand for their signatures, `old/*.psd` pairs with `new/*.psd`.
+fn batch_signatures(
+    batch: &Path,
+    naming: &NamingStrategy,
+) -> Result<BTreeMap<PathBuf, PathBuf>, Box<dyn Error>> {
+    let (root, entries) = match batch.is_dir() {
+        true => (
+            batch.to_path_buf(),
+            globwalk::GlobWalkerBuilder::new(batch, "**/*").build()?,
+        ),
+        false => {
+            let mask = batch
+                .to_str()
+                .ok_or_else(|| format!("{} is not valid UTF-8, globs must be", batch.display()))?;
+            (mask_root(mask), globwalk::glob(mask)?)
+        }
+    };
 
-            let start = Instant::now();
+    let mut signatures: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
 
-            let sig = signature::Signature::generate(
-                &mut reader,
-                self.min_size,
-                self.avg_size,
-                self.max_size,
-            )?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
 
-            let serialized = serde_json::to_string_pretty(&sig)?;
+        // Directories hold the signed files too, only their signatures count
+        let path = entry.path();
+        let signature = match (naming.file_path(path), batch.is_dir()) {
+            (Ok(_), _) => path.to_path_buf(),
+            (Err(_), true) => continue,
+            (Err(_), false) => naming.signature_path(path)?,
+        };
 
-            let mut output_file = File::create(&target_path)?;
-            output_file.write_all(serialized.as_bytes())?;
+        if !signature.is_file() {
+            warn!("Skipping {}: it is not signed", path.display());
+            continue;
+        }
 
-            spinner.finish_with_message(format!(
-                "Took {:.2?}, source file size: {}, saved to: {:}",
-                start.elapsed(),
-                format_size(sig.length(), DECIMAL),
-                target_path
-            ));
+        let file = naming.file_path(&signature)?;
+        if let Some(relative) = exclude::relative_to(&root, &file) {
+            signatures.insert(relative.to_path_buf(), signature);
         }
+    }
 
-        println!();
-        println!(
-            "{}",
-            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
-        );
+    Ok(signatures)
+}
 
-        Ok(())
+/// Returns the directory a mask starts from: the longest leading path
+/// without glob patterns
+fn mask_root(mask: &str) -> PathBuf {
+    let root: PathBuf = Path::new(mask)
+        .components()
+        .take_while(|c| {
+            platform::is_root_component(c)
+                || !c
+                    .as_os_str()
+                    .to_string_lossy()
+                    .contains(['*', '?', '[', '{'])
+        })
+        .collect();
+
+    if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
     }
 }
 
-impl Runner for DiffCommand {
-    fn run(&self) -> Result<(), Box<dyn Error>> {
-        println!("Calculating diff for {} .. {}:", self.source, self.target);
-        println!();
+/// Adds paths of created or modified files to `changed`
+fn collect_changes(event: notify::Result<notify::Event>, changed: &mut HashSet<PathBuf>) {
+    match event {
+        Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+            for path in event.paths {
+                if let Ok(path) = fs::canonicalize(path) {
+                    changed.insert(path);
+                }
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Watch error: {}", e),
+    }
+}
 
-        let total_start = Instant::now();
+/// Prints a line of the scrub report
+fn print_file_health(health: &FileHealth) {
+    match health {
+        FileHealth::Intact { path, length } => println!(
+            "{} {} ({})",
+            style("ok").green(),
+            path,
+            format_size(*length, DECIMAL)
+        ),
+        FileHealth::Corrupted {
+            path,
+            chunks,
+            damaged_length,
+            resized,
+            repaired,
+        } => println!(
+            "{} {}: {} chunks, {}{}",
+            match repaired {
+                true => style("repaired").yellow(),
+                false => style("corrupted").red(),
+            },
+            path,
+            chunks,
+            format_size(*damaged_length, DECIMAL),
+            match resized {
+                true => ", wrong size",
+                false => "",
+            }
+        ),
+        FileHealth::Missing { path } => println!("{} {}", style("missing").red(), path),
+        FileHealth::Skipped { path, reason } => {
+            println!("{} {}: {}", style("skipped").yellow(), path, reason)
+        }
+    }
+}
 
-        let source_sig_file = File::open(&self.source)?;
-        let target_sig_file = File::open(&self.target)?;
+/// Refuses to overwrite existing files unless --force is given. On a terminal
+/// the user is asked instead.
+fn confirm_overwrite(paths: &[&Path], force: bool) -> Result<(), Box<dyn Error>> {
+    let existing: Vec<&&Path> = paths.iter().filter(|path| path.exists()).collect();
 
-        let source_sig: Signature = serde_json::from_reader(source_sig_file)?;
-        let target_sig: Signature = serde_json::from_reader(target_sig_file)?;
+    let first = match existing.first() {
+        Some(first) if !force => first,
+        _ => return Ok(()),
+    };
 
-        let diff = match Diff::new(&source_sig, &target_sig) {
-            Some(diff) => diff,
-            None => {
-                println!("{}", style("Files are equal!").green());
-                return Ok(());
+    let (subject, verb) = match existing.len() {
+        1 => (first.display().to_string(), "exists"),
+        count => (
+            format!("{} and {} more file(s)", first.display(), count - 1),
+            "exist",
+        ),
+    };
+
+    if io::stdin().is_terminal() && io::stderr().is_terminal() {
+        eprint!("Overwrite {}? [y/N] ", subject);
+        io::stderr().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+
+        if matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Ok(());
+        }
+    }
+
+    Err(format!("{} {}, pass --force to overwrite", subject, verb).into())
+}
+
+/// Prints whether a dry run would create or overwrite the file
+fn print_dry_write(path: &Path) {
+    match path.exists() {
+        true => println!("Would overwrite {}", path.display()),
+        false => println!("Would create {}", path.display()),
+    }
+}
+
+/// Prints the result of each pair of a batch diff and the totals
+fn print_batch_stats(batch: &BatchStats) {
+    println!();
+
+    for entry in &batch.files {
+        let status = match (&entry.stats, &entry.error) {
+            (Some(stats), _) if stats.equal => style("equal".to_string()).green(),
+            (Some(stats), _) => style(format!(
+                "fetch {} of {}",
+                format_size(stats.fetch_length, DECIMAL),
+                format_size(stats.target_length, DECIMAL)
+            ))
+            .yellow(),
+            (None, error) => {
+                style(format!("failed: {}", error.as_deref().unwrap_or_default())).red()
             }
         };
 
+        println!("{}: {}", entry.path, status);
+    }
+
+    for path in &batch.only_in_source {
+        println!("{}: {}", path, style("only in source").dim());
+    }
+
+    for path in &batch.only_in_target {
         println!(
-            "Source file size: {} ({} bytes)",
-            format_size(source_sig.length(), DECIMAL),
-            source_sig.length()
+            "{}: {}",
+            path,
+            style("only in target, nothing to diff against").dim()
         );
+    }
+
+    println!();
+    println!(
+        "{} pair(s): {} changed, {} equal, {} failed",
+        batch.files.len(),
+        batch.changed,
+        batch.equal,
+        batch.failed
+    );
+    println!(
+        "To fetch: {} of {} ({:.1}%)",
+        format_size(batch.fetch_length, DECIMAL),
+        format_size(batch.target_length, DECIMAL),
+        batch.fetch_percent()
+    );
+}
+
+/// Prints the diff stats and the ranges to request from the target file
+fn print_diff_stats(stats: &DiffStats, seeds: &[PathBuf], diff: &Diff) {
+    println!(
+        "Source file size: {} ({} bytes)",
+        format_size(stats.source_length, DECIMAL),
+        stats.source_length
+    );
+
+    println!(
+        "Target file size: {} ({} bytes)",
+        format_size(stats.target_length, DECIMAL),
+        stats.target_length
+    );
+
+    let len_diff = stats.length_difference();
+
+    println!(
+        "Difference: {} ({} bytes)",
+        format_size(len_diff, DECIMAL),
+        len_diff
+    );
 
+    println!();
+    println!(
+        "{} COPY ops from the old file: {} ({} bytes)",
+        stats.copy_ops,
+        format_size(stats.copy_length, DECIMAL),
+        stats.copy_length
+    );
+
+    if let Some(shifted) = stats.rolling_length {
         println!(
-            "Target file size: {} ({} bytes)",
-            format_size(target_sig.length(), DECIMAL),
-            target_sig.length()
+            "    of them found by rolling hash: {} ({} bytes)",
+            format_size(shifted, DECIMAL),
+            shifted
         );
+    }
 
-        let len_diff = (target_sig.length() as i64 - source_sig.length() as i64).abs() as usize;
-
+    for (seed, &seed_length) in seeds.iter().zip(&stats.seed_lengths) {
         println!(
-            "Difference: {} ({} bytes)",
-            format_size(len_diff, DECIMAL),
-            len_diff
+            "    of them from seed {}: {} ({} bytes)",
+            seed.display(),
+            format_size(seed_length, DECIMAL),
+            seed_length
         );
+    }
 
-        println!();
+    println!(
+        "{} INSERT to the new file: {} ({} bytes)",
+        stats.insert_ops,
+        format_size(stats.insert_length, DECIMAL),
+        stats.insert_length
+    );
+
+    if stats.reused_ops > 0 {
         println!(
-            "{} COPY ops from the old file: {} ({} bytes)",
-            diff.copy_ops().len(),
-            format_size(diff.copy_length(), DECIMAL),
-            diff.copy_length()
+            "    of them repeated: {}, to fetch: {} ({} bytes)",
+            stats.reused_ops,
+            format_size(stats.fetch_length, DECIMAL),
+            stats.fetch_length
         );
+    }
 
+    if let Some(merged) = stats.merged_length {
         println!(
-            "{} INSERT to the new file: {} ({} bytes)",
+            "    requests merged to {}, fetching extra {} ({} bytes)",
             diff.insert_ops().len(),
-            format_size(diff.insert_length(), DECIMAL),
-            diff.insert_length()
+            format_size(merged, DECIMAL),
+            merged
+        );
+    }
+
+    if stats.zero_ops > 0 {
+        println!(
+            "{} ZERO left as holes in the new file: {} ({} bytes)",
+            stats.zero_ops,
+            format_size(stats.zero_length, DECIMAL),
+            stats.zero_length
         );
+    }
 
+    if let Some(cost) = stats.cost {
         println!();
-        println!("Ranges to request & insert:");
-        println!();
+        println!(
+            "Estimated cost: ${:.4} for the changed ranges, ${:.4} for a full download",
+            cost.delta, cost.full
+        );
+    }
 
-        for (index, op) in diff.insert_ops().iter().enumerate() {
-            println!(
-                "{:<4} [ {:<12}: {:<12} ]",
-                format!("{})", index + 1),
-                op.offset(),
-                op.length()
-            )
-        }
+    println!();
+    println!("Ranges to request & insert:");
+    println!();
 
-        println!();
+    for (index, op) in diff.insert_ops().iter().enumerate() {
+        println!(
+            "{:<4} [ {:<12}: {:<12} ]",
+            format!("{})", index + 1),
+            op.offset(),
+            op.length()
+        )
+    }
 
-        let (source_file_name, _) = self.source.split_at(self.source.len() - SIG_EXT.len());
-        let (target_file_name, _) = self.target.split_at(self.target.len() - SIG_EXT.len());
-        let destination_file_name = String::from(target_file_name) + ".NEW";
+    println!();
+}
 
-        let mut source_file = File::open(source_file_name)?;
-        let mut target_file = File::open(target_file_name)?;
-        let mut diff_file = tempfile::NamedTempFile::new()?;
-        let mut dst_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&destination_file_name)?;
+/// Returns path to read file contents from: the file itself, or its
+/// decompressed copy if the signature describes decompressed contents
+fn readable_path(
+    path: &Path,
+    sig: &Signature,
+    copies: &mut Vec<tempfile::NamedTempFile>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let compression = match sig.decompressed() {
+        Some(compression) => compression,
+        None => return Ok(path.to_path_buf()),
+    };
 
-        println!(
-            "Building {} temporary file...",
-            diff_file.path().to_str().unwrap()
-        );
+    let mut copy = tempfile::NamedTempFile::new()?;
+    compression::decompress(path, compression, copy.as_file_mut())?;
 
-        let diff_pbar = progress_bar::create_bar(diff.insert_ops().len() as u64);
+    let read_path = copy.path().to_path_buf();
+    copies.push(copy);
 
-        // target_file can be a wrapper over Read which does HTTP queries to GCS.
-        // Or, this wrapper may collect the read+seek calls and do actual queries later.
-        // Or, this method may be used in a middleware service to generate a diff file.
-        let diff_schema = builder::build_local_diff_file(
-            &mut target_file,
-            &mut diff_file,
-            diff.insert_ops().iter().progress_with(diff_pbar),
-        )?;
+    Ok(read_path)
+}
 
-        println!(
-            "Built {} segments in the temporary diff file.",
-            diff_schema.len()
-        );
+/// Returns options with the chunk sizes of the previous signature of a
+/// file, picked from the file size if there is none. Explicitly given or
+/// configured sizes take precedence
+fn file_options(
+    options: SignOptions,
+    path: &Path,
+    previous: Option<ChunkSizes>,
+    min_size: Option<u32>,
+    avg_size: Option<u32>,
+    max_size: Option<u32>,
+) -> Result<SignOptions, Box<dyn Error>> {
+    let length = match (avg_size.or(config().avg_size), previous) {
+        (None, None) => Some(fs::metadata(path)?.len()),
+        _ => None,
+    };
 
-        let build_pbar = progress_bar::create_bar(diff.insert_ops().len() as u64);
+    chunk_options(
+        options,
+        &path.display().to_string(),
+        length,
+        previous,
+        min_size,
+        avg_size,
+        max_size,
+    )
+}
 
-        // Builds local file
-        builder::build_local_file(
-            &mut source_file,
-            &mut dst_file,
-            diff.operations().iter().progress_with(build_pbar),
-            diff_file.as_file_mut(),
-            &diff_schema,
-        )?;
+/// Returns options with the previous chunk sizes or chunk sizes picked from
+/// the input length, the default ones if the length is unknown like for stdin
+fn chunk_options(
+    options: SignOptions,
+    name: &str,
+    length: Option<u64>,
+    previous: Option<ChunkSizes>,
+    min_size: Option<u32>,
+    avg_size: Option<u32>,
+    max_size: Option<u32>,
+) -> Result<SignOptions, Box<dyn Error>> {
+    let min_size = min_size.or(config().min_size);
+    let max_size = max_size.or(config().max_size);
 
-        if self.keep_diff_file {
-            diff_file.keep()?;
-        }
+    let options = match (avg_size.or(config().avg_size), previous, length) {
+        (Some(avg_size), _, _) => options.with_avg_size(avg_size),
+        (None, Some(previous), _) => options.with_chunk_sizes(previous),
+        (None, None, Some(length)) => options.with_avg_size(SignOptions::auto_avg_size(length)),
+        (None, None, None) => options.with_avg_size(options.avg_size),
+    };
+    let options = SignOptions {
+        min_size: min_size.unwrap_or(options.min_size),
+        max_size: max_size.unwrap_or(options.max_size),
+        ..options
+    };
 
-        println!();
-        println!("Written the new file: {}", &destination_file_name);
+    options
+        .validate()
+        .map_err(|e| format!("Invalid options for {}: {}", name, e))?;
 
-        println!();
-        println!(
-            "{}",
-            style(format!("Done in {:.2?}!", total_start.elapsed())).green()
-        );
+    Ok(options)
+}
 
-        Ok(())
+/// Returns chunk sizes of the signature at a path, none if there is no
+/// readable signature
+fn signature_sizes(path: &Path) -> Option<ChunkSizes> {
+    let file = File::open(path).ok()?;
+    let sig: Signature = serde_json::from_reader(BufReader::new(file)).ok()?;
+    sig.chunk_sizes()
+}
+
+/// Returns the option defaults, empty until loaded in `main`
+fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Returns the number of attempts for each range read
+fn retries(retries: Option<u32>) -> u32 {
+    retries.or(config().retries).unwrap_or(5).max(1)
+}
+
+/// Returns the read limit in bytes per second, none if reads are not limited
+fn bwlimit(bwlimit: Option<u64>) -> Result<Option<u64>, Box<dyn Error>> {
+    match bwlimit.or(config().bwlimit) {
+        Some(0) => Err("--bwlimit must be greater than zero".into()),
+        rate => Ok(rate),
+    }
+}
+
+/// Sends HTTP requests through the proxy of the option or the config
+fn proxy(proxy: &Option<String>) -> Result<(), Box<dyn Error>> {
+    match proxy.as_ref().or(config().proxy.as_ref()) {
+        Some(proxy) => http::set_proxy(proxy),
+        None => Ok(()),
+    }
+}
+
+/// Reads a path option, see `platform::arg_from_os`
+fn path_arg(value: &str) -> Result<PathBuf, String> {
+    platform::path_from_arg(value).map_err(|e| e.to_string())
+}
+
+/// Reads an option which is not a path: globs, URLs, templates and names must be UTF-8
+fn utf8_arg(value: &str) -> Result<String, String> {
+    platform::text_from_arg(value).ok_or_else(|| {
+        format!(
+            "{} is not valid UTF-8",
+            path_arg(value).unwrap_or_default().display()
+        )
+    })
+}
+
+/// Returns current unix time in seconds
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn main() -> ExitCode {
+    let cli = match parse_args(std::env::args_os()) {
+        Ok(cli) => cli,
+        Err(code) => return code,
+    };
+
+    progress_bar::set_format(cli.progress);
+
+    match Config::load(cli.config.as_deref()) {
+        Ok(config) => {
+            let _ = CONFIG.set(config);
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return ExitCode::from(EXIT_ERROR);
+        }
+    }
+    if let Err(e) = init_logging(cli.quiet, cli.verbose) {
+        eprintln!("Error: {:?}", e);
+        return ExitCode::from(EXIT_ERROR);
     }
+
+    let code = match cli.command.run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    };
+
+    #[cfg(feature = "otlp")]
+    telemetry::shutdown();
+
+    code
+}
+
+/// Sets up logging to stderr, reports of commands are printed to stdout.
+/// Built with the otlp feature, spans are also exported to a collector
+/// if OTEL_EXPORTER_OTLP_ENDPOINT is set
+fn init_logging(quiet: bool, verbose: u8) -> Result<(), Box<dyn Error>> {
+    let level = match (quiet, verbose) {
+        (true, _) => Level::WARN,
+        (false, 0) => Level::INFO,
+        (false, 1) => Level::DEBUG,
+        (false, _) => Level::TRACE,
+    };
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(console::colors_enabled_stderr())
+        .with_target(false)
+        .without_time()
+        .with_filter(LevelFilter::from_level(level));
+
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(telemetry::layer()?);
+
+    registry.try_init()?;
+
+    Ok(())
+}
+
+/// Parses arguments like `argh::from_env`, but invalid arguments exit with `EXIT_ERROR`
+/// Parses the arguments, paths which are not valid UTF-8 reach the path
+/// options through `platform::arg_from_os`.
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<CLI, ExitCode> {
+    let strings: Vec<String> = args
+        .into_iter()
+        .map(|arg| platform::arg_from_os(&arg))
+        .collect();
+
+    let cmd = strings
+        .first()
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("cloud-zsync");
+    // argh takes neither --option=value nor repeated short switches as -vv
+    let strs: Vec<&str> = strings
+        .iter()
+        .skip(1)
+        .flat_map(|arg| {
+            if let Some((name, value)) = arg.split_once('=').filter(|_| arg.starts_with("--")) {
+                return vec![name, value];
+            }
+
+            match arg.len() > 2 && arg.starts_with('-') && arg[1..].bytes().all(|c| c == b'v') {
+                true => vec!["-v"; arg.len() - 1],
+                false => vec![arg.as_str()],
+            }
+        })
+        .collect();
+
+    CLI::from_args(&[cmd], &strs).map_err(|early_exit| match early_exit.status {
+        Ok(()) => {
+            println!("{}", early_exit.output);
+            ExitCode::SUCCESS
+        }
+        Err(()) => {
+            eprintln!(
+                "{}\nRun {} --help for more information.",
+                early_exit.output, cmd
+            );
+            ExitCode::from(EXIT_ERROR)
+        }
+    })
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli: CLI = argh::from_env();
-    cli.command.run()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn diff_and_apply_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &[u8]| dir.path().join(OsStr::from_bytes(name));
+        let run = |args: &[&Path]| {
+            let argv = ["cloud-zsync".as_ref()]
+                .into_iter()
+                .chain(args.iter().copied());
+            let cli = parse_args(argv.map(|arg| arg.as_os_str().to_owned())).unwrap();
+            cli.command.run().unwrap();
+        };
+
+        let mut old = vec![0u8; 1 << 20];
+        fastrand::Rng::with_seed(1).fill(&mut old);
+        let mut new = old.clone();
+        fastrand::Rng::with_seed(2).fill(&mut new[300_000..400_000]);
+
+        fs::create_dir(path(b"old")).unwrap();
+        fs::create_dir(path(b"new")).unwrap();
+        fs::write(path(b"old/caf\xe9.bin"), &old).unwrap();
+        fs::write(path(b"new/caf\xe9.bin"), &new).unwrap();
+
+        run(&["sign".as_ref(), &path(b"*/caf*.bin")]);
+        run(&[
+            "diff".as_ref(),
+            &path(b"old/caf\xe9.bin.rsig"),
+            &path(b"new/caf\xe9.bin.rsig"),
+            "--patch".as_ref(),
+            &path(b"caf\xe9.patch"),
+            "--output".as_ref(),
+            &path(b"caf\xe9.diff.bin"),
+        ]);
+        run(&[
+            "apply".as_ref(),
+            &path(b"old/caf\xe9.bin"),
+            &path(b"caf\xe9.patch"),
+            &path(b"caf\xe9.apply.bin"),
+        ]);
+
+        assert_eq!(fs::read(path(b"caf\xe9.diff.bin")).unwrap(), new);
+        assert_eq!(fs::read(path(b"caf\xe9.apply.bin")).unwrap(), new);
+    }
 }
@@ -0,0 +1,487 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Component, Path};
+
+use crate::platform;
+use crate::signature::{Diff, Op, Operation, Signature};
+
+/// Share of a new file which must be found in a removed file
+/// to consider the new file renamed from it.
+const RENAME_MIN_OVERLAP: f64 = 0.5;
+
+/// Signature of a single file in a tree.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// path relative to the tree root, components are separated with `/`
+    pub path: String,
+
+    /// file length
+    pub length: u64,
+
+    pub signature: Signature,
+}
+
+/// Symbolic link of a tree, recorded instead of the file it points to
+/// with `sign --symlinks preserve`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkEntry {
+    /// path relative to the tree root, components are separated with `/`
+    pub path: String,
+
+    /// link target relative to the directory of the link, components are separated with `/`
+    pub target: String,
+}
+
+/// Describes a whole directory tree: relative paths, sizes and
+/// signatures of its files. Entries are ordered by path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TreeManifest {
+    entries: Vec<ManifestEntry>,
+
+    /// preserved symbolic links, ordered by path
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    links: Vec<LinkEntry>,
+}
+
+/// Change of a single file between two trees.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum FileChange {
+    /// file exists only in the target tree
+    Added { path: String, length: u64 },
+
+    /// file exists only in the source tree
+    Removed { path: String, length: u64 },
+
+    /// file was renamed or moved from `from`, possibly with changes,
+    /// `copy_length` bytes can be reused from the file it was renamed from,
+    /// `operations` build the new file from it
+    Renamed {
+        from: String,
+        path: String,
+        source_length: u64,
+        target_length: u64,
+        copy_length: u64,
+        insert_length: u64,
+        operations: Vec<TreeOp>,
+    },
+
+    /// symbolic link was added or points elsewhere now
+    Link { path: String, target: String },
+
+    /// file differs, `copy_length` bytes can be reused from the source file,
+    /// `insert_length` bytes have to be transferred
+    Modified {
+        path: String,
+        source_length: u64,
+        target_length: u64,
+        copy_length: u64,
+        insert_length: u64,
+    },
+}
+
+/// Step of building a renamed file, in the order of the new file.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum TreeOp {
+    /// `length` bytes at `offset` are copied from `source_offset` of the file `from`
+    Copy {
+        from: String,
+        source_offset: u64,
+        offset: u64,
+        length: u64,
+    },
+
+    /// `length` bytes at `offset` have to be transferred
+    Insert { offset: u64, length: u64 },
+
+    /// `length` bytes at `offset` are zeros
+    Zero { offset: u64, length: u64 },
+}
+
+impl FileChange {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. }
+            | Self::Removed { path, .. }
+            | Self::Renamed { path, .. }
+            | Self::Modified { path, .. }
+            | Self::Link { path, .. } => path,
+        }
+    }
+
+    /// Returns number of bytes which have to be transferred to apply the change.
+    pub fn transfer_length(&self) -> u64 {
+        match self {
+            Self::Added { length, .. } => *length,
+            Self::Removed { .. } | Self::Link { .. } => 0,
+            Self::Renamed { insert_length, .. } | Self::Modified { insert_length, .. } => {
+                *insert_length
+            }
+        }
+    }
+}
+
+impl TreeManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file signature, replacing an existing entry with the same path.
+    ///
+    /// # Parameters:
+    /// - `root`: tree root
+    /// - `file`: file within the tree
+    /// - `signature`: signature of the file
+    pub fn add(
+        &mut self,
+        root: &Path,
+        file: &Path,
+        signature: Signature,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = relative_path(root, file)?;
+        let entry = ManifestEntry {
+            length: signature.length(),
+            path,
+            signature,
+        };
+
+        match self
+            .entries
+            .binary_search_by(|e| e.path.as_str().cmp(&entry.path))
+        {
+            Ok(index) => self.entries[index] = entry,
+            Err(index) => self.entries.insert(index, entry),
+        }
+
+        Ok(())
+    }
+
+    /// Adds a symbolic link, replacing an existing link with the same path.
+    ///
+    /// # Parameters:
+    /// - `root`: tree root
+    /// - `link`: link within the tree
+    /// - `target`: target stored in the link, must stay within the tree, see `safety::link_stays_inside`
+    pub fn add_link(
+        &mut self,
+        root: &Path,
+        link: &Path,
+        target: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let entry = LinkEntry {
+            path: relative_path(root, link)?,
+            target: link_target(target)?,
+        };
+
+        match self
+            .links
+            .binary_search_by(|e| e.path.as_str().cmp(&entry.path))
+        {
+            Ok(index) => self.links[index] = entry,
+            Err(index) => self.links.insert(index, entry),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entry of a file within the tree.
+    ///
+    /// # Parameters:
+    /// - `root`: tree root
+    /// - `file`: file within the tree
+    pub fn find(&self, root: &Path, file: &Path) -> Option<&ManifestEntry> {
+        self.get(&relative_path(root, file).ok()?)
+    }
+
+    /// Returns an entry by its relative path.
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries
+            .binary_search_by(|e| e.path.as_str().cmp(path))
+            .ok()
+            .map(|index| &self.entries[index])
+    }
+
+    /// Returns entries ordered by path.
+    pub fn entries(&self) -> &Vec<ManifestEntry> {
+        &self.entries
+    }
+
+    /// Returns preserved symbolic links ordered by path.
+    pub fn links(&self) -> &Vec<LinkEntry> {
+        &self.links
+    }
+
+    /// Returns total length of all files.
+    pub fn length(&self) -> u64 {
+        self.entries.iter().map(|e| e.length).sum()
+    }
+}
+
+/// Compares two trees. Unchanged files are not reported. A file which
+/// appears in the target tree is reported as renamed if a removed file
+/// has the same contents or shares at least `RENAME_MIN_OVERLAP` of its data.
+///
+/// # Parameters:
+/// - `source`: manifest of the tree which is going to be updated
+/// - `target`: manifest of the desired tree
+///
+/// # Returns:
+/// - `Vec<FileChange>`: changes ordered by path
+pub fn diff(source: &TreeManifest, target: &TreeManifest) -> Vec<FileChange> {
+    let mut changes: Vec<FileChange> = Vec::new();
+    let mut removed: Vec<&ManifestEntry> = Vec::new();
+    let mut added: Vec<&ManifestEntry> = Vec::new();
+
+    let mut sources = source.entries.iter().peekable();
+    let mut targets = target.entries.iter().peekable();
+
+    loop {
+        let order = match (sources.peek(), targets.peek()) {
+            (Some(s), Some(t)) => s.path.cmp(&t.path),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break,
+        };
+
+        match order {
+            Ordering::Less => removed.push(sources.next().expect("peeked")),
+            Ordering::Greater => added.push(targets.next().expect("peeked")),
+            Ordering::Equal => {
+                let s = sources.next().expect("peeked");
+                let t = targets.next().expect("peeked");
+
+                if let Some(diff) = Diff::new(&s.signature, &t.signature) {
+                    changes.push(FileChange::Modified {
+                        path: t.path.clone(),
+                        source_length: s.length,
+                        target_length: t.length,
+                        copy_length: diff.copy_length(),
+                        insert_length: diff.fetch_length(),
+                    });
+                }
+            }
+        }
+    }
+
+    let renames = find_renames(&removed, &added);
+
+    for (index, t) in added.iter().enumerate() {
+        let change = match renames.get(&index) {
+            Some(&from) => {
+                let s = removed[from];
+                let (copy_length, insert_length, operations) =
+                    match Diff::new(&s.signature, &t.signature) {
+                        Some(diff) => (
+                            diff.copy_length(),
+                            diff.fetch_length(),
+                            rename_ops(&s.path, &diff),
+                        ),
+                        None => (t.length, 0, whole_copy(&s.path, t.length)),
+                    };
+
+                FileChange::Renamed {
+                    from: s.path.clone(),
+                    path: t.path.clone(),
+                    source_length: s.length,
+                    target_length: t.length,
+                    copy_length,
+                    insert_length,
+                    operations,
+                }
+            }
+            None => FileChange::Added {
+                path: t.path.clone(),
+                length: t.length,
+            },
+        };
+
+        changes.push(change);
+    }
+
+    let renamed: HashSet<usize> = renames.values().copied().collect();
+
+    for (index, s) in removed.iter().enumerate() {
+        if !renamed.contains(&index) {
+            changes.push(FileChange::Removed {
+                path: s.path.clone(),
+                length: s.length,
+            });
+        }
+    }
+
+    let source_links: HashMap<&str, &str> = source
+        .links
+        .iter()
+        .map(|link| (link.path.as_str(), link.target.as_str()))
+        .collect();
+    let target_links: HashSet<&str> = target.links.iter().map(|link| link.path.as_str()).collect();
+
+    for link in &target.links {
+        if source_links.get(link.path.as_str()) != Some(&link.target.as_str()) {
+            changes.push(FileChange::Link {
+                path: link.path.clone(),
+                target: link.target.clone(),
+            });
+        }
+    }
+
+    for link in &source.links {
+        if !target_links.contains(link.path.as_str()) {
+            changes.push(FileChange::Removed {
+                path: link.path.clone(),
+                length: 0,
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+/// Converts ops of a diff against the file a new file was renamed from,
+/// COPY ops read the old file.
+fn rename_ops(from: &str, diff: &Diff) -> Vec<TreeOp> {
+    diff.operations()
+        .iter()
+        .map(|op| match op {
+            Operation::COPY(copy) => TreeOp::Copy {
+                from: from.to_string(),
+                source_offset: copy.source_offset(),
+                offset: copy.offset(),
+                length: copy.length(),
+            },
+            Operation::ZERO(zero) => TreeOp::Zero {
+                offset: zero.offset(),
+                length: zero.length(),
+            },
+            // Trees are diffed without deltas, a delta is fetched like an insert
+            Operation::INSERT(_) | Operation::DELTA(_) => TreeOp::Insert {
+                offset: op.offset(),
+                length: op.length(),
+            },
+        })
+        .collect()
+}
+
+/// Returns the op of a file renamed without changes.
+fn whole_copy(from: &str, length: u64) -> Vec<TreeOp> {
+    match length {
+        0 => Vec::new(),
+        _ => vec![TreeOp::Copy {
+            from: from.to_string(),
+            source_offset: 0,
+            offset: 0,
+            length,
+        }],
+    }
+}
+
+/// Pairs added files with removed files they were renamed from. Files with
+/// equal contents are paired first, then the rest by the largest chunk overlap.
+/// Each removed file is paired at most once.
+///
+/// # Returns:
+/// - `HashMap<usize, usize>`: index of a removed file by index of an added file
+fn find_renames(removed: &[&ManifestEntry], added: &[&ManifestEntry]) -> HashMap<usize, usize> {
+    let mut renames: HashMap<usize, usize> = HashMap::new();
+    let mut taken: HashSet<usize> = HashSet::new();
+
+    let mut by_hash: HashMap<blake3::Hash, Vec<usize>> = HashMap::new();
+    for (index, s) in removed.iter().enumerate().rev() {
+        by_hash
+            .entry(s.signature.strong_hash())
+            .or_default()
+            .push(index);
+    }
+
+    for (index, t) in added.iter().enumerate() {
+        if let Some(from) = by_hash
+            .get_mut(&t.signature.strong_hash())
+            .and_then(|candidates| candidates.pop())
+        {
+            renames.insert(index, from);
+            taken.insert(from);
+        }
+    }
+
+    let mut by_chunk: HashMap<blake3::Hash, HashSet<usize>> = HashMap::new();
+    for (index, s) in removed.iter().enumerate() {
+        for chunk in s.signature.chunks() {
+            by_chunk
+                .entry(chunk.strong_hash())
+                .or_default()
+                .insert(index);
+        }
+    }
+
+    for (index, t) in added.iter().enumerate() {
+        if renames.contains_key(&index) || t.length == 0 {
+            continue;
+        }
+
+        let mut overlap: HashMap<usize, u64> = HashMap::new();
+        for chunk in t.signature.chunks() {
+            for &from in by_chunk.get(&chunk.strong_hash()).into_iter().flatten() {
+                if !taken.contains(&from) {
+                    *overlap.entry(from).or_default() += chunk.length();
+                }
+            }
+        }
+
+        let best = overlap
+            .into_iter()
+            .max_by_key(|&(from, length)| (length, std::cmp::Reverse(from)));
+
+        if let Some((from, length)) = best {
+            if length as f64 >= t.length as f64 * RENAME_MIN_OVERLAP {
+                renames.insert(index, from);
+                taken.insert(from);
+            }
+        }
+    }
+
+    renames
+}
+
+/// Returns path of `file` relative to `root` with `/` separators.
+fn relative_path(root: &Path, file: &Path) -> Result<String, Box<dyn Error>> {
+    let relative = match file.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return Err(format!("{:?} is outside of {:?}", file, root).into()),
+    };
+
+    let mut parts: Vec<String> = Vec::new();
+
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(platform::escape_path(Path::new(part))),
+            Component::CurDir => {}
+            _ => return Err(format!("{:?} can not be stored in a manifest", file).into()),
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(format!("{:?} is the tree root", file).into());
+    }
+
+    Ok(parts.join("/"))
+}
+
+/// Converts a link target to the form stored in a manifest.
+fn link_target(target: &Path) -> Result<String, Box<dyn Error>> {
+    let mut parts: Vec<String> = Vec::new();
+
+    for component in target.components() {
+        match component {
+            Component::Normal(part) => parts.push(platform::escape_path(Path::new(part))),
+            Component::ParentDir => parts.push("..".to_string()),
+            Component::CurDir => {}
+            _ => return Err(format!("Absolute link target {:?} can not be stored", target).into()),
+        }
+    }
+
+    Ok(parts.join("/"))
+}
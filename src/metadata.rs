@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Metadata of a signed file which can be restored on the reconstructed
+/// file, recorded with `sign --metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// modification time, nanoseconds since the Unix epoch
+    pub mtime: u64,
+
+    /// Unix permission bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+
+    /// Unix owner and group ids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+}
+
+impl FileMetadata {
+    /// Reads metadata of a local file, permissions and ownership only on Unix.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            Ok(Self {
+                mtime,
+                mode: Some(metadata.mode() & 0o7777),
+                uid: Some(metadata.uid()),
+                gid: Some(metadata.gid()),
+            })
+        }
+
+        #[cfg(not(unix))]
+        Ok(Self {
+            mtime,
+            mode: None,
+            uid: None,
+            gid: None,
+        })
+    }
+
+    /// Applies the recorded modification time and permissions to a local file.
+    /// Parts which were not recorded, like permissions of a file signed on Windows,
+    /// are left as they are.
+    ///
+    /// # Parameters:
+    /// - `path`: reconstructed file
+    /// - `ownership`: also restore the owner and group, which usually needs root
+    pub fn restore(&self, path: &Path, ownership: bool) -> io::Result<()> {
+        // Ownership first: changing the owner clears setuid and setgid bits
+        #[cfg(unix)]
+        if ownership && (self.uid.is_some() || self.gid.is_some()) {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+        #[cfg(not(unix))]
+        let _ = ownership;
+
+        // Times are set before the permissions, which may make the file read-only
+        let modified = UNIX_EPOCH + Duration::from_nanos(self.mtime);
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(())
+    }
+}
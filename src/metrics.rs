@@ -0,0 +1,193 @@
+#[cfg(all(not(target_arch = "wasm32"), feature = "serve"))]
+use std::error::Error;
+use std::fmt::Write;
+#[cfg(all(not(target_arch = "wasm32"), feature = "serve"))]
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(all(not(target_arch = "wasm32"), feature = "serve"))]
+use std::thread;
+use std::time::Duration;
+
+/// Upper bounds of range request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Process-wide counters, exposed on /metrics by long-running commands.
+struct Metrics {
+    bytes_fetched: AtomicU64,
+    ranges_fetched: AtomicU64,
+    range_retries: AtomicU64,
+    range_failures: AtomicU64,
+
+    /// requests per latency bucket, not cumulative
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+
+    files_signed: AtomicU64,
+    bytes_signed: AtomicU64,
+
+    patches_served: AtomicU64,
+    patch_bytes_served: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    bytes_fetched: AtomicU64::new(0),
+    ranges_fetched: AtomicU64::new(0),
+    range_retries: AtomicU64::new(0),
+    range_failures: AtomicU64::new(0),
+    latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64::new(0),
+    latency_sum_micros: AtomicU64::new(0),
+    files_signed: AtomicU64::new(0),
+    bytes_signed: AtomicU64::new(0),
+    patches_served: AtomicU64::new(0),
+    patch_bytes_served: AtomicU64::new(0),
+};
+
+/// Records a range read from a target file.
+///
+/// # Parameters:
+/// - `bytes`: bytes read by the request
+/// - `latency`: time the request took
+pub fn record_range(bytes: u64, latency: Duration) {
+    METRICS.bytes_fetched.fetch_add(bytes, Ordering::Relaxed);
+    METRICS.ranges_fetched.fetch_add(1, Ordering::Relaxed);
+
+    let seconds = latency.as_secs_f64();
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+        METRICS.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    METRICS.latency_count.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .latency_sum_micros
+        .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Records a range request which is going to be retried.
+pub fn record_retry() {
+    METRICS.range_retries.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a range which could not be read after all attempts.
+pub fn record_failure() {
+    METRICS.range_failures.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a signed file.
+pub fn record_signed(bytes: u64) {
+    METRICS.files_signed.fetch_add(1, Ordering::Relaxed);
+    METRICS.bytes_signed.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Records a patch sent by the diff service.
+pub fn record_patch(bytes: u64) {
+    METRICS.patches_served.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .patch_bytes_served
+        .fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Renders the metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    let counters = [
+        (
+            "cloud_zsync_bytes_fetched_total",
+            "Bytes read from target files",
+            get(&METRICS.bytes_fetched),
+        ),
+        (
+            "cloud_zsync_ranges_fetched_total",
+            "Ranges read from target files",
+            get(&METRICS.ranges_fetched),
+        ),
+        (
+            "cloud_zsync_range_retries_total",
+            "Range requests retried after a transient error",
+            get(&METRICS.range_retries),
+        ),
+        (
+            "cloud_zsync_range_failures_total",
+            "Ranges which could not be read after all attempts",
+            get(&METRICS.range_failures),
+        ),
+        (
+            "cloud_zsync_files_signed_total",
+            "Files signed",
+            get(&METRICS.files_signed),
+        ),
+        (
+            "cloud_zsync_bytes_signed_total",
+            "Bytes of signed files",
+            get(&METRICS.bytes_signed),
+        ),
+        (
+            "cloud_zsync_patches_served_total",
+            "Patches sent by the diff service",
+            get(&METRICS.patches_served),
+        ),
+        (
+            "cloud_zsync_patch_bytes_served_total",
+            "Bytes of patches sent by the diff service",
+            get(&METRICS.patch_bytes_served),
+        ),
+    ];
+
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    let name = "cloud_zsync_range_request_seconds";
+    let _ = writeln!(out, "# HELP {} Latency of range requests", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+
+    let mut cumulative = 0;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&METRICS.latency_buckets) {
+        cumulative += get(bucket);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+
+    let count = get(&METRICS.latency_count);
+    let sum = get(&METRICS.latency_sum_micros) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+
+    out
+}
+
+/// Response to a `/metrics` request.
+#[cfg(all(not(target_arch = "wasm32"), feature = "serve"))]
+pub fn response() -> tiny_http::Response<Cursor<Vec<u8>>> {
+    tiny_http::Response::from_string(render()).with_header(
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+            .expect("valid header"),
+    )
+}
+
+/// Serves the metrics on `http://{addr}/metrics` from a background thread.
+#[cfg(all(not(target_arch = "wasm32"), feature = "serve"))]
+pub fn serve(addr: &str) -> Result<(), Box<dyn Error>> {
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| format!("Can not serve metrics on {}: {}", addr, e))?;
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match request.url() {
+                "/metrics" => response(),
+                _ => tiny_http::Response::from_string("Not found").with_status_code(404),
+            };
+
+            let _ = request.respond(response);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_SIGNATURE_TEMPLATE: &str = "{name}.rsig";
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "{name}.NEW";
+
+/// Part of a parsed template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    /// full file name: `image.psd`
+    Name,
+    /// file name without the last extension: `image`
+    Stem,
+    /// last extension without the dot: `psd`
+    Ext,
+    /// version given on the command line: `v2`
+    Version,
+}
+
+/// File name template, ex: `{stem}.{version}.rsig`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    tokens: Vec<Token>,
+}
+
+/// Describes how signature and output files are named. Generated files
+/// are placed next to the file they are derived from.
+#[derive(Debug, Clone)]
+pub struct NamingStrategy {
+    signature: Template,
+    output: Template,
+    version: Option<String>,
+}
+
+/// Parts of a file name matched by the placeholders of a template.
+#[derive(Debug, Default)]
+struct Captures<'a> {
+    name: Option<&'a [u8]>,
+    stem: Option<&'a [u8]>,
+    ext: Option<&'a [u8]>,
+}
+
+impl Template {
+    /// Parses template. Supported placeholders: `{name}`, `{stem}`, `{ext}`, `{version}`.
+    pub fn parse(template: &str) -> Result<Self, Box<dyn Error>> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                tokens.push(Token::Literal(rest[..start].to_string()));
+            }
+
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("Unclosed placeholder in {:?}", template).into()),
+            };
+
+            tokens.push(match &rest[start + 1..end] {
+                "name" => Token::Name,
+                "stem" => Token::Stem,
+                "ext" => Token::Ext,
+                "version" => Token::Version,
+                other => {
+                    return Err(
+                        format!("Unknown placeholder {{{}}} in {:?}", other, template).into(),
+                    )
+                }
+            });
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            tokens.push(Token::Literal(rest.to_string()));
+        }
+
+        if tokens.iter().all(|t| matches!(t, Token::Literal(_))) {
+            return Err(format!("Template {:?} has no placeholders", template).into());
+        }
+
+        Ok(Self { tokens })
+    }
+
+    /// Renders the template for a given file. The result is placed
+    /// in the same directory as the file.
+    pub fn render(&self, file: &Path, version: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+        let name = match file.file_name() {
+            Some(name) => name,
+            None => return Err(format!("{:?} has no file name", file).into()),
+        };
+
+        let mut result = OsString::new();
+
+        for token in &self.tokens {
+            match token {
+                Token::Literal(s) => result.push(s),
+                Token::Name => result.push(name),
+                Token::Stem => result.push(file.file_stem().unwrap_or(name)),
+                Token::Ext => result.push(file.extension().unwrap_or_default()),
+                Token::Version => match version {
+                    Some(version) => result.push(version),
+                    None => {
+                        return Err("Template has a {version} placeholder, pass --version".into())
+                    }
+                },
+            }
+        }
+
+        Ok(file.with_file_name(result))
+    }
+
+    /// Reverses `render`: returns the path of a file the given path was
+    /// generated for. The template must keep the whole file name, with
+    /// `{name}` or with both `{stem}` and `{ext}`. `{version}` matches the
+    /// given version, or any version if none is given.
+    pub fn reverse(
+        &self,
+        generated: &Path,
+        version: Option<&str>,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let has = |token: Token| self.tokens.contains(&token);
+        if !(has(Token::Name) || has(Token::Stem) && has(Token::Ext)) {
+            return Err(
+                "Only templates with {name} or both {stem} and {ext} can be mapped back to the original file"
+                    .into(),
+            );
+        }
+
+        // Captures are split at literals, file names are not necessarily UTF-8
+        if self.tokens.windows(2).any(|pair| {
+            !matches!(pair[0], Token::Literal(_)) && !matches!(pair[1], Token::Literal(_))
+        }) {
+            return Err(
+                "Templates with adjacent placeholders can not be mapped back to the original file"
+                    .into(),
+            );
+        }
+
+        let name = match generated.file_name() {
+            Some(name) => name.as_encoded_bytes(),
+            None => return Err(format!("{:?} has no file name", generated).into()),
+        };
+
+        let mut captures = Captures::default();
+        if !match_tokens(&self.tokens, name, version, &mut captures) {
+            return Err(format!("{:?} does not match the naming template", generated).into());
+        }
+
+        let original = match (captures.name, captures.stem, captures.ext) {
+            (Some(name), _, _) => name.to_vec(),
+            (None, Some(stem), Some([])) => stem.to_vec(),
+            (None, Some(stem), Some(ext)) => [stem, b".", ext].concat(),
+            _ => unreachable!("checked above"),
+        };
+
+        // SAFETY: the name is split right after and before valid UTF-8 strings
+        let original = unsafe { OsStr::from_encoded_bytes_unchecked(&original) };
+        Ok(generated.with_file_name(original))
+    }
+}
+
+/// Matches a file name against template tokens. Placeholders take the
+/// longest part which lets the rest match, ex: `{name}.{version}.rsig`
+/// splits `image.psd.v2.rsig` into `image.psd` and `v2`.
+fn match_tokens<'a>(
+    tokens: &[Token],
+    name: &'a [u8],
+    version: Option<&str>,
+    captures: &mut Captures<'a>,
+) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return name.is_empty(),
+    };
+
+    let literal = match token {
+        Token::Literal(literal) => Some(literal.as_str()),
+        Token::Version => version,
+        _ => None,
+    };
+
+    if let Some(literal) = literal {
+        return match name.strip_prefix(literal.as_bytes()) {
+            Some(name) => match_tokens(rest, name, version, captures),
+            None => false,
+        };
+    }
+
+    // Only a missing extension is empty
+    let min = match token {
+        Token::Ext => 0,
+        _ => 1,
+    };
+
+    for end in (min..=name.len()).rev() {
+        let part = &name[..end];
+        let previous = match captures.slot(token) {
+            // A placeholder repeated in the template matches the same part
+            Some(Some(captured)) if *captured != part => continue,
+            Some(slot) => slot.replace(part),
+            // Any version, the part is not kept
+            None => None,
+        };
+
+        if match_tokens(rest, &name[end..], version, captures) {
+            return true;
+        }
+
+        if let Some(slot) = captures.slot(token) {
+            *slot = previous;
+        }
+    }
+
+    false
+}
+
+impl<'a> Captures<'a> {
+    fn slot(&mut self, token: &Token) -> Option<&mut Option<&'a [u8]>> {
+        match token {
+            Token::Name => Some(&mut self.name),
+            Token::Stem => Some(&mut self.stem),
+            Token::Ext => Some(&mut self.ext),
+            _ => None,
+        }
+    }
+}
+
+impl Default for NamingStrategy {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIGNATURE_TEMPLATE, DEFAULT_OUTPUT_TEMPLATE)
+            .expect("default templates are valid")
+    }
+}
+
+impl NamingStrategy {
+    /// Creates naming strategy from templates.
+    ///
+    /// # Parameters:
+    /// - `signature`: template for signature files, ex: `{name}.rsig`
+    /// - `output`: template for reconstructed files, ex: `{name}.patched`
+    pub fn new(signature: &str, output: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            signature: Template::parse(signature)?,
+            output: Template::parse(output)?,
+            version: None,
+        })
+    }
+
+    /// Sets the value of the `{version}` placeholder. Without a version
+    /// templates with `{version}` can only be reversed.
+    pub fn with_version(mut self, version: Option<&str>) -> Self {
+        self.version = version.map(str::to_string);
+        self
+    }
+
+    /// Returns path of a signature for a given file.
+    pub fn signature_path(&self, file: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        self.signature.render(file, self.version.as_deref())
+    }
+
+    /// Returns path of a file the signature was generated for.
+    pub fn file_path(&self, signature: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        self.signature.reverse(signature, self.version.as_deref())
+    }
+
+    /// Returns path of a reconstructed file for a given target file.
+    pub fn output_path(&self, target: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        self.output.render(target, self.version.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverse(template: &str, generated: &str, version: Option<&str>) -> Option<PathBuf> {
+        Template::parse(template)
+            .unwrap()
+            .reverse(Path::new(generated), version)
+            .ok()
+    }
+
+    #[test]
+    fn default_names_map_back() {
+        let naming = NamingStrategy::default();
+        let signature = naming.signature_path(Path::new("psd/image.psd")).unwrap();
+
+        assert_eq!(signature, Path::new("psd/image.psd.rsig"));
+        assert_eq!(
+            naming.file_path(&signature).unwrap(),
+            Path::new("psd/image.psd")
+        );
+        assert_eq!(
+            naming.output_path(Path::new("psd/image.psd")).unwrap(),
+            Path::new("psd/image.psd.NEW")
+        );
+    }
+
+    #[test]
+    fn reverse_splits_several_placeholders() {
+        assert_eq!(
+            reverse(
+                "{stem}.{version}.{ext}.rsig",
+                "image.v2.psd.rsig",
+                Some("v2")
+            ),
+            Some(PathBuf::from("image.psd"))
+        );
+        assert_eq!(
+            reverse("{name}.{version}.rsig", "image.psd.v2.rsig", None),
+            Some(PathBuf::from("image.psd"))
+        );
+        assert_eq!(
+            reverse(
+                "{stem}-{version}.{ext}.rsig",
+                "my-image-2024-01.psd.rsig",
+                Some("2024-01")
+            ),
+            Some(PathBuf::from("my-image.psd"))
+        );
+        assert_eq!(
+            reverse("{name}.{version}.rsig", "image.psd.v2.rsig", Some("v3")),
+            None
+        );
+    }
+
+    #[test]
+    fn reverse_restores_missing_extensions() {
+        let template = Template::parse("{stem}.{ext}.rsig").unwrap();
+        let generated = template.render(Path::new("Makefile"), None).unwrap();
+
+        assert_eq!(generated, Path::new("Makefile..rsig"));
+        assert_eq!(
+            template.reverse(&generated, None).unwrap(),
+            Path::new("Makefile")
+        );
+    }
+
+    #[test]
+    fn reverse_matches_repeated_placeholders_to_the_same_part() {
+        assert_eq!(
+            reverse("sig-{stem}.{ext}.{stem}", "sig-a.psd.a", None),
+            Some(PathBuf::from("a.psd"))
+        );
+        assert_eq!(
+            reverse("sig-{stem}.{ext}.{stem}", "sig-a.psd.b", None),
+            None
+        );
+    }
+
+    #[test]
+    fn reverse_rejects_templates_which_lose_the_name() {
+        assert_eq!(reverse("{stem}.rsig", "image.rsig", None), None);
+        assert_eq!(reverse("{stem}{ext}.rsig", "imagepsd.rsig", None), None);
+        assert_eq!(reverse("{name}.rsig", "image.psd.sig", None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reverse_keeps_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.psd");
+        let naming = NamingStrategy::default();
+        let signature = naming.signature_path(Path::new(name)).unwrap();
+
+        assert_eq!(naming.file_path(&signature).unwrap(), Path::new(name));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        assert!(Template::parse("{name").is_err());
+        assert!(Template::parse("{size}.rsig").is_err());
+        assert!(Template::parse("signature").is_err());
+    }
+
+    #[test]
+    fn render_requires_a_version_for_version_placeholders() {
+        let naming = NamingStrategy::new("{name}.{version}.rsig", DEFAULT_OUTPUT_TEMPLATE).unwrap();
+
+        assert!(naming.signature_path(Path::new("image.psd")).is_err());
+        assert_eq!(
+            naming
+                .with_version(Some("v2"))
+                .signature_path(Path::new("image.psd"))
+                .unwrap(),
+            Path::new("image.psd.v2.rsig")
+        );
+    }
+}
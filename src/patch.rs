@@ -0,0 +1,367 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::blake3_serde_hex;
+use crate::builder::{self, CopySource, DiffSchema, MappedSource, RetryPolicy};
+use crate::platform;
+use crate::signature::{Diff, Operation, Signature};
+
+/// First line of a patch file.
+const MAGIC: &str = "cloud-zsync patch v1";
+
+/// Encrypted files start with the age header.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// Error of builds without the encrypt feature.
+#[cfg(not(feature = "encrypt"))]
+const ENCRYPT_FEATURE: &str =
+    "Patches can not be encrypted or decrypted, built without the encrypt feature";
+
+/// Recipient an encrypted patch is written to.
+#[cfg(feature = "encrypt")]
+pub use age::x25519::Recipient;
+
+/// Recipient an encrypted patch is written to, there are none without the
+/// encrypt feature.
+#[cfg(not(feature = "encrypt"))]
+#[derive(Debug, Clone)]
+pub enum Recipient {}
+
+/// Second line of a patch file, followed by the diff file data.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatchHeader {
+    #[serde(with = "blake3_serde_hex")]
+    target_hash: blake3::Hash,
+    target_length: u64,
+
+    /// id of the key the target hash is keyed with, see `key::id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+
+    /// ops which build the target file from the source file
+    operations: Vec<Operation>,
+
+    /// diff file segments, offsets are relative to the data start
+    segments: DiffSchema,
+}
+
+/// Data readers of a patch file can seek in, plain or decrypted.
+pub trait PatchData: Read + Seek {}
+
+impl<T: Read + Seek> PatchData for T {}
+
+/// Patch file opened for applying.
+pub struct Patch {
+    header: PatchHeader,
+    data: Box<dyn PatchData>,
+}
+
+/// Part of a stream starting at `start`, seen as a whole stream.
+struct Section<R: Read + Seek> {
+    inner: R,
+    start: u64,
+}
+
+impl<R: Read + Seek> Read for Section<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read + Seek> Seek for Section<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => self.inner.seek(SeekFrom::Start(self.start + offset))?,
+            pos => self.inner.seek(pos)?,
+        };
+
+        position.checked_sub(self.start).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek before the patch data")
+        })
+    }
+}
+
+impl PatchHeader {
+    pub fn new(target: &Signature, operations: Vec<Operation>, segments: DiffSchema) -> Self {
+        Self {
+            target_hash: target.strong_hash(),
+            target_length: target.length(),
+            key_id: target.key_id().map(String::from),
+            operations,
+            segments,
+        }
+    }
+
+    pub fn target_hash(&self) -> blake3::Hash {
+        self.target_hash
+    }
+
+    pub fn target_length(&self) -> u64 {
+        self.target_length
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    pub fn operations(&self) -> &Vec<Operation> {
+        &self.operations
+    }
+
+    pub fn segments(&self) -> &DiffSchema {
+        &self.segments
+    }
+}
+
+impl Patch {
+    /// Opens a patch file, encrypted patches are decrypted on the fly.
+    ///
+    /// # Parameters:
+    /// - `path`: patch file path
+    /// - `identity`: age identity file, required for encrypted patches
+    pub fn open(path: &Path, identity: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let data: Box<dyn PatchData> = match file.fill_buf()?.starts_with(AGE_MAGIC) {
+            true => {
+                let identity = match identity {
+                    Some(identity) => identity,
+                    None => {
+                        return Err(
+                            format!("{} is encrypted, pass --identity", path.display()).into()
+                        )
+                    }
+                };
+
+                Self::decrypt(file, identity)?
+            }
+            false => Box::new(file),
+        };
+
+        Self::read(data, path)
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn decrypt(
+        file: BufReader<File>,
+        identity: &Path,
+    ) -> Result<Box<dyn PatchData>, Box<dyn Error>> {
+        let identities = age::IdentityFile::from_buffer(BufReader::new(File::open(identity)?))?
+            .into_identities()?;
+
+        let decryptor = age::Decryptor::new_buffered(file)?;
+        let reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref() as _))?;
+
+        Ok(Box::new(BufReader::new(reader)))
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    fn decrypt(
+        _file: BufReader<File>,
+        _identity: &Path,
+    ) -> Result<Box<dyn PatchData>, Box<dyn Error>> {
+        Err(ENCRYPT_FEATURE.into())
+    }
+
+    fn read(mut data: Box<dyn PatchData>, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(&mut data);
+        let mut line = String::new();
+
+        reader.read_line(&mut line)?;
+        if line.trim_end() != MAGIC {
+            return Err(format!("{} is not a patch file", path.display()).into());
+        }
+
+        let mut start = line.len() as u64;
+
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header: PatchHeader = serde_json::from_str(&line)?;
+        start += line.len() as u64;
+
+        drop(reader);
+
+        Ok(Self {
+            header,
+            data: Box::new(Section { inner: data, start }),
+        })
+    }
+
+    pub fn header(&self) -> &PatchHeader {
+        &self.header
+    }
+
+    /// Returns the header and the diff file data the segments point to.
+    pub fn into_parts(self) -> (PatchHeader, Box<dyn PatchData>) {
+        (self.header, self.data)
+    }
+}
+
+/// Writes a patch file: the header followed by the diff file.
+///
+/// # Parameters:
+/// - `path`: patch file path
+/// - `header`: ops and segments of the diff file
+/// - `diff_file`: diff file data
+/// - `recipients`: age recipients to encrypt the patch to, none for a plain patch
+pub fn write_patch(
+    path: &Path,
+    header: &PatchHeader,
+    diff_file: &mut dyn Read,
+    recipients: &[Recipient],
+) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+
+    match recipients.is_empty() {
+        true => {
+            let mut w = io::BufWriter::new(file);
+            write_plain(&mut w, header, diff_file)?;
+            w.flush()?;
+        }
+        #[cfg(not(feature = "encrypt"))]
+        false => return Err(ENCRYPT_FEATURE.into()),
+        #[cfg(feature = "encrypt")]
+        false => {
+            let encryptor = age::Encryptor::with_recipients(
+                recipients.iter().map(|r| r as &dyn age::Recipient),
+            )?;
+            let mut w = encryptor.wrap_output(io::BufWriter::new(file))?;
+            write_plain(&mut w, header, diff_file)?;
+            w.finish()?.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a plain patch of a diff.
+///
+/// # Parameters:
+/// - `target`: file the diff builds, INSERT ops read it
+/// - `target_sig`: signature of the target file
+/// - `diff`: diff of the source and target signatures
+/// - `policy`: retries of range reads
+/// - `path`: patch file path
+pub fn build(
+    target: &Path,
+    target_sig: &Signature,
+    diff: &Diff,
+    policy: &RetryPolicy,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut target_file = platform::open_shared(target)?;
+    let mut diff_file = tempfile::tempfile()?;
+
+    let diff_schema = builder::build_local_diff_file(
+        &mut target_file,
+        &mut diff_file,
+        diff.insert_ops(),
+        policy,
+    )?;
+
+    let header = PatchHeader::new(target_sig, diff.operations().clone(), diff_schema);
+
+    diff_file.seek(SeekFrom::Start(0))?;
+    write_patch(path, &header, &mut diff_file, &[])
+}
+
+/// Writes a plain patch of a diff to a temporary file, see `build`.
+///
+/// # Returns:
+/// - `File`: patch file, rewound
+pub fn build_temporary(
+    target: &Path,
+    target_sig: &Signature,
+    diff: &Diff,
+    policy: &RetryPolicy,
+) -> Result<File, Box<dyn Error>> {
+    let patch_file = tempfile::NamedTempFile::new()?;
+    build(target, target_sig, diff, policy, patch_file.path())?;
+
+    let mut patch = patch_file.into_file();
+    patch.seek(SeekFrom::Start(0))?;
+
+    Ok(patch)
+}
+
+/// Builds the target of a plain patch from the source file and verifies
+/// it. Patches of keyed signatures can not be verified without the key.
+///
+/// # Parameters:
+/// - `source`: file the patch is made against
+/// - `patch_path`: patch file
+/// - `destination`: path of the new file
+///
+/// # Returns:
+/// - `PatchHeader`: header of the applied patch
+pub fn rebuild(
+    source: &Path,
+    patch_path: &Path,
+    destination: &Path,
+) -> Result<PatchHeader, Box<dyn Error>> {
+    let (header, mut data) = Patch::open(patch_path, None)?.into_parts();
+
+    if header.key_id().is_some() {
+        return Err("The patch target is keyed, it can not be verified".into());
+    }
+
+    // Empty files can not be mapped
+    let mut source_file: Box<dyn CopySource> = match fs::metadata(source)?.len() {
+        0 => Box::new(platform::open_shared(source)?),
+        _ => Box::new(MappedSource::open(source)?),
+    };
+
+    let mut w = io::BufWriter::new(File::create(destination)?);
+    builder::build_local_file(
+        &mut *source_file,
+        &mut w,
+        header.operations(),
+        &mut data,
+        header.segments(),
+    )?;
+    w.flush()?;
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_mmap(destination)?;
+
+    if fs::metadata(destination)?.len() != header.target_length()
+        || hasher.finalize() != header.target_hash()
+    {
+        return Err(format!(
+            "The result does not match the patch target, the patch is not made against {}",
+            source.display()
+        )
+        .into());
+    }
+
+    Ok(header)
+}
+
+/// Parses an age recipient, `age1...`.
+#[cfg(feature = "encrypt")]
+pub fn parse_recipient(recipient: &str) -> Result<Recipient, Box<dyn Error>> {
+    recipient
+        .parse()
+        .map_err(|e| format!("Invalid recipient {}: {}", recipient, e).into())
+}
+
+/// Patches can not be encrypted without the encrypt feature.
+#[cfg(not(feature = "encrypt"))]
+pub fn parse_recipient(_recipient: &str) -> Result<Recipient, Box<dyn Error>> {
+    Err(ENCRYPT_FEATURE.into())
+}
+
+fn write_plain(
+    w: &mut dyn Write,
+    header: &PatchHeader,
+    diff_file: &mut dyn Read,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "{}", MAGIC)?;
+    writeln!(w, "{}", serde_json::to_string(header)?)?;
+    io::copy(diff_file, w)?;
+
+    Ok(())
+}
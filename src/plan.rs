@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::blake3_serde_hex;
+use crate::signature::{Diff, InsertOp, Operation, Signature};
+
+/// File a plan reads, and what it is expected to contain.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlannedFile {
+    path: PathBuf,
+    length: u64,
+
+    /// hash from the signature, keyed for keyed signatures
+    #[serde(with = "blake3_serde_hex")]
+    strong_hash: blake3::Hash,
+}
+
+/// Transfer plan written by `diff --plan`: everything a build is going to
+/// read and write, so it can be reviewed before `apply-plan` executes it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferPlan {
+    /// source file followed by the seed files, COPY ops refer to them by index
+    sources: Vec<PlannedFile>,
+
+    /// file the ranges are requested from
+    target: PlannedFile,
+
+    /// file which is going to be written
+    destination: PathBuf,
+
+    /// destination is allowed to overwrite the target file
+    in_place: bool,
+
+    /// id of the key the hashes are keyed with, see `key::id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+
+    /// length of chunk hashes of the signatures in bytes
+    hash_length: usize,
+
+    /// ranges to request from the target file, each becomes a diff file segment
+    ranges: Vec<InsertOp>,
+
+    /// ops which build the destination file, in order
+    operations: Vec<Operation>,
+}
+
+impl PlannedFile {
+    pub fn new(path: &Path, sig: &Signature) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            length: sig.length(),
+            strong_hash: sig.strong_hash(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn strong_hash(&self) -> blake3::Hash {
+        self.strong_hash
+    }
+
+    /// Fails if the file length differs from the planned one: the file
+    /// changed since the plan was made.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        let length = fs::metadata(&self.path)?.len();
+
+        if length != self.length {
+            return Err(format!(
+                "{} changed since the plan was made: {} bytes instead of {}",
+                self.path.display(),
+                length,
+                self.length
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+impl TransferPlan {
+    /// Makes a plan of a build.
+    ///
+    /// # Parameters:
+    /// - `sources`: source file and seed files with their signatures
+    /// - `target`: target file and its signature
+    /// - `destination`: path of the new file
+    /// - `in_place`: destination is allowed to overwrite the target
+    /// - `diff`: diff of the signatures
+    pub fn new(
+        sources: &[(&Path, &Signature)],
+        target: (&Path, &Signature),
+        destination: &Path,
+        in_place: bool,
+        diff: &Diff,
+    ) -> Self {
+        let (target_path, target_sig) = target;
+
+        Self {
+            sources: sources
+                .iter()
+                .map(|(path, sig)| PlannedFile::new(path, sig))
+                .collect(),
+            target: PlannedFile::new(target_path, target_sig),
+            destination: destination.to_path_buf(),
+            in_place,
+            key_id: target_sig.key_id().map(String::from),
+            hash_length: target_sig.hash_length(),
+            ranges: diff.insert_ops().clone(),
+            operations: diff.operations().clone(),
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut w = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut w, self)?;
+        w.flush()?;
+
+        Ok(())
+    }
+
+    pub fn sources(&self) -> &Vec<PlannedFile> {
+        &self.sources
+    }
+
+    pub fn target(&self) -> &PlannedFile {
+        &self.target
+    }
+
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    pub fn in_place(&self) -> bool {
+        self.in_place
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    pub fn hash_length(&self) -> usize {
+        self.hash_length
+    }
+
+    pub fn ranges(&self) -> &Vec<InsertOp> {
+        &self.ranges
+    }
+
+    pub fn operations(&self) -> &Vec<Operation> {
+        &self.operations
+    }
+}
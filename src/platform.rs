@@ -0,0 +1,202 @@
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tracing::debug;
+
+/// Attempts to open a file another process holds without sharing it
+const OPEN_ATTEMPTS: u32 = 6;
+
+/// Delay before the second attempt, doubled after each one
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Opens a local file for reading.
+///
+/// On Windows the file is opened with backup semantics, so an elevated
+/// process can read files its ACL denies, and opening is retried while
+/// another process, like an editor saving an asset, holds the file without
+/// sharing it. Paths longer than `MAX_PATH` are prefixed with `\\?\` by
+/// the standard library.
+pub fn open_shared(path: &Path) -> io::Result<File> {
+    let mut delay = OPEN_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match open(path) {
+            Err(e) if is_locked(&e) && attempt < OPEN_ATTEMPTS => {
+                debug!("{} is locked, retrying in {:?}", path.display(), delay);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns true if a file could not be opened or read because another
+/// process holds it. Always false outside of Windows.
+pub fn is_locked(e: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(e.raw_os_error(), Some(32) | Some(33))
+}
+
+/// Returns true if a path component is a Windows prefix like `C:`,
+/// `\\server\share` or `\\?\C:`, or the root. Such components are never
+/// globs even though the verbatim `\\?\` prefix contains `?`.
+pub fn is_root_component(component: &Component) -> bool {
+    matches!(component, Component::Prefix(_) | Component::RootDir)
+}
+
+/// Converts a path to the string manifests, the store and the sign cache
+/// keep: `%` and bytes which are not valid UTF-8 are written as `%XX`, so
+/// any path is kept and read back, see `unescape_path`.
+pub fn escape_path(path: &Path) -> String {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let mut escaped = String::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '%' => escaped.push_str("%25"),
+                c => escaped.push(c),
+            }
+        }
+
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "%{:02X}", byte);
+        }
+    }
+
+    escaped
+}
+
+/// Converts a string written by `escape_path` back to the path. A `%`
+/// without two hex digits after it is kept as it is, older versions did
+/// not escape paths.
+pub fn unescape_path(escaped: &str) -> io::Result<PathBuf> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = match (byte, tail.get(..2)) {
+            (b'%', Some(hex)) if hex.iter().all(u8::is_ascii_hexdigit) => {
+                let hex = std::str::from_utf8(hex).expect("hex digits are ASCII");
+                u8::from_str_radix(hex, 16).ok()
+            }
+            _ => None,
+        };
+
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    path_from_bytes(bytes, escaped)
+}
+
+/// Converts a command line argument to the string argh parses: bytes which
+/// are not valid UTF-8, and the bytes of characters in the range starting
+/// at `ARG_BYTES`, are written as the character `ARG_BYTES` plus the byte,
+/// so `path_from_arg` reads any path back.
+pub fn arg_from_os(arg: &OsStr) -> String {
+    let mut encoded = String::with_capacity(arg.len());
+
+    for chunk in arg.as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match is_arg_byte(c) {
+                true => encoded.extend(c.to_string().bytes().map(arg_byte)),
+                false => encoded.push(c),
+            }
+        }
+
+        encoded.extend(chunk.invalid().iter().copied().map(arg_byte));
+    }
+
+    encoded
+}
+
+/// Converts an argument written by `arg_from_os` back to the path.
+pub fn path_from_arg(arg: &str) -> io::Result<PathBuf> {
+    path_from_bytes(arg_bytes(arg), arg)
+}
+
+/// Converts an argument written by `arg_from_os` back to the text, none if
+/// it was not valid UTF-8.
+pub fn text_from_arg(arg: &str) -> Option<String> {
+    String::from_utf8(arg_bytes(arg)).ok()
+}
+
+/// First of the 256 private use characters argument bytes are mapped to
+const ARG_BYTES: u32 = 0xF700;
+
+fn arg_byte(byte: u8) -> char {
+    char::from_u32(ARG_BYTES + byte as u32).expect("private use characters are valid")
+}
+
+fn is_arg_byte(c: char) -> bool {
+    (ARG_BYTES..ARG_BYTES + 256).contains(&(c as u32))
+}
+
+fn arg_bytes(arg: &str) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(arg.len());
+
+    for c in arg.chars() {
+        match is_arg_byte(c) {
+            true => bytes.push((c as u32 - ARG_BYTES) as u8),
+            false => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+
+    bytes
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>, _escaped: &str) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+/// Paths of other platforms are Unicode, only valid UTF-8 is a path there.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>, escaped: &str) -> io::Result<PathBuf> {
+    String::from_utf8(bytes).map(PathBuf::from).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a valid path on this platform", escaped),
+        )
+    })
+}
+
+#[cfg(windows)]
+fn open(path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 0x1;
+    const FILE_SHARE_WRITE: u32 = 0x2;
+    const FILE_SHARE_DELETE: u32 = 0x4;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+
+    OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+#[cfg(not(windows))]
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
@@ -1,6 +1,132 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Minimal interval between JSON progress events of a single phase
+const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Window the throughput of tracked ops is measured over, fetches slow down
+/// and speed up, so the average since the start is a poor projection
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Print JSON progress events instead of drawing bars
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// How progress is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// indicatif bars and spinners
+    Bar,
+
+    /// JSON lines on stderr, see `Event`
+    Json,
+}
+
+/// Progress event printed as a line of JSON with --progress json
+#[derive(Serialize)]
+struct Event<'a> {
+    phase: &'a str,
+    file: &'a str,
+    bytes_done: u64,
+    bytes_total: u64,
+
+    /// bytes per second since the phase started
+    rate: f64,
+
+    /// bytes per second over the last seconds
+    throughput: f64,
+
+    /// seconds left at the current throughput, unknown until it is measured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta: Option<f64>,
+}
+
+/// Bytes done over the last `THROUGHPUT_WINDOW`.
+struct Throughput {
+    /// time and bytes done, the first sample is at or before the window start
+    samples: VecDeque<(Instant, u64)>,
+}
+
+/// Iterator over ops which reports bytes of the ops done so far, an op is
+/// done once the next one is requested.
+pub struct Tracked<I, F> {
+    inner: I,
+    length: F,
+    bar: ProgressBar,
+    phase: &'static str,
+    file: String,
+    done: u64,
+    pending: u64,
+    total: u64,
+    started: Instant,
+    reported: Option<Instant>,
+    throughput: Throughput,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bar" => Ok(Self::Bar),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Unknown progress format {}, expected bar or json",
+                s
+            )),
+        }
+    }
+}
+
+pub fn set_format(format: ProgressFormat) {
+    JSON_EVENTS.store(format == ProgressFormat::Json, Ordering::Relaxed);
+}
+
+fn json_events() -> bool {
+    JSON_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Prints a progress event if JSON events are on
+pub fn report(phase: &str, file: &str, bytes_done: u64, bytes_total: u64, elapsed: Duration) {
+    let rate = match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => bytes_done as f64 / secs,
+        _ => 0.0,
+    };
+
+    print_event(&Event {
+        phase,
+        file,
+        bytes_done,
+        bytes_total,
+        rate,
+        throughput: rate,
+        eta: eta(bytes_total.saturating_sub(bytes_done), rate).map(|eta| eta.as_secs_f64()),
+    });
+}
+
+fn print_event(event: &Event) {
+    if !json_events() {
+        return;
+    }
+
+    if let Ok(line) = serde_json::to_string(event) {
+        eprintln!("{}", line);
+    }
+}
+
+/// Returns time left to process the remaining bytes at a rate in bytes per second.
+fn eta(remaining: u64, rate: f64) -> Option<Duration> {
+    (rate > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / rate))
+}
 
 pub fn create_spinner(message: String) -> ProgressBar {
+    if json_events() {
+        return ProgressBar::hidden();
+    }
+
     let spinner = ProgressBar::new_spinner();
 
     spinner.set_style(
@@ -15,6 +141,10 @@ pub fn create_spinner(message: String) -> ProgressBar {
 }
 
 pub fn create_bar(len: u64) -> ProgressBar {
+    if json_events() {
+        return ProgressBar::hidden();
+    }
+
     let pb = ProgressBar::new(len);
 
     pb.set_style(
@@ -27,3 +157,141 @@ pub fn create_bar(len: u64) -> ProgressBar {
 
     pb
 }
+
+fn create_bytes_bar(len: u64) -> ProgressBar {
+    if json_events() {
+        return ProgressBar::hidden();
+    }
+
+    let pb = ProgressBar::new(len);
+
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} {msg}",
+            )
+            .progress_chars("#>-"),
+    );
+
+    pb
+}
+
+/// Tracks bytes of ops as they are processed, with a bar or JSON events.
+///
+/// # Parameters:
+/// - `ops`: ops to track
+/// - `phase`: name of the phase in events, `fetch` or `build`
+/// - `file`: file the phase reads or writes
+/// - `length`: returns length of an op in bytes
+pub fn track<I, F>(ops: I, phase: &'static str, file: String, length: F) -> Tracked<I::IntoIter, F>
+where
+    I: IntoIterator,
+    I::IntoIter: Clone,
+    F: Fn(&I::Item) -> u64,
+{
+    let inner = ops.into_iter();
+    let total = inner.clone().map(|op| length(&op)).sum();
+
+    Tracked {
+        inner,
+        length,
+        bar: create_bytes_bar(total),
+        phase,
+        file,
+        done: 0,
+        pending: 0,
+        total,
+        started: Instant::now(),
+        reported: None,
+        throughput: Throughput::new(),
+    }
+}
+
+impl Throughput {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::from([(Instant::now(), 0)]),
+        }
+    }
+
+    fn record(&mut self, done: u64) {
+        let now = Instant::now();
+        self.samples.push_back((now, done));
+
+        while self.samples.len() > 1 && now - self.samples[1].0 >= THROUGHPUT_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns bytes per second over the window.
+    fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(&(start, from)), Some(&(end, to))) if end > start => {
+                (to - from) as f64 / (end - start).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl<I: Iterator, F: Fn(&I::Item) -> u64> Iterator for Tracked<I, F> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.done += std::mem::take(&mut self.pending);
+        self.bar.set_position(self.done);
+        self.throughput.record(self.done);
+
+        // The whole list is projected at the recent throughput
+        if let Some(eta) = eta(self.total - self.done, self.throughput.rate()) {
+            self.bar.set_message(format!(
+                "{}/s, {} left",
+                HumanBytes(self.throughput.rate() as u64),
+                HumanDuration(eta)
+            ));
+        }
+
+        let item = self.inner.next();
+
+        match &item {
+            Some(op) => {
+                self.pending = (self.length)(op);
+
+                if self
+                    .reported
+                    .is_none_or(|reported| reported.elapsed() >= EVENT_INTERVAL)
+                {
+                    self.report();
+                }
+            }
+            None if !self.bar.is_finished() => {
+                self.bar.finish();
+                self.report();
+            }
+            None => {}
+        }
+
+        item
+    }
+}
+
+impl<I, F> Tracked<I, F> {
+    fn report(&mut self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = self.throughput.rate();
+
+        print_event(&Event {
+            phase: self.phase,
+            file: &self.file,
+            bytes_done: self.done,
+            bytes_total: self.total,
+            rate: match elapsed {
+                secs if secs > 0.0 => self.done as f64 / secs,
+                _ => 0.0,
+            },
+            throughput,
+            eta: eta(self.total - self.done, throughput).map(|eta| eta.as_secs_f64()),
+        });
+        self.reported = Some(Instant::now());
+    }
+}
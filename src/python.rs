@@ -0,0 +1,185 @@
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::builder::RetryPolicy;
+use crate::patch;
+use crate::signature::{self, Op, SignOptions};
+
+/// Signature of a file.
+#[pyclass(module = "cloudrsync", frozen)]
+pub struct Signature {
+    inner: signature::Signature,
+}
+
+/// Difference between a source and a target file.
+#[pyclass(module = "cloudrsync", frozen)]
+pub struct Diff {
+    inner: signature::Diff,
+    target: signature::Signature,
+}
+
+#[pymethods]
+impl Signature {
+    /// Signs a local file.
+    ///
+    /// # Parameters:
+    /// - `path`: file to sign
+    /// - `avg_size`: average chunk size in bytes, picked by the file length if omitted
+    #[staticmethod]
+    #[pyo3(signature = (path, avg_size = None))]
+    fn generate(py: Python<'_>, path: PathBuf, avg_size: Option<u32>) -> PyResult<Self> {
+        let generate = || -> Result<Self, Box<dyn Error>> {
+            let avg_size = match avg_size {
+                Some(avg_size) => avg_size,
+                None => SignOptions::auto_avg_size(fs::metadata(&path)?.len()),
+            };
+            let options = SignOptions {
+                mmap: true,
+                ..Default::default()
+            }
+            .with_avg_size(avg_size);
+
+            let inner = signature::Signature::generate_file(&path, &options)?;
+            Ok(Self { inner })
+        };
+
+        // Box<dyn Error> is not Send, errors are converted before taking the GIL back
+        py.allow_threads(|| generate().map_err(py_error))
+    }
+
+    /// Reads a signature file written by `sign` or `save`.
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        let read = || -> Result<Self, Box<dyn Error>> {
+            let inner = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+            Ok(Self { inner })
+        };
+
+        read().map_err(py_error)
+    }
+
+    /// Writes the signature file.
+    fn save(&self, path: PathBuf) -> PyResult<()> {
+        let write = || -> Result<(), Box<dyn Error>> {
+            let mut w = BufWriter::new(File::create(&path)?);
+            serde_json::to_writer(&mut w, &self.inner)?;
+            w.flush()?;
+            Ok(())
+        };
+
+        write().map_err(py_error)
+    }
+
+    /// Length of the signed file.
+    #[getter]
+    fn length(&self) -> u64 {
+        self.inner.length()
+    }
+
+    /// blake3 hash of the signed file, hex encoded.
+    #[getter]
+    fn strong_hash(&self) -> String {
+        self.inner.strong_hash().to_hex().to_string()
+    }
+
+    /// Number of chunks.
+    fn __len__(&self) -> usize {
+        self.inner.chunks().len()
+    }
+}
+
+#[pymethods]
+impl Diff {
+    /// Diffs the signature of the file a client has against the target one.
+    /// Returns `None` if the files are equal.
+    #[staticmethod]
+    fn compute(source: &Signature, target: &Signature) -> Option<Self> {
+        signature::Diff::new(&source.inner, &target.inner).map(|inner| Self {
+            inner,
+            target: target.inner.clone(),
+        })
+    }
+
+    /// Bytes reused from the source file.
+    #[getter]
+    fn copy_length(&self) -> u64 {
+        self.inner.copy_length()
+    }
+
+    /// Bytes to fetch from the target file.
+    #[getter]
+    fn fetch_length(&self) -> u64 {
+        self.inner.fetch_length()
+    }
+
+    /// Ranges of the target file to fetch, as `(offset, length)`.
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.inner
+            .insert_ops()
+            .iter()
+            .map(|op| (op.offset(), op.length()))
+            .collect()
+    }
+
+    /// Writes a plain patch, `apply` builds the target file with it.
+    ///
+    /// # Parameters:
+    /// - `target_path`: target file, the patch carries its changed ranges
+    /// - `patch_path`: patch file to write
+    fn write_patch(
+        &self,
+        py: Python<'_>,
+        target_path: PathBuf,
+        patch_path: PathBuf,
+    ) -> PyResult<()> {
+        py.allow_threads(|| {
+            patch::build(
+                &target_path,
+                &self.target,
+                &self.inner,
+                &RetryPolicy::default(),
+                &patch_path,
+            )
+            .map_err(py_error)
+        })
+    }
+}
+
+/// Builds the target of a plain patch from the source file, the result
+/// is verified against the patch target.
+///
+/// # Parameters:
+/// - `source`: file the patch is made against
+/// - `patch_path`: patch file
+/// - `destination`: path of the new file
+#[pyfunction]
+fn apply(
+    py: Python<'_>,
+    source: PathBuf,
+    patch_path: PathBuf,
+    destination: PathBuf,
+) -> PyResult<()> {
+    py.allow_threads(|| {
+        patch::rebuild(&source, &patch_path, &destination)
+            .map(|_| ())
+            .map_err(py_error)
+    })
+}
+
+/// `cloudrsync` Python module, built with maturin, see pyproject.toml
+#[pymodule]
+fn cloudrsync(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Signature>()?;
+    m.add_class::<Diff>()?;
+    m.add_function(wrap_pyfunction!(apply, m)?)?;
+
+    Ok(())
+}
+
+fn py_error(e: Box<dyn Error>) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
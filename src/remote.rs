@@ -0,0 +1,460 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use tracing::{debug, info};
+
+use crate::builder::RetryPolicy;
+use crate::patch;
+use crate::signature::{ChunkSizes, Diff, SignOptions, Signature};
+
+/// Version of the protocol, both sides must speak the same one
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Request of the client, a line of JSON. `Patch` and `Apply` are followed
+/// by a blob: the client signature and the patch.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum Request {
+    Hello {
+        version: u32,
+    },
+
+    /// signature of a file, generated with the given chunk sizes
+    Signature {
+        path: PathBuf,
+        chunk_sizes: ChunkSizes,
+    },
+
+    /// patch which builds a file from the client file
+    Patch {
+        path: PathBuf,
+    },
+
+    /// replace a file with the result of a patch made against it
+    Apply {
+        path: PathBuf,
+    },
+}
+
+/// Reply of the server, a line of JSON. `Data` is followed by a blob.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+enum Reply {
+    Hello { version: u32 },
+    Data,
+    Missing,
+    Equal,
+    Done,
+    Error { message: String },
+}
+
+/// Connection to `cloud-zsync server` spawned over a remote shell.
+pub struct RemoteSession {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+fn send<W: Write, T: Serialize>(w: &mut W, message: &T) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "{}", serde_json::to_string(message)?)?;
+    Ok(())
+}
+
+fn receive<R: BufRead, T: for<'de> Deserialize<'de>>(
+    r: &mut R,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+/// Writes a blob: its length as big-endian u64 followed by the data.
+fn send_blob<W: Write, R: Read + Seek>(w: &mut W, data: &mut R) -> Result<(), Box<dyn Error>> {
+    let length = data.seek(SeekFrom::End(0))?;
+    data.seek(SeekFrom::Start(0))?;
+
+    w.write_all(&length.to_be_bytes())?;
+    let copied = io::copy(data, w)?;
+
+    if copied != length {
+        return Err("Blob changed while it was sent".into());
+    }
+
+    Ok(())
+}
+
+/// Reads a blob into a temporary file.
+fn receive_blob<R: Read>(r: &mut R) -> Result<File, Box<dyn Error>> {
+    let mut length = [0u8; 8];
+    r.read_exact(&mut length)?;
+    let length = u64::from_be_bytes(length);
+
+    let mut file = tempfile::tempfile()?;
+    let copied = io::copy(&mut r.take(length), &mut file)?;
+
+    if copied != length {
+        return Err("Connection closed in the middle of a blob".into());
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Signs a file with the given chunk sizes and hash length.
+pub fn sign_with(
+    path: &Path,
+    chunk_sizes: ChunkSizes,
+    hash_length: usize,
+) -> Result<Signature, Box<dyn Error>> {
+    let options = SignOptions {
+        mmap: true,
+        hash_length,
+        ..Default::default()
+    }
+    .with_chunk_sizes(chunk_sizes);
+
+    options.validate()?;
+    Signature::generate_file(path, &options)
+}
+
+/// Chunk sizes picked for a file of the given length.
+pub fn auto_chunk_sizes(length: u64) -> ChunkSizes {
+    let options = SignOptions::default().with_avg_size(SignOptions::auto_avg_size(length));
+
+    ChunkSizes {
+        min_size: options.min_size,
+        avg_size: options.avg_size,
+        max_size: options.max_size,
+    }
+}
+
+/// Builds a file from a base and a patch next to it and replaces the file,
+/// a missing base is an empty file.
+///
+/// # Parameters:
+/// - `path`: file to replace
+/// - `patch_path`: patch made against the current contents of the file
+pub fn replace_with_patch(path: &Path, patch_path: &Path) -> Result<(), Box<dyn Error>> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let empty;
+    let base = match path.exists() {
+        true => path,
+        false => {
+            empty = tempfile::NamedTempFile::new()?;
+            empty.path()
+        }
+    };
+
+    let new_file = tempfile::NamedTempFile::new_in(directory)?;
+    patch::rebuild(base, patch_path, new_file.path())?;
+
+    // Keep permissions of the replaced file
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(new_file.path(), metadata.permissions())?;
+    }
+
+    new_file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Signature of an empty file, missing files are built from it.
+fn empty_signature(chunk_sizes: ChunkSizes) -> Result<Signature, Box<dyn Error>> {
+    let empty = tempfile::NamedTempFile::new()?;
+    sign_with(empty.path(), chunk_sizes, blake3::OUT_LEN)
+}
+
+/// Serves the requests of a client on stdin and stdout until it
+/// disconnects. Failed requests are reported to the client, the session
+/// goes on.
+pub fn serve<R: BufRead, W: Write>(r: &mut R, w: &mut W) -> Result<(), Box<dyn Error>> {
+    match receive(r)? {
+        Some(Request::Hello { version }) if version == PROTOCOL_VERSION => {
+            send(w, &Reply::Hello { version })?;
+            w.flush()?;
+        }
+        Some(Request::Hello { version }) => {
+            send(
+                w,
+                &Reply::Error {
+                    message: format!(
+                        "Protocol version {} is not supported, the server speaks {}",
+                        version, PROTOCOL_VERSION
+                    ),
+                },
+            )?;
+            w.flush()?;
+            return Ok(());
+        }
+        _ => return Err("Expected a hello from the client".into()),
+    }
+
+    while let Some(request) = receive::<_, Request>(r)? {
+        debug!(?request, "Remote request");
+
+        // Blobs must be read even if the request fails
+        let blob = match request {
+            Request::Patch { .. } | Request::Apply { .. } => Some(receive_blob(r)?),
+            _ => None,
+        };
+
+        match handle(request, blob) {
+            Ok((reply, data)) => {
+                send(w, &reply)?;
+                if let Some(mut data) = data {
+                    send_blob(w, &mut data)?;
+                }
+            }
+            Err(e) => send(
+                w,
+                &Reply::Error {
+                    message: e.to_string(),
+                },
+            )?,
+        }
+
+        w.flush()?;
+    }
+
+    Ok(())
+}
+
+fn handle(request: Request, blob: Option<File>) -> Result<(Reply, Option<File>), Box<dyn Error>> {
+    match (request, blob) {
+        (Request::Signature { path, chunk_sizes }, _) => {
+            if !path.exists() {
+                return Ok((Reply::Missing, None));
+            }
+
+            let sig = sign_with(&path, chunk_sizes, blake3::OUT_LEN)?;
+            let mut data = tempfile::tempfile()?;
+            serde_json::to_writer(&mut data, &sig)?;
+
+            Ok((Reply::Data, Some(data)))
+        }
+        (Request::Patch { path }, Some(blob)) => {
+            let client: Signature = serde_json::from_reader(BufReader::new(blob))?;
+            let chunk_sizes = client
+                .chunk_sizes()
+                .ok_or("The client signature has no chunk sizes")?;
+
+            if !path.exists() {
+                return Err(format!("{} does not exist", path.display()).into());
+            }
+
+            let sig = sign_with(&path, chunk_sizes, client.hash_length())?;
+
+            match Diff::new_multi(&[&client], &sig) {
+                Some(diff) => {
+                    let patch =
+                        patch::build_temporary(&path, &sig, &diff, &RetryPolicy::default())?;
+                    Ok((Reply::Data, Some(patch)))
+                }
+                None => Ok((Reply::Equal, None)),
+            }
+        }
+        (Request::Apply { path }, Some(mut blob)) => {
+            let mut patch_file = tempfile::NamedTempFile::new()?;
+            io::copy(&mut blob, patch_file.as_file_mut())?;
+
+            replace_with_patch(&path, patch_file.path())?;
+            Ok((Reply::Done, None))
+        }
+        (request, _) => Err(format!("Unexpected request {:?}", request).into()),
+    }
+}
+
+impl RemoteSession {
+    /// Spawns `{remote_bin} server` on a host with a remote shell.
+    ///
+    /// # Parameters:
+    /// - `rsh`: remote shell command, split on whitespace, ex: `ssh -p 2222`
+    /// - `host`: host, passed to the remote shell as is
+    /// - `remote_bin`: path of the binary on the host
+    pub fn connect(rsh: &str, host: &str, remote_bin: &str) -> Result<Self, Box<dyn Error>> {
+        let mut words = rsh.split_whitespace();
+        let program = words.next().ok_or("Empty remote shell command")?;
+
+        info!("Connecting to {} with {}...", host, rsh);
+
+        let mut child = Command::new(program)
+            .args(words)
+            .arg(host)
+            .arg(remote_bin)
+            .arg("-q")
+            .arg("server")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Can not run {}: {}", program, e))?;
+
+        let mut session = Self {
+            stdin: BufWriter::new(child.stdin.take().ok_or("No stdin of the remote shell")?),
+            stdout: BufReader::new(child.stdout.take().ok_or("No stdout of the remote shell")?),
+            child,
+        };
+
+        session.request(&Request::Hello {
+            version: PROTOCOL_VERSION,
+        })?;
+
+        match session.reply()? {
+            Reply::Hello { .. } => Ok(session),
+            reply => Err(format!("Unexpected reply to hello: {:?}", reply).into()),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<(), Box<dyn Error>> {
+        send(&mut self.stdin, request)
+    }
+
+    fn reply(&mut self) -> Result<Reply, Box<dyn Error>> {
+        self.stdin.flush()?;
+
+        match receive(&mut self.stdout)? {
+            Some(Reply::Error { message }) => Err(format!("Remote: {}", message).into()),
+            Some(reply) => Ok(reply),
+            None => Err("The remote side closed the connection".into()),
+        }
+    }
+
+    /// Returns a signature of a remote file, `None` if it does not exist.
+    pub fn signature(
+        &mut self,
+        path: &Path,
+        chunk_sizes: ChunkSizes,
+    ) -> Result<Option<Signature>, Box<dyn Error>> {
+        self.request(&Request::Signature {
+            path: path.to_path_buf(),
+            chunk_sizes,
+        })?;
+
+        match self.reply()? {
+            Reply::Data => {
+                let blob = receive_blob(&mut self.stdout)?;
+                Ok(Some(serde_json::from_reader(BufReader::new(blob))?))
+            }
+            Reply::Missing => Ok(None),
+            reply => Err(format!("Unexpected reply: {:?}", reply).into()),
+        }
+    }
+
+    /// Returns a patch which builds a remote file from the local file of
+    /// the signature, `None` if the files are equal.
+    pub fn patch(
+        &mut self,
+        path: &Path,
+        local: &Signature,
+    ) -> Result<Option<File>, Box<dyn Error>> {
+        self.request(&Request::Patch {
+            path: path.to_path_buf(),
+        })?;
+
+        let mut data = tempfile::tempfile()?;
+        serde_json::to_writer(&mut data, local)?;
+        send_blob(&mut self.stdin, &mut data)?;
+
+        match self.reply()? {
+            Reply::Data => Ok(Some(receive_blob(&mut self.stdout)?)),
+            Reply::Equal => Ok(None),
+            reply => Err(format!("Unexpected reply: {:?}", reply).into()),
+        }
+    }
+
+    /// Replaces a remote file with the result of a patch made against it.
+    pub fn apply(&mut self, path: &Path, patch: &mut File) -> Result<(), Box<dyn Error>> {
+        self.request(&Request::Apply {
+            path: path.to_path_buf(),
+        })?;
+        send_blob(&mut self.stdin, patch)?;
+
+        match self.reply()? {
+            Reply::Done => Ok(()),
+            reply => Err(format!("Unexpected reply: {:?}", reply).into()),
+        }
+    }
+
+    /// Closes the connection and waits for the remote shell to exit.
+    pub fn close(mut self) -> Result<(), Box<dyn Error>> {
+        drop(self.stdin);
+        let status = self.child.wait()?;
+
+        match status.success() {
+            true => Ok(()),
+            false => Err(format!("Remote shell exited with {}", status).into()),
+        }
+    }
+}
+
+/// Pushes a local file to a remote one: the remote side signs its file,
+/// the local side sends a patch.
+pub fn push(
+    session: &mut RemoteSession,
+    local: &Path,
+    remote: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    let chunk_sizes = auto_chunk_sizes(fs::metadata(local)?.len());
+
+    let remote_sig = match session.signature(remote, chunk_sizes)? {
+        Some(sig) => sig,
+        None => empty_signature(chunk_sizes)?,
+    };
+
+    let local_sig = sign_with(local, chunk_sizes, blake3::OUT_LEN)?;
+
+    let diff = match Diff::new_multi(&[&remote_sig], &local_sig) {
+        Some(diff) => diff,
+        None => return Ok(false),
+    };
+
+    info!(
+        "Sending {} ranges of {}, {} bytes are copied on the remote side",
+        diff.insert_ops().len(),
+        local.display(),
+        diff.copy_length()
+    );
+
+    let mut patch = patch::build_temporary(local, &local_sig, &diff, &RetryPolicy::default())?;
+    session.apply(remote, &mut patch)?;
+
+    Ok(true)
+}
+
+/// Pulls a remote file into a local one: the local side signs its file,
+/// the remote side sends a patch.
+pub fn pull(
+    session: &mut RemoteSession,
+    remote: &Path,
+    local: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    let chunk_sizes = match fs::metadata(local) {
+        Ok(metadata) => auto_chunk_sizes(metadata.len()),
+        Err(_) => auto_chunk_sizes(0),
+    };
+
+    let local_sig = match local.exists() {
+        true => sign_with(local, chunk_sizes, blake3::OUT_LEN)?,
+        false => empty_signature(chunk_sizes)?,
+    };
+
+    let mut patch = match session.patch(remote, &local_sig)? {
+        Some(patch) => patch,
+        None => return Ok(false),
+    };
+
+    let mut patch_file = tempfile::NamedTempFile::new()?;
+    io::copy(&mut patch, patch_file.as_file_mut())?;
+
+    replace_with_patch(local, patch_file.path())?;
+
+    Ok(true)
+}
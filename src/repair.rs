@@ -0,0 +1,198 @@
+use memmap2::Mmap;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use crate::builder::{self, memory_range, RetryPolicy};
+use crate::platform;
+use crate::signature::{Chunk, Signature};
+
+/// Ranges of a local file which do not match its signature.
+#[derive(Debug, Clone, Default)]
+pub struct Damage {
+    /// number of chunks which fail verification
+    pub chunks: usize,
+
+    /// merged `(offset, length)` ranges of those chunks, in the file order
+    pub ranges: Vec<(u64, u64)>,
+
+    /// true if the file length differs from the signature
+    pub resized: bool,
+}
+
+impl Damage {
+    /// Returns the number of bytes to fetch.
+    pub fn length(&self) -> u64 {
+        self.ranges.iter().map(|(_, length)| length).sum()
+    }
+
+    /// Returns true if the file matches the signature.
+    pub fn is_intact(&self) -> bool {
+        self.ranges.is_empty() && !self.resized
+    }
+
+    fn add(&mut self, chunk: &Chunk) {
+        self.chunks += 1;
+
+        match self.ranges.last_mut() {
+            Some((offset, length)) if *offset + *length == chunk.offset() => {
+                *length += chunk.length()
+            }
+            _ => self.ranges.push((chunk.offset(), chunk.length())),
+        }
+    }
+}
+
+/// Result of the scrub of a single file of a tree.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum FileHealth {
+    /// file matches its signature
+    Intact { path: String, length: u64 },
+
+    /// some chunks do not match the signature or the length differs,
+    /// `repaired` is set if the file was fixed from a good copy
+    Corrupted {
+        path: String,
+        chunks: usize,
+        damaged_length: u64,
+        resized: bool,
+        repaired: bool,
+    },
+
+    /// file is in the manifest but not in the tree
+    Missing { path: String },
+
+    /// file could not be verified
+    Skipped { path: String, reason: String },
+}
+
+impl FileHealth {
+    /// Returns true if the file is still corrupted or missing.
+    pub fn is_bad(&self) -> bool {
+        match self {
+            Self::Corrupted { repaired, .. } => !repaired,
+            Self::Missing { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Verifies every chunk of a local file against the signature of the
+/// version it must hold. Chunks beyond the end of a truncated file are damaged.
+///
+/// # Parameters:
+/// - `path`: local file
+/// - `sig`: authoritative signature of the file, must not be keyed
+pub fn find_damage(path: &Path, sig: &Signature) -> Result<Damage, Box<dyn Error>> {
+    check_verifiable(sig)?;
+
+    let file = platform::open_shared(path)?;
+    // The file must not be modified while it is verified anyway
+    let map = unsafe { Mmap::map(&file)? };
+
+    let damaged: Vec<bool> = sig
+        .chunks()
+        .par_iter()
+        .map(|chunk| {
+            let data = memory_range(chunk.offset(), chunk.length()).and_then(|r| map.get(r));
+            data.is_none_or(|data| sig.chunk_hash(data) != chunk.strong_hash())
+        })
+        .collect();
+
+    let mut damage = Damage {
+        resized: map.len() as u64 != sig.length(),
+        ..Default::default()
+    };
+
+    for (chunk, _) in sig.chunks().iter().zip(damaged).filter(|(_, d)| *d) {
+        damage.add(chunk);
+    }
+
+    Ok(damage)
+}
+
+/// Verifies chunks reading the file sequentially, see `find_damage`.
+/// Used to verify at a limited rate, see `throttle::Throttled`.
+///
+/// # Parameters:
+/// - `r`: local file stream, read from the start
+/// - `sig`: authoritative signature of the file, must not be keyed
+pub fn find_damage_streaming<R: Read>(
+    r: &mut R,
+    sig: &Signature,
+) -> Result<Damage, Box<dyn Error>> {
+    check_verifiable(sig)?;
+
+    let mut damage = Damage::default();
+    let mut data: Vec<u8> = Vec::new();
+    let mut read: u64 = 0;
+
+    for chunk in sig.chunks() {
+        data.clear();
+        r.by_ref().take(chunk.length()).read_to_end(&mut data)?;
+        read += data.len() as u64;
+
+        if data.len() as u64 != chunk.length() || sig.chunk_hash(&data) != chunk.strong_hash() {
+            damage.add(chunk);
+        }
+    }
+
+    // Anything left means the file is longer than the signature
+    let mut rest = [0u8; 1];
+    damage.resized = read != sig.length() || r.read(&mut rest)? > 0;
+
+    Ok(damage)
+}
+
+/// Overwrites damaged ranges of a local file with the data of a good copy,
+/// sets its length and checks the whole file against the signature.
+///
+/// # Parameters:
+/// - `path`: damaged file
+/// - `sig`: authoritative signature of the file
+/// - `good`: stream of a good copy of the file
+/// - `ranges`: damaged ranges, see `Damage::ranges`
+/// - `policy`: retry policy for range reads from the good copy
+pub fn repair_file<'a, R, I>(
+    path: &Path,
+    sig: &Signature,
+    good: &mut R,
+    ranges: I,
+    policy: &RetryPolicy,
+) -> Result<(), Box<dyn Error>>
+where
+    R: Read + Seek,
+    I: IntoIterator<Item = &'a (u64, u64)>,
+{
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(sig.length())?;
+
+    builder::patch_local_file(good, &mut file, ranges, policy)?;
+    drop(file);
+
+    // Chunks with truncated hashes could still look intact
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_mmap(path)?;
+
+    if hasher.finalize() != sig.strong_hash() {
+        return Err(format!("{} still does not match the signature", path.display()).into());
+    }
+
+    Ok(())
+}
+
+fn check_verifiable(sig: &Signature) -> Result<(), Box<dyn Error>> {
+    if sig.key_id().is_some() {
+        return Err("Chunks of a keyed signature can not be verified".into());
+    }
+
+    if sig.decompressed().is_some() {
+        return Err("Chunks of a file signed with --decompress can not be verified".into());
+    }
+
+    Ok(())
+}
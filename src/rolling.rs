@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Read;
+
+/// Weak and strong checksums of fixed-size blocks of a file, the same
+/// as rsync uses. Lets a client find target data at any offset of its
+/// local file, not only at chunk boundaries. The last block is indexed
+/// only if it is full.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockIndex {
+    /// block size in bytes
+    size: usize,
+
+    /// rolling checksum of each block
+    weak: Vec<u32>,
+
+    /// first 8 bytes of blake3 of each block
+    strong: Vec<u64>,
+}
+
+/// Block of the target file found in the source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockMatch {
+    /// offset in the source file
+    pub source_offset: u64,
+
+    /// offset in the target file
+    pub offset: u64,
+
+    pub length: u64,
+}
+
+/// rsync rolling checksum of a window which can be moved by one byte.
+pub(crate) struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    pub(crate) fn new(data: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        let length = data.len() as u32;
+
+        for (i, &x) in data.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((length - i as u32).wrapping_mul(x as u32));
+        }
+
+        Self { a, b, length }
+    }
+
+    /// Moves the window by one byte: `out` leaves it, `inc` enters it.
+    pub(crate) fn roll(&mut self, out: u8, inc: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inc as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.length.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Returns the lower 16 bits of both sums, `a` and `b`, which zsync stores.
+    pub(crate) fn parts(&self) -> (u16, u16) {
+        (self.a as u16, self.b as u16)
+    }
+}
+
+impl BlockIndex {
+    /// Calculates checksums of a file split into blocks.
+    ///
+    /// # Parameters:
+    /// - `reader`: file reader
+    /// - `size`: block size in bytes
+    pub fn generate(reader: &mut dyn Read, size: usize) -> Result<Self, Box<dyn Error>> {
+        if size == 0 {
+            return Err("Block size must be positive".into());
+        }
+
+        let mut index = Self {
+            size,
+            weak: Vec::new(),
+            strong: Vec::new(),
+        };
+
+        let mut block = vec![0u8; size];
+
+        loop {
+            let mut filled = 0;
+            while filled < size {
+                match reader.read(&mut block[filled..])? {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+
+            if filled < size {
+                break;
+            }
+
+            index.weak.push(Rolling::new(&block).digest());
+            index.strong.push(strong(&block));
+        }
+
+        Ok(index)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Searches the source file for target blocks which lie entirely
+    /// within the given target ranges. The rolling checksum is moved byte
+    /// by byte and jumps over a block when it is found.
+    ///
+    /// # Parameters:
+    /// - `source`: source file contents
+    /// - `ranges`: offsets and lengths of target ranges to search for
+    ///
+    /// # Returns:
+    /// - `Vec<BlockMatch>`: found blocks ordered by target offset
+    pub fn find_matches(&self, source: &[u8], ranges: &[(u64, u64)]) -> Vec<BlockMatch> {
+        let size = self.size as u64;
+        let mut candidates: HashMap<u32, Vec<usize>> = HashMap::new();
+
+        for &(offset, length) in ranges {
+            let first = offset.div_ceil(size);
+            let end = (offset + length) / size;
+
+            for block in first..end.min(self.weak.len() as u64) {
+                let block = block as usize;
+                candidates.entry(self.weak[block]).or_default().push(block);
+            }
+        }
+
+        let mut matches: Vec<BlockMatch> = Vec::new();
+        if candidates.is_empty() || source.len() < self.size {
+            return matches;
+        }
+
+        let mut found: HashSet<usize> = HashSet::new();
+        let mut position: usize = 0;
+        let mut rolling = Rolling::new(&source[..self.size]);
+
+        loop {
+            let mut jump = false;
+
+            if let Some(blocks) = candidates.get(&rolling.digest()) {
+                let window = &source[position..position + self.size];
+                let hash = strong(window);
+
+                // Identical blocks of the target are all copied from this window
+                for &block in blocks {
+                    if self.strong[block] == hash && found.insert(block) {
+                        matches.push(BlockMatch {
+                            source_offset: position as u64,
+                            offset: block as u64 * size,
+                            length: size,
+                        });
+                        jump = true;
+                    }
+                }
+            }
+
+            if jump {
+                position += self.size;
+                if position + self.size > source.len() {
+                    break;
+                }
+                rolling = Rolling::new(&source[position..position + self.size]);
+            } else {
+                if position + self.size >= source.len() {
+                    break;
+                }
+                rolling.roll(source[position], source[position + self.size]);
+                position += 1;
+            }
+        }
+
+        matches.sort_by_key(|m| m.offset);
+        matches
+    }
+}
+
+fn strong(data: &[u8]) -> u64 {
+    let hash = blake3::hash(data);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+
+    #[test]
+    fn roll_equals_checksum_of_the_moved_window() {
+        let data = random_data(1, 600);
+        let mut rolling = Rolling::new(&data[..512]);
+
+        for position in 1..=data.len() - 512 {
+            rolling.roll(data[position - 1], data[position + 511]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[position..position + 512]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn generate_skips_the_partial_last_block() {
+        let data = random_data(2, 10 * 64 + 10);
+        let index = BlockIndex::generate(&mut &data[..], 64).unwrap();
+
+        assert_eq!(index.weak.len(), 10);
+        assert_eq!(index.strong.len(), 10);
+        assert!(BlockIndex::generate(&mut &data[..], 0).is_err());
+    }
+
+    #[test]
+    fn find_matches_finds_blocks_at_any_offset() {
+        let target = random_data(3, 64 * 16);
+        let mut source = random_data(4, 3);
+        source.extend_from_slice(&target[..64 * 8]);
+        source.extend_from_slice(&random_data(5, 17));
+        source.extend_from_slice(&target[64 * 8..]);
+
+        let index = BlockIndex::generate(&mut &target[..], 64).unwrap();
+        let matches = index.find_matches(&source, &[(0, target.len() as u64)]);
+
+        assert_eq!(matches.len(), 16);
+        for (block, m) in matches.iter().enumerate() {
+            let shift = if block < 8 { 3 } else { 20 };
+            assert_eq!(m.offset, block as u64 * 64);
+            assert_eq!(m.source_offset, m.offset + shift);
+            assert_eq!(m.length, 64);
+        }
+    }
+
+    #[test]
+    fn find_matches_searches_only_blocks_within_the_ranges() {
+        let target = random_data(6, 64 * 8);
+        let index = BlockIndex::generate(&mut &target[..], 64).unwrap();
+
+        // Blocks 2 and 3 lie within the range, 1 and 4 only partly
+        let matches = index.find_matches(&target, &[(100, 200)]);
+
+        let offsets: Vec<u64> = matches.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![128, 192]);
+        assert!(index.find_matches(&target[..63], &[(0, 512)]).is_empty());
+    }
+
+    #[test]
+    fn find_matches_copies_repeated_blocks_from_one_window() {
+        let block = random_data(7, 64);
+        let target = [&block[..], &block[..]].concat();
+        let index = BlockIndex::generate(&mut &target[..], 64).unwrap();
+
+        let source = [&random_data(8, 5)[..], &block[..]].concat();
+        let matches = index.find_matches(&source, &[(0, 128)]);
+
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|m| m.source_offset == 5));
+    }
+}
@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// How tree operations treat symbolic links
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkMode {
+    /// sign the file a link points to, links leading outside the tree are skipped
+    Follow,
+
+    /// record the link target in the manifest instead of the file
+    Preserve,
+
+    /// leave links out
+    Skip,
+}
+
+impl FromStr for SymlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(Self::Follow),
+            "preserve" => Ok(Self::Preserve),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "Unknown symlink mode {}, expected follow, preserve or skip",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns true if both paths refer to the same file, either by path
+/// or by device and inode (hard links, symlinks). Missing files never collide
+/// unless their paths are equal.
+pub fn is_same_file(a: &Path, b: &Path) -> bool {
+    a == b || same_file::is_same_file(a, b).unwrap_or(false)
+}
+
+/// Fails if `output` refers to the same file as one of `inputs`.
+///
+/// # Parameters:
+/// - `output`: path which is going to be written
+/// - `inputs`: paths which are going to be read
+pub fn ensure_distinct(output: &Path, inputs: &[&Path]) -> Result<(), Box<dyn Error>> {
+    for input in inputs {
+        if is_same_file(output, input) {
+            return Err(format!(
+                "Refusing to write {:?}: it is the same file as input {:?}",
+                output, input
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if `path` resolves to a location outside of `root`,
+/// following every link on the way. Paths which can not be resolved,
+/// like dangling links, are considered escaping.
+pub fn escapes(root: &Path, path: &Path) -> bool {
+    match (fs::canonicalize(root), fs::canonicalize(path)) {
+        (Ok(root), Ok(path)) => !path.starts_with(root),
+        _ => true,
+    }
+}
+
+/// Returns true if a link target stored in a manifest stays within the tree
+/// once the link is recreated. Checked lexically, so it holds on the
+/// machine the tree is restored on as well: absolute targets and targets
+/// climbing above the tree root with `..` are rejected.
+///
+/// # Parameters:
+/// - `link`: link path relative to the tree root
+/// - `target`: target stored in the link
+pub fn link_stays_inside(link: &Path, target: &Path) -> bool {
+    // Directories of the link below the root
+    let mut depth = link.components().count() as i64 - 1;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => depth -= 1,
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+
+        if depth < 0 {
+            return false;
+        }
+    }
+
+    true
+}
@@ -0,0 +1,312 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{Cursor, Write};
+
+use crate::builder::{self, RetryPolicy};
+use crate::signature::{Diff, Op, Operation, SignOptions, Signature};
+
+/// Expected results shipped with the crate.
+const GOLDEN: &str = include_str!("../vectors/golden.json");
+
+/// Canonical test case. The source file is generated from a seed, the target
+/// file is the source with a single edit applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vector {
+    pub name: String,
+
+    /// generator input
+    pub seed: u64,
+    pub length: usize,
+
+    /// generate zeros instead of pseudo-random data
+    #[serde(default)]
+    pub zeros: bool,
+
+    /// edit: `remove` bytes at `offset` are replaced with `insert` new bytes
+    pub offset: usize,
+    pub remove: usize,
+    pub insert: usize,
+
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+
+    /// blake3 of the serialized source signature
+    pub source_signature: String,
+
+    /// blake3 of the serialized target signature
+    pub target_signature: String,
+
+    /// blake3 of the operation list, see `describe_operations`
+    pub operations: String,
+
+    /// blake3 of the diff file contents
+    pub patch: String,
+}
+
+/// Result of a single test case.
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+
+    /// description of every check which did not pass
+    pub failures: Vec<String>,
+}
+
+/// Actual results of a test case calculated by this build.
+struct Actual {
+    source_signature: String,
+    target_signature: String,
+    operations: String,
+    patch: String,
+}
+
+/// splitmix64, defined here so the inputs never depend on a crate version.
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(length + 8);
+        while data.len() < length {
+            data.extend_from_slice(&self.next().to_le_bytes());
+        }
+
+        data.truncate(length);
+        data
+    }
+}
+
+impl Vector {
+    /// Generates source and target files of the test case.
+    pub fn files(&self) -> (Vec<u8>, Vec<u8>) {
+        let source = match self.zeros {
+            true => vec![0u8; self.length],
+            false => Generator(self.seed).bytes(self.length),
+        };
+
+        let inserted = Generator(self.seed.wrapping_add(1)).bytes(self.insert);
+
+        let mut target = source[..self.offset].to_vec();
+        target.extend_from_slice(&inserted);
+        target.extend_from_slice(&source[self.offset + self.remove..]);
+
+        (source, target)
+    }
+
+    fn options(&self) -> SignOptions {
+        SignOptions {
+            min_size: self.min_size,
+            avg_size: self.avg_size,
+            max_size: self.max_size,
+            ..Default::default()
+        }
+    }
+
+    /// Runs the case through this build.
+    fn calculate(&self) -> Result<(Actual, Vec<String>), Box<dyn Error>> {
+        let (source, target) = self.files();
+        let mut failures: Vec<String> = Vec::new();
+
+        let source_sig = sign(&source, &self.options(), &mut failures)?;
+        let target_sig = sign(&target, &self.options(), &mut failures)?;
+
+        let diff = Diff::new(&source_sig, &target_sig);
+        let operations: &[Operation] = match &diff {
+            Some(diff) => diff.operations(),
+            None => &[],
+        };
+
+        let inserts = diff.iter().flat_map(|diff| diff.insert_ops());
+
+        let mut patch: Vec<u8> = Vec::new();
+        builder::build_local_diff_file(
+            &mut Cursor::new(&target),
+            &mut patch,
+            inserts.clone(),
+            &RetryPolicy::default(),
+        )?;
+
+        let segments =
+            builder::fetch_segments(&mut Cursor::new(&target), inserts, &RetryPolicy::default())?;
+
+        if !operations.is_empty() {
+            match builder::build_in_memory(&source, &target_sig, operations, &segments) {
+                Ok(built) if built == target => {}
+                Ok(_) => failures.push(String::from("reconstructed file differs from target")),
+                Err(e) => failures.push(format!("reconstruction failed: {}", e)),
+            }
+        }
+
+        let actual = Actual {
+            source_signature: digest(serde_json::to_string(&source_sig)?.as_bytes()),
+            target_signature: digest(serde_json::to_string(&target_sig)?.as_bytes()),
+            operations: digest(describe_operations(operations).as_bytes()),
+            patch: digest(&patch),
+        };
+
+        Ok((actual, failures))
+    }
+
+    /// Returns the case with expected values replaced by the actual ones.
+    fn regenerate(&self) -> Result<Self, Box<dyn Error>> {
+        let (actual, _) = self.calculate()?;
+
+        Ok(Self {
+            source_signature: actual.source_signature,
+            target_signature: actual.target_signature,
+            operations: actual.operations,
+            patch: actual.patch,
+            ..self.clone()
+        })
+    }
+
+    /// Runs the case and compares results with the expected ones.
+    fn check(&self) -> Result<Outcome, Box<dyn Error>> {
+        let (actual, mut failures) = self.calculate()?;
+
+        let pairs = [
+            (
+                "source signature",
+                &self.source_signature,
+                &actual.source_signature,
+            ),
+            (
+                "target signature",
+                &self.target_signature,
+                &actual.target_signature,
+            ),
+            ("operations", &self.operations, &actual.operations),
+            ("patch", &self.patch, &actual.patch),
+        ];
+
+        for (what, expected, actual) in pairs {
+            if expected != actual {
+                failures.push(format!(
+                    "{} mismatch: expected {}, got {}",
+                    what, expected, actual
+                ));
+            }
+        }
+
+        Ok(Outcome {
+            name: self.name.clone(),
+            failures,
+        })
+    }
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Returns the canonical test cases.
+pub fn vectors() -> Result<Vec<Vector>, Box<dyn Error>> {
+    Ok(serde_json::from_str(GOLDEN)?)
+}
+
+/// Validates this build against the canonical test cases.
+///
+/// # Returns:
+/// - `Result<Vec<Outcome>, Box<dyn Error>>`: outcome of every test case
+pub fn run() -> Result<Vec<Outcome>, Box<dyn Error>> {
+    vectors()?.iter().map(Vector::check).collect()
+}
+
+/// Recalculates expected values of the canonical test cases with this build.
+/// Used to produce `vectors/golden.json` after an intended format change.
+pub fn regenerate() -> Result<Vec<Vector>, Box<dyn Error>> {
+    vectors()?.iter().map(Vector::regenerate).collect()
+}
+
+/// Signs data with every generation path and checks they agree.
+fn sign(
+    data: &[u8],
+    options: &SignOptions,
+    failures: &mut Vec<String>,
+) -> Result<Signature, Box<dyn Error>> {
+    let sig = Signature::generate_with_options(&mut Cursor::new(data), options)?;
+    let expected = serde_json::to_string(&sig)?;
+
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(data)?;
+    file.flush()?;
+
+    let variants = [
+        (
+            "threaded",
+            SignOptions {
+                threads: 2,
+                ..*options
+            },
+            false,
+        ),
+        (
+            "mapped",
+            SignOptions {
+                mmap: true,
+                ..*options
+            },
+            true,
+        ),
+        (
+            "mapped threaded",
+            SignOptions {
+                mmap: true,
+                threads: 2,
+                ..*options
+            },
+            true,
+        ),
+    ];
+
+    for (name, variant, from_file) in variants {
+        let other = match from_file {
+            true => Signature::generate_file(file.path(), &variant)?,
+            false => Signature::generate_with_options(&mut Cursor::new(data), &variant)?,
+        };
+
+        if serde_json::to_string(&other)? != expected {
+            failures.push(format!("{} signature differs from streaming one", name));
+        }
+    }
+
+    Ok(sig)
+}
+
+/// Returns operations as text, one per line. Op ids are random and left out.
+fn describe_operations(operations: &[Operation]) -> String {
+    operations
+        .iter()
+        .map(|op| match op {
+            Operation::COPY(cp) => format!(
+                "COPY {} {} {}\n",
+                cp.source_offset(),
+                cp.offset(),
+                cp.length()
+            ),
+            Operation::INSERT(ins) => format!("INSERT {} {}\n", ins.offset(), ins.length()),
+            Operation::DELTA(delta) => format!(
+                "DELTA {} {} {} {}\n",
+                delta.source_offset(),
+                delta.source_length(),
+                delta.offset(),
+                delta.length()
+            ),
+            Operation::ZERO(zero) => format!("ZERO {} {}\n", zero.offset(), zero.length()),
+        })
+        .collect()
+}
+
+fn digest(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
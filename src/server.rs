@@ -0,0 +1,459 @@
+use std::error::Error;
+use std::fs::{self, File};
+#[cfg(feature = "grpc")]
+use std::io::Write;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response};
+use tracing::{debug_span, info, warn};
+
+use crate::builder::RetryPolicy;
+use crate::metrics;
+use crate::naming::NamingStrategy;
+use crate::patch;
+#[cfg(feature = "grpc")]
+use crate::signature::SignOptions;
+use crate::signature::{Diff, Signature};
+
+/// Prefix of object URLs: `/objects/{name}/signature`, `/objects/{name}/patch`
+const OBJECTS: &str = "/objects/";
+
+/// Default limit of uploaded signatures, enough for a file of tens of GB
+pub const DEFAULT_MAX_BODY: u64 = 256 * 1024 * 1024;
+
+/// Diff service: clients upload signatures of their files and get back
+/// patches which build the canonical objects from them, so they do no
+/// chunk matching themselves. Objects are files under the root directory
+/// signed with `sign`, a patch is applied with `apply`.
+#[derive(Clone)]
+pub struct DiffService {
+    root: PathBuf,
+    naming: NamingStrategy,
+    policy: RetryPolicy,
+    max_body: u64,
+}
+
+/// Failed request: HTTP status and the message sent to the client.
+pub(crate) struct Rejection {
+    pub(crate) status: u16,
+    pub(crate) message: String,
+}
+
+impl Rejection {
+    pub(crate) fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for Rejection {
+    fn from(e: Box<dyn Error>) -> Self {
+        Self::new(500, e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Rejection {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(500, e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Rejection {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(500, e.to_string())
+    }
+}
+
+impl DiffService {
+    pub fn new(root: &Path, naming: NamingStrategy, policy: RetryPolicy) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            naming,
+            policy,
+            max_body: DEFAULT_MAX_BODY,
+        }
+    }
+
+    /// Sets the largest signature in bytes a client may upload.
+    pub fn with_max_body(self, max_body: u64) -> Self {
+        Self { max_body, ..self }
+    }
+
+    /// Serves requests on `addr` until the process is stopped, `workers`
+    /// threads handle them, the rest wait in the queue of the server.
+    pub fn serve(self, addr: &str, workers: usize) -> Result<(), Box<dyn Error>> {
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| format!("Can not listen on {}: {}", addr, e))?;
+        let server = Arc::new(server);
+        let service = Arc::new(self);
+
+        let handles: Vec<thread::JoinHandle<()>> = (0..workers.max(1))
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let service = Arc::clone(&service);
+                thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        service.handle(request);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().map_err(|_| "A server worker panicked")?;
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, mut request: Request) {
+        let method = request.method().clone();
+        let url = request.url().to_string();
+
+        let result = match (&method, url.as_str()) {
+            (Method::Get, "/metrics") => {
+                let _ = request.respond(metrics::response());
+                return;
+            }
+            (Method::Get, url) => match object_route(url, "/signature") {
+                Some(name) => self.signature(name),
+                None => Err(Rejection::new(404, "Not found")),
+            },
+            (Method::Post, url) => match object_route(url, "/patch") {
+                Some(name) => self.patch_response(name, &mut request),
+                None => Err(Rejection::new(404, "Not found")),
+            },
+            _ => Err(Rejection::new(405, "Method not allowed")),
+        };
+
+        let responded = match result {
+            Ok(response) => {
+                info!("{} {} {}", method, url, response.status_code().0);
+                request.respond(response)
+            }
+            Err(rejection) => {
+                warn!(
+                    "{} {} {}: {}",
+                    method, url, rejection.status, rejection.message
+                );
+                request.respond(
+                    Response::from_string(rejection.message).with_status_code(rejection.status),
+                )
+            }
+        };
+
+        if let Err(e) = responded {
+            warn!("Can not respond to {} {}: {}", method, url, e);
+        }
+    }
+
+    /// Returns the path of an object. Names are relative to the root,
+    /// anything which leads out of it is rejected.
+    pub(crate) fn object_path(&self, name: &str) -> Result<PathBuf, Rejection> {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Rejection::new(400, format!("Invalid object name {}", name)));
+        }
+
+        let path = self.root.join(relative);
+        if !path.is_file() {
+            return Err(Rejection::new(404, format!("No object {}", name)));
+        }
+
+        Ok(path)
+    }
+
+    /// Returns the object file and its signature.
+    pub(crate) fn object(&self, name: &str) -> Result<(PathBuf, Signature), Rejection> {
+        let path = self.object_path(name)?;
+
+        let sig_path = self.naming.signature_path(&path)?;
+        let sig_file = match File::open(&sig_path) {
+            Ok(file) => file,
+            Err(_) => return Err(Rejection::new(500, format!("{} is not signed", name))),
+        };
+        let sig: Signature = serde_json::from_reader(BufReader::new(sig_file))
+            .map_err(|e| Rejection::new(500, format!("{}: {}", sig_path.display(), e)))?;
+
+        // A stale signature produces patches which do not build the object
+        if fs::metadata(&path)?.len() != sig.length() {
+            return Err(Rejection::new(
+                500,
+                format!("{} changed since it was signed", name),
+            ));
+        }
+
+        if sig.decompressed().is_some() {
+            return Err(Rejection::new(
+                500,
+                format!(
+                    "{} is signed with --decompress, it can not be patched",
+                    name
+                ),
+            ));
+        }
+
+        Ok((path, sig))
+    }
+
+    fn signature(&self, name: &str) -> Result<Response<File>, Rejection> {
+        let (path, _) = self.object(name)?;
+        let sig_file = File::open(self.naming.signature_path(&path)?)?;
+
+        Ok(Response::from_file(sig_file).with_header(content_type("application/json")))
+    }
+
+    fn patch_response(
+        &self,
+        name: &str,
+        request: &mut Request,
+    ) -> Result<Response<File>, Rejection> {
+        // The body is read into memory, so its length is checked first
+        let length = match request.body_length() {
+            Some(length) => length as u64,
+            None => return Err(Rejection::new(411, "Content-Length is required")),
+        };
+        if length > self.max_body {
+            return Err(Rejection::new(
+                413,
+                format!("Signature is larger than {} bytes", self.max_body),
+            ));
+        }
+
+        let client: Signature = serde_json::from_reader(request.as_reader().take(length))
+            .map_err(|e| Rejection::new(400, format!("Invalid signature: {}", e)))?;
+
+        let _span = debug_span!("serve", object = name).entered();
+
+        match self.patch(name, &client)? {
+            Some(patch) => Ok(
+                Response::from_file(patch).with_header(content_type("application/octet-stream"))
+            ),
+            None => Ok(Response::from_file(tempfile::tempfile()?).with_status_code(204)),
+        }
+    }
+
+    /// Builds a patch from the client file to the object.
+    ///
+    /// # Parameters:
+    /// - `name`: object name, relative to the root
+    /// - `client`: signature of the client file
+    ///
+    /// # Returns:
+    /// - `Option<File>`: patch file, rewound, `None` if the client file is equal to the object
+    fn patch(&self, name: &str, client: &Signature) -> Result<Option<File>, Rejection> {
+        let (path, sig, diff) = self.diff(name, client)?;
+
+        match diff {
+            Some(diff) => Ok(Some(self.build_patch(name, &path, &sig, &diff)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Diffs the client signature against the object.
+    ///
+    /// # Returns:
+    /// - object path, its signature and the diff, `None` if the client file is equal to the object
+    pub(crate) fn diff(
+        &self,
+        name: &str,
+        client: &Signature,
+    ) -> Result<(PathBuf, Signature, Option<Diff>), Rejection> {
+        let (path, sig) = self.object(name)?;
+
+        if client.hash_length() != sig.hash_length() {
+            return Err(Rejection::new(
+                400,
+                format!(
+                    "{} is signed with --hash-length {}",
+                    name,
+                    sig.hash_length()
+                ),
+            ));
+        }
+
+        if client.key_id() != sig.key_id() {
+            return Err(Rejection::new(
+                400,
+                format!("Signature is not keyed with the key of {}", name),
+            ));
+        }
+
+        if client.decompressed().is_some() {
+            return Err(Rejection::new(
+                400,
+                "Signatures made with --decompress can not be patched",
+            ));
+        }
+
+        if client.chunk_sizes() != sig.chunk_sizes() {
+            warn!(
+                "{}: signatures have different chunk sizes, few chunks will match",
+                name
+            );
+        }
+
+        let diff = Diff::new_multi(&[client], &sig);
+
+        Ok((path, sig, diff))
+    }
+
+    /// Writes a patch of the diff to a temporary file.
+    ///
+    /// # Returns:
+    /// - `File`: patch file, rewound
+    pub(crate) fn build_patch(
+        &self,
+        name: &str,
+        path: &Path,
+        sig: &Signature,
+        diff: &Diff,
+    ) -> Result<File, Rejection> {
+        let mut patch = patch::build_temporary(path, sig, diff, &self.policy)?;
+        let length = patch.seek(SeekFrom::End(0))?;
+        patch.seek(SeekFrom::Start(0))?;
+
+        metrics::record_patch(length);
+        info!(
+            "{}: {} ranges, {} bytes of patch",
+            name,
+            diff.insert_ops().len(),
+            length
+        );
+
+        Ok(patch)
+    }
+}
+
+/// Object updates of the gRPC API.
+#[cfg(feature = "grpc")]
+impl DiffService {
+    /// Signs an object and saves the signature next to it.
+    ///
+    /// # Parameters:
+    /// - `name`: object name, relative to the root
+    /// - `avg_size`: average chunk size, the one of the current signature or
+    ///   picked from the object size if `None`
+    /// - `hash_length`: bytes of each chunk hash to keep, full hashes if `None`
+    pub(crate) fn sign(
+        &self,
+        name: &str,
+        avg_size: Option<u32>,
+        hash_length: Option<usize>,
+    ) -> Result<Signature, Rejection> {
+        let path = self.object_path(name)?;
+        let length = fs::metadata(&path)?.len();
+
+        let options = SignOptions {
+            mmap: true,
+            ..Default::default()
+        };
+        let options = match (avg_size, self.previous_sizes(&path)) {
+            (Some(avg_size), _) => options.with_avg_size(avg_size),
+            (None, Some(previous)) => options.with_chunk_sizes(previous),
+            (None, None) => options.with_avg_size(SignOptions::auto_avg_size(length)),
+        };
+        let options = SignOptions {
+            hash_length: hash_length.unwrap_or(options.hash_length),
+            ..options
+        };
+
+        options
+            .validate()
+            .map_err(|e| Rejection::new(400, e.to_string()))?;
+
+        let sig = Signature::generate_file(&path, &options)?;
+        self.save_signature(&path, &sig)?;
+        metrics::record_signed(length);
+
+        Ok(sig)
+    }
+
+    /// Returns chunk sizes of the current signature of an object, so it is
+    /// signed again with the same ones.
+    fn previous_sizes(&self, path: &Path) -> Option<crate::signature::ChunkSizes> {
+        let sig_file = File::open(self.naming.signature_path(path).ok()?).ok()?;
+        let sig: Signature = serde_json::from_reader(BufReader::new(sig_file)).ok()?;
+        sig.chunk_sizes()
+    }
+
+    /// Updates an object with a patch built against its current contents
+    /// and signs it again with the same chunk sizes and hash length.
+    ///
+    /// # Parameters:
+    /// - `name`: object name, relative to the root
+    /// - `patch_path`: plain patch file written by diff --patch
+    ///
+    /// # Returns:
+    /// - `Signature`: signature of the new contents
+    pub(crate) fn apply(&self, name: &str, patch_path: &Path) -> Result<Signature, Rejection> {
+        let (path, sig) = self.object(name)?;
+
+        if sig.key_id().is_some() {
+            return Err(Rejection::new(
+                400,
+                format!("{} is keyed, the server can not verify patches of it", name),
+            ));
+        }
+
+        // Written next to the object, so it replaces the object with a rename
+        let directory = path.parent().unwrap_or(&self.root);
+        let new_file = tempfile::NamedTempFile::new_in(directory)?;
+
+        let header = patch::rebuild(&path, patch_path, new_file.path()).map_err(|e| {
+            Rejection::new(400, format!("Can not apply the patch to {}: {}", name, e))
+        })?;
+
+        let mut options = SignOptions {
+            mmap: true,
+            hash_length: sig.hash_length(),
+            ..Default::default()
+        };
+        if let Some(sizes) = sig.chunk_sizes() {
+            options = options.with_chunk_sizes(sizes);
+        }
+
+        let new_sig = Signature::generate_file(new_file.path(), &options)?;
+
+        new_file.persist(&path).map_err(|e| e.error)?;
+        self.save_signature(&path, &new_sig)?;
+
+        info!(
+            "{}: applied {} ops, {} bytes",
+            name,
+            header.operations().len(),
+            new_sig.length()
+        );
+
+        Ok(new_sig)
+    }
+
+    fn save_signature(&self, path: &Path, sig: &Signature) -> Result<(), Rejection> {
+        let sig_path = self.naming.signature_path(path)?;
+        let directory = sig_path.parent().unwrap_or(&self.root);
+
+        let mut sig_file = tempfile::NamedTempFile::new_in(directory)?;
+        sig_file.write_all(serde_json::to_string_pretty(sig)?.as_bytes())?;
+        sig_file.persist(&sig_path).map_err(|e| e.error)?;
+
+        Ok(())
+    }
+}
+
+/// Returns the object name of `/objects/{name}{action}` URLs.
+fn object_route<'a>(url: &'a str, action: &str) -> Option<&'a str> {
+    url.strip_prefix(OBJECTS)?
+        .strip_suffix(action)
+        .filter(|name| !name.is_empty())
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).expect("valid header")
+}
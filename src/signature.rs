@@ -1,44 +1,193 @@
-use fastcdc::v2020::StreamCDC;
+use base64::prelude::{Engine, BASE64_STANDARD};
+#[cfg(feature = "async")]
+use fastcdc::v2020::AsyncStreamCDC;
+use fastcdc::v2020::{self, ChunkData, FastCDC, Normalization, StreamCDC};
+use md5::{Digest, Md5};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::Read;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
 
 use crate::blake3_serde_hex;
-
-/// TODO:
-///
-/// I think, it worth trying to merge CopyOp and InsertOp into a single struct.
-/// This struct would have: kind, target_offset, source_offset, length, uuid.
-/// InsertOp would have both offsets the same.
-///
-/// It may make things simpler.
+use crate::chunk_offset::{self, ChunkOffset};
+use crate::compression::Compression;
+use crate::key::{self, HashKey};
+use crate::metadata::FileMetadata;
+use crate::platform;
+use crate::rolling::BlockIndex;
+use crate::tar;
+use crate::truncated_hash::TruncatedHash;
+
+/// Chunks are hashed in batches of about this many bytes,
+/// which bounds memory used by parallel hashing.
+const BATCH_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Automatic chunk sizes aim for about this many chunks per file.
+const AUTO_CHUNK_COUNT: u64 = 16 * 1024;
+
+/// Bounds of the automatic average chunk size. Smaller files get the
+/// default sizes, the upper bound keeps `max_size` within fastcdc limits.
+const AUTO_MIN_AVG_SIZE: u32 = 16 * 1024;
+const AUTO_MAX_AVG_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Shortest chunk hash prefix a signature can keep, in bytes.
+pub const MIN_HASH_LENGTH: usize = 4;
+
+// TODO:
+//
+// I think, it worth trying to merge CopyOp and InsertOp into a single struct.
+// This struct would have: kind, target_offset, source_offset, length, uuid.
+// InsertOp would have both offsets the same.
+//
+// It may make things simpler.
 
 /// Represents the chunk of a file
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Chunk {
-    length: usize,
-    offset: u64,
+    length: u64,
 
-    #[serde(with = "blake3_serde_hex")]
-    strong_hash: blake3::Hash,
+    /// left out of signature files with implicit offsets, see `SignOptions::implicit_offsets`
+    #[serde(
+        default = "ChunkOffset::missing",
+        skip_serializing_if = "ChunkOffset::is_implicit"
+    )]
+    offset: ChunkOffset,
+
+    /// may be truncated, see `SignOptions::hash_length`
+    strong_hash: TruncatedHash,
+
+    /// unix time when the chunk was first seen, set only if change tracking is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    changed_at: Option<u64>,
+
+    /// crc32c of the chunk, set only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crc32c: Option<u32>,
+
+    /// all bytes of the chunk are zero, never set in keyed signatures
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    zero: bool,
+}
+
+/// Chunk sizes a signature was generated with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSizes {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+/// File format hint, chunk boundaries are aligned to its structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// no alignment
+    #[default]
+    Raw,
+
+    /// chunks start at tar entry headers
+    Tar,
+}
+
+/// Options for signature generation
+#[derive(Debug, Clone, Copy)]
+pub struct SignOptions {
+    /// minimum chunk size in bytes
+    pub min_size: u32,
+
+    /// average chunk size in bytes
+    pub avg_size: u32,
+
+    /// maximum chunk size in bytes
+    pub max_size: u32,
+
+    /// calculate crc32c for each chunk
+    pub crc32c: bool,
+
+    /// calculate md5 of a whole file
+    pub md5: bool,
+
+    /// number of threads used to hash chunks, 0 means all cores
+    pub threads: usize,
+
+    /// memory-map local files instead of streaming them
+    pub mmap: bool,
+
+    /// size of blocks indexed for the rolling hash matcher, 0 means no index
+    pub block_size: usize,
+
+    /// file format hint, aligned formats are always memory-mapped
+    pub format: Format,
+
+    /// key for keyed blake3 hashes, see `key::HashKey`
+    pub key: Option<HashKey>,
+
+    /// number of leading bytes of chunk hashes to keep, the whole-file
+    /// hash is always kept in full to verify the result of a build
+    pub hash_length: usize,
+
+    /// leave chunk offsets out of the signature file, they are restored
+    /// from chunk lengths on load; older versions can not read such files
+    pub implicit_offsets: bool,
 }
 
 /// Represents the signature for a file
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Signature {
     #[serde(with = "blake3_serde_hex")]
     strong_hash: blake3::Hash,
-    length: usize,
+    length: u64,
+
+    #[serde(deserialize_with = "chunk_offset::deserialize_chunks")]
     chunks: Vec<Chunk>,
+
+    /// chunk sizes, not set in signatures of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk_sizes: Option<ChunkSizes>,
+
+    /// hex md5 of a whole file, set only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    md5: Option<String>,
+
+    /// fixed-size block checksums, set only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blocks: Option<BlockIndex>,
+
+    /// compression of the file, set if the signature describes decompressed contents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decompressed: Option<Compression>,
+
+    /// hex blake3 of the compressed file, set with `decompressed`, a file
+    /// compressed again is checked against it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compressed_hash: Option<String>,
+
+    /// id of the key hashes are keyed with, set only for keyed signatures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+
+    /// length of chunk hashes in bytes, set only if they are truncated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash_length: Option<usize>,
+
+    /// modification time, permissions and ownership, set only if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<FileMetadata>,
 }
 
 /// CopyOp represents COPY operation for a target diff.
 /// COPY takes the segment of a source file and copies
 /// it to a destination file.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct CopyOp {
+    /// index of the source file, non-zero only for additional seed files
+    source_index: usize,
+
     //// offset in the source file (used for download/copy)
     source_offset: u64,
 
@@ -46,38 +195,81 @@ pub struct CopyOp {
     offset: u64,
 
     /// length of the segment
-    length: usize,
+    length: u64,
 }
 
 /// InsertOp represents INSERT operation for a target diff.
 /// INSERT takes the segment of a target file and copies it
 /// to a destination file.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct InsertOp {
     /// offset in the target file
     offset: u64,
 
     /// length of the segment
-    length: usize,
+    length: u64,
+
+    /// id used to navigate diff file
+    uuid: uuid::Uuid,
+
+    /// offset within the diff file segment, non-zero only for chunks
+    /// which repeat data fetched by another op
+    segment_offset: u64,
+}
+
+/// DeltaOp represents DELTA operation for a target diff.
+/// DELTA patches a range of the source file with a binary delta
+/// taken from the diff file and writes the result to a destination file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct DeltaOp {
+    /// offset in the target file
+    offset: u64,
+
+    /// length of the segment
+    length: u64,
 
     /// id used to navigate diff file
     uuid: uuid::Uuid,
+
+    /// offset of the patched range in the source file
+    source_offset: u64,
+
+    /// length of the patched range
+    source_length: u64,
+}
+
+/// ZeroOp represents ZERO operation for a target diff.
+/// ZERO fills a segment of a destination file with zeros,
+/// nothing is read or transferred for it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ZeroOp {
+    /// offset in the target file
+    offset: u64,
+
+    /// length of the segment
+    length: u64,
 }
 
-/// Represents an INSERT or COPY operation in a sequential list
-#[derive(Debug, PartialEq, Eq)]
+/// Represents an INSERT, COPY, DELTA or ZERO operation in a sequential list
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Operation {
     INSERT(InsertOp),
     COPY(CopyOp),
+    DELTA(DeltaOp),
+    ZERO(ZeroOp),
 }
 
 /// Represents difference between two files.
 #[derive(Debug)]
 pub struct Diff {
     insert_ops: Vec<InsertOp>,
+    reused_ops: Vec<InsertOp>,
+    delta_ops: Vec<DeltaOp>,
+    zero_ops: Vec<ZeroOp>,
     copy_ops: Vec<CopyOp>,
-    copy_length: usize,
-    insert_length: usize,
+    copy_length: u64,
+    insert_length: u64,
     operations: Vec<Operation>,
 }
 
@@ -90,6 +282,24 @@ impl PartialEq for Signature {
 
 impl Eq for Signature {}
 
+/// Returns the id of a diff file segment, derived from the data it starts
+/// with and its offset in the target file, so the same signatures always
+/// make the same diff file.
+///
+/// # Parameters:
+/// - `origin`: hash of the first chunk, or the id of the op the segment is split from
+/// - `offset`: offset of the segment in the target file
+fn segment_id(origin: &[u8], offset: u64) -> uuid::Uuid {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(origin);
+    hasher.update(&offset.to_le_bytes());
+
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
+
 /// Represents op which can be chained
 trait ChainableOp {
     /// Returns true if the current chunk precedes the given chunk
@@ -112,7 +322,7 @@ trait ChainableOp {
     /// # Parameters:
     ///
     /// - `length`: length to extend the current chunk by.
-    fn chain(&mut self, length: usize);
+    fn chain(&mut self, length: u64);
 }
 
 pub trait Op {
@@ -125,28 +335,39 @@ pub trait Op {
     /// Returns length of current op
     ///
     /// # Returns:
-    /// - `usize`: chunk size
-    fn length(&self) -> usize;
+    /// - `u64`: chunk size
+    fn length(&self) -> u64;
 }
 
 impl ChainableOp for CopyOp {
     fn can_chain(&self, other: &Self) -> bool {
-        self.source_offset + (self.length as u64) == other.source_offset
-            && self.offset + (self.length as u64) == other.offset
+        self.source_index == other.source_index
+            && self.source_offset + self.length == other.source_offset
+            && self.offset + self.length == other.offset
     }
 
-    fn chain(&mut self, length: usize) {
-        self.length = self.length + length
+    fn chain(&mut self, length: u64) {
+        self.length += length
     }
 }
 
 impl ChainableOp for InsertOp {
     fn can_chain(&self, other: &Self) -> bool {
-        self.offset + (self.length as u64) == other.offset
+        self.offset + self.length == other.offset
+    }
+
+    fn chain(&mut self, length: u64) {
+        self.length += length
+    }
+}
+
+impl ChainableOp for ZeroOp {
+    fn can_chain(&self, other: &Self) -> bool {
+        self.offset + self.length == other.offset
     }
 
-    fn chain(&mut self, length: usize) {
-        self.length = self.length + length
+    fn chain(&mut self, length: u64) {
+        self.length += length
     }
 }
 
@@ -155,7 +376,7 @@ impl Op for CopyOp {
         self.offset
     }
 
-    fn length(&self) -> usize {
+    fn length(&self) -> u64 {
         self.length
     }
 }
@@ -165,12 +386,36 @@ impl Op for InsertOp {
         self.offset
     }
 
-    fn length(&self) -> usize {
+    fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+impl Op for DeltaOp {
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+impl Op for ZeroOp {
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn length(&self) -> u64 {
         self.length
     }
 }
 
 impl CopyOp {
+    pub fn source_index(&self) -> usize {
+        self.source_index
+    }
+
     pub fn source_offset(&self) -> u64 {
         self.source_offset
     }
@@ -180,6 +425,24 @@ impl InsertOp {
     pub fn uuid(&self) -> uuid::Uuid {
         self.uuid
     }
+
+    pub fn segment_offset(&self) -> u64 {
+        self.segment_offset
+    }
+}
+
+impl DeltaOp {
+    pub fn uuid(&self) -> uuid::Uuid {
+        self.uuid
+    }
+
+    pub fn source_offset(&self) -> u64 {
+        self.source_offset
+    }
+
+    pub fn source_length(&self) -> u64 {
+        self.source_length
+    }
 }
 
 impl From<InsertOp> for Operation {
@@ -194,11 +457,34 @@ impl From<CopyOp> for Operation {
     }
 }
 
+impl From<DeltaOp> for Operation {
+    fn from(op: DeltaOp) -> Self {
+        Self::DELTA(op)
+    }
+}
+
+impl From<ZeroOp> for Operation {
+    fn from(op: ZeroOp) -> Self {
+        Self::ZERO(op)
+    }
+}
+
 impl Operation {
     pub fn offset(&self) -> u64 {
         match self {
             Self::COPY(op) => op.offset(),
             Self::INSERT(op) => op.offset(),
+            Self::DELTA(op) => op.offset(),
+            Self::ZERO(op) => op.offset(),
+        }
+    }
+
+    pub fn length(&self) -> u64 {
+        match self {
+            Self::COPY(op) => op.length(),
+            Self::INSERT(op) => op.length(),
+            Self::DELTA(op) => op.length(),
+            Self::ZERO(op) => op.length(),
         }
     }
 }
@@ -215,6 +501,207 @@ impl Ord for Operation {
     }
 }
 
+impl Chunk {
+    pub fn offset(&self) -> u64 {
+        self.offset.value()
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Sets the offset if it was left out of the signature file.
+    pub(crate) fn restore_offset(&mut self, offset: u64) {
+        if self.offset.is_implicit() {
+            self.offset = ChunkOffset::new(offset, true);
+        }
+    }
+
+    /// Returns hash of the chunk, truncated hashes are padded with zeros.
+    pub fn strong_hash(&self) -> blake3::Hash {
+        self.strong_hash.hash()
+    }
+
+    pub fn changed_at(&self) -> Option<u64> {
+        self.changed_at
+    }
+
+    pub fn crc32c(&self) -> Option<u32> {
+        self.crc32c
+    }
+
+    /// Returns true if all bytes of the chunk are zero.
+    pub fn is_zero(&self) -> bool {
+        self.zero
+    }
+
+    /// Hashes chunk data according to the options.
+    fn from_data(offset: u64, data: &[u8], options: &SignOptions) -> Self {
+        Self::from_hashed(offset, data, key::hash(options.key.as_ref(), data), options)
+    }
+
+    /// Creates chunk for data which strong hash is already known.
+    fn from_hashed(
+        offset: u64,
+        data: &[u8],
+        strong_hash: blake3::Hash,
+        options: &SignOptions,
+    ) -> Self {
+        Self {
+            length: data.len() as u64,
+            offset: ChunkOffset::new(offset, options.implicit_offsets),
+            strong_hash: TruncatedHash::new(strong_hash, options.hash_length),
+            changed_at: None,
+            crc32c: options.crc32c.then(|| crc32c::crc32c(data)),
+            zero: options.key.is_none() && data.iter().all(|&b| b == 0),
+        }
+    }
+}
+
+impl Default for SignOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 4096,
+            avg_size: 16384,
+            max_size: 65536,
+            crc32c: false,
+            md5: false,
+            threads: 1,
+            mmap: false,
+            block_size: 0,
+            format: Format::Raw,
+            key: None,
+            hash_length: blake3::OUT_LEN,
+            implicit_offsets: false,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "tar" => Ok(Self::Tar),
+            _ => Err(format!("Unknown format {}, expected raw or tar", s)),
+        }
+    }
+}
+
+impl SignOptions {
+    /// Returns average chunk size suitable for a file of the given length:
+    /// the default one for files up to 256 MiB, growing with the file length
+    /// for larger files.
+    pub fn auto_avg_size(length: u64) -> u32 {
+        let avg_size = (length / AUTO_CHUNK_COUNT).next_power_of_two();
+        avg_size.clamp(AUTO_MIN_AVG_SIZE as u64, AUTO_MAX_AVG_SIZE as u64) as u32
+    }
+
+    /// Sets the average chunk size, minimum and maximum sizes are set
+    /// to a quarter and four times of it.
+    pub fn with_avg_size(self, avg_size: u32) -> Self {
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+            ..self
+        }
+    }
+
+    /// Sets the chunk sizes another signature was generated with.
+    pub fn with_chunk_sizes(self, sizes: ChunkSizes) -> Self {
+        Self {
+            min_size: sizes.min_size,
+            avg_size: sizes.avg_size,
+            max_size: sizes.max_size,
+            ..self
+        }
+    }
+
+    /// Checks chunk sizes against fastcdc bounds and each other,
+    /// fastcdc panics on sizes out of its bounds.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let bounds = [
+            // min and max default to a fraction of avg, so avg goes first
+            ("avg", self.avg_size, v2020::AVERAGE_MIN, v2020::AVERAGE_MAX),
+            ("min", self.min_size, v2020::MINIMUM_MIN, v2020::MINIMUM_MAX),
+            ("max", self.max_size, v2020::MAXIMUM_MIN, v2020::MAXIMUM_MAX),
+        ];
+
+        for (name, size, lower, upper) in bounds {
+            if size < lower || size > upper {
+                return Err(format!(
+                    "{} chunk size {} is out of range, it must be from {} to {}",
+                    name, size, lower, upper
+                )
+                .into());
+            }
+        }
+
+        if self.min_size > self.avg_size {
+            return Err(format!(
+                "min chunk size {} is larger than avg chunk size {}",
+                self.min_size, self.avg_size
+            )
+            .into());
+        }
+
+        if self.avg_size > self.max_size {
+            return Err(format!(
+                "avg chunk size {} is larger than max chunk size {}",
+                self.avg_size, self.max_size
+            )
+            .into());
+        }
+
+        // Plain checksums tell equal data of keyed signatures apart
+        if self.key.is_some() && (self.crc32c || self.md5 || self.block_size > 0) {
+            return Err("crc32c, md5 and block index can not be used with a key".into());
+        }
+
+        if self.hash_length < MIN_HASH_LENGTH || self.hash_length > blake3::OUT_LEN {
+            return Err(format!(
+                "hash length {} is out of range, it must be from {} to {}",
+                self.hash_length,
+                MIN_HASH_LENGTH,
+                blake3::OUT_LEN
+            )
+            .into());
+        }
+
+        // Builds from truncated hashes are verified against the plain whole-file hash
+        if self.key.is_some() && self.truncated_length().is_some() {
+            return Err("truncated hashes can not be used with a key".into());
+        }
+
+        Ok(())
+    }
+
+    pub fn chunk_sizes(&self) -> ChunkSizes {
+        ChunkSizes {
+            min_size: self.min_size,
+            avg_size: self.avg_size,
+            max_size: self.max_size,
+        }
+    }
+
+    /// Returns id of the key, see `key::id`.
+    pub fn key_id(&self) -> Option<String> {
+        self.key.as_ref().map(key::id)
+    }
+
+    /// Returns chunk hash length if hashes are truncated.
+    pub fn truncated_length(&self) -> Option<usize> {
+        (self.hash_length < blake3::OUT_LEN).then_some(self.hash_length)
+    }
+
+    /// Returns seed of the chunker gear table, see `key::gear_seed`.
+    fn gear_seed(&self) -> u64 {
+        key::gear_seed(self.key.as_ref())
+    }
+}
+
 impl Signature {
     /// Generates file signature. Uses `fastcdc` to split file into chunks.
     /// Calculates blake3 strong hash for each chunk.
@@ -234,113 +721,838 @@ impl Signature {
         avg_size: u32,
         max_size: u32,
     ) -> Result<Self, Box<dyn Error>> {
-        let mut hasher = blake3::Hasher::new();
-        let mut chunks: Vec<Chunk> = Vec::new();
-        let mut length: usize = 0;
+        Self::generate_with_options(
+            reader,
+            &SignOptions {
+                min_size,
+                avg_size,
+                max_size,
+                ..Default::default()
+            },
+        )
+    }
 
-        let chunker = StreamCDC::new(reader, min_size, avg_size, max_size);
-        for source_chunk in chunker {
-            let source_chunk = source_chunk?;
-            hasher.update(&source_chunk.data);
+    /// Generates file signature with the given options.
+    ///
+    /// # Parameters:
+    ///
+    /// - `reader`: source file reader
+    /// - `options`: chunking parameters and optional checksums
+    ///
+    /// # Returns:
+    /// - `Result<Self, Box<dyn Error>>`: signature for a file or error
+    pub fn generate_with_options(
+        reader: &mut dyn Read,
+        options: &SignOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        options.validate()?;
+        if options.format != Format::Raw {
+            return Err("Aligned formats can be signed only from a local file".into());
+        }
 
-            let strong_hash = blake3::hash(&source_chunk.data);
-            let chunk = Chunk {
-                length: source_chunk.length,
-                offset: source_chunk.offset,
-                strong_hash,
-            };
+        let pool = Self::build_pool(options)?;
+
+        let mut hasher = key::hasher(options.key.as_ref());
+        let mut sig = Self::generate_chunks(reader, options, pool.as_ref(), Some(&mut hasher))?;
+        sig.strong_hash = hasher.finalize();
+
+        Ok(sig)
+    }
+
+    /// Async variant of `generate_with_options` for a stream behind an async
+    /// client. Chunks are hashed as they arrive, on the calling task.
+    ///
+    /// # Parameters:
+    ///
+    /// - `reader`: source file reader
+    /// - `options`: chunking parameters and optional checksums
+    ///
+    /// # Returns:
+    /// - `Result<Self, Box<dyn Error>>`: signature for a file or error
+    #[cfg(feature = "async")]
+    pub async fn generate_async<R>(reader: R, options: &SignOptions) -> Result<Self, Box<dyn Error>>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio_stream::StreamExt;
+
+        options.validate()?;
+        if options.format != Format::Raw {
+            return Err("Aligned formats can be signed only from a local file".into());
+        }
 
-            length += chunk.length;
+        let mut hasher = key::hasher(options.key.as_ref());
+        let mut md5_hasher = options.md5.then(Md5::new);
+        let mut chunks: Vec<Chunk> = Vec::new();
 
-            chunks.push(chunk);
+        let mut chunker = AsyncStreamCDC::with_level_and_seed(
+            reader,
+            options.min_size,
+            options.avg_size,
+            options.max_size,
+            Normalization::Level1,
+            options.gear_seed(),
+        );
+        let mut stream = std::pin::pin!(chunker.as_stream());
+
+        while let Some(source_chunk) = stream.next().await {
+            let source_chunk = source_chunk?;
+            chunks.extend(Self::hash_batch(
+                std::slice::from_ref(&source_chunk),
+                options,
+                None,
+                Some(&mut hasher),
+                md5_hasher.as_mut(),
+            ));
         }
 
-        let strong_hash = hasher.finalize();
+        let length = chunks.iter().map(|c| c.length).sum();
 
         Ok(Self {
-            strong_hash,
+            strong_hash: hasher.finalize(),
             chunks,
             length,
+            md5: md5_hasher.map(|h| format!("{:x}", h.finalize())),
+            chunk_sizes: Some(options.chunk_sizes()),
+            blocks: None,
+            decompressed: None,
+            compressed_hash: None,
+            key_id: options.key_id(),
+            hash_length: options.truncated_length(),
+            metadata: None,
         })
     }
 
-    /// Returns a map of chunks by strong hash
-    pub(crate) fn chunks_map(&self) -> HashMap<blake3::Hash, &Chunk> {
-        let mut m = HashMap::<blake3::Hash, &Chunk>::new();
-
-        for chunk in &self.chunks {
-            m.entry(chunk.strong_hash).or_insert(&chunk);
-        }
+    /// Generates signature for a local file. When more than one thread is
+    /// requested, chunks are hashed on the thread pool while the whole-file
+    /// hash is updated in the same pass over the file.
+    ///
+    /// # Parameters:
+    ///
+    /// - `path`: path to a local file
+    /// - `options`: chunking parameters and optional checksums
+    ///
+    /// # Returns:
+    /// - `Result<Self, Box<dyn Error>>`: signature for a file or error
+    pub fn generate_file(path: &Path, options: &SignOptions) -> Result<Self, Box<dyn Error>> {
+        options.validate()?;
 
-        m
-    }
+        let mut sig = Self::generate_file_chunks(path, options)?;
+        sig.index_blocks(path, options)?;
 
-    /// Returns total length of a file.
-    pub fn length(&self) -> usize {
-        self.length
+        Ok(sig)
     }
-}
-
-impl Diff {
-    pub fn new(source: &Signature, target: &Signature) -> Option<Self> {
-        if source == target {
-            return None;
-        }
-
-        let mut copy_ops: Vec<CopyOp> = Vec::new();
-        let mut insert_ops: Vec<InsertOp> = Vec::new();
-        let mut copy_length: usize = 0;
-        let mut insert_length: usize = 0;
-        let mut operations: Vec<Operation> = Vec::new();
 
-        let source_map = source.chunks_map();
+    fn generate_file_chunks(path: &Path, options: &SignOptions) -> Result<Self, Box<dyn Error>> {
+        if options.mmap || options.format != Format::Raw {
+            let file = platform::open_shared(path)?;
+            // The file must not be modified while the signature is being generated,
+            // the same as for the streaming path.
+            let map = unsafe { Mmap::map(&file)? };
+            let pool = Self::build_pool(options)?;
 
-        for target_chunk in target.chunks.iter() {
-            // If we have a chunk in the source file - use it
-            if let Some(source_chunk) = source_map.get(&target_chunk.strong_hash) {
-                let op = Self::create_copy_op(source_chunk, target_chunk, &mut copy_ops);
-                copy_length += op.length();
-            } else {
-                let op = Self::create_insert_op(target_chunk, &mut insert_ops);
-                insert_length += op.length();
-            }
+            return Ok(Self::generate_mapped(&map, options, pool.as_ref()));
         }
 
-        for op in &copy_ops {
-            operations.push(op.clone().into());
-        }
+        let mut reader = BufReader::new(platform::open_shared(path)?);
 
-        for op in &insert_ops {
-            operations.push(op.clone().into());
-        }
+        let pool = match Self::build_pool(options)? {
+            Some(pool) => pool,
+            None => return Self::generate_with_options(&mut reader, options),
+        };
 
-        operations.sort();
+        // The whole-file hash is updated while the pool hashes chunks, the file is read once
+        let mut hasher = key::hasher(options.key.as_ref());
+        let mut sig = Self::generate_chunks(&mut reader, options, Some(&pool), Some(&mut hasher))?;
+        sig.strong_hash = hasher.finalize();
 
-        Some(Self {
-            operations,
-            copy_length,
-            insert_length,
-            copy_ops,
-            insert_ops,
-        })
+        Ok(sig)
     }
 
-    /// Creates new CopyOp from source and target chunks. Adds it to copy_ops
-    /// vec or extends last copy_op if copies are sequential.
+    /// Generates signature for decompressed contents of a local file.
+    /// Compressed data changes everywhere after a small edit, decompressed
+    /// data does not.
     ///
     /// # Parameters:
-    /// - `source_chunk`: Chunk of a source file
-    /// - `target_chunk`: Chunk of a target file
-    /// - `ops`: Borrowed reference to a copy ops array.
+    ///
+    /// - `path`: path to a compressed local file
+    /// - `compression`: compression of the file
+    /// - `options`: chunking parameters and optional checksums
     ///
     /// # Returns:
-    /// - `usize`: Length of the created chunk.
-    fn create_copy_op(source_chunk: &Chunk, target_chunk: &Chunk, ops: &mut Vec<CopyOp>) -> CopyOp {
+    /// - `Result<Self, Box<dyn Error>>`: signature for a file or error
+    pub fn generate_decompressed(
+        path: &Path,
+        compression: Compression,
+        options: &SignOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let open = || compression.decoder(BufReader::new(platform::open_shared(path)?));
+
+        let mut sig = Self::generate_with_options(&mut open()?, options)?;
+        sig.decompressed = Some(compression);
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(platform::open_shared(path)?)?;
+        sig.compressed_hash = Some(hasher.finalize().to_hex().to_string());
+
+        if options.block_size > 0 {
+            sig.blocks = Some(BlockIndex::generate(&mut open()?, options.block_size)?);
+        }
+
+        Ok(sig)
+    }
+
+    /// Generates signature for a new version of a local file, reusing chunk
+    /// boundaries of its previous signature. Unchanged leading and trailing
+    /// chunks are only verified against their hashes, chunking runs over the
+    /// changed middle until it lines up with the unchanged tail again.
+    /// The result is the same as of `generate_file`. Falls back to
+    /// `generate_file` if memory mapping is disabled.
+    ///
+    /// # Parameters:
+    ///
+    /// - `path`: path to a local file
+    /// - `previous`: previous signature of the file, generated with the same chunk sizes
+    /// - `options`: chunking parameters and optional checksums
+    ///
+    /// # Returns:
+    /// - `Result<Self, Box<dyn Error>>`: signature for a file or error
+    pub fn generate_file_from(
+        path: &Path,
+        previous: &Signature,
+        options: &SignOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !options.mmap || options.format != Format::Raw {
+            return Self::generate_file(path, options);
+        }
+
+        options.validate()?;
+
+        let file = platform::open_shared(path)?;
+        // See `generate_file`
+        let map = unsafe { Mmap::map(&file)? };
+        let pool = Self::build_pool(options)?;
+
+        let mut sig = Self::generate_mapped_from(&map, previous, options, pool.as_ref());
+        sig.index_blocks(path, options)?;
+
+        Ok(sig)
+    }
+
+    /// Reads the file once more to index its fixed-size blocks
+    /// if `block_size` is set.
+    fn index_blocks(&mut self, path: &Path, options: &SignOptions) -> Result<(), Box<dyn Error>> {
+        if options.block_size > 0 {
+            let mut reader = BufReader::new(platform::open_shared(path)?);
+            self.blocks = Some(BlockIndex::generate(&mut reader, options.block_size)?);
+        }
+
+        Ok(())
+    }
+
+    /// Returns thread pool for hashing if more than one thread is requested.
+    fn build_pool(options: &SignOptions) -> Result<Option<ThreadPool>, Box<dyn Error>> {
+        match options.threads {
+            1 => Ok(None),
+            threads => Ok(Some(ThreadPoolBuilder::new().num_threads(threads).build()?)),
+        }
+    }
+
+    /// Splits the stream into chunks and hashes them. The whole-file hash
+    /// is fed into `hasher` if it is given, and left empty in the result.
+    fn generate_chunks(
+        reader: &mut dyn Read,
+        options: &SignOptions,
+        pool: Option<&ThreadPool>,
+        mut hasher: Option<&mut blake3::Hasher>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut md5_hasher = options.md5.then(Md5::new);
+        let mut chunks: Vec<Chunk> = Vec::new();
+
+        let mut batch: Vec<ChunkData> = Vec::new();
+        let mut batch_length: usize = 0;
+
+        let chunker = StreamCDC::with_level_and_seed(
+            reader,
+            options.min_size,
+            options.avg_size,
+            options.max_size,
+            Normalization::Level1,
+            options.gear_seed(),
+        );
+        for source_chunk in chunker {
+            let source_chunk = source_chunk?;
+            batch_length += source_chunk.length;
+            batch.push(source_chunk);
+
+            if batch_length >= BATCH_LENGTH {
+                let hashed = Self::hash_batch(
+                    &batch,
+                    options,
+                    pool,
+                    hasher.as_deref_mut(),
+                    md5_hasher.as_mut(),
+                );
+                chunks.extend(hashed);
+
+                batch.clear();
+                batch_length = 0;
+            }
+        }
+
+        let hashed = Self::hash_batch(&batch, options, pool, hasher, md5_hasher.as_mut());
+        chunks.extend(hashed);
+
+        let length = chunks.iter().map(|c| c.length).sum();
+        let strong_hash = blake3::Hash::from([0; 32]);
+        let md5 = md5_hasher.map(|h| format!("{:x}", h.finalize()));
+
+        Ok(Self {
+            strong_hash,
+            chunks,
+            length,
+            md5,
+            chunk_sizes: Some(options.chunk_sizes()),
+            blocks: None,
+            decompressed: None,
+            compressed_hash: None,
+            key_id: options.key_id(),
+            hash_length: options.truncated_length(),
+            metadata: None,
+        })
+    }
+
+    /// Hashes a batch of chunks. Chunk hashes are calculated on the pool
+    /// if it is given, while the whole-file hashers are updated in order.
+    ///
+    /// # Returns:
+    /// - `Vec<Chunk>`: chunks of the batch in the file order
+    fn hash_batch(
+        batch: &[ChunkData],
+        options: &SignOptions,
+        pool: Option<&ThreadPool>,
+        hasher: Option<&mut blake3::Hasher>,
+        md5_hasher: Option<&mut Md5>,
+    ) -> Vec<Chunk> {
+        let update_whole = || {
+            let mut hasher = hasher;
+            let mut md5_hasher = md5_hasher;
+
+            for source_chunk in batch {
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(&source_chunk.data);
+                }
+                if let Some(md5_hasher) = md5_hasher.as_mut() {
+                    md5_hasher.update(&source_chunk.data);
+                }
+            }
+        };
+
+        let to_chunk = |source_chunk: &ChunkData| {
+            Chunk::from_data(source_chunk.offset, &source_chunk.data, options)
+        };
+
+        match pool {
+            Some(pool) => pool.install(|| {
+                let (_, chunks) = rayon::join(update_whole, || {
+                    batch.par_iter().map(to_chunk).collect::<Vec<Chunk>>()
+                });
+                chunks
+            }),
+            None => {
+                update_whole();
+                batch.iter().map(to_chunk).collect()
+            }
+        }
+    }
+
+    /// Generates signature for data mapped into memory. Chunks are hashed
+    /// in place, without copying them into intermediate buffers.
+    fn generate_mapped(data: &[u8], options: &SignOptions, pool: Option<&ThreadPool>) -> Self {
+        let boundaries = Self::boundaries(data, options);
+
+        let to_chunk = |boundary: &v2020::Chunk| {
+            let chunk_data = &data[boundary.offset..boundary.offset + boundary.length];
+            Chunk::from_data(boundary.offset as u64, chunk_data, options)
+        };
+
+        let mut hasher = key::hasher(options.key.as_ref());
+
+        let chunks: Vec<Chunk> = match pool {
+            Some(pool) => pool.install(|| {
+                hasher.update_rayon(data);
+                boundaries.par_iter().map(to_chunk).collect()
+            }),
+            None => {
+                hasher.update(data);
+                boundaries.iter().map(to_chunk).collect()
+            }
+        };
+
+        let md5 = options.md5.then(|| format!("{:x}", Md5::digest(data)));
+
+        Self {
+            strong_hash: hasher.finalize(),
+            length: data.len() as u64,
+            chunks,
+            md5,
+            chunk_sizes: Some(options.chunk_sizes()),
+            blocks: None,
+            decompressed: None,
+            compressed_hash: None,
+            key_id: options.key_id(),
+            hash_length: options.truncated_length(),
+            metadata: None,
+        }
+    }
+
+    /// Splits data into chunks. Aligned formats are split into parts at
+    /// their structure boundaries first, each part is chunked separately.
+    fn boundaries(data: &[u8], options: &SignOptions) -> Vec<v2020::Chunk> {
+        let chunker = |part: &[u8]| -> Vec<v2020::Chunk> {
+            FastCDC::with_level_and_seed(
+                part,
+                options.min_size,
+                options.avg_size,
+                options.max_size,
+                Normalization::Level1,
+                options.gear_seed(),
+            )
+            .collect()
+        };
+
+        let starts = match options.format {
+            Format::Raw => return chunker(data),
+            Format::Tar => tar::entry_offsets(data),
+        };
+
+        let mut boundaries: Vec<v2020::Chunk> = Vec::new();
+
+        for (index, &start) in starts.iter().enumerate() {
+            let end = starts.get(index + 1).copied().unwrap_or(data.len());
+
+            boundaries.extend(
+                chunker(&data[start..end])
+                    .into_iter()
+                    .map(|chunk| v2020::Chunk {
+                        offset: start + chunk.offset,
+                        ..chunk
+                    }),
+            );
+        }
+
+        boundaries
+    }
+
+    /// Generates signature for data mapped into memory using chunk boundaries
+    /// of the previous signature where the data is unchanged.
+    ///
+    /// FastCDC cut point depends only on the data from the chunk start up to
+    /// `max_size` bytes or the end of data, and on one byte following the cut.
+    /// A chunk of the previous signature is reused when it starts at a known
+    /// boundary, its data (and the byte after it) is the same and the distance
+    /// to the end of data is the same as before, or larger than `max_size`
+    /// in both versions.
+    fn generate_mapped_from(
+        data: &[u8],
+        previous: &Signature,
+        options: &SignOptions,
+        pool: Option<&ThreadPool>,
+    ) -> Self {
+        if !previous.fits(options) {
+            return Self::generate_mapped(data, options, pool);
+        }
+
+        let max_size = options.max_size as u64;
+        let length = data.len() as u64;
+        let window = |length: u64, offset: u64| (length - offset).min(max_size);
+
+        let verify = |chunk: &Chunk, offset: u64| {
+            let start = offset as usize;
+            let chunk_data = data.get(start..start + chunk.length as usize)?;
+            let strong_hash = key::hash(options.key.as_ref(), chunk_data);
+
+            (TruncatedHash::new(strong_hash, options.hash_length).hash() == chunk.strong_hash())
+                .then(|| Chunk::from_hashed(offset, chunk_data, strong_hash, options))
+        };
+
+        // Unchanged head, offsets are the same
+        let mut chunks: Vec<Chunk> = Vec::new();
+
+        for chunk in &previous.chunks {
+            let offset = chunk.offset();
+            if offset >= length || window(previous.length, offset) != window(length, offset) {
+                break;
+            }
+
+            match verify(chunk, offset) {
+                Some(chunk) => chunks.push(chunk),
+                None => break,
+            }
+        }
+
+        // The byte following the last verified chunk is unknown
+        if let Some(last) = chunks.last() {
+            if last.length < window(length, last.offset()) {
+                chunks.pop();
+            }
+        }
+
+        let head_end = chunks.last().map_or(0, |c| c.offset() + c.length);
+
+        // Unchanged tail, offsets are shifted by the change of the file length
+        let shift = length as i64 - previous.length as i64;
+        let mut tail: Vec<Chunk> = Vec::new();
+
+        for chunk in previous.chunks.iter().rev() {
+            let offset = chunk.offset() as i64 + shift;
+            if offset < head_end as i64 {
+                break;
+            }
+
+            match verify(chunk, offset as u64) {
+                Some(chunk) => tail.push(chunk),
+                None => break,
+            }
+        }
+
+        tail.reverse();
+
+        let tail_starts: HashMap<u64, usize> = tail
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| (chunk.offset(), index))
+            .collect();
+
+        // Changed middle is chunked until a cut lines up with the tail
+        let mut tail_index = tail_starts.get(&head_end).copied();
+
+        if tail_index.is_none() {
+            let middle = FastCDC::with_level_and_seed(
+                &data[head_end as usize..],
+                options.min_size,
+                options.avg_size,
+                options.max_size,
+                Normalization::Level1,
+                options.gear_seed(),
+            );
+
+            for boundary in middle {
+                let start = head_end as usize + boundary.offset;
+                let end = start + boundary.length;
+                chunks.push(Chunk::from_data(start as u64, &data[start..end], options));
+
+                tail_index = tail_starts.get(&(end as u64)).copied();
+                if tail_index.is_some() {
+                    break;
+                }
+            }
+        }
+
+        if let Some(index) = tail_index {
+            chunks.extend_from_slice(&tail[index..]);
+        }
+
+        let mut hasher = key::hasher(options.key.as_ref());
+        match pool {
+            Some(pool) => {
+                pool.install(|| hasher.update_rayon(data));
+            }
+            None => {
+                hasher.update(data);
+            }
+        }
+
+        let md5 = options.md5.then(|| format!("{:x}", Md5::digest(data)));
+
+        Self {
+            strong_hash: hasher.finalize(),
+            length: data.len() as u64,
+            chunks,
+            md5,
+            chunk_sizes: Some(options.chunk_sizes()),
+            blocks: None,
+            decompressed: None,
+            compressed_hash: None,
+            key_id: options.key_id(),
+            hash_length: options.truncated_length(),
+            metadata: None,
+        }
+    }
+
+    /// Returns true if the signature could be generated with the given chunk sizes.
+    fn fits(&self, options: &SignOptions) -> bool {
+        if self
+            .chunk_sizes
+            .is_some_and(|sizes| sizes != options.chunk_sizes())
+            || self.key_id != options.key_id()
+            || self.hash_length != options.truncated_length()
+        {
+            return false;
+        }
+
+        let min_size = options.min_size as u64;
+        let max_size = options.max_size as u64;
+
+        let last = self.chunks.len().saturating_sub(1);
+        let bounded = self.chunks.iter().enumerate().all(|(index, chunk)| {
+            chunk.length <= max_size && (index == last || chunk.length >= min_size)
+        });
+
+        bounded && self.chunks.iter().map(|c| c.length).sum::<u64>() == self.length
+    }
+
+    /// Returns a map of chunks by strong hash
+    pub(crate) fn chunks_map(&self) -> HashMap<blake3::Hash, &Chunk> {
+        let mut m = HashMap::<blake3::Hash, &Chunk>::new();
+
+        for chunk in &self.chunks {
+            m.entry(chunk.strong_hash()).or_insert(chunk);
+        }
+
+        m
+    }
+
+    /// Returns total length of a file.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Returns strong hash of a whole file.
+    pub fn strong_hash(&self) -> blake3::Hash {
+        self.strong_hash
+    }
+
+    /// Returns chunks in the file order.
+    pub fn chunks(&self) -> &Vec<Chunk> {
+        &self.chunks
+    }
+
+    /// Returns compression of the file if the signature describes its decompressed contents.
+    pub fn decompressed(&self) -> Option<Compression> {
+        self.decompressed
+    }
+
+    /// Returns hex blake3 of the compressed file, set if the signature
+    /// describes its decompressed contents.
+    pub fn compressed_hash(&self) -> Option<&str> {
+        self.compressed_hash.as_deref()
+    }
+
+    /// Returns id of the key hashes are keyed with, see `key::id`.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Returns length of chunk hashes in bytes.
+    pub fn hash_length(&self) -> usize {
+        self.hash_length.unwrap_or(blake3::OUT_LEN)
+    }
+
+    /// Returns metadata of the signed file if it is recorded.
+    pub fn metadata(&self) -> Option<&FileMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Records metadata of the signed file to restore on reconstructed files.
+    pub fn set_metadata(&mut self, metadata: FileMetadata) {
+        self.metadata = Some(metadata);
+    }
+
+    /// Hashes chunk data the way chunks of the signature are hashed,
+    /// keyed signatures can not be checked without the key.
+    pub fn chunk_hash(&self, data: &[u8]) -> blake3::Hash {
+        self.keyed_chunk_hash(None, data)
+    }
+
+    /// Hashes chunk data the way chunks of the signature are hashed,
+    /// with the key the signature was generated with, if any.
+    pub fn keyed_chunk_hash(&self, key: Option<&HashKey>, data: &[u8]) -> blake3::Hash {
+        TruncatedHash::new(key::hash(key, data), self.hash_length()).hash()
+    }
+
+    /// Returns chunk sizes the signature was generated with.
+    pub fn chunk_sizes(&self) -> Option<ChunkSizes> {
+        self.chunk_sizes
+    }
+
+    /// Returns fixed-size block checksums, set if the file was
+    /// signed with a block size.
+    pub fn blocks(&self) -> Option<&BlockIndex> {
+        self.blocks.as_ref()
+    }
+
+    /// Returns hex md5 of a whole file.
+    pub fn md5(&self) -> Option<&str> {
+        self.md5.as_deref()
+    }
+
+    /// Returns md5 of a whole file in base64, the form of `md5Hash` GCS
+    /// reports for non-composite objects.
+    pub fn md5_base64(&self) -> Option<String> {
+        let md5 = self.md5.as_deref()?;
+        let bytes = (0..md5.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(md5.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        Some(BASE64_STANDARD.encode(bytes))
+    }
+
+    /// Returns crc32c of a whole file combined from chunk checksums,
+    /// the same value GCS reports for an object (including composite objects).
+    /// Returns `None` if the signature has no chunk checksums.
+    pub fn crc32c(&self) -> Option<u32> {
+        let mut crc: u32 = 0;
+
+        for chunk in &self.chunks {
+            crc = crc32c::crc32c_combine(crc, chunk.crc32c?, chunk.length as usize);
+        }
+
+        Some(crc)
+    }
+
+    /// Sets the time each chunk was last changed. Chunks which exist in the
+    /// previous signature of the same file keep their time, new chunks get `now`.
+    ///
+    /// # Parameters:
+    /// - `previous`: previous signature of the same file, if any
+    /// - `now`: current unix time
+    pub fn track_changes(&mut self, previous: Option<&Signature>, now: u64) {
+        let mut seen = HashMap::<blake3::Hash, u64>::new();
+
+        if let Some(previous) = previous {
+            for chunk in &previous.chunks {
+                seen.entry(chunk.strong_hash())
+                    .or_insert(chunk.changed_at.unwrap_or(now));
+            }
+        }
+
+        for chunk in &mut self.chunks {
+            chunk.changed_at = Some(*seen.get(&chunk.strong_hash()).unwrap_or(&now));
+        }
+    }
+}
+
+impl Diff {
+    pub fn new(source: &Signature, target: &Signature) -> Option<Self> {
+        Self::new_multi(&[source], target)
+    }
+
+    /// Creates diff which copies chunks from any of several source files.
+    /// When a chunk exists in several sources, the first one is used.
+    ///
+    /// # Parameters:
+    /// - `sources`: signatures of the source file followed by additional seed files
+    /// - `target`: target file signature
+    ///
+    /// # Returns:
+    /// - `Option<Self>`: `None` if the first source is equal to the target
+    pub fn new_multi(sources: &[&Signature], target: &Signature) -> Option<Self> {
+        if sources.first().is_some_and(|source| *source == target) {
+            return None;
+        }
+
+        let mut copy_ops: Vec<CopyOp> = Vec::new();
+        let mut insert_ops: Vec<InsertOp> = Vec::new();
+        let mut reused_ops: Vec<InsertOp> = Vec::new();
+        let mut zero_ops: Vec<ZeroOp> = Vec::new();
+        let mut copy_length: u64 = 0;
+        let mut insert_length: u64 = 0;
+        let mut operations: Vec<Operation> = Vec::new();
+
+        let mut source_map = HashMap::<blake3::Hash, (usize, &Chunk)>::new();
+        for (index, source) in sources.iter().enumerate() {
+            for (hash, chunk) in source.chunks_map() {
+                source_map.entry(hash).or_insert((index, chunk));
+            }
+        }
+
+        // Chunks fetched for INSERT ops: op id and offset within its segment
+        let mut inserted = HashMap::<blake3::Hash, (uuid::Uuid, u64)>::new();
+
+        for target_chunk in target.chunks.iter() {
+            // Zeros are neither read nor fetched
+            if target_chunk.is_zero() {
+                let op = ZeroOp {
+                    offset: target_chunk.offset(),
+                    length: target_chunk.length,
+                };
+                Self::chain_or_push(op, &mut zero_ops);
+            } else if let Some((index, source_chunk)) = source_map.get(&target_chunk.strong_hash())
+            {
+                // If we have a chunk in one of the source files - use it
+                let op = Self::create_copy_op(*index, source_chunk, target_chunk, &mut copy_ops);
+                copy_length += op.length();
+            } else if let Some(&(uuid, segment_offset)) = inserted.get(&target_chunk.strong_hash())
+            {
+                // The same data is already fetched for another op
+                reused_ops.push(InsertOp {
+                    offset: target_chunk.offset(),
+                    length: target_chunk.length,
+                    uuid,
+                    segment_offset,
+                });
+                insert_length += target_chunk.length;
+            } else {
+                let op = Self::create_insert_op(target_chunk, &mut insert_ops);
+                insert_length += op.length();
+
+                let segment = insert_ops.last().expect("op was just added");
+                let segment_offset = target_chunk.offset() - segment.offset;
+                inserted.insert(target_chunk.strong_hash(), (segment.uuid, segment_offset));
+            }
+        }
+
+        for op in &copy_ops {
+            operations.push((*op).into());
+        }
+
+        for op in insert_ops.iter().chain(&reused_ops) {
+            operations.push((*op).into());
+        }
+
+        for op in &zero_ops {
+            operations.push((*op).into());
+        }
+
+        operations.sort();
+
+        Some(Self {
+            operations,
+            copy_length,
+            insert_length,
+            copy_ops,
+            insert_ops,
+            reused_ops,
+            delta_ops: Vec::new(),
+            zero_ops,
+        })
+    }
+
+    /// Creates new CopyOp from source and target chunks. Adds it to copy_ops
+    /// vec or extends last copy_op if copies are sequential.
+    ///
+    /// # Parameters:
+    /// - `source_index`: Index of a source file
+    /// - `source_chunk`: Chunk of a source file
+    /// - `target_chunk`: Chunk of a target file
+    /// - `ops`: Borrowed reference to a copy ops array.
+    ///
+    /// # Returns:
+    /// - `CopyOp`: the created op, before it is chained.
+    fn create_copy_op(
+        source_index: usize,
+        source_chunk: &Chunk,
+        target_chunk: &Chunk,
+        ops: &mut Vec<CopyOp>,
+    ) -> CopyOp {
         let length = source_chunk.length;
 
         let op = CopyOp {
-            source_offset: source_chunk.offset,
-            offset: target_chunk.offset,
+            source_index,
+            source_offset: source_chunk.offset(),
+            offset: target_chunk.offset(),
             length,
         };
 
@@ -351,12 +1563,13 @@ impl Diff {
 
     fn create_insert_op(target_chunk: &Chunk, ops: &mut Vec<InsertOp>) -> InsertOp {
         let length = target_chunk.length;
-        let uuid = uuid::Uuid::new_v4();
+        let uuid = segment_id(target_chunk.strong_hash().as_bytes(), target_chunk.offset());
 
         let op = InsertOp {
-            offset: target_chunk.offset,
+            offset: target_chunk.offset(),
             length,
             uuid,
+            segment_offset: 0,
         };
 
         Self::chain_or_push(op, ops);
@@ -379,11 +1592,268 @@ impl Diff {
         }
     }
 
-    pub fn copy_length(&self) -> usize {
+    /// Replaces parts of INSERT ops with COPY ops for target blocks found
+    /// at any offset of the source file by the rolling hash. Only ops whose
+    /// data is not reused by other ops are searched.
+    ///
+    /// # Parameters:
+    /// - `source`: contents of the source file, `sources[0]` of the diff
+    /// - `blocks`: block index of the target file
+    ///
+    /// # Returns:
+    /// - `u64`: number of bytes which are copied instead of fetched now
+    pub fn refine(&mut self, source: &[u8], blocks: &BlockIndex) -> u64 {
+        let reused: HashSet<uuid::Uuid> = self.reused_ops.iter().map(|op| op.uuid).collect();
+
+        let ranges: Vec<(u64, u64)> = self
+            .insert_ops
+            .iter()
+            .filter(|op| !reused.contains(&op.uuid))
+            .map(|op| (op.offset, op.length))
+            .collect();
+
+        let matches = blocks.find_matches(source, &ranges);
+        if matches.is_empty() {
+            return 0;
+        }
+
+        let mut insert_ops: Vec<InsertOp> = Vec::new();
+        let mut copy_ops: Vec<CopyOp> = Vec::new();
+        let mut matches = matches.iter().peekable();
+        let mut matched: u64 = 0;
+
+        for op in &self.insert_ops {
+            if reused.contains(&op.uuid) {
+                insert_ops.push(*op);
+                continue;
+            }
+
+            let end = op.offset + op.length;
+            let mut position = op.offset;
+
+            while let Some(m) = matches.next_if(|m| m.offset < end) {
+                if m.offset > position {
+                    insert_ops.push(InsertOp {
+                        offset: position,
+                        length: m.offset - position,
+                        uuid: segment_id(op.uuid.as_bytes(), position),
+                        segment_offset: 0,
+                    });
+                }
+
+                let copy = CopyOp {
+                    source_index: 0,
+                    source_offset: m.source_offset,
+                    offset: m.offset,
+                    length: m.length,
+                };
+
+                Self::chain_or_push(copy, &mut copy_ops);
+
+                matched += m.length;
+                position = m.offset + m.length;
+            }
+
+            if position < end {
+                insert_ops.push(InsertOp {
+                    offset: position,
+                    length: end - position,
+                    uuid: segment_id(op.uuid.as_bytes(), position),
+                    segment_offset: 0,
+                });
+            }
+        }
+
+        self.copy_ops.extend(copy_ops);
+        self.copy_ops.sort_by_key(|op| op.offset);
+        self.insert_ops = insert_ops;
+        self.copy_length += matched;
+        self.insert_length -= matched;
+        self.collect_operations();
+
+        matched
+    }
+
+    /// Merges INSERT ops separated by the shortest gaps until at most `max`
+    /// ranges of the target file are fetched. The gaps are fetched along with
+    /// the ops, COPY, ZERO and repeated INSERT ops within them are dropped.
+    /// Must be called before `convert_to_deltas`.
+    ///
+    /// # Parameters:
+    /// - `max`: number of ranges to fetch, at least one
+    ///
+    /// # Returns:
+    /// - `u64`: number of extra bytes fetched
+    pub fn merge_inserts(&mut self, max: usize) -> u64 {
+        let max = max.max(1);
+        if self.insert_ops.len() <= max {
+            return 0;
+        }
+
+        // Merged ops keep the offsets of their ends, so the other gaps stay
+        // the same and the shortest ones can be picked at once
+        let mut gaps: Vec<(u64, usize)> = self
+            .insert_ops
+            .windows(2)
+            .enumerate()
+            .map(|(index, pair)| (pair[1].offset - (pair[0].offset + pair[0].length), index))
+            .collect();
+        gaps.sort();
+
+        let mut joined = vec![false; self.insert_ops.len()];
+        for &(_, index) in gaps.iter().take(self.insert_ops.len() - max) {
+            joined[index + 1] = true;
+        }
+
+        let fetch_length = self.fetch_length();
+        let mut insert_ops: Vec<InsertOp> = Vec::new();
+        // Segments of merged ops: id of the segment they are in and offset within it
+        let mut moved = HashMap::<uuid::Uuid, (uuid::Uuid, u64)>::new();
+
+        for (op, joined) in self.insert_ops.iter().zip(joined) {
+            match insert_ops.last_mut() {
+                Some(last) if joined => {
+                    moved.insert(op.uuid, (last.uuid, op.offset - last.offset));
+                    last.length = op.offset + op.length - last.offset;
+                }
+                _ => insert_ops.push(*op),
+            }
+        }
+
+        let covered = |offset: u64| {
+            let index = insert_ops.partition_point(|op| op.offset <= offset);
+            index > 0 && offset < insert_ops[index - 1].offset + insert_ops[index - 1].length
+        };
+
+        let mut dropped_copies: u64 = 0;
+        self.copy_ops.retain(|op| {
+            let keep = !covered(op.offset);
+            if !keep {
+                dropped_copies += op.length;
+            }
+            keep
+        });
+
+        let mut dropped_zeros: u64 = 0;
+        self.zero_ops.retain(|op| {
+            let keep = !covered(op.offset);
+            if !keep {
+                dropped_zeros += op.length;
+            }
+            keep
+        });
+
+        self.reused_ops.retain(|op| !covered(op.offset));
+        for op in self.reused_ops.iter_mut() {
+            if let Some(&(uuid, shift)) = moved.get(&op.uuid) {
+                op.uuid = uuid;
+                op.segment_offset += shift;
+            }
+        }
+
+        self.insert_ops = insert_ops;
+        self.copy_length -= dropped_copies;
+        self.insert_length += dropped_copies + dropped_zeros;
+        self.collect_operations();
+
+        self.fetch_length() - fetch_length
+    }
+
+    /// Rebuilds the sequential list of ops after the ops of a kind change.
+    fn collect_operations(&mut self) {
+        self.operations = self
+            .copy_ops
+            .iter()
+            .map(|op| (*op).into())
+            .chain(
+                self.insert_ops
+                    .iter()
+                    .chain(&self.reused_ops)
+                    .map(|op| (*op).into()),
+            )
+            .chain(self.zero_ops.iter().map(|op| (*op).into()))
+            .collect();
+        self.operations.sort();
+    }
+
+    /// Returns the position in the source file which corresponds to a target
+    /// offset, assuming data between COPY ops changed in place: the end of the
+    /// preceding COPY from the source file shifted by the distance from it.
+    pub fn source_position(&self, offset: u64) -> u64 {
+        let mut copies = self.copy_ops.iter().filter(|op| op.source_index == 0);
+
+        let first = match copies.next() {
+            Some(op) => op,
+            None => return offset,
+        };
+
+        let preceding = std::iter::once(first)
+            .chain(copies)
+            .take_while(|op| op.offset <= offset)
+            .last();
+
+        match preceding {
+            Some(op) => {
+                let end = op.offset + op.length;
+                op.source_offset + op.length + offset.saturating_sub(end)
+            }
+            None => first.source_offset.saturating_sub(first.offset - offset),
+        }
+    }
+
+    /// Replaces INSERT ops with DELTA ops patching the given source file
+    /// ranges. The diff file segments of the ops must hold the deltas,
+    /// ops whose data is reused by other ops are left as they are.
+    ///
+    /// # Parameters:
+    /// - `bases`: offset and length of the patched source range by op id
+    ///
+    /// # Returns:
+    /// - `usize`: number of replaced ops
+    pub fn convert_to_deltas(&mut self, bases: &HashMap<uuid::Uuid, (u64, u64)>) -> usize {
+        let reused: HashSet<uuid::Uuid> = self.reused_ops.iter().map(|op| op.uuid).collect();
+        let mut converted: HashMap<uuid::Uuid, DeltaOp> = HashMap::new();
+
+        self.insert_ops.retain(|op| {
+            let (source_offset, source_length) = match bases.get(&op.uuid) {
+                Some(&base) if !reused.contains(&op.uuid) => base,
+                _ => return true,
+            };
+
+            converted.insert(
+                op.uuid,
+                DeltaOp {
+                    offset: op.offset,
+                    length: op.length,
+                    uuid: op.uuid,
+                    source_offset,
+                    source_length,
+                },
+            );
+
+            false
+        });
+
+        for operation in self.operations.iter_mut() {
+            if let Operation::INSERT(op) = operation {
+                if let Some(delta) = converted.get(&op.uuid) {
+                    *operation = (*delta).into();
+                }
+            }
+        }
+
+        let count = converted.len();
+        self.delta_ops.extend(converted.into_values());
+        self.delta_ops.sort_by_key(|op| op.offset);
+
+        count
+    }
+
+    pub fn copy_length(&self) -> u64 {
         self.copy_length
     }
 
-    pub fn insert_length(&self) -> usize {
+    pub fn insert_length(&self) -> u64 {
         self.insert_length
     }
 
@@ -391,11 +1861,267 @@ impl Diff {
         &self.copy_ops
     }
 
+    /// Returns INSERT ops which data has to be fetched from the target file.
     pub fn insert_ops(&self) -> &Vec<InsertOp> {
         &self.insert_ops
     }
 
+    /// Returns INSERT ops which repeat data fetched for `insert_ops`.
+    pub fn reused_ops(&self) -> &Vec<InsertOp> {
+        &self.reused_ops
+    }
+
+    /// Returns INSERT ops replaced with DELTA ops, see `convert_to_deltas`.
+    pub fn delta_ops(&self) -> &Vec<DeltaOp> {
+        &self.delta_ops
+    }
+
+    /// Returns ZERO ops, ranges of the target file which are all zeros.
+    pub fn zero_ops(&self) -> &Vec<ZeroOp> {
+        &self.zero_ops
+    }
+
+    /// Returns number of bytes which are zeros in the target file.
+    pub fn zero_length(&self) -> u64 {
+        self.zero_ops.iter().map(|op| op.length).sum()
+    }
+
+    /// Returns number of bytes which have to be fetched from the target file.
+    pub fn fetch_length(&self) -> u64 {
+        self.insert_ops.iter().map(|op| op.length).sum()
+    }
+
     pub fn operations(&self) -> &Vec<Operation> {
         &self.operations
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn chunk(offset: u64, length: u64, label: &str) -> Chunk {
+        Chunk {
+            length,
+            offset: ChunkOffset::new(offset, false),
+            strong_hash: TruncatedHash::new(blake3::hash(label.as_bytes()), blake3::OUT_LEN),
+            changed_at: None,
+            crc32c: None,
+            zero: label == "zero",
+        }
+    }
+
+    /// Signature of a file made of labelled chunks of the given lengths,
+    /// chunks with the same label have the same data.
+    fn signature(chunks: &[(&str, u64)]) -> Signature {
+        let mut offset: u64 = 0;
+        let mut hasher = blake3::Hasher::new();
+        let chunks = chunks
+            .iter()
+            .map(|&(label, length)| {
+                hasher.update(label.as_bytes());
+                let chunk = chunk(offset, length, label);
+                offset += length;
+                chunk
+            })
+            .collect();
+
+        Signature {
+            strong_hash: hasher.finalize(),
+            length: offset,
+            chunks,
+            chunk_sizes: None,
+            md5: None,
+            blocks: None,
+            decompressed: None,
+            compressed_hash: None,
+            key_id: None,
+            hash_length: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn diff_of_files_over_4_gib_keeps_u64_offsets() {
+        let source = signature(&[("a", 2 * GIB), ("b", 3 * GIB), ("c", GIB)]);
+        let target = signature(&[
+            ("zero", GIB),
+            ("b", 3 * GIB),
+            ("new", GIB + 1),
+            ("a", 2 * GIB),
+        ]);
+
+        let diff = Diff::new(&source, &target).unwrap();
+
+        assert_eq!(diff.copy_length(), 5 * GIB);
+        assert_eq!(diff.insert_length(), GIB + 1);
+        assert_eq!(diff.zero_length(), GIB);
+        assert_eq!(diff.fetch_length(), GIB + 1);
+        assert_eq!(
+            diff.copy_ops(),
+            &vec![
+                CopyOp {
+                    source_index: 0,
+                    source_offset: 2 * GIB,
+                    offset: GIB,
+                    length: 3 * GIB,
+                },
+                CopyOp {
+                    source_index: 0,
+                    source_offset: 0,
+                    offset: 5 * GIB + 1,
+                    length: 2 * GIB,
+                },
+            ]
+        );
+        assert_eq!(diff.insert_ops()[0].offset(), 4 * GIB);
+
+        let lengths: u64 = diff.operations().iter().map(|op| op.length()).sum();
+        assert_eq!(lengths, target.length());
+        assert!(target.length() > u32::MAX as u64);
+    }
+
+    #[test]
+    fn implicit_offsets_over_4_gib_are_restored() {
+        let mut sig = signature(&[("a", 3 * GIB), ("b", 2 * GIB), ("c", 7)]);
+        for chunk in sig.chunks.iter_mut() {
+            chunk.offset = ChunkOffset::new(chunk.offset(), true);
+        }
+
+        let json = serde_json::to_string(&sig).unwrap();
+        assert!(!json.contains("offset"));
+
+        let read: Signature = serde_json::from_str(&json).unwrap();
+        let offsets: Vec<u64> = read.chunks().iter().map(Chunk::offset).collect();
+        assert_eq!(offsets, vec![0, 3 * GIB, 5 * GIB]);
+    }
+
+    #[test]
+    fn diff_copies_chunks_from_seeds_and_reuses_repeated_inserts() {
+        let source = signature(&[("a", 10), ("b", 20)]);
+        let seed = signature(&[("c", 30)]);
+        let target = signature(&[("c", 30), ("new", 5), ("a", 10), ("new", 5)]);
+
+        let diff = Diff::new_multi(&[&source, &seed], &target).unwrap();
+
+        assert_eq!(diff.copy_ops()[0].source_index(), 1);
+        assert_eq!(diff.copy_ops()[1].source_index(), 0);
+        assert_eq!(diff.copy_length(), 40);
+        assert_eq!(diff.insert_ops().len(), 1);
+        assert_eq!(diff.reused_ops().len(), 1);
+        assert_eq!(diff.reused_ops()[0].uuid(), diff.insert_ops()[0].uuid());
+        assert_eq!(diff.fetch_length(), 5);
+        assert_eq!(diff.insert_length(), 10);
+    }
+
+    #[test]
+    fn diff_of_equal_files_is_none() {
+        let source = signature(&[("a", 10)]);
+        let target = signature(&[("a", 10)]);
+        assert!(Diff::new(&source, &target).is_none());
+    }
+
+    #[test]
+    fn merge_inserts_joins_ops_over_the_shortest_gaps() {
+        let source = signature(&[("a", 10), ("b", 100)]);
+        let target = signature(&[
+            ("x", 5),
+            ("a", 10),
+            ("y", 5),
+            ("b", 100),
+            ("z", 5),
+            ("x", 5),
+        ]);
+
+        let mut diff = Diff::new(&source, &target).unwrap();
+        assert_eq!(diff.insert_ops().len(), 3);
+
+        let extra = diff.merge_inserts(2);
+
+        assert_eq!(extra, 10);
+        assert_eq!(
+            diff.insert_ops()
+                .iter()
+                .map(|op| (op.offset(), op.length()))
+                .collect::<Vec<_>>(),
+            vec![(0, 20), (120, 5)]
+        );
+        assert_eq!(diff.copy_ops().len(), 1);
+        assert_eq!(diff.copy_length(), 100);
+        assert_eq!(diff.insert_length(), 30);
+
+        // The repeated chunk still points into the first segment
+        let reused = diff.reused_ops()[0];
+        assert_eq!(reused.uuid(), diff.insert_ops()[0].uuid());
+        assert_eq!(reused.segment_offset(), 0);
+
+        let lengths: u64 = diff.operations().iter().map(|op| op.length()).sum();
+        assert_eq!(lengths, target.length());
+    }
+
+    #[test]
+    fn merge_inserts_keeps_ops_within_the_limit() {
+        let source = signature(&[("a", 10)]);
+        let target = signature(&[("x", 5), ("a", 10), ("y", 5)]);
+
+        let mut diff = Diff::new(&source, &target).unwrap();
+
+        assert_eq!(diff.merge_inserts(2), 0);
+        assert_eq!(diff.insert_ops().len(), 2);
+        assert_eq!(diff.merge_inserts(0), 10);
+        assert_eq!(diff.insert_ops().len(), 1);
+        assert!(diff.copy_ops().is_empty());
+    }
+
+    #[test]
+    fn merge_inserts_moves_reused_ops_into_merged_segments() {
+        let source = signature(&[("a", 10)]);
+        let target = signature(&[("x", 5), ("a", 10), ("y", 5), ("y", 5)]);
+
+        let mut diff = Diff::new(&source, &target).unwrap();
+        diff.merge_inserts(1);
+
+        let reused = diff.reused_ops()[0];
+        assert_eq!(reused.uuid(), diff.insert_ops()[0].uuid());
+        assert_eq!(reused.segment_offset(), 15);
+    }
+
+    #[test]
+    fn refine_copies_shifted_blocks() {
+        let data = random_data(1, 256 * 1024);
+        let options = SignOptions {
+            min_size: 4 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+            ..Default::default()
+        };
+
+        // A byte inserted at the start shifts all blocks of the source
+        let mut source_data = vec![7u8];
+        source_data.extend_from_slice(&data);
+        let mut target_data = data.clone();
+        target_data[100_000] ^= 1;
+
+        let source = Signature::generate_with_options(&mut &source_data[..], &options).unwrap();
+        let target = Signature::generate_with_options(&mut &target_data[..], &options).unwrap();
+        let blocks = BlockIndex::generate(&mut &target_data[..], 1024).unwrap();
+
+        let mut diff = Diff::new(&source, &target).unwrap();
+        let (inserted, ops) = (diff.insert_length(), diff.insert_ops().len() as u64);
+        let matched = diff.refine(&source_data, &blocks);
+
+        assert!(matched > 0);
+        assert_eq!(diff.insert_length(), inserted - matched);
+        for op in diff.copy_ops() {
+            let source = &source_data[op.source_offset() as usize..][..op.length() as usize];
+            let target = &target_data[op.offset() as usize..][..op.length() as usize];
+            assert_eq!(source, target);
+        }
+
+        // The changed block and parts of blocks at the ends of the ops are left
+        assert!(diff.insert_length() < 1024 * (2 * ops + 1));
+    }
+}
@@ -0,0 +1,458 @@
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::signature::{Diff, Op, Operation, Signature};
+
+/// Chunks with lengths in `lower..upper`.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeBucket {
+    pub lower: u64,
+    pub upper: u64,
+    pub count: usize,
+    pub bytes: u64,
+}
+
+/// Expected cost of updating the file after several small edits.
+#[derive(Debug, Clone, Copy)]
+pub struct EditEstimate {
+    /// number of single-byte edits at random positions
+    pub edits: usize,
+
+    /// expected number of ranges to request, adjacent changed chunks make a single range
+    pub requests: f64,
+
+    /// expected number of bytes to fetch
+    pub bytes: f64,
+}
+
+/// Prices of a storage endpoint, see `DiffStats::estimate_cost`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pricing {
+    /// egress price, $ per GB
+    pub egress: f64,
+
+    /// request price, $ per 10k requests
+    pub requests: f64,
+}
+
+/// Estimated cost of a transfer in $.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CostEstimate {
+    /// fetching the changed ranges
+    pub delta: f64,
+
+    /// downloading the whole target file in one request
+    pub full: f64,
+}
+
+/// Chunk statistics of a file.
+#[derive(Debug, Clone)]
+pub struct ChunkStats {
+    pub count: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub median: u64,
+
+    /// power of two buckets from the smallest to the largest chunk
+    pub buckets: Vec<SizeBucket>,
+
+    /// chunks which repeat an earlier chunk of the file, and their length
+    pub duplicates: usize,
+    pub duplicate_bytes: u64,
+}
+
+/// Summary of a diff between two files, `diff --json` prints it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffStats {
+    pub equal: bool,
+    pub source_length: u64,
+    pub target_length: u64,
+
+    pub copy_ops: usize,
+    pub copy_length: u64,
+
+    /// bytes copied from each seed file, in the order of the seeds
+    pub seed_lengths: Vec<u64>,
+
+    /// bytes of COPY ops found by the rolling hash, set only if it is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rolling_length: Option<u64>,
+
+    /// extra bytes fetched to merge INSERT ops, set only if requests are capped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merged_length: Option<u64>,
+
+    /// INSERT ops including the repeated ones, and bytes they insert
+    pub insert_ops: usize,
+    pub insert_length: u64,
+
+    /// INSERT ops which repeat data of another one, and bytes to fetch
+    pub reused_ops: usize,
+    pub fetch_length: u64,
+
+    /// INSERT ops turned into deltas of the source file
+    pub delta_ops: usize,
+
+    /// ZERO ops and bytes they fill, nothing is fetched for them
+    pub zero_ops: usize,
+    pub zero_length: u64,
+
+    /// bytes stored in the diff file, set once it is built
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff_file_length: Option<u64>,
+
+    /// estimated transfer cost, set only if prices are given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<CostEstimate>,
+
+    /// set if the whole target file was downloaded instead of the changed ranges
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub full_download: bool,
+
+    /// ops which build the target file, in order
+    pub operations: Vec<Operation>,
+}
+
+impl DiffStats {
+    /// Summarizes a diff, `None` means the files are equal.
+    ///
+    /// # Parameters:
+    /// - `source`: signature of the source file
+    /// - `target`: signature of the target file
+    /// - `diff`: diff between them
+    /// - `seeds`: number of additional seed files
+    pub fn new(source: &Signature, target: &Signature, diff: Option<&Diff>, seeds: usize) -> Self {
+        let diff = match diff {
+            Some(diff) => diff,
+            None => {
+                return Self {
+                    equal: true,
+                    source_length: source.length(),
+                    target_length: target.length(),
+                    copy_ops: 0,
+                    copy_length: target.length(),
+                    seed_lengths: vec![0; seeds],
+                    rolling_length: None,
+                    merged_length: None,
+                    insert_ops: 0,
+                    insert_length: 0,
+                    reused_ops: 0,
+                    fetch_length: 0,
+                    delta_ops: 0,
+                    zero_ops: 0,
+                    zero_length: 0,
+                    diff_file_length: None,
+                    cost: None,
+                    full_download: false,
+                    operations: Vec::new(),
+                }
+            }
+        };
+
+        let seed_lengths = (1..=seeds)
+            .map(|index| {
+                diff.copy_ops()
+                    .iter()
+                    .filter(|op| op.source_index() == index)
+                    .map(|op| op.length())
+                    .sum()
+            })
+            .collect();
+
+        Self {
+            equal: false,
+            source_length: source.length(),
+            target_length: target.length(),
+            copy_ops: diff.copy_ops().len(),
+            copy_length: diff.copy_length(),
+            seed_lengths,
+            rolling_length: None,
+            merged_length: None,
+            insert_ops: diff.insert_ops().len() + diff.reused_ops().len(),
+            insert_length: diff.insert_length(),
+            reused_ops: diff.reused_ops().len(),
+            fetch_length: diff.fetch_length(),
+            delta_ops: diff.delta_ops().len(),
+            zero_ops: diff.zero_ops().len(),
+            zero_length: diff.zero_length(),
+            diff_file_length: None,
+            cost: None,
+            full_download: false,
+            operations: diff.operations().clone(),
+        }
+    }
+
+    /// Returns the difference between file lengths.
+    pub fn length_difference(&self) -> u64 {
+        self.target_length.abs_diff(self.source_length)
+    }
+
+    /// Estimates the cost of fetching the changed ranges against a full download.
+    ///
+    /// # Parameters:
+    /// - `pricing`: prices of the endpoint the target file is on
+    pub fn estimate_cost(&self, pricing: &Pricing) -> CostEstimate {
+        let cost = |bytes: u64, requests: usize| {
+            bytes as f64 / 1e9 * pricing.egress + requests as f64 / 1e4 * pricing.requests
+        };
+
+        CostEstimate {
+            delta: cost(self.fetch_length, self.insert_ops - self.reused_ops),
+            full: cost(self.target_length, 1),
+        }
+    }
+
+    /// Returns the share of the target file to fetch in percent.
+    pub fn fetch_percent(&self) -> f64 {
+        match self.target_length {
+            0 => 0.0,
+            length => self.fetch_length as f64 * 100.0 / length as f64,
+        }
+    }
+}
+
+/// Summary of a diff of many file pairs, `diff --json` prints it for
+/// directories or globs.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BatchStats {
+    /// pairs matched by the path relative to the directories or glob roots
+    pub files: Vec<BatchEntry>,
+
+    /// files signed on one side only
+    pub only_in_source: Vec<String>,
+    pub only_in_target: Vec<String>,
+
+    /// pairs of different, equal and failed files
+    pub changed: usize,
+    pub equal: usize,
+    pub failed: usize,
+
+    /// totals of the pairs which did not fail
+    pub target_length: u64,
+    pub fetch_length: u64,
+}
+
+/// Result of a pair of a batch diff: its stats or why it failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchEntry {
+    pub path: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<DiffStats>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchStats {
+    /// Adds the result of a pair and counts it in the totals.
+    pub fn push(&mut self, path: String, result: Result<DiffStats, String>) {
+        let (stats, error) = match result {
+            Ok(stats) => {
+                match stats.equal {
+                    true => self.equal += 1,
+                    false => self.changed += 1,
+                }
+                self.target_length += stats.target_length;
+                self.fetch_length += stats.fetch_length;
+
+                (Some(stats), None)
+            }
+            Err(e) => {
+                self.failed += 1;
+                (None, Some(e))
+            }
+        };
+
+        self.files.push(BatchEntry { path, stats, error });
+    }
+
+    /// Returns the share of the target files to fetch in percent.
+    pub fn fetch_percent(&self) -> f64 {
+        match self.target_length {
+            0 => 0.0,
+            length => self.fetch_length as f64 * 100.0 / length as f64,
+        }
+    }
+}
+
+/// Calculates chunk statistics of a file.
+pub fn analyze(sig: &Signature) -> ChunkStats {
+    let mut lengths: Vec<u64> = sig.chunks().iter().map(|c| c.length()).collect();
+    lengths.sort_unstable();
+
+    let mut buckets: Vec<SizeBucket> = Vec::new();
+    for &length in &lengths {
+        let lower = match length {
+            0 => 0,
+            length => 1 << length.ilog2(),
+        };
+
+        match buckets.last_mut() {
+            Some(bucket) if bucket.lower == lower => {
+                bucket.count += 1;
+                bucket.bytes += length;
+            }
+            _ => buckets.push(SizeBucket {
+                lower,
+                upper: (lower * 2).max(1),
+                count: 1,
+                bytes: length,
+            }),
+        }
+    }
+
+    let mut seen: HashSet<blake3::Hash> = HashSet::new();
+    let (mut duplicates, mut duplicate_bytes) = (0, 0);
+
+    for chunk in sig.chunks() {
+        if !seen.insert(chunk.strong_hash()) {
+            duplicates += 1;
+            duplicate_bytes += chunk.length();
+        }
+    }
+
+    ChunkStats {
+        count: lengths.len(),
+        min: lengths.first().copied().unwrap_or(0),
+        max: lengths.last().copied().unwrap_or(0),
+        mean: sig.length() as f64 / lengths.len().max(1) as f64,
+        median: lengths.get(lengths.len() / 2).copied().unwrap_or(0),
+        buckets,
+        duplicates,
+        duplicate_bytes,
+    }
+}
+
+/// Returns probability that a chunk of another file with as many chunks
+/// falsely matches a chunk of the file because of truncated hashes:
+/// the number of chunk pairs compared by a diff over the number of hash values.
+pub fn collision_probability(sig: &Signature) -> f64 {
+    let pairs = (sig.chunks().len() as f64).powi(2);
+    let values = 2f64.powi(8 * sig.hash_length() as i32);
+
+    (pairs / values).min(1.0)
+}
+
+/// Estimates requests and fetched bytes after `edits` single-byte edits at
+/// uniformly random positions. An edit is assumed to change only the chunk
+/// it falls into, so the result is a lower bound for edits which move
+/// chunk boundaries.
+pub fn estimate_edits(sig: &Signature, edits: usize) -> EditEstimate {
+    let length = sig.length() as f64;
+
+    // Probability that none of the edits falls into `share` of the file
+    let missed = |share: f64| (1.0 - share).max(0.0).powi(edits as i32);
+
+    let shares: Vec<f64> = match length > 0.0 {
+        true => sig
+            .chunks()
+            .iter()
+            .map(|c| c.length() as f64 / length)
+            .collect(),
+        false => Vec::new(),
+    };
+
+    let mut requests = 0.0;
+    let mut bytes = 0.0;
+
+    for (index, &share) in shares.iter().enumerate() {
+        let hit = 1.0 - missed(share);
+        bytes += hit * share * length;
+        requests += hit;
+
+        // A range continues rather than starts if the previous chunk is hit too
+        if let Some(&previous) = index.checked_sub(1).and_then(|i| shares.get(i)) {
+            let both = 1.0 - missed(share) - missed(previous) + missed(share + previous);
+            requests -= both;
+        }
+    }
+
+    EditEstimate {
+        edits,
+        requests,
+        bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(
+        target_length: u64,
+        fetch_length: u64,
+        insert_ops: usize,
+        reused_ops: usize,
+    ) -> DiffStats {
+        DiffStats {
+            equal: false,
+            source_length: target_length,
+            target_length,
+            copy_ops: 0,
+            copy_length: target_length - fetch_length,
+            seed_lengths: Vec::new(),
+            rolling_length: None,
+            merged_length: None,
+            insert_ops,
+            insert_length: fetch_length,
+            reused_ops,
+            fetch_length,
+            delta_ops: 0,
+            zero_ops: 0,
+            zero_length: 0,
+            diff_file_length: None,
+            cost: None,
+            full_download: false,
+            operations: Vec::new(),
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn estimate_cost_prices_bytes_and_requests() {
+        let pricing = Pricing {
+            egress: 0.12,
+            requests: 0.004,
+        };
+
+        // 10 GB file, 1 GB in 20k ranges, 5k of them repeat fetched data
+        let cost = stats(10_000_000_000, 1_000_000_000, 25_000, 5_000).estimate_cost(&pricing);
+
+        assert_close(cost.delta, 0.12 + 2.0 * 0.004);
+        assert_close(cost.full, 1.2 + 0.0001 * 0.004);
+    }
+
+    #[test]
+    fn estimate_cost_favours_full_downloads_of_scattered_changes() {
+        let pricing = Pricing {
+            egress: 0.0,
+            requests: 0.4,
+        };
+
+        let cost = stats(1_000_000, 10_000, 1_000, 0).estimate_cost(&pricing);
+
+        assert!(cost.delta > cost.full);
+        assert_close(cost.delta, 0.04);
+    }
+
+    #[test]
+    fn estimate_cost_of_equal_files_is_free_to_fetch() {
+        let cost = stats(1_000_000_000, 0, 0, 0).estimate_cost(&Pricing {
+            egress: 0.09,
+            requests: 0.005,
+        });
+
+        assert_close(cost.delta, 0.0);
+        assert_close(cost.full, 0.09 + 0.0000005);
+    }
+}
@@ -0,0 +1,164 @@
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::platform;
+use crate::signature::Signature;
+
+/// Signature store, a single SQLite database with signatures
+/// indexed by file path and content hash.
+pub struct Store {
+    conn: Connection,
+}
+
+/// Signature summary as listed by the store.
+#[derive(Debug, Clone)]
+pub struct StoreEntry {
+    /// canonical path of the signed file
+    pub path: PathBuf,
+
+    /// hex blake3 of the whole file
+    pub strong_hash: String,
+
+    /// length of the file
+    pub length: u64,
+
+    /// unix time the signature was added
+    pub signed_at: u64,
+}
+
+impl Store {
+    /// Opens the store creating the database if it does not exist.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS signatures (
+                path TEXT PRIMARY KEY,
+                strong_hash TEXT NOT NULL,
+                length INTEGER NOT NULL,
+                signed_at INTEGER NOT NULL,
+                signature TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS signatures_strong_hash ON signatures (strong_hash);",
+        )?;
+
+        Ok(Self { conn })
+    }
+
+    /// Adds or replaces the signature of a file.
+    ///
+    /// # Parameters:
+    /// - `file`: signed file, stored by its canonical path
+    /// - `sig`: signature of the file
+    /// - `signed_at`: unix time
+    pub fn put(&self, file: &Path, sig: &Signature, signed_at: u64) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO signatures (path, strong_hash, length, signed_at, signature)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                key(file),
+                sig.strong_hash().to_hex().as_str(),
+                sig.length() as i64,
+                signed_at as i64,
+                serde_json::to_string(sig)?,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the signature of a file.
+    pub fn get(&self, file: &Path) -> Result<Option<Signature>, Box<dyn Error>> {
+        let json: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT signature FROM signatures WHERE path = ?1",
+                params![key(file)],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns files with the given content.
+    pub fn find_by_hash(
+        &self,
+        strong_hash: &blake3::Hash,
+    ) -> Result<Vec<StoreEntry>, Box<dyn Error>> {
+        self.query(
+            "WHERE strong_hash = ?1",
+            params![strong_hash.to_hex().as_str()],
+        )
+    }
+
+    /// Returns all entries ordered by path.
+    pub fn entries(&self) -> Result<Vec<StoreEntry>, Box<dyn Error>> {
+        self.query("", params![])
+    }
+
+    /// Removes the signature of a file.
+    ///
+    /// # Returns:
+    /// - `Result<bool, Box<dyn Error>>`: true if the signature existed
+    pub fn remove(&self, file: &Path) -> Result<bool, Box<dyn Error>> {
+        let removed = self
+            .conn
+            .execute("DELETE FROM signatures WHERE path = ?1", params![key(file)])?;
+
+        Ok(removed > 0)
+    }
+
+    /// Removes signatures of files which no longer exist.
+    ///
+    /// # Returns:
+    /// - `Result<Vec<PathBuf>, Box<dyn Error>>`: paths which were removed
+    pub fn prune(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut removed: Vec<PathBuf> = Vec::new();
+
+        for entry in self.entries()? {
+            if !entry.path.exists() && self.remove(&entry.path)? {
+                removed.push(entry.path);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn query(
+        &self,
+        filter: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<StoreEntry>, Box<dyn Error>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT path, strong_hash, length, signed_at FROM signatures {} ORDER BY path",
+            filter
+        ))?;
+
+        let rows = statement.query_map(params, |row| {
+            Ok(StoreEntry {
+                path: platform::unescape_path(&row.get::<_, String>(0)?).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+                })?,
+                strong_hash: row.get(1)?,
+                length: row.get::<_, i64>(2)? as u64,
+                signed_at: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<Vec<StoreEntry>, _>>()?)
+    }
+}
+
+/// Returns the key a file is stored by: its canonical path, or the path
+/// as is if the file does not exist anymore, see `platform::escape_path`.
+fn key(file: &Path) -> String {
+    let path = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    platform::escape_path(&path)
+}
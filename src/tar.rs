@@ -0,0 +1,91 @@
+/// Tar archives consist of 512 byte blocks.
+const BLOCK_LENGTH: usize = 512;
+
+/// Returns offsets where entry headers and entry data of a tar archive
+/// start, beginning with 0. Headers change with file metadata, so they are
+/// split from the data to keep data chunks intact. Parsing stops at the end
+/// of archive marker or at the first block which is not a valid header, so
+/// data which is not a tar archive gives `[0]`.
+pub fn entry_offsets(data: &[u8]) -> Vec<usize> {
+    let mut offsets: Vec<usize> = vec![0];
+    let mut offset: usize = 0;
+
+    while let Some(header) = data.get(offset..offset + BLOCK_LENGTH) {
+        let size = match entry_size(header) {
+            Some(size) => size,
+            None => break,
+        };
+
+        let start = offset + BLOCK_LENGTH;
+        if size > 0 && start < data.len() {
+            offsets.push(start);
+        }
+
+        let next = size
+            .div_ceil(BLOCK_LENGTH)
+            .checked_mul(BLOCK_LENGTH)
+            .and_then(|length| length.checked_add(start));
+
+        match next {
+            Some(next) if next < data.len() => {
+                offsets.push(next);
+                offset = next;
+            }
+            _ => break,
+        }
+    }
+
+    offsets
+}
+
+/// Returns length of entry data if the block is a valid header.
+fn entry_size(header: &[u8]) -> Option<usize> {
+    let stored = parse_octal(&header[148..156])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| match index {
+            // The checksum field itself is counted as spaces
+            148..=155 => b' ' as u64,
+            _ => byte as u64,
+        })
+        .sum();
+
+    if sum != stored {
+        return None;
+    }
+
+    let size = &header[124..136];
+
+    // GNU base-256 encoding of large sizes
+    if size[0] & 0x80 != 0 {
+        let value = size[1..].iter().try_fold(0u64, |value, &byte| {
+            value.checked_mul(256)?.checked_add(byte as u64)
+        })?;
+        return usize::try_from(value).ok();
+    }
+
+    usize::try_from(parse_octal(size)?).ok()
+}
+
+/// Parses a NUL or space terminated octal number.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+
+    let mut value: u64 = 0;
+    let mut any = false;
+
+    for &byte in digits {
+        if !(b'0'..=b'7').contains(&byte) {
+            return None;
+        }
+
+        value = value.checked_mul(8)?.checked_add((byte - b'0') as u64)?;
+        any = true;
+    }
+
+    any.then_some(value)
+}
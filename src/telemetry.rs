@@ -0,0 +1,58 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::env;
+use std::error::Error;
+use std::sync::OnceLock;
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Standard variables with the collector URL, spans are exported only if one is set
+const ENDPOINT_VARS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Returns a layer which exports spans with OTLP over HTTP, or none if
+/// no collector is configured
+pub fn layer<S>() -> Result<Option<impl Layer<S>>, Box<dyn Error>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if ENDPOINT_VARS.iter().all(|var| env::var_os(var).is_none()) {
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let _ = PROVIDER.set(provider);
+
+    Ok(Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(LevelFilter::DEBUG),
+    ))
+}
+
+/// Exports the spans which are still buffered
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Can not export traces: {}", e);
+        }
+    }
+}
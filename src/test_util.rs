@@ -0,0 +1,6 @@
+/// Returns pseudo-random data, the same for the same seed.
+pub(crate) fn random_data(seed: u64, length: usize) -> Vec<u8> {
+    let mut data = vec![0u8; length];
+    fastrand::Rng::with_seed(seed).fill(&mut data);
+    data
+}
@@ -0,0 +1,144 @@
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket which refills at `rate` bytes per second and holds
+/// at most one second worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+/// Limit several streams take their bytes from, so streams read or
+/// written on different threads stay within one rate together.
+#[derive(Debug, Clone)]
+pub struct Limit(Arc<Mutex<TokenBucket>>);
+
+/// Wraps a reader or a writer limiting its throughput.
+///
+/// Used for streams backed by remote storage so large syncs
+/// don't saturate the link.
+#[derive(Debug)]
+pub struct Throttled<T> {
+    inner: T,
+    limit: Limit,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+
+    /// Blocks until at least one byte is available and returns
+    /// the number of bytes (up to `want`) which can be transferred.
+    fn take(&mut self, want: usize) -> usize {
+        self.refill();
+
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.rate as f64;
+            thread::sleep(Duration::from_secs_f64(wait));
+            self.refill();
+        }
+
+        let n = (self.tokens as usize).clamp(1, want.max(1));
+        self.tokens -= n as f64;
+        n
+    }
+
+    /// Returns tokens which were taken but not used.
+    fn refund(&mut self, n: usize) {
+        self.tokens += n as f64;
+    }
+}
+
+impl Limit {
+    /// Creates a limit of `rate` bytes per second, it must be greater than zero.
+    pub fn new(rate: u64) -> Self {
+        Self(Arc::new(Mutex::new(TokenBucket::new(rate.max(1)))))
+    }
+
+    fn take(&self, want: usize) -> usize {
+        // Other streams wait for tokens anyway while one sleeps holding the lock
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take(want)
+    }
+
+    fn refund(&self, n: usize) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .refund(n)
+    }
+}
+
+impl<T> Throttled<T> {
+    /// Creates new wrapper.
+    ///
+    /// # Parameters:
+    /// - `inner`: wrapped stream
+    /// - `rate`: limit in bytes per second, must be greater than zero
+    pub fn new(inner: T, rate: u64) -> Self {
+        Self::with_limit(inner, Limit::new(rate))
+    }
+
+    /// Creates new wrapper sharing the limit with other streams.
+    pub fn with_limit(inner: T, limit: Limit) -> Self {
+        Self { inner, limit }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let allowed = self.limit.take(buf.len());
+        let read = self.inner.read(&mut buf[..allowed]);
+        let used = *read.as_ref().unwrap_or(&0);
+        self.limit.refund(allowed - used);
+
+        read
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let allowed = self.limit.take(buf.len());
+        let written = self.inner.write(&buf[..allowed]);
+        let used = *written.as_ref().unwrap_or(&0);
+        self.limit.refund(allowed - used);
+
+        written
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Throttled<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,84 @@
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt;
+
+/// blake3 hash which keeps only its first `length` bytes, the rest are
+/// zeros. Serialized as hex of the kept bytes, so a full hash is serialized
+/// the same way as by `blake3_serde_hex`.
+#[derive(Debug, Clone, Copy)]
+pub struct TruncatedHash {
+    hash: blake3::Hash,
+    length: usize,
+}
+
+impl TruncatedHash {
+    pub fn new(hash: blake3::Hash, length: usize) -> Self {
+        let length = length.min(blake3::OUT_LEN);
+
+        let mut bytes = [0u8; blake3::OUT_LEN];
+        bytes[..length].copy_from_slice(&hash.as_bytes()[..length]);
+
+        Self {
+            hash: bytes.into(),
+            length,
+        }
+    }
+
+    /// Returns the hash padded with zeros.
+    pub fn hash(&self) -> blake3::Hash {
+        self.hash
+    }
+}
+
+impl Serialize for TruncatedHash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let hex = self.hash.to_hex();
+        serializer.serialize_str(&hex.as_str()[..self.length * 2])
+    }
+}
+
+impl<'de> Deserialize<'de> for TruncatedHash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct TruncatedHashVisitor;
+
+        impl Visitor<'_> for TruncatedHashVisitor {
+            type Value = TruncatedHash;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("Hash or its prefix as hex string")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<TruncatedHash, E>
+            where
+                E: de::Error,
+            {
+                if v.is_empty() || !v.len().is_multiple_of(2) || v.len() > blake3::OUT_LEN * 2 {
+                    return Err(de::Error::custom(format!(
+                        "invalid hash length {}",
+                        v.len()
+                    )));
+                }
+
+                let padded = format!("{:0<width$}", v, width = blake3::OUT_LEN * 2);
+
+                match blake3::Hash::from_hex(padded) {
+                    Ok(hash) => Ok(TruncatedHash {
+                        hash,
+                        length: v.len() / 2,
+                    }),
+                    Err(e) => Err(de::Error::custom(e)),
+                }
+            }
+        }
+
+        deserializer.deserialize_str(TruncatedHashVisitor)
+    }
+}
@@ -0,0 +1,130 @@
+use std::error::Error;
+use wasm_bindgen::prelude::*;
+
+use crate::builder::{self, FetchedSegments};
+use crate::signature::{Diff, Op, SignOptions, Signature};
+
+/// Reconstruction of an updated download in the browser from a cached old
+/// version and ranges of the new one, fetched by the page itself:
+///
+/// ```js
+/// const update = new Reconstruction(cached, await (await fetch(sigUrl)).text());
+/// const ranges = update.ranges();
+/// const parts = [];
+/// for (let i = 0; i < ranges.length; i += 2) {
+///   const headers = { Range: `bytes=${ranges[i]}-${ranges[i] + ranges[i + 1] - 1}` };
+///   parts.push(new Uint8Array(await (await fetch(url, { headers })).arrayBuffer()));
+/// }
+/// const result = update.build(cached, concat(parts));
+/// ```
+#[wasm_bindgen]
+pub struct Reconstruction {
+    target: Signature,
+
+    /// `None` if the cached file is equal to the target
+    diff: Option<Diff>,
+}
+
+#[wasm_bindgen]
+impl Reconstruction {
+    /// Diffs the cached file against the target signature. The cached file
+    /// is signed with the chunk sizes and hash length of the target.
+    ///
+    /// # Parameters:
+    /// - `cached`: contents of the cached old version
+    /// - `target_signature`: signature JSON of the new version, as written by `sign`
+    #[wasm_bindgen(constructor)]
+    pub fn new(cached: &[u8], target_signature: &str) -> Result<Reconstruction, JsError> {
+        let target: Signature = serde_json::from_str(target_signature)
+            .map_err(|e| JsError::new(&format!("Invalid signature: {}", e)))?;
+
+        let source = sign(cached, &target).map_err(js_error)?;
+        let diff = Diff::new(&source, &target);
+
+        Ok(Self { target, diff })
+    }
+
+    /// Ranges of the new version to fetch, in the order `build` expects
+    /// their data: offset and length of each one, flattened.
+    pub fn ranges(&self) -> Vec<f64> {
+        self.diff
+            .iter()
+            .flat_map(|diff| diff.insert_ops())
+            .flat_map(|op| [op.offset() as f64, op.length() as f64])
+            .collect()
+    }
+
+    /// Total length of the ranges to fetch.
+    #[wasm_bindgen(getter, js_name = fetchLength)]
+    pub fn fetch_length(&self) -> f64 {
+        self.diff.as_ref().map_or(0, |diff| diff.fetch_length()) as f64
+    }
+
+    /// Length of the new version.
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> f64 {
+        self.target.length() as f64
+    }
+
+    /// Builds the new version, the result is verified against the target signature.
+    ///
+    /// # Parameters:
+    /// - `cached`: contents of the cached old version, the same as given to the constructor
+    /// - `fetched`: data of all the ranges, concatenated in the order of `ranges`
+    pub fn build(&self, cached: &[u8], fetched: &[u8]) -> Result<Vec<u8>, JsError> {
+        let diff = match &self.diff {
+            Some(diff) => diff,
+            None => return Ok(cached.to_vec()),
+        };
+
+        let mut segments = FetchedSegments::new();
+        let mut at = 0;
+
+        for op in diff.insert_ops() {
+            let data = fetched.get(at..at + op.length() as usize).ok_or_else(|| {
+                JsError::new(&format!(
+                    "Fetched data is too short, {} bytes are expected",
+                    diff.fetch_length()
+                ))
+            })?;
+
+            segments.insert(op.uuid(), data.to_vec());
+            at += op.length() as usize;
+        }
+
+        if at != fetched.len() {
+            return Err(JsError::new(&format!(
+                "Fetched data is too long, {} bytes are expected",
+                diff.fetch_length()
+            )));
+        }
+
+        builder::build_in_memory(cached, &self.target, diff.operations(), &segments)
+            .map_err(js_error)
+    }
+}
+
+/// Signs the cached file so its chunks line up with the target ones.
+fn sign(data: &[u8], target: &Signature) -> Result<Signature, Box<dyn Error>> {
+    if target.key_id().is_some() {
+        return Err("Keyed signatures can not be matched in the browser".into());
+    }
+
+    if target.decompressed().is_some() {
+        return Err("Signatures made with --decompress can not be matched in the browser".into());
+    }
+
+    let mut options = SignOptions {
+        hash_length: target.hash_length(),
+        ..Default::default()
+    };
+    if let Some(sizes) = target.chunk_sizes() {
+        options = options.with_chunk_sizes(sizes);
+    }
+
+    Signature::generate_with_options(&mut &data[..], &options)
+}
+
+fn js_error(e: Box<dyn Error>) -> JsError {
+    JsError::new(&e.to_string())
+}
@@ -0,0 +1,575 @@
+use md4::{Digest, Md4};
+use sha1::Sha1;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+use crate::platform;
+use crate::rolling::{BlockMatch, Rolling};
+
+/// Version written to control files, the one of zsyncmake they are compatible with
+const ZSYNC_VERSION: &str = "0.6.2";
+
+/// Files up to this length get 2 KiB blocks, longer ones 4 KiB, the same as zsyncmake picks
+const SMALL_FILE_LENGTH: u64 = 100_000_000;
+
+/// Length of MD4 digest
+const MD4_LENGTH: usize = 16;
+
+/// zsync control file: length, SHA-1 and checksums of fixed-size blocks of
+/// a file published on a web server. Blocks are matched with the rsync
+/// rolling checksum and MD4, both truncated, so they can not be converted
+/// to chunks of a signature. Control files of compressed files (`Z-URL`)
+/// are not supported.
+#[derive(Debug, Clone)]
+pub struct ControlFile {
+    /// `Filename` header, name the file is saved as
+    pub filename: String,
+
+    /// `MTime` header as is, an RFC 2822 date
+    pub mtime: Option<String>,
+
+    /// `URL` headers, absolute or relative to the control file
+    pub urls: Vec<String>,
+
+    block_size: usize,
+    length: u64,
+
+    /// consecutive blocks which must match before local data is reused
+    seq_matches: usize,
+
+    /// stored bytes of the rolling checksum of each block
+    rsum_bytes: usize,
+
+    /// stored bytes of MD4 of each block
+    checksum_bytes: usize,
+
+    sha1: [u8; 20],
+
+    /// rolling checksum of each block, `a` in the high half, masked to `rsum_bytes`
+    rsums: Vec<u32>,
+
+    /// truncated MD4 of each block, one after another
+    checksums: Vec<u8>,
+}
+
+impl ControlFile {
+    /// Calculates checksums of a file split into blocks, the last block is
+    /// padded with zeros. Has no URL, see `urls`.
+    ///
+    /// # Parameters:
+    /// - `reader`: file reader
+    /// - `block_size`: block size in bytes, a power of two, see `default_block_size`
+    /// - `filename`: name the file is saved as by clients
+    pub fn generate(
+        reader: &mut dyn Read,
+        block_size: usize,
+        filename: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !block_size.is_power_of_two() {
+            return Err(format!("Block size must be a power of two, got {}", block_size).into());
+        }
+
+        let mut sha1 = Sha1::new();
+        let mut length: u64 = 0;
+        let mut rsums: Vec<u32> = Vec::new();
+        let mut digests: Vec<[u8; MD4_LENGTH]> = Vec::new();
+        let mut block = vec![0u8; block_size];
+
+        loop {
+            let mut filled = 0;
+            while filled < block_size {
+                match reader.read(&mut block[filled..])? {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+
+            if filled == 0 {
+                break;
+            }
+
+            sha1.update(&block[..filled]);
+            length += filled as u64;
+            block[filled..].fill(0);
+
+            rsums.push(rsum(&Rolling::new(&block)));
+            digests.push(Md4::digest(&block).into());
+
+            if filled < block_size {
+                break;
+            }
+        }
+
+        let (seq_matches, rsum_bytes, checksum_bytes) = hash_lengths(length, block_size);
+        let mask = rsum_mask(rsum_bytes);
+
+        Ok(Self {
+            filename: filename.to_string(),
+            mtime: None,
+            urls: Vec::new(),
+            block_size,
+            length,
+            seq_matches,
+            rsum_bytes,
+            checksum_bytes,
+            sha1: sha1.finalize().into(),
+            rsums: rsums.into_iter().map(|rsum| rsum & mask).collect(),
+            checksums: digests
+                .iter()
+                .flat_map(|digest| &digest[..checksum_bytes])
+                .copied()
+                .collect(),
+        })
+    }
+
+    /// Reads a control file written by zsyncmake or `write`.
+    pub fn read(r: &mut dyn BufRead) -> Result<Self, Box<dyn Error>> {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut urls: Vec<String> = Vec::new();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? == 0 {
+                return Err("Unexpected end of the control file headers".into());
+            }
+
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid control file header {:?}", line))?;
+            let value = value.trim().to_string();
+
+            match name {
+                "URL" => urls.push(value),
+                "Z-URL" | "Z-Map2" | "Recompress" => {
+                    return Err("Control files of compressed files are not supported".into())
+                }
+                _ => {
+                    headers.insert(name.to_string(), value);
+                }
+            }
+        }
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .ok_or_else(|| format!("The control file has no {} header", name))
+        };
+
+        header("zsync")?;
+        let block_size: usize = header("Blocksize")?.parse()?;
+        let length: u64 = header("Length")?.parse()?;
+
+        if !block_size.is_power_of_two() {
+            return Err(format!("Invalid block size {}", block_size).into());
+        }
+
+        let lengths: Vec<usize> = header("Hash-Lengths")?
+            .split(',')
+            .map(|part| part.trim().parse())
+            .collect::<Result<_, _>>()?;
+
+        let (seq_matches, rsum_bytes, checksum_bytes) = match lengths[..] {
+            [seq, rsum, checksum]
+                if (1..=2).contains(&seq)
+                    && (1..=4).contains(&rsum)
+                    && (1..=MD4_LENGTH).contains(&checksum) =>
+            {
+                (seq, rsum, checksum)
+            }
+            _ => return Err(format!("Invalid Hash-Lengths {:?}", header("Hash-Lengths")?).into()),
+        };
+
+        let mut sha1 = [0u8; 20];
+        let hex = header("SHA-1")?;
+        if hex.len() != 40 || !hex.is_ascii() {
+            return Err(format!("Invalid SHA-1 {:?}", hex).into());
+        }
+        for (i, byte) in sha1.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+
+        let blocks = length.div_ceil(block_size as u64) as usize;
+        let mut rsums: Vec<u32> = Vec::with_capacity(blocks);
+        let mut checksums: Vec<u8> = vec![0u8; blocks * checksum_bytes];
+        let mut rsum = [0u8; 4];
+
+        for checksum in checksums.chunks_exact_mut(checksum_bytes) {
+            r.read_exact(&mut rsum[4 - rsum_bytes..])?;
+            rsums.push(u32::from_be_bytes(rsum));
+            r.read_exact(checksum)?;
+        }
+
+        Ok(Self {
+            filename: header("Filename")?.clone(),
+            mtime: headers.get("MTime").cloned(),
+            urls,
+            block_size,
+            length,
+            seq_matches,
+            rsum_bytes,
+            checksum_bytes,
+            sha1,
+            rsums,
+            checksums,
+        })
+    }
+
+    /// Writes the control file, zsync 0.6 clients can read it.
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        writeln!(w, "zsync: {}", ZSYNC_VERSION)?;
+        writeln!(w, "Filename: {}", self.filename)?;
+        if let Some(mtime) = &self.mtime {
+            writeln!(w, "MTime: {}", mtime)?;
+        }
+        writeln!(w, "Blocksize: {}", self.block_size)?;
+        writeln!(w, "Length: {}", self.length)?;
+        writeln!(
+            w,
+            "Hash-Lengths: {},{},{}",
+            self.seq_matches, self.rsum_bytes, self.checksum_bytes
+        )?;
+        for url in &self.urls {
+            writeln!(w, "URL: {}", url)?;
+        }
+        writeln!(w, "SHA-1: {}", self.sha1_hex())?;
+        writeln!(w)?;
+
+        for (block, rsum) in self.rsums.iter().enumerate() {
+            w.write_all(&rsum.to_be_bytes()[4 - self.rsum_bytes..])?;
+            w.write_all(self.checksum(block))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.rsums.len()
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        self.sha1
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Searches a local file for blocks of the published file. The rolling
+    /// checksum is moved byte by byte and jumps over a block when it is found.
+    /// With `seq_matches` of 2 a block is reused only if the next block
+    /// follows it in the local file too, or it continues a found run.
+    ///
+    /// # Parameters:
+    /// - `source`: local file contents
+    ///
+    /// # Returns:
+    /// - `Vec<BlockMatch>`: found blocks ordered by offset in the published file,
+    ///   the last one is cut at the file length
+    pub fn find_matches(&self, source: &[u8]) -> Vec<BlockMatch> {
+        let size = self.block_size;
+        let mut matches: Vec<BlockMatch> = Vec::new();
+
+        if self.rsums.is_empty() || source.len() < size {
+            return matches;
+        }
+
+        let mut candidates: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (block, &rsum) in self.rsums.iter().enumerate() {
+            candidates.entry(rsum).or_default().push(block);
+        }
+
+        let mask = rsum_mask(self.rsum_bytes);
+        let mut found: HashSet<usize> = HashSet::new();
+        let mut last: Option<(usize, usize)> = None;
+        let mut position: usize = 0;
+        let mut rolling = Rolling::new(&source[..size]);
+
+        loop {
+            let mut jump = false;
+
+            if let Some(blocks) = candidates.get(&(rsum(&rolling) & mask)) {
+                let window = &source[position..position + size];
+                let digest = Md4::digest(window);
+                let checksum = &digest[..self.checksum_bytes];
+
+                // Identical blocks of the published file are all copied from this window
+                for &block in blocks {
+                    if self.checksum(block) != checksum || found.contains(&block) {
+                        continue;
+                    }
+
+                    let continues = last.is_some_and(|(previous, previous_position)| {
+                        previous + 1 == block && previous_position + size == position
+                    });
+
+                    if !continues && !self.next_matches(source, block, position) {
+                        continue;
+                    }
+
+                    let offset = block as u64 * size as u64;
+                    found.insert(block);
+                    matches.push(BlockMatch {
+                        source_offset: position as u64,
+                        offset,
+                        length: (size as u64).min(self.length - offset),
+                    });
+                    last = Some((block, position));
+                    jump = true;
+                }
+            }
+
+            if jump {
+                position += size;
+                if position + size > source.len() {
+                    break;
+                }
+                rolling = Rolling::new(&source[position..position + size]);
+            } else {
+                if position + size >= source.len() {
+                    break;
+                }
+                rolling.roll(source[position], source[position + size]);
+                position += 1;
+            }
+        }
+
+        matches.sort_by_key(|m| m.offset);
+        matches
+    }
+
+    /// Returns ranges of the published file which are not covered by found blocks.
+    ///
+    /// # Parameters:
+    /// - `matches`: found blocks ordered by offset, see `find_matches`
+    ///
+    /// # Returns:
+    /// - `Vec<(u64, u64)>`: merged `(offset, length)` ranges to fetch
+    pub fn missing_ranges(&self, matches: &[BlockMatch]) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let mut position: u64 = 0;
+
+        for m in matches.iter().chain([&BlockMatch {
+            source_offset: 0,
+            offset: self.length,
+            length: 0,
+        }]) {
+            if m.offset > position {
+                ranges.push((position, m.offset - position));
+            }
+            position = position.max(m.offset + m.length);
+        }
+
+        ranges
+    }
+
+    /// Checks the length and SHA-1 of a local file.
+    pub fn verify(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut sha1 = Sha1::new();
+        let length = std::io::copy(&mut platform::open_shared(path)?, &mut sha1)?;
+
+        if length != self.length || <[u8; 20]>::from(sha1.finalize()) != self.sha1 {
+            return Err(format!(
+                "{} does not match the SHA-1 of the control file",
+                path.display()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn checksum(&self, block: usize) -> &[u8] {
+        &self.checksums[block * self.checksum_bytes..(block + 1) * self.checksum_bytes]
+    }
+
+    /// Returns true if the block after `block` follows it in the local file,
+    /// or no following block is required.
+    fn next_matches(&self, source: &[u8], block: usize, position: usize) -> bool {
+        if self.seq_matches < 2 || block + 1 >= self.blocks() {
+            return true;
+        }
+
+        let next = position + self.block_size;
+        match source.get(next..next + self.block_size) {
+            Some(window) => {
+                rsum(&Rolling::new(window)) & rsum_mask(self.rsum_bytes) == self.rsums[block + 1]
+                    && Md4::digest(window)[..self.checksum_bytes] == *self.checksum(block + 1)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Returns the block size zsyncmake picks for a file length.
+pub fn default_block_size(length: u64) -> usize {
+    match length < SMALL_FILE_LENGTH {
+        true => 2048,
+        false => 4096,
+    }
+}
+
+/// Rolling checksum as zsync stores it: `a` in the high half, `b` in the low one.
+fn rsum(rolling: &Rolling) -> u32 {
+    let (a, b) = rolling.parts();
+    ((a as u32) << 16) | b as u32
+}
+
+fn rsum_mask(rsum_bytes: usize) -> u32 {
+    u32::MAX >> (32 - rsum_bytes * 8)
+}
+
+/// Picks `seq_matches`, `rsum_bytes` and `checksum_bytes` like zsyncmake,
+/// long enough to keep false matches unlikely for the file length.
+fn hash_lengths(length: u64, block_size: usize) -> (usize, usize, usize) {
+    let seq_matches: usize = match length > block_size as u64 {
+        true => 2,
+        false => 1,
+    };
+
+    let log_length = (length.max(1) as f64).log2();
+    let log_blocks = ((1 + length / block_size as u64) as f64).log2();
+
+    let rsum_bytes = (((log_length + (block_size as f64).log2()) - 8.6) / 8.0).ceil();
+    let rsum_bytes = (rsum_bytes as usize).clamp(2, 4);
+
+    let checksum_bytes = ((20.0 + log_length + log_blocks) / seq_matches as f64 / 8.0).ceil();
+    let min_checksum_bytes = ((7.9 + 20.0 + log_blocks) / 8.0) as usize;
+    let checksum_bytes = (checksum_bytes as usize)
+        .max(min_checksum_bytes)
+        .min(MD4_LENGTH);
+
+    (seq_matches, rsum_bytes, checksum_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+    use std::io::Write;
+
+    #[test]
+    fn write_and_read_round_trip() {
+        let data = random_data(1, 10 * 2048 + 100);
+        let mut control = ControlFile::generate(&mut &data[..], 2048, "file.bin").unwrap();
+        control.mtime = Some("Tue, 01 Oct 2024 12:00:00 +0000".to_string());
+        control.urls = vec!["file.bin".to_string(), "http://mirror/file.bin".to_string()];
+
+        let mut written: Vec<u8> = Vec::new();
+        control.write(&mut written).unwrap();
+        let read = ControlFile::read(&mut &written[..]).unwrap();
+
+        assert_eq!(read.filename, control.filename);
+        assert_eq!(read.mtime, control.mtime);
+        assert_eq!(read.urls, control.urls);
+        assert_eq!(read.length(), data.len() as u64);
+        assert_eq!(read.block_size(), 2048);
+        assert_eq!(read.blocks(), 11);
+        assert_eq!(read.sha1_hex(), control.sha1_hex());
+        assert_eq!(
+            (read.seq_matches, read.rsum_bytes, read.checksum_bytes),
+            (
+                control.seq_matches,
+                control.rsum_bytes,
+                control.checksum_bytes
+            )
+        );
+        assert_eq!(read.rsums, control.rsums);
+        assert_eq!(read.checksums, control.checksums);
+
+        let mut rewritten: Vec<u8> = Vec::new();
+        read.write(&mut rewritten).unwrap();
+        assert_eq!(rewritten, written);
+    }
+
+    #[test]
+    fn generate_hashes_the_whole_file_with_sha1() {
+        let control = ControlFile::generate(&mut &b"abc"[..], 2048, "abc").unwrap();
+
+        assert_eq!(
+            control.sha1_hex(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(control.blocks(), 1);
+        assert!(ControlFile::generate(&mut &b"abc"[..], 1000, "abc").is_err());
+    }
+
+    #[test]
+    fn read_rejects_truncated_headers() {
+        assert!(ControlFile::read(&mut &b"zsync: 0.6.2\nFilename: a\n"[..]).is_err());
+    }
+
+    #[test]
+    fn pull_rebuilds_the_file_from_a_shifted_seed() {
+        let published = random_data(2, 40 * 2048 + 1000);
+        let control = ControlFile::generate(&mut &published[..], 2048, "file.bin").unwrap();
+
+        // The seed lacks blocks 10..12 and has the rest shifted by 5 bytes
+        let mut seed = random_data(3, 5);
+        seed.extend_from_slice(&published[..10 * 2048]);
+        seed.extend_from_slice(&published[12 * 2048..]);
+
+        let matches = control.find_matches(&seed);
+        let ranges = control.missing_ranges(&matches);
+
+        // The last block is padded with zeros, the seed ends without them
+        assert_eq!(ranges, vec![(10 * 2048, 2 * 2048), (40 * 2048, 1000)]);
+
+        let mut rebuilt = vec![0u8; published.len()];
+        for m in &matches {
+            let (offset, source) = (m.offset as usize, m.source_offset as usize);
+            rebuilt[offset..offset + m.length as usize]
+                .copy_from_slice(&seed[source..source + m.length as usize]);
+        }
+        for &(offset, length) in &ranges {
+            let range = offset as usize..(offset + length) as usize;
+            rebuilt[range.clone()].copy_from_slice(&published[range]);
+        }
+        assert_eq!(rebuilt, published);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&rebuilt).unwrap();
+        control.verify(file.path()).unwrap();
+
+        file.as_file().set_len(published.len() as u64 - 1).unwrap();
+        assert!(control.verify(file.path()).is_err());
+    }
+
+    #[test]
+    fn find_matches_cuts_the_last_block_at_the_file_length() {
+        let published = random_data(6, 4 * 2048 + 1000);
+        let control = ControlFile::generate(&mut &published[..], 2048, "file.bin").unwrap();
+
+        let mut seed = published.clone();
+        seed.resize(5 * 2048, 0);
+
+        let matches = control.find_matches(&seed);
+
+        assert_eq!(matches.len(), 5);
+        assert_eq!(matches.last().unwrap().length, 1000);
+        assert!(control.missing_ranges(&matches).is_empty());
+    }
+
+    #[test]
+    fn missing_ranges_of_no_matches_is_the_whole_file() {
+        let data = random_data(4, 3 * 2048);
+        let control = ControlFile::generate(&mut &data[..], 2048, "file.bin").unwrap();
+
+        assert!(control.find_matches(&random_data(5, 4096)).is_empty());
+        assert_eq!(control.missing_ranges(&[]), vec![(0, 3 * 2048)]);
+    }
+}